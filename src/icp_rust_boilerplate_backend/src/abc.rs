@@ -0,0 +1,123 @@
+// ABC analysis of a warehouse: its items ranked by the value that moved out over a period,
+// as stock_out quantities (corrections left out) times the current unit cost. The items
+// making up the first 80% of that value are class A, the next 15% class B, and the rest,
+// including items that did not move, class C. Values in different currencies are not
+// comparable, so each currency is ranked on its own; items without a cost are not ranked.
+use crate::ids::{ItemId, WarehouseId};
+use crate::{ledger, store, Error, _get_warehouse};
+use crate::env::time;
+use std::collections::BTreeMap;
+
+const DEFAULT_PERIOD_DAYS: u64 = 90;
+const MAX_PERIOD_DAYS: u64 = 3_650;
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const CLASS_A_SHARE: u128 = 80; // Percent of the value, cumulative
+const CLASS_B_SHARE: u128 = 95;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum AbcClass {
+    A,
+    B,
+    C,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AbcAnalysis {
+    warehouse_id: WarehouseId,
+    since: u64, // Start of the period
+    currencies: Vec<CurrencyAbc>,
+    unpriced_items: Vec<ItemId>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CurrencyAbc {
+    currency: Option<String>, // None for items in the organization's currency
+    total_value: u128,
+    classes: Vec<AbcClassTotals>, // A, B and C, in that order
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AbcClassTotals {
+    class: AbcClass,
+    value: u128,
+    items: Vec<AbcItem>, // Highest value first
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AbcItem {
+    item_id: ItemId,
+    item_name: String,
+    quantity_out: u64,
+    value: u128, // quantity_out × unit_cost
+}
+
+// Over the last `period_days` days, 90 by default
+#[ic_cdk::query]
+fn get_abc_analysis(warehouse_id: WarehouseId, period_days: Option<u64>) -> Result<AbcAnalysis, Error> {
+    _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    let period_days = period_days.unwrap_or(DEFAULT_PERIOD_DAYS);
+    if !(1..=MAX_PERIOD_DAYS).contains(&period_days) {
+        return Err(Error::invalid_input(
+            "period_days",
+            "VALUE_OUT_OF_RANGE",
+            format!("period_days must be between 1 and {}, got {}", MAX_PERIOD_DAYS, period_days),
+        ));
+    }
+    let since = time().saturating_sub(period_days * DAY_NS);
+
+    let quantities_out = ledger::quantities_out(warehouse_id, since);
+    let mut by_currency: BTreeMap<Option<String>, Vec<AbcItem>> = BTreeMap::new();
+    let mut unpriced_items = Vec::new();
+    for item in store::items_in_warehouse(warehouse_id, None, usize::MAX) {
+        let Some(unit_cost) = item.unit_cost else {
+            unpriced_items.push(item.item_id);
+            continue;
+        };
+        let quantity_out = quantities_out.get(&item.item_id).copied().unwrap_or(0);
+        by_currency.entry(item.currency).or_default().push(AbcItem {
+            item_id: item.item_id,
+            item_name: item.item_name,
+            quantity_out,
+            value: quantity_out as u128 * unit_cost as u128,
+        });
+    }
+
+    Ok(AbcAnalysis {
+        warehouse_id,
+        since,
+        currencies: by_currency.into_iter().map(|(currency, items)| classify(currency, items)).collect(),
+        unpriced_items,
+    })
+}
+
+fn classify(currency: Option<String>, mut items: Vec<AbcItem>) -> CurrencyAbc {
+    items.sort_by(|a, b| b.value.cmp(&a.value).then(a.item_id.cmp(&b.item_id)));
+    let total_value: u128 = items.iter().map(|item| item.value).sum();
+    let mut classes: Vec<AbcClassTotals> = [AbcClass::A, AbcClass::B, AbcClass::C]
+        .into_iter()
+        .map(|class| AbcClassTotals { class, value: 0, items: Vec::new() })
+        .collect();
+
+    // An item belongs to the class the value ranked before it ends in
+    let mut cumulative = 0;
+    for item in items {
+        let class = if item.value == 0 {
+            2
+        } else if cumulative * 100 < total_value * CLASS_A_SHARE {
+            0
+        } else if cumulative * 100 < total_value * CLASS_B_SHARE {
+            1
+        } else {
+            2
+        };
+        cumulative += item.value;
+        classes[class].value += item.value;
+        classes[class].items.push(item);
+    }
+
+    CurrencyAbc {
+        currency,
+        total_value,
+        classes,
+    }
+}
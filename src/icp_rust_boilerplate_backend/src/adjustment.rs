@@ -0,0 +1,79 @@
+// Corrections of a stock quantity that are not a movement of goods, e.g. the result of a
+// count. Every adjustment is kept so the correction can be traced back to its cause.
+use crate::ids::{ItemId, WarehouseId};
+use crate::tenancy::caller_org;
+use crate::{Error, StockItem, ADJUSTMENTS};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum AdjustmentReason {
+    CountCorrection,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Adjustment {
+    id: u64,
+    org_id: u64,
+    warehouse_id: WarehouseId,
+    item_id: ItemId,
+    previous_quantity: u64,
+    new_quantity: u64,
+    reason: AdjustmentReason,
+    count_session_id: Option<u64>, // The count that found the difference
+    created_at: u64,
+    created_by: Principal,
+}
+
+impl Storable for Adjustment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Adjustment {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_adjustment(id: u64) -> Result<Adjustment, Error> {
+    ADJUSTMENTS
+        .with(|adjustments| adjustments.borrow().get(&id))
+        .filter(|adjustment| adjustment.org_id == caller_org())
+        .ok_or_else(|| Error::not_found("adjustment", id))
+}
+
+// Records that `item` was changed from `previous_quantity` to its current quantity and
+// returns the id of the adjustment
+pub(crate) fn record(
+    item: &StockItem,
+    previous_quantity: u64,
+    reason: AdjustmentReason,
+    count_session_id: Option<u64>,
+) -> u64 {
+    ADJUSTMENTS.with(|adjustments| {
+        let mut adjustments = adjustments.borrow_mut();
+        let id = adjustments.last_key_value().map_or(1, |(id, _)| id + 1);
+        let adjustment = Adjustment {
+            id,
+            org_id: item.org_id,
+            warehouse_id: item.warehouse_id,
+            item_id: item.item_id,
+            previous_quantity,
+            new_quantity: item.quantity,
+            reason,
+            count_session_id,
+            created_at: time(),
+            created_by: crate::env::caller(),
+        };
+        adjustments.insert(id, adjustment);
+        id
+    })
+}
@@ -0,0 +1,78 @@
+// Aging of the stock in a warehouse: items in stock grouped by the days since the item
+// last changed, from `updated_at`, or `created_at` for an item never changed. Any change
+// counts, so a rename or a move to another bin makes the item young again.
+use crate::ids::{ItemId, WarehouseId};
+use crate::{store, Error, _get_warehouse};
+use crate::env::time;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const BUCKETS: [(u64, Option<u64>); 4] = [(0, Some(30)), (31, Some(90)), (91, Some(180)), (181, None)]; // Days, inclusive
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AgingReport {
+    warehouse_id: WarehouseId,
+    as_of: u64,
+    buckets: Vec<AgingBucket>, // Youngest first
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AgingBucket {
+    min_days: u64,
+    max_days: Option<u64>, // None for the last bucket
+    item_count: u64,
+    items: Vec<AgedItem>, // Oldest first
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AgedItem {
+    item_id: ItemId,
+    item_name: String,
+    quantity: u64,
+    last_changed_at: u64,
+    age_days: u64,
+}
+
+// Items with no stock are left out
+#[ic_cdk::query]
+fn get_aging_report(warehouse_id: WarehouseId) -> Result<AgingReport, Error> {
+    _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    let now = time();
+
+    let mut buckets: Vec<AgingBucket> = BUCKETS
+        .iter()
+        .map(|(min_days, max_days)| AgingBucket {
+            min_days: *min_days,
+            max_days: *max_days,
+            item_count: 0,
+            items: Vec::new(),
+        })
+        .collect();
+    for item in store::items_in_warehouse(warehouse_id, None, usize::MAX) {
+        if item.quantity == 0 {
+            continue;
+        }
+        let last_changed_at = item.updated_at.unwrap_or(item.created_at);
+        let age_days = now.saturating_sub(last_changed_at) / DAY_NS;
+        let bucket = buckets
+            .iter_mut()
+            .find(|bucket| bucket.max_days.is_none_or(|max_days| age_days <= max_days))
+            .expect("the last bucket has no upper bound");
+        bucket.item_count += 1;
+        bucket.items.push(AgedItem {
+            item_id: item.item_id,
+            item_name: item.item_name,
+            quantity: item.quantity,
+            last_changed_at,
+            age_days,
+        });
+    }
+    for bucket in &mut buckets {
+        bucket.items.sort_by_key(|item| (item.last_changed_at, item.item_id));
+    }
+
+    Ok(AgingReport {
+        warehouse_id,
+        as_of: now,
+        buckets,
+    })
+}
@@ -0,0 +1,298 @@
+// Low-stock alerts pushed to webhooks over HTTPS outcalls. Every low_stock_alert_interval_secs
+// (settings.rs) a timer posts, to each webhook that is due, the items of its organization at
+// or below their reorder point; nothing is sent while there are none. A failed delivery is
+// retried with exponential backoff, and the attempt state is kept with the webhook in stable
+// memory so an upgrade does not reset it. The timer pauses in maintenance mode, like the purge.
+use crate::organization::{self, Role};
+use crate::tenancy::caller_org;
+use crate::{idempotency, maintenance, reorder, settings, store, Error, MutationOptions, ALERTS_RUNNING, ALERT_TIMER, WEBHOOKS};
+use crate::_ensure_fits;
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+const MAX_BACKOFF_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_WEBHOOKS: usize = 5; // Per organization
+const MAX_URL_LENGTH: usize = 512;
+const MAX_ALERT_ITEMS: usize = 100; // Per delivery; `truncated` tells the receiver there are more
+const MAX_ERROR_LENGTH: usize = 200;
+const MAX_RESPONSE_BYTES: u64 = 2048; // Only the status is read
+const SUBNET_SIZE: u128 = 13;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Webhook {
+    id: u64,
+    org_id: u64,
+    url: String,
+    created_at: u64,
+    created_by: Principal,
+    failures: u32, // Consecutive failed deliveries
+    next_attempt_at: u64,
+    last_delivered_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl Storable for Webhook {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Webhook {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Webhooks of the caller's organization, for its Admins and Owners
+#[ic_cdk::query]
+fn list_webhooks() -> Result<Vec<Webhook>, Error> {
+    let org_id = caller_org();
+    organization::ensure_role_or_controller(org_id, Role::Admin)?;
+    Ok(webhooks_in_org(org_id))
+}
+
+#[ic_cdk::update]
+fn register_webhook(url: String, options: Option<MutationOptions>) -> Result<Webhook, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("register_webhook", options, || _register_webhook(url))
+}
+
+fn _register_webhook(url: String) -> Result<Webhook, Error> {
+    validate_url(&url)?;
+    let org_id = caller_org();
+    organization::ensure_role_or_controller(org_id, Role::Admin)?;
+    let webhooks = webhooks_in_org(org_id).len();
+    if webhooks >= MAX_WEBHOOKS {
+        return Err(Error::capacity_exceeded(
+            "TOO_MANY_ENTRIES",
+            MAX_WEBHOOKS as u64,
+            webhooks as u64 + 1,
+            format!("An organization has at most {} webhooks", MAX_WEBHOOKS),
+        ));
+    }
+
+    // Webhooks are never re-numbered, so the next id follows the highest one in use
+    let id = WEBHOOKS.with(|webhooks| webhooks.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let webhook = Webhook {
+        id,
+        org_id,
+        url,
+        created_at: time(),
+        created_by: crate::env::caller(),
+        failures: 0,
+        next_attempt_at: 0,
+        last_delivered_at: None,
+        last_error: None,
+    };
+    _ensure_fits("webhook", &webhook)?;
+
+    WEBHOOKS.with(|webhooks| webhooks.borrow_mut().insert(id, webhook.clone()));
+    resume();
+    Ok(webhook)
+}
+
+#[ic_cdk::update]
+fn remove_webhook(id: u64, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("remove_webhook", options, || _remove_webhook(id))
+}
+
+fn _remove_webhook(id: u64) -> Result<(), Error> {
+    let webhook = WEBHOOKS
+        .with(|webhooks| webhooks.borrow().get(&id))
+        .filter(|webhook| webhook.org_id == caller_org())
+        .ok_or_else(|| Error::not_found("webhook", id))?;
+    organization::ensure_role_or_controller(webhook.org_id, Role::Admin)?;
+
+    WEBHOOKS.with(|webhooks| webhooks.borrow_mut().remove(&id));
+    Ok(())
+}
+
+pub(crate) fn validate_url(url: &str) -> Result<(), Error> {
+    if !url.starts_with("https://") || url.len() <= "https://".len() {
+        return Err(Error::invalid_input(
+            "url",
+            "INVALID_URL",
+            format!("url must be an https:// URL, got {:?}", url),
+        ));
+    }
+    if url.len() > MAX_URL_LENGTH {
+        return Err(Error::invalid_input(
+            "url",
+            "VALUE_TOO_LONG",
+            format!("url must be at most {} bytes, got {}", MAX_URL_LENGTH, url.len()),
+        ));
+    }
+    Ok(())
+}
+
+// Removes the webhooks of an organization
+pub(crate) fn clear_org(org_id: u64) {
+    for webhook in webhooks_in_org(org_id) {
+        WEBHOOKS.with(|webhooks| webhooks.borrow_mut().remove(&webhook.id));
+    }
+}
+
+// Timers do not survive upgrades; started again while there is a webhook
+pub(crate) fn resume() {
+    let idle = ALERT_TIMER.with(|timer| timer.borrow().is_none());
+    if idle && WEBHOOKS.with(|webhooks| !webhooks.borrow().is_empty()) {
+        let interval = settings::low_stock_alert_interval();
+        let timer = ic_cdk_timers::set_timer_interval(interval, || ic_cdk::spawn(deliver_due()));
+        ALERT_TIMER.with(|current| *current.borrow_mut() = Some(timer));
+    }
+}
+
+// Starts the timer again with the current interval, after the setting changed
+pub(crate) fn restart() {
+    if let Some(timer) = ALERT_TIMER.with(|timer| timer.borrow_mut().take()) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+    resume();
+}
+
+async fn deliver_due() {
+    if WEBHOOKS.with(|webhooks| webhooks.borrow().is_empty()) {
+        if let Some(timer) = ALERT_TIMER.with(|timer| timer.borrow_mut().take()) {
+            ic_cdk_timers::clear_timer(timer);
+        }
+        return;
+    }
+    // Deliveries can outlast an interval; the next tick then skips
+    if maintenance::is_enabled() || ALERTS_RUNNING.with(|running| running.replace(true)) {
+        return;
+    }
+
+    let now = time();
+    let due: Vec<Webhook> = WEBHOOKS.with(|webhooks| {
+        webhooks
+            .borrow()
+            .iter()
+            .map(|(_, webhook)| webhook)
+            .filter(|webhook| webhook.next_attempt_at <= now)
+            .collect()
+    });
+    for webhook in due {
+        let Some(body) = alert_body(webhook.org_id) else {
+            continue;
+        };
+        let result = post(&webhook.url, body).await;
+        record_attempt(webhook.id, result);
+    }
+
+    ALERTS_RUNNING.with(|running| running.replace(false));
+}
+
+// The JSON payload for an organization, or None when no item is low
+fn alert_body(org_id: u64) -> Option<Vec<u8>> {
+    let items = reorder::low_stock(&store::warehouse_ids_in_org(org_id));
+    if items.is_empty() {
+        return None;
+    }
+    let truncated = items.len() > MAX_ALERT_ITEMS;
+    let items: Vec<serde_json::Value> = items
+        .iter()
+        .take(MAX_ALERT_ITEMS)
+        .map(|item| {
+            serde_json::json!({
+                "item_id": item.item_id,
+                "warehouse_id": item.warehouse_id,
+                "item_name": item.item_name,
+                "quantity": item.quantity,
+                "reorder_point": item.reorder_point,
+                "sku": item.sku,
+            })
+        })
+        .collect();
+    let body = serde_json::json!({
+        "event": "low_stock",
+        "org_id": org_id,
+        "generated_at": time(),
+        "items": items,
+        "truncated": truncated,
+    });
+    Some(body.to_string().into_bytes())
+}
+
+async fn post(url: &str, body: Vec<u8>) -> Result<(), String> {
+    let cycles = outcall_cycles(url.len() + body.len());
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        }],
+        body: Some(body),
+        transform: Some(TransformContext::from_name("transform_webhook_response".to_string(), Vec::new())),
+    };
+
+    match http_request(request, cycles).await {
+        Ok((response,)) if response.status >= 200u32 && response.status < 300u32 => Ok(()),
+        Ok((response,)) => Err(format!("HTTP status {}", response.status)),
+        Err((code, message)) => Err(format!("{:?}: {}", code, message)),
+    }
+}
+
+// Replicas see different headers and bodies; only the status has to agree
+#[ic_cdk::query]
+fn transform_webhook_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+fn record_attempt(webhook_id: u64, result: Result<(), String>) {
+    let now = time();
+    WEBHOOKS.with(|webhooks| {
+        let mut webhooks = webhooks.borrow_mut();
+        // Removed while the request was in flight
+        let Some(mut webhook) = webhooks.get(&webhook_id) else {
+            return;
+        };
+        match result {
+            Ok(()) => {
+                webhook.failures = 0;
+                webhook.next_attempt_at = now;
+                webhook.last_delivered_at = Some(now);
+                webhook.last_error = None;
+            }
+            Err(error) => {
+                webhook.failures = webhook.failures.saturating_add(1);
+                let backoff = (settings::low_stock_alert_interval().as_nanos() as u64).saturating_mul(1 << webhook.failures.min(16));
+                webhook.next_attempt_at = now.saturating_add(backoff.min(MAX_BACKOFF_NS));
+                webhook.last_error = Some(error.chars().take(MAX_ERROR_LENGTH).collect());
+            }
+        }
+        webhooks.insert(webhook_id, webhook);
+    });
+}
+
+// The published outcall price for a request of `request_bytes`; unused cycles are refunded
+fn outcall_cycles(request_bytes: usize) -> u128 {
+    let n = SUBNET_SIZE;
+    (3_000_000 + 60_000 * n) * n + 400 * n * request_bytes as u128 + 800 * n * MAX_RESPONSE_BYTES as u128
+}
+
+fn webhooks_in_org(org_id: u64) -> Vec<Webhook> {
+    WEBHOOKS.with(|webhooks| {
+        webhooks
+            .borrow()
+            .iter()
+            .map(|(_, webhook)| webhook)
+            .filter(|webhook| webhook.org_id == org_id)
+            .collect()
+    })
+}
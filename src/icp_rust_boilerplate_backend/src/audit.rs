@@ -0,0 +1,215 @@
+// Append-only log of every write to a warehouse or stock item. store.rs appends one entry
+// per record it inserts or removes, so each mutation is logged whichever endpoint made it;
+// the method comes from idempotency::run, or from the timer job doing the write. The same
+// log is the change feed of get_changes, which mirrors use to sync incrementally.
+use crate::ids::WarehouseId;
+use crate::organization::{self, Role};
+use crate::settings::page_size;
+use crate::tenancy::caller_org;
+use crate::{Error, StockItem, Warehouse, AUDIT_LOG, AUDIT_METHOD};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::Storable;
+use std::borrow::Cow;
+
+const MAX_SCAN: u64 = 5_000; // Entries read per call while filtering
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum AuditEntity {
+    Warehouse,
+    Item,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum AuditOp {
+    Created,
+    Updated,
+    Removed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AuditEntry {
+    at: u64,
+    caller: Principal,
+    method: String, // "system" for writes outside an update call or timer job
+    org_id: u64,
+    entity: AuditEntity,
+    id: u64,
+    warehouse_id: WarehouseId, // The warehouse itself for warehouse entries
+    op: AuditOp,
+    old_quantity: Option<u64>, // Item entries only
+    new_quantity: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AuditRecord {
+    seq: u64, // Position in the log, from 0
+    entry: AuditEntry,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct AuditFilter {
+    warehouse_id: Option<WarehouseId>,
+    principal: Option<Principal>, // The caller that made the change
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AuditPage {
+    entries: Vec<AuditRecord>,
+    next: Option<u64>, // Pass as `from` to continue; None at the end of the log
+}
+
+// One write as seen by the change feed; read the record itself to get its new state
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ChangeEvent {
+    seq: u64,
+    entity: AuditEntity,
+    id: u64,
+    warehouse_id: WarehouseId,
+    op: AuditOp,
+    at: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ChangePage {
+    changes: Vec<ChangeEvent>,
+    next: u64, // Pass as `since` to continue; equal to `since` when nothing has changed
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Entries from position `from` on, oldest first. Controllers read every organization;
+// Admins and Owners read their own. With a filter a page may hold fewer than `limit`
+// entries while `next` is set, because at most MAX_SCAN entries are read per call.
+#[ic_cdk::query]
+fn get_audit_entries(from: u64, limit: Option<u64>, filter: Option<AuditFilter>) -> Result<AuditPage, Error> {
+    let limit = page_size(limit)?;
+    let org_id = caller_org();
+    organization::ensure_role_or_controller(org_id, Role::Admin)?;
+    let all_orgs = crate::env::is_controller(&crate::env::caller());
+    let filter = filter.unwrap_or_default();
+
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let len = log.len();
+        let end = from.saturating_add(MAX_SCAN).min(len);
+        let mut entries = Vec::new();
+        let mut seq = from;
+        while seq < end && entries.len() < limit {
+            let entry = log.get(seq).unwrap();
+            let visible = (all_orgs || entry.org_id == org_id)
+                && filter.warehouse_id.is_none_or(|id| entry.warehouse_id == id)
+                && filter.principal.is_none_or(|principal| entry.caller == principal);
+            if visible {
+                entries.push(AuditRecord { seq, entry });
+            }
+            seq += 1;
+        }
+        Ok(AuditPage {
+            entries,
+            next: (seq < len).then_some(seq),
+        })
+    })
+}
+
+// Writes to the caller's organization from position `since` on, oldest first. Start from 0
+// and keep the returned `next`. As with get_audit_entries, a page may hold fewer than
+// `limit` changes, or none, before the end of the log.
+#[ic_cdk::query]
+fn get_changes(since: u64, limit: u64) -> Result<ChangePage, Error> {
+    let limit = page_size(Some(limit))?;
+    let org_id = caller_org();
+
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let end = since.saturating_add(MAX_SCAN).min(log.len());
+        let mut changes = Vec::new();
+        let mut seq = since;
+        while seq < end && changes.len() < limit {
+            let entry = log.get(seq).unwrap();
+            if entry.org_id == org_id {
+                changes.push(ChangeEvent {
+                    seq,
+                    entity: entry.entity,
+                    id: entry.id,
+                    warehouse_id: entry.warehouse_id,
+                    op: entry.op,
+                    at: entry.at,
+                });
+            }
+            seq += 1;
+        }
+        Ok(ChangePage {
+            changes,
+            next: seq,
+        })
+    })
+}
+
+// Runs `write` with `method` named in the entries it causes
+pub(crate) fn in_method<T>(method: &str, write: impl FnOnce() -> T) -> T {
+    let outer = AUDIT_METHOD.with(|current| current.replace(Some(method.to_string())));
+    let result = write();
+    AUDIT_METHOD.with(|current| *current.borrow_mut() = outer);
+    result
+}
+
+pub(crate) fn record_warehouse(old: Option<&Warehouse>, new: Option<&Warehouse>) {
+    if let Some(warehouse) = new.or(old) {
+        append(AuditEntity::Warehouse, warehouse.id.into(), warehouse.id, warehouse.org_id, op(old, new), None, None);
+    }
+}
+
+pub(crate) fn record_item(old: Option<&StockItem>, new: Option<&StockItem>) {
+    if let Some(item) = new.or(old) {
+        append(
+            AuditEntity::Item,
+            item.item_id.into(),
+            item.warehouse_id,
+            item.org_id,
+            op(old, new),
+            old.map(|old| old.quantity),
+            new.map(|new| new.quantity),
+        );
+    }
+}
+
+fn op<T>(old: Option<&T>, new: Option<&T>) -> AuditOp {
+    match (old, new) {
+        (None, _) => AuditOp::Created,
+        (Some(_), Some(_)) => AuditOp::Updated,
+        (Some(_), None) => AuditOp::Removed,
+    }
+}
+
+fn append(
+    entity: AuditEntity,
+    id: u64,
+    warehouse_id: WarehouseId,
+    org_id: u64,
+    op: AuditOp,
+    old_quantity: Option<u64>,
+    new_quantity: Option<u64>,
+) {
+    let entry = AuditEntry {
+        at: time(),
+        caller: crate::env::caller(),
+        method: AUDIT_METHOD.with(|current| current.borrow().clone()).unwrap_or_else(|| "system".to_string()),
+        org_id,
+        entity,
+        id,
+        warehouse_id,
+        op,
+        old_quantity,
+        new_quantity,
+    };
+    AUDIT_LOG.with(|log| log.borrow().append(&entry)).expect("audit log is out of stable memory");
+}
@@ -0,0 +1,495 @@
+// Backups of the whole stable state, for disaster-recovery copies off-chain. create_backup
+// copies every memory in metrics::MEMORIES, byte for byte, one after the other into a
+// memory of its own; the backup record says where each memory starts in the copy, and
+// get_backup_chunk serves the copy in BACKUP_CHUNK_BYTES pieces. Only the latest backup is
+// kept. The copy is made in one message, so it is consistent, but a state too large to
+// copy within the instruction limit of a message cannot be backed up this way.
+//
+// A restore takes the backup record and its chunks, in order, while maintenance mode is on,
+// and commit_restore then writes every memory back in one message. The restored state
+// replaces the current one entirely: the stable structures are opened again over it, and
+// it is loaded as after an upgrade, migrations included, so it can be used at once.
+// Maintenance mode stays on after the commit, as it was before it. A backup only restores
+// into a release with the same format and the same memories, and a schema version (see
+// migrations.rs) up to this release's. Controllers only.
+use crate::metrics::MEMORIES;
+use crate::migrations::{self, UpgradeArgs};
+use crate::{idempotency, maintenance, Error, MutationOptions, _ensure_admin, _ensure_fits};
+use crate::{load_state, reopen_stable_state, BACKUPS, BACKUP_DATA, MAINTENANCE, MEMORY_MANAGER, RESTORES, RESTORE_DATA};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Memory, StableCell, Storable, VectorMemory};
+use std::borrow::Cow;
+
+const BACKUP_FORMAT: u32 = 1; // Layout of the copy; bumped whenever it changes
+pub(crate) const BACKUP_CHUNK_BYTES: u64 = 1024 * 1024;
+const NOT_BACKED_UP: &[&str] = &["backup_data", "backups", "restore_data", "restores"]; // Backups and restores themselves
+const PAGE_BYTES: u64 = 64 * 1024;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Backup {
+    id: u64,
+    format: u32,
+    created_at: u64,
+    created_by: Principal,
+    size_bytes: u64,
+    chunk_count: u64,
+    memories: Vec<BackupMemory>, // In the order they were copied
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct BackupMemory {
+    name: String,
+    memory_id: u8,
+    offset: u64, // Of its first byte in the copy
+    size_bytes: u64,
+}
+
+// A restore in progress; chunks arrive in order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RestoreSession {
+    id: u64,
+    backup: Backup,
+    next_chunk: u64,
+    started_at: u64,
+    started_by: Principal,
+}
+
+impl Storable for Backup {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Backup {
+    const MAX_SIZE: u32 = 8 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for RestoreSession {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RestoreSession {
+    const MAX_SIZE: u32 = 9 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_backup(backup_id: u64) -> Result<Backup, Error> {
+    _ensure_admin()?;
+    backup(backup_id)
+}
+
+// Chunks are numbered from 0; the last one can be shorter
+#[ic_cdk::query]
+fn get_backup_chunk(backup_id: u64, index: u64) -> Result<Vec<u8>, Error> {
+    _ensure_admin()?;
+    let backup = backup(backup_id)?;
+    if index >= backup.chunk_count {
+        return Err(Error::invalid_input(
+            "index",
+            "VALUE_OUT_OF_RANGE",
+            format!("index must be below {}, got {}", backup.chunk_count, index),
+        ));
+    }
+    let offset = index * BACKUP_CHUNK_BYTES;
+    let mut chunk = vec![0; BACKUP_CHUNK_BYTES.min(backup.size_bytes - offset) as usize];
+    BACKUP_DATA.with(|data| data.borrow().read(offset, &mut chunk));
+    Ok(chunk)
+}
+
+// Replaces the previous backup
+#[ic_cdk::update]
+fn create_backup(options: Option<MutationOptions>) -> Result<Backup, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("create_backup", options, _create_backup)
+}
+
+fn _create_backup() -> Result<Backup, Error> {
+    _ensure_admin()?;
+
+    let mut memories = Vec::new();
+    let mut size_bytes = 0;
+    for (name, memory_id) in backed_up() {
+        let memory_bytes = memory(memory_id).size() * PAGE_BYTES;
+        memories.push(BackupMemory {
+            name: name.to_string(),
+            memory_id,
+            offset: size_bytes,
+            size_bytes: memory_bytes,
+        });
+        size_bytes += memory_bytes;
+    }
+    BACKUP_DATA.with(|data| ensure_room(&*data.borrow(), size_bytes))?;
+
+    BACKUP_DATA.with(|data| {
+        let data = data.borrow();
+        for copied in &memories {
+            copy(&memory(copied.memory_id), 0, &*data, copied.offset, copied.size_bytes);
+        }
+    });
+
+    let id = BACKUPS.with(|backups| backups.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let backup = Backup {
+        id,
+        format: BACKUP_FORMAT,
+        created_at: time(),
+        created_by: crate::env::caller(),
+        size_bytes,
+        chunk_count: size_bytes.div_ceil(BACKUP_CHUNK_BYTES),
+        memories,
+    };
+    BACKUPS.with(|backups| {
+        let mut backups = backups.borrow_mut();
+        let previous: Vec<u64> = backups.iter().map(|(id, _)| id).collect();
+        for previous_id in previous {
+            backups.remove(&previous_id);
+        }
+        backups.insert(id, backup.clone());
+    });
+    Ok(backup)
+}
+
+// Starts a restore of `backup`, replacing one that was not committed
+#[ic_cdk::update]
+fn begin_restore(backup: Backup, options: Option<MutationOptions>) -> Result<RestoreSession, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("begin_restore", options, || _begin_restore(backup))
+}
+
+fn _begin_restore(backup: Backup) -> Result<RestoreSession, Error> {
+    _ensure_admin()?;
+    ensure_maintenance()?;
+    if backup.format != BACKUP_FORMAT {
+        return Err(Error::invalid_input(
+            "backup.format",
+            "UNSUPPORTED_FORMAT",
+            format!("backup.format must be {}, got {}", BACKUP_FORMAT, backup.format),
+        ));
+    }
+    // The memories of this release, laid out one after the other as create_backup does
+    let mut offset = 0;
+    let mut expected = Vec::new();
+    for (name, memory_id) in backed_up() {
+        let size_bytes = backup.memories.get(expected.len()).map_or(0, |memory| memory.size_bytes);
+        expected.push(BackupMemory {
+            name: name.to_string(),
+            memory_id,
+            offset,
+            size_bytes,
+        });
+        offset += size_bytes;
+    }
+    if backup.memories != expected || backup.size_bytes != offset {
+        return Err(Error::invalid_input(
+            "backup.memories",
+            "SCHEMA_MISMATCH",
+            "backup.memories must list the memories of this release in order, each starting where the previous one ends".to_string(),
+        ));
+    }
+    if backup.chunk_count != backup.size_bytes.div_ceil(BACKUP_CHUNK_BYTES) {
+        return Err(Error::invalid_input(
+            "backup.chunk_count",
+            "VALUE_OUT_OF_RANGE",
+            format!("backup.chunk_count must be {}, got {}", backup.size_bytes.div_ceil(BACKUP_CHUNK_BYTES), backup.chunk_count),
+        ));
+    }
+    RESTORE_DATA.with(|data| ensure_room(&*data.borrow(), backup.size_bytes))?;
+
+    let id = RESTORES.with(|restores| restores.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let session = RestoreSession {
+        id,
+        backup,
+        next_chunk: 0,
+        started_at: time(),
+        started_by: crate::env::caller(),
+    };
+    _ensure_fits("restore", &session)?;
+    RESTORES.with(|restores| {
+        let mut restores = restores.borrow_mut();
+        let previous: Vec<u64> = restores.iter().map(|(id, _)| id).collect();
+        for previous_id in previous {
+            restores.remove(&previous_id);
+        }
+        restores.insert(id, session.clone());
+    });
+    Ok(session)
+}
+
+// Chunk `index` as get_backup_chunk returned it; a chunk already received can be sent again
+#[ic_cdk::update]
+fn upload_restore_chunk(restore_id: u64, index: u64, bytes: Vec<u8>, options: Option<MutationOptions>) -> Result<RestoreSession, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("upload_restore_chunk", options, || _upload_restore_chunk(restore_id, index, bytes))
+}
+
+fn _upload_restore_chunk(restore_id: u64, index: u64, bytes: Vec<u8>) -> Result<RestoreSession, Error> {
+    _ensure_admin()?;
+    ensure_maintenance()?;
+    let mut session = restore(restore_id)?;
+    if index > session.next_chunk || index >= session.backup.chunk_count {
+        return Err(Error::invalid_input(
+            "index",
+            "CHUNK_OUT_OF_ORDER",
+            format!("index must be at most {}, got {}", session.next_chunk.min(session.backup.chunk_count.saturating_sub(1)), index),
+        ));
+    }
+    let offset = index * BACKUP_CHUNK_BYTES;
+    let expected_length = BACKUP_CHUNK_BYTES.min(session.backup.size_bytes - offset);
+    if bytes.len() as u64 != expected_length {
+        return Err(Error::invalid_input(
+            "bytes",
+            "VALUE_OUT_OF_RANGE",
+            format!("Chunk {} must have {} bytes, got {}", index, expected_length, bytes.len()),
+        ));
+    }
+
+    RESTORE_DATA.with(|data| data.borrow().write(offset, &bytes));
+    session.next_chunk = session.next_chunk.max(index + 1);
+    RESTORES.with(|restores| restores.borrow_mut().insert(restore_id, session.clone()));
+    Ok(session)
+}
+
+// The response is stored in the restored state, so a retry with the same key is answered
+// from there
+#[ic_cdk::update]
+fn commit_restore(restore_id: u64, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("commit_restore", options, || _commit_restore(restore_id))
+}
+
+fn _commit_restore(restore_id: u64) -> Result<(), Error> {
+    _ensure_admin()?;
+    ensure_maintenance()?;
+    let session = restore(restore_id)?;
+    if session.next_chunk < session.backup.chunk_count {
+        return Err(Error::conflict(
+            "restore",
+            Some(restore_id),
+            "RESTORE_INCOMPLETE",
+            format!(
+                "Restore with id={} has {} of {} chunks",
+                restore_id, session.next_chunk, session.backup.chunk_count
+            ),
+        ));
+    }
+    let schema_version = staged_schema_version(&session.backup)?;
+    if schema_version > migrations::SCHEMA_VERSION_CURRENT {
+        return Err(Error::conflict(
+            "restore",
+            Some(restore_id),
+            "SCHEMA_MISMATCH",
+            format!(
+                "Backup is in schema version {}, this release only knows up to {}",
+                schema_version,
+                migrations::SCHEMA_VERSION_CURRENT
+            ),
+        ));
+    }
+    // Growing a memory changes none of its contents
+    for restored in &session.backup.memories {
+        ensure_room(&memory(restored.memory_id), restored.size_bytes).map_err(|error| error.with_context(&restored.name))?;
+    }
+
+    let mode = MAINTENANCE.with(|cell| cell.borrow().get().clone());
+    RESTORES.with(|restores| restores.borrow_mut().remove(&restore_id));
+    write_back(&session.backup.memories);
+    MAINTENANCE.with(|cell| cell.borrow_mut().set(mode)).unwrap();
+    // A backup with records from before tenancy has no Owner for them: the migration traps
+    // and the commit is rolled back
+    load_state(&UpgradeArgs::default());
+    Ok(())
+}
+
+// Replaces every memory with its bytes in RESTORE_DATA and opens the structures over them
+fn write_back(memories: &[BackupMemory]) {
+    RESTORE_DATA.with(|data| {
+        let data = data.borrow();
+        for restored in memories {
+            let target = memory(restored.memory_id);
+            copy(&*data, restored.offset, &target, 0, restored.size_bytes);
+            // Whatever the current state holds beyond the restored bytes is cleared
+            clear(&target, restored.size_bytes, target.size() * PAGE_BYTES - restored.size_bytes);
+        }
+    });
+    reopen_stable_state();
+}
+
+// The schema version in the uploaded copy, read from a copy of its memory; 0 when the backup
+// was made before versioning
+fn staged_schema_version(backup: &Backup) -> Result<u64, Error> {
+    let Some(staged) = backup.memories.iter().find(|memory| memory.name == "schema_version") else {
+        return Ok(0);
+    };
+    let mut bytes = vec![0; staged.size_bytes as usize];
+    RESTORE_DATA.with(|data| data.borrow().read(staged.offset, &mut bytes));
+    let copy = VectorMemory::new(std::cell::RefCell::new(bytes));
+    StableCell::init(copy, 0).map(|cell| *cell.get()).map_err(|_| {
+        Error::invalid_input(
+            "backup.memories",
+            "SCHEMA_MISMATCH",
+            "The schema_version memory of the backup does not hold a schema version".to_string(),
+        )
+    })
+}
+
+fn ensure_maintenance() -> Result<(), Error> {
+    if !maintenance::is_enabled() {
+        return Err(Error::conflict(
+            "maintenance",
+            None,
+            "MAINTENANCE_REQUIRED",
+            "A restore needs maintenance mode, so that no call changes the state meanwhile".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn restore(restore_id: u64) -> Result<RestoreSession, Error> {
+    RESTORES
+        .with(|restores| restores.borrow().get(&restore_id))
+        .ok_or_else(|| Error::not_found("restore", restore_id))
+}
+
+fn backup(backup_id: u64) -> Result<Backup, Error> {
+    BACKUPS
+        .with(|backups| backups.borrow().get(&backup_id))
+        .ok_or_else(|| Error::not_found("backup", backup_id))
+}
+
+// The memories a backup holds, in metrics::MEMORIES order
+fn backed_up() -> impl Iterator<Item = (&'static str, u8)> {
+    MEMORIES
+        .iter()
+        .filter(|(name, _)| !NOT_BACKED_UP.contains(name))
+        .map(|(name, memory_id)| (*name, *memory_id))
+}
+
+fn memory(memory_id: u8) -> crate::Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)))
+}
+
+// Grows `memory` to hold `size_bytes`; the pages stay for later copies
+fn ensure_room(memory: &impl Memory, size_bytes: u64) -> Result<(), Error> {
+    let pages = size_bytes.div_ceil(PAGE_BYTES);
+    if pages > memory.size() && memory.grow(pages - memory.size()) < 0 {
+        return Err(Error::capacity_exceeded(
+            "STABLE_MEMORY_FULL",
+            memory.size(),
+            pages,
+            format!("Stable memory has no room to copy {} bytes", size_bytes),
+        ));
+    }
+    Ok(())
+}
+
+// Copies `length` bytes, BACKUP_CHUNK_BYTES at a time
+fn copy(source: &impl Memory, from: u64, target: &impl Memory, to: u64, length: u64) {
+    let mut buffer = vec![0; BACKUP_CHUNK_BYTES.min(length) as usize];
+    let mut copied = 0;
+    while copied < length {
+        let part = BACKUP_CHUNK_BYTES.min(length - copied) as usize;
+        source.read(from + copied, &mut buffer[..part]);
+        target.write(to + copied, &buffer[..part]);
+        copied += part as u64;
+    }
+}
+
+fn clear(target: &impl Memory, from: u64, length: u64) {
+    let zeroes = vec![0; BACKUP_CHUNK_BYTES.min(length) as usize];
+    let mut cleared = 0;
+    while cleared < length {
+        let part = BACKUP_CHUNK_BYTES.min(length - cleared) as usize;
+        target.write(from + cleared, &zeroes[..part]);
+        cleared += part as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ids::WarehouseId;
+    use crate::{PENDING_PURGES, REOPENED_MEMORIES, SCHEMA_VERSION, WAREHOUSE_ID_INCREMENT};
+
+    // The maps migrations.rs empties are read there, under their old bound
+    const V1_MEMORIES: &[u8] = &[2, 3, 60];
+
+    #[test]
+    fn every_backed_up_memory_is_reopened() {
+        for (name, memory_id) in backed_up() {
+            assert!(
+                REOPENED_MEMORIES.contains(&memory_id) || V1_MEMORIES.contains(&memory_id),
+                "{} ({}) is not reopened after a restore",
+                name,
+                memory_id
+            );
+        }
+    }
+
+    #[test]
+    fn write_back_replaces_the_state_with_the_staged_copy() {
+        WAREHOUSE_ID_INCREMENT.with(|cell| cell.borrow_mut().set(5)).unwrap();
+        SCHEMA_VERSION.with(|cell| cell.borrow_mut().set(2)).unwrap();
+        PENDING_PURGES.with(|purges| purges.borrow_mut().insert(WarehouseId(1), ()));
+
+        // Staged as create_backup lays it out and upload_restore_chunk writes it
+        let mut memories = Vec::new();
+        let mut size_bytes = 0;
+        for (name, memory_id) in backed_up() {
+            let memory_bytes = memory(memory_id).size() * PAGE_BYTES;
+            memories.push(BackupMemory {
+                name: name.to_string(),
+                memory_id,
+                offset: size_bytes,
+                size_bytes: memory_bytes,
+            });
+            size_bytes += memory_bytes;
+        }
+        RESTORE_DATA.with(|data| {
+            let data = data.borrow();
+            ensure_room(&*data, size_bytes).unwrap();
+            for staged in &memories {
+                copy(&memory(staged.memory_id), 0, &*data, staged.offset, staged.size_bytes);
+            }
+        });
+        let backup = Backup {
+            id: 1,
+            format: BACKUP_FORMAT,
+            created_at: 0,
+            created_by: Principal::anonymous(),
+            size_bytes,
+            chunk_count: size_bytes.div_ceil(BACKUP_CHUNK_BYTES),
+            memories,
+        };
+        assert_eq!(staged_schema_version(&backup).unwrap(), 2);
+
+        WAREHOUSE_ID_INCREMENT.with(|cell| cell.borrow_mut().set(9)).unwrap();
+        SCHEMA_VERSION.with(|cell| cell.borrow_mut().set(3)).unwrap();
+        PENDING_PURGES.with(|purges| {
+            let mut purges = purges.borrow_mut();
+            purges.remove(&WarehouseId(1));
+            purges.insert(WarehouseId(2), ());
+        });
+
+        write_back(&backup.memories);
+        assert_eq!(WAREHOUSE_ID_INCREMENT.with(|cell| *cell.borrow().get()), 5);
+        assert_eq!(SCHEMA_VERSION.with(|cell| *cell.borrow().get()), 2);
+        PENDING_PURGES.with(|purges| {
+            let purges = purges.borrow();
+            assert!(purges.contains_key(&WarehouseId(1)));
+            assert!(!purges.contains_key(&WarehouseId(2)));
+        });
+    }
+}
@@ -0,0 +1,269 @@
+// Several reads, or several writes, in one message. get_many answers each lookup on its
+// own, and add_items_to_warehouse adds each item on its own, like add_item_to_warehouse;
+// apply_batch and transfer_items apply all their changes or none. Every operation is
+// validated before the first is applied, and one that then fails traps, which rolls back
+// those before it: the reject message is "op N: CODE: msg" or "line N: CODE: msg".
+use crate::ids::{ItemId, WarehouseId};
+use crate::idempotency::{self, MutationOptions};
+use crate::tenancy::caller_org;
+use crate::totals::{self, InventoryTotals, WarehouseTotals};
+use crate::{check_stock, counting, get_warehouse, killswitch, reservation, units, validation};
+use crate::{Error, StockItem, StockItemPayload, UpdateItemPayload, Warehouse};
+use crate::{_add_item_to_warehouse, _add_quantity, _delete_item, _ensure_not_archived, _get_item, _get_warehouse};
+use crate::{_transfer_item, _update_item};
+use std::collections::BTreeSet;
+
+const MAX_READS: usize = 100; // Lookups per get_many call
+const MAX_OPS: usize = 100; // Operations per apply_batch call
+const MAX_ADDS: usize = 100; // Items per add_items_to_warehouse call
+pub(crate) const MAX_TRANSFERS: usize = 100; // Lines per transfer_items call
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum ReadRequest {
+    Warehouse(WarehouseId),
+    Item(ItemId),
+    InventoryTotals,
+    WarehouseTotals(WarehouseId),
+}
+
+// One response per request, in request order; a failed lookup does not fail the batch
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum ReadResponse {
+    Warehouse(Result<Warehouse, Error>),
+    Item(Result<StockItem, Error>),
+    InventoryTotals(InventoryTotals),
+    WarehouseTotals(Result<WarehouseTotals, Error>),
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum InventoryOp {
+    AddItem(StockItemPayload),
+    AdjustQuantity { item_id: ItemId, delta: i64 }, // A negative delta removes stock like delete_item
+    Transfer { item_id: ItemId, from_warehouse_id: WarehouseId, to_warehouse_id: WarehouseId, quantity: u64 },
+    DeleteItem { item_id: ItemId, quantity: u64 },
+}
+
+// One result per operation, in operation order
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum OpResult {
+    AddItem(StockItem),
+    AdjustQuantity(StockItem),
+    Transfer,
+    DeleteItem(StockItem),
+}
+
+#[ic_cdk::query]
+fn get_many(requests: Vec<ReadRequest>) -> Result<Vec<ReadResponse>, Error> {
+    if requests.len() > MAX_READS {
+        return Err(Error::invalid_input(
+            "requests",
+            "TOO_MANY_ENTRIES",
+            format!("requests must have at most {} entries, got {}", MAX_READS, requests.len()),
+        ));
+    }
+
+    Ok(requests
+        .into_iter()
+        .map(|request| match request {
+            ReadRequest::Warehouse(id) => ReadResponse::Warehouse(get_warehouse(id)),
+            ReadRequest::Item(id) => ReadResponse::Item(check_stock(id)),
+            ReadRequest::InventoryTotals => ReadResponse::InventoryTotals(totals::org_totals(caller_org())),
+            ReadRequest::WarehouseTotals(id) => {
+                ReadResponse::WarehouseTotals(totals::get_warehouse_totals(id))
+            }
+        })
+        .collect())
+}
+
+// One result per item, in request order. The warehouse_id of each payload is replaced by
+// `warehouse_id`; an item whose name is already in the warehouse, or earlier in the list,
+// is added to that item's quantity.
+#[ic_cdk::update]
+fn add_items_to_warehouse(
+    warehouse_id: WarehouseId,
+    items: Vec<StockItemPayload>,
+    options: Option<MutationOptions>,
+) -> Result<Vec<Result<StockItem, Error>>, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("add_items_to_warehouse", options, || _add_items_to_warehouse(warehouse_id, items))
+}
+
+fn _add_items_to_warehouse(warehouse_id: WarehouseId, items: Vec<StockItemPayload>) -> Result<Vec<Result<StockItem, Error>>, Error> {
+    validate_add_count(items.len())?;
+    killswitch::ensure_enabled("add_item_to_warehouse")?;
+    _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+
+    Ok(items
+        .into_iter()
+        .map(|payload| _add_item_to_warehouse(StockItemPayload { warehouse_id, ..payload }, None))
+        .collect())
+}
+
+pub(crate) fn validate_add_count(count: usize) -> Result<(), Error> {
+    if count == 0 || count > MAX_ADDS {
+        return Err(Error::invalid_input(
+            "items",
+            "VALUE_OUT_OF_RANGE",
+            format!("items must have 1 to {} entries, got {}", MAX_ADDS, count),
+        ));
+    }
+    Ok(())
+}
+
+// Moves `quantity` of each item from one warehouse to the other, like transfer_item. Every
+// line is checked before the first moves; a line that still fails then, e.g. on a quota,
+// traps like a failing apply_batch operation.
+#[ic_cdk::update]
+fn transfer_items(
+    from_warehouse_id: WarehouseId,
+    to_warehouse_id: WarehouseId,
+    lines: Vec<(ItemId, u64)>,
+    options: Option<MutationOptions>,
+) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("transfer_items", options, || _transfer_items(from_warehouse_id, to_warehouse_id, lines))
+}
+
+fn _transfer_items(from_warehouse_id: WarehouseId, to_warehouse_id: WarehouseId, lines: Vec<(ItemId, u64)>) -> Result<(), Error> {
+    validate_transfer_lines(&lines)?;
+    killswitch::ensure_enabled("transfer_item")?;
+    if from_warehouse_id == to_warehouse_id {
+        return Err(Error::invalid_input(
+            "to_warehouse_id",
+            "SAME_WAREHOUSE",
+            "to_warehouse_id must differ from from_warehouse_id".to_string(),
+        ));
+    }
+    for warehouse_id in [from_warehouse_id, to_warehouse_id] {
+        let warehouse = _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+        counting::ensure_unfrozen(warehouse_id)?;
+        if warehouse_id == to_warehouse_id {
+            _ensure_not_archived(&warehouse)?;
+        }
+    }
+    for (item_id, quantity) in &lines {
+        let item = _get_item(item_id).ok_or_else(|| Error::not_found("item", *item_id))?;
+        if item.warehouse_id != from_warehouse_id {
+            return Err(Error::conflict(
+                "item",
+                Some((*item_id).into()),
+                "ITEM_NOT_IN_WAREHOUSE",
+                format!(
+                    "Item with id={} is stored in warehouse_id={}, not warehouse_id={}",
+                    item_id, item.warehouse_id, from_warehouse_id
+                ),
+            ));
+        }
+        reservation::ensure_available(&item, *quantity)?;
+    }
+
+    for (index, (item_id, quantity)) in lines.into_iter().enumerate() {
+        if let Err(error) = _transfer_item(item_id, from_warehouse_id, to_warehouse_id, quantity, None) {
+            ic_cdk::trap(&format!("line {}: {}", index, error.summary()));
+        }
+    }
+    Ok(())
+}
+
+// Lines are (item_id, quantity); an item is on at most one line
+pub(crate) fn validate_transfer_lines(lines: &[(ItemId, u64)]) -> Result<(), Error> {
+    if lines.is_empty() || lines.len() > MAX_TRANSFERS {
+        return Err(Error::invalid_input(
+            "lines",
+            "VALUE_OUT_OF_RANGE",
+            format!("lines must have 1 to {} entries, got {}", MAX_TRANSFERS, lines.len()),
+        ));
+    }
+    let mut item_ids = BTreeSet::new();
+    for (item_id, quantity) in lines {
+        validation::validate_quantity("quantity", *quantity)?;
+        if !item_ids.insert(*item_id) {
+            return Err(Error::invalid_input(
+                "lines",
+                "DUPLICATE_LINE",
+                format!("Item with id={} is on more than one line", item_id),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn apply_batch(ops: Vec<InventoryOp>, options: Option<MutationOptions>) -> Result<Vec<OpResult>, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("apply_batch", options, || _apply_batch(ops))
+}
+
+fn _apply_batch(ops: Vec<InventoryOp>) -> Result<Vec<OpResult>, Error> {
+    validate_ops(&ops)?;
+
+    let mut results = Vec::with_capacity(ops.len());
+    for (index, op) in ops.into_iter().enumerate() {
+        match apply(op) {
+            Ok(result) => results.push(result),
+            Err(error) => ic_cdk::trap(&format!("op {}: {}", index, error.summary())),
+        }
+    }
+    Ok(results)
+}
+
+pub(crate) fn apply(op: InventoryOp) -> Result<OpResult, Error> {
+    match op {
+        InventoryOp::AddItem(payload) => _add_item_to_warehouse(payload, None).map(OpResult::AddItem),
+        InventoryOp::AdjustQuantity { item_id, delta } if delta < 0 => {
+            _delete_item(item_id, delta.unsigned_abs(), None).map(OpResult::AdjustQuantity)
+        }
+        InventoryOp::AdjustQuantity { item_id, delta } => {
+            let item = _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+            let payload = UpdateItemPayload {
+                quantity: Some(_add_quantity(item_id, item.quantity, delta as u64)?),
+                ..Default::default()
+            };
+            _update_item(item_id, payload, None).map(OpResult::AdjustQuantity)
+        }
+        InventoryOp::Transfer { item_id, from_warehouse_id, to_warehouse_id, quantity } => {
+            _transfer_item(item_id, from_warehouse_id, to_warehouse_id, quantity, None).map(|()| OpResult::Transfer)
+        }
+        InventoryOp::DeleteItem { item_id, quantity } => _delete_item(item_id, quantity, None).map(OpResult::DeleteItem),
+    }
+}
+
+// The checks that do not depend on stored state; an endpoint switched off by the kill
+// switch cannot be reached through a batch either
+pub(crate) fn validate_ops(ops: &[InventoryOp]) -> Result<(), Error> {
+    if ops.is_empty() || ops.len() > MAX_OPS {
+        return Err(Error::invalid_input(
+            "ops",
+            "VALUE_OUT_OF_RANGE",
+            format!("ops must have 1 to {} entries, got {}", MAX_OPS, ops.len()),
+        ));
+    }
+    for op in ops {
+        let method = match op {
+            InventoryOp::AddItem(payload) => {
+                validation::validate_name("item_name", &payload.item_name)?;
+                validation::validate_quantity("quantity", payload.quantity)?;
+                if let Some(unit) = payload.unit {
+                    units::validate_unit("unit", unit)?;
+                }
+                "add_item_to_warehouse"
+            }
+            InventoryOp::AdjustQuantity { delta, .. } => {
+                if *delta == 0 {
+                    return Err(Error::invalid_input("delta", "VALUE_NOT_POSITIVE", "delta must not be zero".to_string()));
+                }
+                if *delta < 0 { "delete_item" } else { "update_item" }
+            }
+            InventoryOp::Transfer { quantity, .. } => {
+                validation::validate_quantity("quantity", *quantity)?;
+                "transfer_item"
+            }
+            InventoryOp::DeleteItem { quantity, .. } => {
+                validation::validate_quantity("quantity", *quantity)?;
+                "delete_item"
+            }
+        };
+        killswitch::ensure_enabled(method)?;
+    }
+    Ok(())
+}
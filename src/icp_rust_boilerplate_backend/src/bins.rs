@@ -0,0 +1,225 @@
+// Put-away locations inside a warehouse, such as a shelf or a pallet space. Admins and
+// Owners, or controllers, set up the bins of a warehouse; any member moves items into
+// them. An item is in at most one bin of its own warehouse and leaves it when it moves to
+// another warehouse. Moving between bins moves the whole item record: an item name is
+// unique within a warehouse, so stock of one item cannot be split across bins.
+// A bin is only deleted once it is empty; the bins of a deleted warehouse go with its stock.
+use crate::ids::{ItemId, WarehouseId};
+use crate::organization::{self, Role};
+use crate::store::name_key;
+use crate::tenancy::caller_org;
+use crate::{idempotency, store, validation, Error, MutationOptions, StockItem, BINS, ITEMS_BY_BIN, WAREHOUSE_BINS};
+use crate::{_check_version, _ensure_fits, _ensure_not_archived, _get_item, _get_warehouse};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+pub(crate) const MAX_BINS: usize = 1_000; // Per warehouse
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Bin {
+    id: u64,
+    org_id: u64,
+    warehouse_id: WarehouseId,
+    code: String, // Unique within the warehouse, ignoring case
+    description: Option<String>,
+    created_at: u64,
+    created_by: Principal,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct BinPayload {
+    code: String,
+    description: Option<String>,
+}
+
+impl Storable for Bin {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Bin {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_bin(id: u64) -> Result<Bin, Error> {
+    bin(id).ok_or_else(|| Error::not_found("bin", id))
+}
+
+// Bins of a warehouse in id order
+#[ic_cdk::query]
+fn list_bins(warehouse_id: WarehouseId) -> Result<Vec<Bin>, Error> {
+    _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    Ok(bins_in_warehouse(warehouse_id))
+}
+
+// Items in a bin in id order
+#[ic_cdk::query]
+fn get_bin_stock(bin_id: u64) -> Result<Vec<StockItem>, Error> {
+    bin(bin_id).ok_or_else(|| Error::not_found("bin", bin_id))?;
+    Ok(item_ids_in_bin(bin_id).iter().filter_map(_get_item).collect())
+}
+
+#[ic_cdk::update]
+fn create_bin(warehouse_id: WarehouseId, payload: BinPayload, options: Option<MutationOptions>) -> Result<Bin, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("create_bin", options, || _create_bin(warehouse_id, payload))
+}
+
+fn _create_bin(warehouse_id: WarehouseId, payload: BinPayload) -> Result<Bin, Error> {
+    let warehouse = _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    organization::ensure_role_or_controller(warehouse.org_id, Role::Admin)?;
+    _ensure_not_archived(&warehouse)?;
+
+    let bins = bins_in_warehouse(warehouse_id);
+    if bins.len() >= MAX_BINS {
+        return Err(Error::capacity_exceeded(
+            "TOO_MANY_ENTRIES",
+            MAX_BINS as u64,
+            bins.len() as u64 + 1,
+            format!("A warehouse has at most {} bins", MAX_BINS),
+        ));
+    }
+    let code = validation::validate_name("code", &payload.code)?;
+    let key = name_key(&code);
+    if let Some(existing) = bins.iter().find(|other| name_key(&other.code) == key) {
+        return Err(Error::conflict(
+            "bin",
+            Some(existing.id),
+            "DUPLICATE_BIN_CODE",
+            format!("Bin with id={} already uses the code {:?}", existing.id, code),
+        ));
+    }
+
+    // Bins are never re-numbered, so the next id follows the highest one in use
+    let id = BINS.with(|bins| bins.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let bin = Bin {
+        id,
+        org_id: warehouse.org_id,
+        warehouse_id,
+        code,
+        description: payload.description.map(|description| description.trim().to_string()).filter(|description| !description.is_empty()),
+        created_at: time(),
+        created_by: crate::env::caller(),
+    };
+    _ensure_fits("bin", &bin)?;
+
+    BINS.with(|bins| bins.borrow_mut().insert(id, bin.clone()));
+    WAREHOUSE_BINS.with(|index| index.borrow_mut().insert((warehouse_id, id), ()));
+    Ok(bin)
+}
+
+#[ic_cdk::update]
+fn delete_bin(id: u64, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("delete_bin", options, || _delete_bin(id))
+}
+
+fn _delete_bin(id: u64) -> Result<(), Error> {
+    let bin = bin(id).ok_or_else(|| Error::not_found("bin", id))?;
+    organization::ensure_role_or_controller(bin.org_id, Role::Admin)?;
+    if !item_ids_in_bin(id).is_empty() {
+        return Err(Error::conflict(
+            "bin",
+            Some(id),
+            "BIN_IN_USE",
+            format!("Bin with id={} still has items", id),
+        ));
+    }
+
+    remove_bin(&bin);
+    Ok(())
+}
+
+// `expected_version` refers to the item; None takes the item out of its bin
+#[ic_cdk::update]
+fn move_item_to_bin(item_id: ItemId, bin_id: Option<u64>, options: Option<MutationOptions>) -> Result<StockItem, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("move_item_to_bin", options, || _move_item_to_bin(item_id, bin_id, expected_version))
+}
+
+fn _move_item_to_bin(item_id: ItemId, bin_id: Option<u64>, expected_version: Option<u64>) -> Result<StockItem, Error> {
+    let mut item = _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    _check_version("item", item_id, item.version, expected_version)?;
+    if let Some(bin_id) = bin_id {
+        let bin = bin(bin_id).ok_or_else(|| Error::not_found("bin", bin_id))?;
+        if bin.warehouse_id != item.warehouse_id {
+            return Err(Error::conflict(
+                "bin",
+                Some(bin_id),
+                "BIN_IN_OTHER_WAREHOUSE",
+                format!("Bin with id={} is not in warehouse with id={}", bin_id, item.warehouse_id),
+            ));
+        }
+    }
+    if item.bin_id == bin_id {
+        return Ok(item);
+    }
+
+    item.bin_id = bin_id;
+    item.updated_at = Some(time());
+    item.updated_by = Some(crate::env::caller());
+    item.version += 1;
+    _ensure_fits("item", &item)?;
+
+    store::insert_item(item.clone());
+    Ok(item)
+}
+
+pub(crate) fn validate_payload(payload: &BinPayload) -> Result<(), Error> {
+    validation::validate_name("code", &payload.code).map(|_| ())
+}
+
+// Removes the bins of a warehouse whose items are all gone
+pub(crate) fn clear_warehouse(warehouse_id: WarehouseId) {
+    for bin in bins_in_warehouse(warehouse_id) {
+        remove_bin(&bin);
+    }
+}
+
+// Whether a bin of the warehouse exists; a restored item only returns to a bin that is still there
+pub(crate) fn exists_in(bin_id: u64, warehouse_id: WarehouseId) -> bool {
+    BINS.with(|bins| bins.borrow().get(&bin_id)).is_some_and(|bin| bin.warehouse_id == warehouse_id)
+}
+
+fn remove_bin(bin: &Bin) {
+    BINS.with(|bins| bins.borrow_mut().remove(&bin.id));
+    WAREHOUSE_BINS.with(|index| index.borrow_mut().remove(&(bin.warehouse_id, bin.id)));
+}
+
+// A bin of the caller's organization
+fn bin(id: u64) -> Option<Bin> {
+    BINS.with(|bins| bins.borrow().get(&id)).filter(|bin| bin.org_id == caller_org())
+}
+
+fn bins_in_warehouse(warehouse_id: WarehouseId) -> Vec<Bin> {
+    let bin_ids: Vec<u64> = WAREHOUSE_BINS.with(|index| {
+        index
+            .borrow()
+            .range((warehouse_id, 0)..=(warehouse_id, u64::MAX))
+            .map(|((_, bin_id), _)| bin_id)
+            .collect()
+    });
+    BINS.with(|bins| {
+        let bins = bins.borrow();
+        bin_ids.iter().filter_map(|id| bins.get(id)).collect()
+    })
+}
+
+fn item_ids_in_bin(bin_id: u64) -> Vec<ItemId> {
+    ITEMS_BY_BIN.with(|index| {
+        index
+            .borrow()
+            .range((bin_id, ItemId(0))..=(bin_id, ItemId(u64::MAX)))
+            .map(|((_, item_id), _)| item_id)
+            .collect()
+    })
+}
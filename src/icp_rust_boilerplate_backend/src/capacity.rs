@@ -0,0 +1,28 @@
+// Stable memory use against the per-canister limit, for controllers to see how much room
+// is left.
+use crate::{Error, _ensure_admin};
+use ic_cdk::api::stable::{stable64_size, WASM_PAGE_SIZE_IN_BYTES};
+
+const STABLE_MEMORY_LIMIT_BYTES: u64 = 400 * 1024 * 1024 * 1024; // Per canister
+const NEAR_LIMIT_PERCENT: u64 = 80;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct StorageStatus {
+    stable_memory_bytes: u64,
+    limit_bytes: u64,
+    used_percent: u64,
+    near_limit: bool, // At or above NEAR_LIMIT_PERCENT
+}
+
+#[ic_cdk::query]
+fn get_storage_status() -> Result<StorageStatus, Error> {
+    _ensure_admin()?;
+    let used = stable64_size() * WASM_PAGE_SIZE_IN_BYTES as u64;
+    let used_percent = used * 100 / STABLE_MEMORY_LIMIT_BYTES;
+    Ok(StorageStatus {
+        stable_memory_bytes: used,
+        limit_bytes: STABLE_MEMORY_LIMIT_BYTES,
+        used_percent,
+        near_limit: used_percent >= NEAR_LIMIT_PERCENT,
+    })
+}
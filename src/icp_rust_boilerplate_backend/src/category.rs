@@ -0,0 +1,317 @@
+// Product families of an organization, as a tree: a category without a parent is a root.
+// Admins and Owners, or controllers, manage the tree; any member files items under it.
+// A category is only deleted once no item and no other category refers to it.
+use crate::ids::ItemId;
+use crate::organization::{self, Role};
+use crate::store::name_key;
+use crate::tenancy::caller_org;
+use crate::{idempotency, store, validation, Error, MutationOptions, StockItem, CATEGORIES, ITEMS_BY_CATEGORY};
+use crate::{_check_version, _ensure_fits, _get_item};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+pub(crate) const MAX_CATEGORIES: usize = 500; // Per organization
+pub(crate) const MAX_DEPTH: usize = 8; // Categories on the path from a root, the root included
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Category {
+    id: u64,
+    org_id: u64,
+    name: String,
+    parent_id: Option<u64>,
+    created_at: u64,
+    created_by: Principal,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CategoryPayload {
+    name: String,
+    parent_id: Option<u64>,
+}
+
+impl Storable for Category {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Category {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_category(id: u64) -> Result<Category, Error> {
+    category(id).ok_or_else(|| Error::not_found("category", id))
+}
+
+// Categories of the caller's organization in id order
+#[ic_cdk::query]
+fn list_categories() -> Vec<Category> {
+    categories_in_org(caller_org())
+}
+
+// With `include_descendants`, also the items of every category below it
+#[ic_cdk::query]
+fn get_items_by_category(category_id: u64, include_descendants: bool) -> Result<Vec<StockItem>, Error> {
+    category(category_id).ok_or_else(|| Error::not_found("category", category_id))?;
+    let category_ids = if include_descendants {
+        subtree(caller_org(), category_id)
+    } else {
+        vec![category_id]
+    };
+
+    let item_ids: Vec<ItemId> = ITEMS_BY_CATEGORY.with(|index| {
+        let index = index.borrow();
+        category_ids
+            .iter()
+            .flat_map(|id| index.range((*id, ItemId(0))..=(*id, ItemId(u64::MAX))).map(|((_, item_id), _)| item_id))
+            .collect()
+    });
+    Ok(item_ids.iter().filter_map(_get_item).collect())
+}
+
+#[ic_cdk::update]
+fn create_category(payload: CategoryPayload, options: Option<MutationOptions>) -> Result<Category, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("create_category", options, || _create_category(payload))
+}
+
+fn _create_category(payload: CategoryPayload) -> Result<Category, Error> {
+    let org_id = caller_org();
+    organization::ensure_role_or_controller(org_id, Role::Admin)?;
+    let categories = categories_in_org(org_id).len();
+    if categories >= MAX_CATEGORIES {
+        return Err(Error::capacity_exceeded(
+            "TOO_MANY_ENTRIES",
+            MAX_CATEGORIES as u64,
+            categories as u64 + 1,
+            format!("An organization has at most {} categories", MAX_CATEGORIES),
+        ));
+    }
+
+    // Categories are never re-numbered, so the next id follows the highest one in use
+    let id = CATEGORIES.with(|categories| categories.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let category = build_category(id, org_id, payload)?;
+    _ensure_fits("category", &category)?;
+
+    CATEGORIES.with(|categories| categories.borrow_mut().insert(id, category.clone()));
+    Ok(category)
+}
+
+// Replaces the name and the parent; the category keeps its items and children
+#[ic_cdk::update]
+fn update_category(id: u64, payload: CategoryPayload, options: Option<MutationOptions>) -> Result<Category, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("update_category", options, || _update_category(id, payload))
+}
+
+fn _update_category(id: u64, payload: CategoryPayload) -> Result<Category, Error> {
+    let existing = category(id).ok_or_else(|| Error::not_found("category", id))?;
+    organization::ensure_role_or_controller(existing.org_id, Role::Admin)?;
+
+    let category = Category {
+        created_at: existing.created_at,
+        created_by: existing.created_by,
+        updated_at: Some(time()),
+        ..build_category(id, existing.org_id, payload)?
+    };
+    // The subtree moves along, so it must still fit under the new parent
+    let depth = depth(category.parent_id) + subtree_height(category.org_id, id);
+    if depth > MAX_DEPTH {
+        return Err(too_deep(depth));
+    }
+    _ensure_fits("category", &category)?;
+
+    CATEGORIES.with(|categories| categories.borrow_mut().insert(id, category.clone()));
+    Ok(category)
+}
+
+#[ic_cdk::update]
+fn delete_category(id: u64, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("delete_category", options, || _delete_category(id))
+}
+
+fn _delete_category(id: u64) -> Result<(), Error> {
+    let category = category(id).ok_or_else(|| Error::not_found("category", id))?;
+    organization::ensure_role_or_controller(category.org_id, Role::Admin)?;
+
+    let in_use = ITEMS_BY_CATEGORY.with(|index| index.borrow().range((id, ItemId(0))..=(id, ItemId(u64::MAX))).next().is_some())
+        || categories_in_org(category.org_id).iter().any(|child| child.parent_id == Some(id));
+    if in_use {
+        return Err(Error::conflict(
+            "category",
+            Some(id),
+            "CATEGORY_IN_USE",
+            format!("Category with id={} still has items or subcategories", id),
+        ));
+    }
+
+    CATEGORIES.with(|categories| categories.borrow_mut().remove(&id));
+    Ok(())
+}
+
+// `expected_version` refers to the item; None files the item under no category
+#[ic_cdk::update]
+fn set_item_category(item_id: ItemId, category_id: Option<u64>, options: Option<MutationOptions>) -> Result<StockItem, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("set_item_category", options, || _set_item_category(item_id, category_id, expected_version))
+}
+
+fn _set_item_category(item_id: ItemId, category_id: Option<u64>, expected_version: Option<u64>) -> Result<StockItem, Error> {
+    let mut item = _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    _check_version("item", item_id, item.version, expected_version)?;
+    if let Some(category_id) = category_id {
+        category(category_id).ok_or_else(|| Error::not_found("category", category_id))?;
+    }
+    if item.category_id == category_id {
+        return Ok(item);
+    }
+
+    item.category_id = category_id;
+    item.updated_at = Some(time());
+    item.updated_by = Some(crate::env::caller());
+    item.version += 1;
+    _ensure_fits("item", &item)?;
+
+    store::insert_item(item.clone());
+    Ok(item)
+}
+
+pub(crate) fn validate_payload(payload: &CategoryPayload) -> Result<(), Error> {
+    validation::validate_name("name", &payload.name).map(|_| ())
+}
+
+// Removes the categories of an organization whose items are all gone
+pub(crate) fn clear_org(org_id: u64) {
+    for category in categories_in_org(org_id) {
+        CATEGORIES.with(|categories| categories.borrow_mut().remove(&category.id));
+    }
+}
+
+// Checks the name and the parent; the name is unique among the children of the parent
+fn build_category(id: u64, org_id: u64, payload: CategoryPayload) -> Result<Category, Error> {
+    let name = validation::validate_name("name", &payload.name)?;
+    if let Some(parent_id) = payload.parent_id {
+        let parent = category(parent_id)
+            .filter(|parent| parent.org_id == org_id)
+            .ok_or_else(|| Error::not_found("category", parent_id))?;
+        if parent_id == id || ancestors(parent.parent_id).contains(&id) {
+            return Err(Error::conflict(
+                "category",
+                Some(parent_id),
+                "CATEGORY_CYCLE",
+                format!("Category with id={} cannot be placed under its own subcategory", id),
+            ));
+        }
+        let depth = depth(Some(parent_id)) + 1;
+        if depth > MAX_DEPTH {
+            return Err(too_deep(depth));
+        }
+    }
+
+    let key = name_key(&name);
+    let sibling = categories_in_org(org_id)
+        .into_iter()
+        .find(|other| other.id != id && other.parent_id == payload.parent_id && name_key(&other.name) == key);
+    if let Some(sibling) = sibling {
+        return Err(Error::conflict(
+            "category",
+            Some(sibling.id),
+            "DUPLICATE_CATEGORY_NAME",
+            format!("Category with id={} already uses the name {:?}", sibling.id, name),
+        ));
+    }
+
+    Ok(Category {
+        id,
+        org_id,
+        name,
+        parent_id: payload.parent_id,
+        created_at: time(),
+        created_by: crate::env::caller(),
+        updated_at: None,
+    })
+}
+
+fn too_deep(depth: usize) -> Error {
+    Error::invalid_input(
+        "parent_id",
+        "TOO_DEEP",
+        format!("Categories nest at most {} levels deep, this would be {}", MAX_DEPTH, depth),
+    )
+}
+
+// A category of the caller's organization
+pub(crate) fn category(id: u64) -> Option<Category> {
+    CATEGORIES
+        .with(|categories| categories.borrow().get(&id))
+        .filter(|category| category.org_id == caller_org())
+}
+
+fn categories_in_org(org_id: u64) -> Vec<Category> {
+    CATEGORIES.with(|categories| {
+        categories
+            .borrow()
+            .iter()
+            .map(|(_, category)| category)
+            .filter(|category| category.org_id == org_id)
+            .collect()
+    })
+}
+
+// `start` and the categories above it, nearest first; bounded in case of a stored cycle
+fn ancestors(start: Option<u64>) -> Vec<u64> {
+    let mut ids = Vec::new();
+    let mut next = start;
+    while let Some(id) = next {
+        if ids.len() > MAX_DEPTH || ids.contains(&id) {
+            break;
+        }
+        ids.push(id);
+        next = CATEGORIES.with(|categories| categories.borrow().get(&id)).and_then(|category| category.parent_id);
+    }
+    ids
+}
+
+// Levels from a root down to `category_id`, the category included; 0 for none
+fn depth(category_id: Option<u64>) -> usize {
+    ancestors(category_id).len()
+}
+
+// `root` and every category below it
+fn subtree(org_id: u64, root: u64) -> Vec<u64> {
+    let categories = categories_in_org(org_id);
+    let mut ids = vec![root];
+    let mut next = 0;
+    while next < ids.len() {
+        let parent = ids[next];
+        ids.extend(categories.iter().filter(|child| child.parent_id == Some(parent)).map(|child| child.id));
+        next += 1;
+    }
+    ids
+}
+
+// Levels in the subtree of `root`, the root included
+fn subtree_height(org_id: u64, root: u64) -> usize {
+    let categories = categories_in_org(org_id);
+    let mut level = vec![root];
+    let mut height = 0;
+    while !level.is_empty() && height <= MAX_DEPTH {
+        height += 1;
+        level = categories
+            .iter()
+            .filter(|child| child.parent_id.is_some_and(|parent| level.contains(&parent)))
+            .map(|child| child.id)
+            .collect();
+    }
+    height
+}
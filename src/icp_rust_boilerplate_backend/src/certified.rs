@@ -0,0 +1,339 @@
+// A digest of the whole inventory, certified through set_certified_data. The digest is
+// the sum modulo 2^256 of leaf_hash(candid(record)) (see merkle.rs) over every warehouse
+// and stock item, so store.rs keeps it current by adding the hash of a written record and
+// subtracting the hash of the record it replaces, and a verifier holding a full export
+// recomputes it without knowing the order of past writes.
+//
+// Next to the digest, the same leaf hashes are kept in a tree so one record can be proven
+// on its own. Warehouses fall into buckets 0..256 and stock items into buckets 256..512 by
+// id modulo 256; a bucket root is merkle_root over its leaves in id order, and the records
+// root is merkle_root over all BUCKETS bucket roots, empty ones included. The certified
+// data is sha256(0x02 || digest || records root). A witness lists the siblings from a
+// record's leaf to the records root, so a client holding the record, its witness and the
+// certificate checks the record without trusting the replica that answered the query.
+use crate::ids::{ItemId, WarehouseId};
+use crate::merkle::{leaf_hash, merkle_path, merkle_root, to_hex};
+use crate::{StockItem, Warehouse, BUCKET_HASHES, RECORD_HASHES, STATE_DIGEST, STOCK_STORAGE, WAREHOUSE_STORAGE};
+use crate::{Error, _get_item, _get_warehouse};
+use candid::{CandidType, Encode};
+use ic_cdk::api::{data_certificate, set_certified_data};
+use ic_stable_structures::{BoundedStorable, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::Cell;
+
+const BUCKETS: u64 = 512;
+const ITEM_BUCKETS: u64 = 256; // First bucket of stock items
+
+thread_local! {
+    static UNCERTIFIED: Cell<bool> = const { Cell::new(false) };
+}
+
+// The record a leaf hash belongs to
+#[derive(Clone, Copy)]
+pub(crate) enum RecordKey {
+    Warehouse(WarehouseId),
+    Item(ItemId),
+}
+
+impl RecordKey {
+    fn bucket(self) -> (u64, u64) {
+        match self {
+            RecordKey::Warehouse(WarehouseId(id)) => (id % ITEM_BUCKETS, id),
+            RecordKey::Item(ItemId(id)) => (ITEM_BUCKETS + id % ITEM_BUCKETS, id),
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq)]
+pub(crate) struct StateDigest([u8; 32]);
+
+impl Storable for StateDigest {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StateDigest(bytes.as_ref().try_into().unwrap())
+    }
+}
+
+impl BoundedStorable for StateDigest {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct StateCertificate {
+    digest: String,                  // Lowercase hex
+    records_root: String,            // Lowercase hex
+    certificate: Option<Vec<u8>>,    // CBOR system certificate; None unless called as a query
+}
+
+// Hashes from a leaf up to the records root. Apply them in order: with `left` the parent
+// is sha256(0x01 || sibling || hash), otherwise sha256(0x01 || hash || sibling).
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Witness {
+    digest: String, // Lowercase hex, to recompute the certified data from the records root
+    path: Vec<WitnessStep>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct WitnessStep {
+    sibling: String, // Lowercase hex
+    left: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CertifiedWarehouse {
+    warehouse: Warehouse,
+    witness: Witness,
+    certificate: Option<Vec<u8>>, // None unless called as a query
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CertifiedStockItem {
+    item: StockItem,
+    witness: Witness,
+    certificate: Option<Vec<u8>>, // None unless called as a query
+}
+
+#[ic_cdk::query]
+fn get_state_certificate() -> StateCertificate {
+    StateCertificate {
+        digest: to_hex(&digest().0),
+        records_root: to_hex(&records_root()),
+        certificate: data_certificate(),
+    }
+}
+
+// get_warehouse with a proof; a missing warehouse is not certified
+#[ic_cdk::query]
+fn get_warehouse_certified(id: WarehouseId) -> Result<CertifiedWarehouse, Error> {
+    let warehouse = _get_warehouse(&id).ok_or_else(|| Error::not_found("warehouse", id))?;
+    Ok(CertifiedWarehouse {
+        warehouse,
+        witness: witness(RecordKey::Warehouse(id)),
+        certificate: data_certificate(),
+    })
+}
+
+// check_stock with a proof; a missing item is not certified
+#[ic_cdk::query]
+fn check_stock_certified(item_id: ItemId) -> Result<CertifiedStockItem, Error> {
+    let item = _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    Ok(CertifiedStockItem {
+        item,
+        witness: witness(RecordKey::Item(item_id)),
+        certificate: data_certificate(),
+    })
+}
+
+// Hash of one record as it enters the digest
+pub(crate) fn leaf<T: CandidType>(record: &T) -> [u8; 32] {
+    leaf_hash(&Encode!(record).unwrap())
+}
+
+// Replaces `old` by `new` as the record under `key` and certifies the result; either may
+// be absent
+pub(crate) fn record_change(key: RecordKey, old: Option<[u8; 32]>, new: Option<[u8; 32]>) {
+    let mut digest = digest();
+    if let Some(old) = old {
+        digest.0 = sub(&digest.0, &old);
+    }
+    if let Some(new) = new {
+        digest.0 = add(&digest.0, &new);
+    }
+
+    let (bucket, id) = key.bucket();
+    RECORD_HASHES.with(|hashes| match new {
+        Some(new) => hashes.borrow_mut().insert((bucket, id), StateDigest(new)),
+        None => hashes.borrow_mut().remove(&(bucket, id)),
+    });
+    update_bucket(bucket);
+    set_digest(digest);
+}
+
+// Runs `write` with the digest and the tree kept current but not certified, since
+// set_certified_data traps in queries. For previews, whose writes the query discards.
+pub(crate) fn uncertified<T>(write: impl FnOnce() -> T) -> T {
+    UNCERTIFIED.with(|uncertified| uncertified.set(true));
+    let result = write();
+    UNCERTIFIED.with(|uncertified| uncertified.set(false));
+    result
+}
+
+// Recomputes the digest and the tree from the primary maps
+pub(crate) fn rebuild() {
+    RECORD_HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        let keys: Vec<(u64, u64)> = hashes.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            hashes.remove(&key);
+        }
+        for (key, leaf) in compute_leaves() {
+            hashes.insert(key.bucket(), StateDigest(leaf));
+        }
+    });
+    for bucket in 0..BUCKETS {
+        update_bucket(bucket);
+    }
+    set_digest(compute());
+}
+
+// Certified data does not survive upgrades; the digest and the tree are computed on the
+// first upgrade after they were introduced
+pub(crate) fn ensure_digest() {
+    let has_records = WAREHOUSE_STORAGE.with(|storage| !storage.borrow().is_empty());
+    let missing = digest() == StateDigest::default() || RECORD_HASHES.with(|hashes| hashes.borrow().is_empty());
+    if has_records && missing {
+        rebuild();
+    } else {
+        certify(&digest());
+    }
+}
+
+pub(crate) fn verify() -> bool {
+    if digest() != compute() {
+        return false;
+    }
+    let mut buckets = vec![Vec::new(); BUCKETS as usize];
+    let mut leaves = 0;
+    for (key, leaf) in compute_leaves() {
+        buckets[key.bucket().0 as usize].push(leaf);
+        leaves += 1;
+    }
+    let roots: Vec<[u8; 32]> = buckets.into_iter().map(merkle_root).collect();
+    leaves == RECORD_HASHES.with(|hashes| hashes.borrow().len()) && merkle_root(roots) == records_root()
+}
+
+fn witness(key: RecordKey) -> Witness {
+    let (bucket, id) = key.bucket();
+    let leaves = bucket_leaves(bucket);
+    let index = leaves.iter().position(|(leaf_id, _)| *leaf_id == id).unwrap_or_default();
+    let mut path = merkle_path(leaves.into_iter().map(|(_, leaf)| leaf).collect(), index);
+    path.extend(merkle_path(bucket_roots(), bucket as usize));
+    Witness {
+        digest: to_hex(&digest().0),
+        path: path
+            .into_iter()
+            .map(|(sibling, left)| WitnessStep {
+                sibling: to_hex(&sibling),
+                left,
+            })
+            .collect(),
+    }
+}
+
+fn update_bucket(bucket: u64) {
+    let leaves = bucket_leaves(bucket);
+    BUCKET_HASHES.with(|roots| {
+        let mut roots = roots.borrow_mut();
+        if leaves.is_empty() {
+            roots.remove(&bucket);
+        } else {
+            roots.insert(bucket, StateDigest(merkle_root(leaves.into_iter().map(|(_, leaf)| leaf).collect())));
+        }
+    });
+}
+
+// (record id, leaf hash) in id order
+fn bucket_leaves(bucket: u64) -> Vec<(u64, [u8; 32])> {
+    RECORD_HASHES.with(|hashes| {
+        hashes
+            .borrow()
+            .range((bucket, 0)..)
+            .take_while(|((leaf_bucket, _), _)| *leaf_bucket == bucket)
+            .map(|((_, id), leaf)| (id, leaf.0))
+            .collect()
+    })
+}
+
+fn bucket_roots() -> Vec<[u8; 32]> {
+    let empty = merkle_root(Vec::new());
+    BUCKET_HASHES.with(|roots| {
+        let roots = roots.borrow();
+        (0..BUCKETS).map(|bucket| roots.get(&bucket).map_or(empty, |root| root.0)).collect()
+    })
+}
+
+fn records_root() -> [u8; 32] {
+    merkle_root(bucket_roots())
+}
+
+fn compute_leaves() -> Vec<(RecordKey, [u8; 32])> {
+    let mut leaves = Vec::new();
+    WAREHOUSE_STORAGE.with(|storage| {
+        for (id, warehouse) in storage.borrow().iter() {
+            leaves.push((RecordKey::Warehouse(id), leaf::<Warehouse>(&warehouse)));
+        }
+    });
+    STOCK_STORAGE.with(|storage| {
+        for (id, item) in storage.borrow().iter() {
+            leaves.push((RecordKey::Item(id), leaf::<StockItem>(&item)));
+        }
+    });
+    leaves
+}
+
+fn compute() -> StateDigest {
+    let mut sum = [0; 32];
+    WAREHOUSE_STORAGE.with(|storage| {
+        for (_, warehouse) in storage.borrow().iter() {
+            sum = add(&sum, &leaf::<Warehouse>(&warehouse));
+        }
+    });
+    STOCK_STORAGE.with(|storage| {
+        for (_, item) in storage.borrow().iter() {
+            sum = add(&sum, &leaf::<StockItem>(&item));
+        }
+    });
+    StateDigest(sum)
+}
+
+fn digest() -> StateDigest {
+    STATE_DIGEST.with(|cell| cell.borrow().get().clone())
+}
+
+fn set_digest(digest: StateDigest) {
+    certify(&digest);
+    STATE_DIGEST.with(|cell| cell.borrow_mut().set(digest)).unwrap();
+}
+
+fn certify(digest: &StateDigest) {
+    let mut hasher = Sha256::new();
+    hasher.update([0x02]);
+    hasher.update(digest.0);
+    hasher.update(records_root());
+    let certified: [u8; 32] = hasher.finalize().into();
+    if !UNCERTIFIED.with(Cell::get) {
+        set_certified_data(&certified);
+    }
+}
+
+// Big-endian arithmetic modulo 2^256
+fn add(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut out = [0; 32];
+    let mut carry = 0u16;
+    for i in (0..32).rev() {
+        let sum = a[i] as u16 + b[i] as u16 + carry;
+        out[i] = sum as u8;
+        carry = sum >> 8;
+    }
+    out
+}
+
+fn sub(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut out = [0; 32];
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let mut difference = a[i] as i16 - b[i] as i16 - borrow;
+        borrow = 0;
+        if difference < 0 {
+            difference += 256;
+            borrow = 1;
+        }
+        out[i] = difference as u8;
+    }
+    out
+}
@@ -0,0 +1,138 @@
+// SKUs and barcodes of stock items, for scanners that know the code but not the item id.
+// A code is unique within a warehouse, not across warehouses: a transfer creates a record
+// for the same product in the destination, and it keeps the codes of the source. Codes are
+// matched without regard to case.
+use crate::ids::{ItemId, WarehouseId};
+use crate::{idempotency, store, Error, MutationOptions, StockItem};
+use crate::{_check_version, _ensure_fits, _get_item, _get_warehouse};
+use crate::env::time;
+
+pub(crate) const MAX_CODE_LENGTH: usize = 40; // In ASCII characters
+pub(crate) const MAX_BARCODES: usize = 5; // Per item
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) struct ItemCodes {
+    sku: Option<String>,
+    barcodes: Vec<String>,
+}
+
+#[ic_cdk::query]
+fn get_item_by_sku(warehouse_id: WarehouseId, sku: String) -> Result<StockItem, Error> {
+    _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    store::item_id_by_sku(warehouse_id, &sku)
+        .and_then(|item_id| _get_item(&item_id))
+        .ok_or_else(|| Error::not_found_by("item", format!("No item in warehouse_id={} has SKU {:?}", warehouse_id, sku)))
+}
+
+#[ic_cdk::query]
+fn get_item_by_barcode(warehouse_id: WarehouseId, barcode: String) -> Result<StockItem, Error> {
+    _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    store::item_id_by_barcode(warehouse_id, &barcode)
+        .and_then(|item_id| _get_item(&item_id))
+        .ok_or_else(|| {
+            Error::not_found_by("item", format!("No item in warehouse_id={} has barcode {:?}", warehouse_id, barcode))
+        })
+}
+
+// Replaces both the SKU and the barcodes; `expected_version` refers to the item
+#[ic_cdk::update]
+fn set_item_codes(item_id: ItemId, codes: ItemCodes, options: Option<MutationOptions>) -> Result<StockItem, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("set_item_codes", options, || _set_item_codes(item_id, codes, expected_version))
+}
+
+fn _set_item_codes(item_id: ItemId, codes: ItemCodes, expected_version: Option<u64>) -> Result<StockItem, Error> {
+    let codes = validate_codes(codes)?;
+    let mut item = _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    _check_version("item", item_id, item.version, expected_version)?;
+    if codes == codes_of(&item) {
+        return Ok(item);
+    }
+
+    item.sku = codes.sku;
+    item.barcodes = codes.barcodes;
+    ensure_codes_free(&item)?;
+    item.updated_at = Some(time());
+    item.updated_by = Some(crate::env::caller());
+    item.version += 1;
+    _ensure_fits("item", &item)?;
+
+    store::insert_item(item.clone());
+    Ok(item)
+}
+
+// Checks no other item in the warehouse of `item` uses one of its codes
+pub(crate) fn ensure_codes_free(item: &StockItem) -> Result<(), Error> {
+    let taken = |field: &str, code: &str, existing_id: ItemId| {
+        Error::conflict(
+            "item",
+            Some(existing_id.into()),
+            "DUPLICATE_ITEM_CODE",
+            format!(
+                "Item with id={} in warehouse_id={} already has {} {:?}",
+                existing_id, item.warehouse_id, field, code
+            ),
+        )
+    };
+    if let Some(sku) = &item.sku {
+        if let Some(existing_id) = store::item_id_by_sku(item.warehouse_id, sku).filter(|id| *id != item.item_id) {
+            return Err(taken("SKU", sku, existing_id));
+        }
+    }
+    for barcode in &item.barcodes {
+        if let Some(existing_id) = store::item_id_by_barcode(item.warehouse_id, barcode).filter(|id| *id != item.item_id) {
+            return Err(taken("barcode", barcode, existing_id));
+        }
+    }
+    Ok(())
+}
+
+// Returns the codes trimmed, with duplicate barcodes dropped
+pub(crate) fn validate_codes(codes: ItemCodes) -> Result<ItemCodes, Error> {
+    if codes.barcodes.len() > MAX_BARCODES {
+        return Err(Error::invalid_input(
+            "barcodes",
+            "TOO_MANY_ENTRIES",
+            format!("An item has at most {} barcodes, got {}", MAX_BARCODES, codes.barcodes.len()),
+        ));
+    }
+
+    let sku = codes.sku.map(|sku| validate_code("sku", &sku)).transpose()?;
+    let mut barcodes: Vec<String> = Vec::new();
+    for barcode in &codes.barcodes {
+        let barcode = validate_code("barcodes", barcode)?;
+        if !barcodes.iter().any(|seen| seen.eq_ignore_ascii_case(&barcode)) {
+            barcodes.push(barcode);
+        }
+    }
+    Ok(ItemCodes { sku, barcodes })
+}
+
+fn validate_code(field: &str, code: &str) -> Result<String, Error> {
+    let code = code.trim();
+    if code.is_empty() {
+        return Err(Error::invalid_input(field, "EMPTY_VALUE", format!("{} must not be empty", field)));
+    }
+    if !code.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(Error::invalid_input(
+            field,
+            "INVALID_CODE",
+            format!("{} must be printable ASCII without spaces, got {:?}", field, code),
+        ));
+    }
+    if code.len() > MAX_CODE_LENGTH {
+        return Err(Error::invalid_input(
+            field,
+            "VALUE_TOO_LONG",
+            format!("{} must be at most {} characters, got {}", field, MAX_CODE_LENGTH, code.len()),
+        ));
+    }
+    Ok(code.to_string())
+}
+
+fn codes_of(item: &StockItem) -> ItemCodes {
+    ItemCodes {
+        sku: item.sku.clone(),
+        barcodes: item.barcodes.clone(),
+    }
+}
@@ -0,0 +1,512 @@
+// Cycle counts. A session is started for a warehouse and a scope of items, counted
+// quantities are submitted while it is open, and posting it sets every counted item to
+// its counted quantity, recording each difference as a CountCorrection adjustment.
+// Variances are taken against the quantity at posting time; items that are not counted
+// keep their quantity.
+//
+// A physical inventory is a session over the whole warehouse that freezes it: nothing is
+// added, removed, transferred or deleted there until the session is approved or cancelled.
+// It is approved instead of posted, by an Admin or Owner of the organization or by a
+// controller, and approval also sets every item without a count to zero. Submitting and
+// posting counts can be limited to staff on shift, see staffing.rs.
+use crate::ids::{ItemId, WarehouseId};
+use crate::adjustment::{self, AdjustmentReason};
+use crate::organization::{self, Role};
+use crate::staffing::{self, Duty};
+use crate::tenancy::caller_org;
+use crate::{idempotency, store, Error, MutationOptions, StockItem, COUNT_LINES, COUNT_SESSIONS, FROZEN_WAREHOUSES, STOCK_STORAGE};
+use crate::{_ensure_fits, _get_warehouse};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+const MAX_SCOPE_ITEMS: usize = 500;
+const MAX_COUNTS_PER_CALL: usize = 500;
+const MAX_SESSION_LINES: usize = 2_000; // Distinct items counted in one session
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum CountScope {
+    AllItems,
+    Items(Vec<ItemId>),
+    PhysicalInventory,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) enum CountStatus {
+    Open,
+    Posted,
+    Cancelled,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CountSession {
+    id: u64,
+    org_id: u64,
+    warehouse_id: WarehouseId,
+    scope: CountScope,
+    status: CountStatus,
+    started_at: u64,
+    started_by: Principal,
+    posted_at: Option<u64>, // Also the time of approval or cancellation
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CountEntry {
+    item_id: ItemId,
+    counted_quantity: u64, // Zero is a valid count
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CountLine {
+    item_id: ItemId,
+    counted_quantity: u64,
+    counted_at: u64,
+    counted_by: Principal,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CountSessionView {
+    session: CountSession,
+    lines: Vec<CountLine>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Variance {
+    item_id: ItemId,
+    system_quantity: u64,
+    counted_quantity: u64,
+    difference: i128, // Counted minus system
+}
+
+// What posting or approving the session would change right now
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct VarianceReport {
+    session: CountSession,
+    variances: Vec<Variance>,
+    unchanged_items: u64,
+    uncounted_items: u64, // Part of `variances` for a physical inventory, counted as zero
+    skipped_item_ids: Vec<ItemId>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CountResult {
+    session: CountSession,
+    variances: Vec<Variance>,   // Counted items whose quantity differed
+    adjustment_ids: Vec<u64>,
+    unchanged_items: u64,       // Counted items that matched
+    uncounted_items: u64,       // Items in scope without a count
+    skipped_item_ids: Vec<ItemId>, // Counted items that were deleted or moved before posting
+}
+
+// Variances of a session with the records that correct them
+struct Evaluation {
+    variances: Vec<Variance>,
+    corrected: Vec<(StockItem, u64)>, // Updated item and its previous quantity
+    unchanged_items: u64,
+    uncounted_items: u64,
+    skipped_item_ids: Vec<ItemId>,
+}
+
+impl Storable for CountSession {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CountSession {
+    const MAX_SIZE: u32 = 8 * 1024; // Room for MAX_SCOPE_ITEMS ids
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for CountLine {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CountLine {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_count_session(session_id: u64) -> Result<CountSessionView, Error> {
+    let session = session(session_id)?;
+    Ok(CountSessionView {
+        lines: lines(session_id),
+        session,
+    })
+}
+
+#[ic_cdk::query]
+fn get_variance_report(session_id: u64) -> Result<VarianceReport, Error> {
+    let session = open_session(session_id)?;
+    let evaluation = evaluate(&session)?;
+    Ok(VarianceReport {
+        session,
+        variances: evaluation.variances,
+        unchanged_items: evaluation.unchanged_items,
+        uncounted_items: evaluation.uncounted_items,
+        skipped_item_ids: evaluation.skipped_item_ids,
+    })
+}
+
+#[ic_cdk::update]
+fn start_count(warehouse_id: WarehouseId, scope: CountScope, options: Option<MutationOptions>) -> Result<CountSession, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("start_count", options, || _start_count(warehouse_id, scope))
+}
+
+fn _start_count(warehouse_id: WarehouseId, scope: CountScope) -> Result<CountSession, Error> {
+    validate_scope(&scope)?;
+    let warehouse = _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    if let CountScope::Items(item_ids) = &scope {
+        for item_id in item_ids {
+            if !in_warehouse(*item_id, warehouse_id) {
+                return Err(not_in_scope(*item_id));
+            }
+        }
+    }
+    let freeze = matches!(scope, CountScope::PhysicalInventory);
+    if freeze {
+        organization::ensure_role_or_controller(warehouse.org_id, Role::Admin)?;
+        ensure_unfrozen(warehouse_id)?;
+    }
+
+    let session = COUNT_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let id = sessions.last_key_value().map_or(1, |(id, _)| id + 1);
+        let session = CountSession {
+            id,
+            org_id: warehouse.org_id,
+            warehouse_id,
+            scope,
+            status: CountStatus::Open,
+            started_at: time(),
+            started_by: crate::env::caller(),
+            posted_at: None,
+        };
+        sessions.insert(id, session.clone());
+        session
+    });
+    if freeze {
+        FROZEN_WAREHOUSES.with(|frozen| frozen.borrow_mut().insert(warehouse_id, session.id));
+    }
+    Ok(session)
+}
+
+// A later count of the same item replaces the earlier one
+#[ic_cdk::update]
+fn submit_counts(session_id: u64, counts: Vec<CountEntry>, options: Option<MutationOptions>) -> Result<u64, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("submit_counts", options, || _submit_counts(session_id, counts))
+}
+
+fn _submit_counts(session_id: u64, counts: Vec<CountEntry>) -> Result<u64, Error> {
+    validate_counts(&counts)?;
+    let session = open_session(session_id)?;
+    staffing::ensure_on_shift(session.warehouse_id, session.org_id, Duty::Counting)?;
+    for entry in &counts {
+        if !session.covers(entry.item_id) {
+            return Err(not_in_scope(entry.item_id));
+        }
+    }
+    let new_items = counts
+        .iter()
+        .map(|entry| entry.item_id)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|item_id| !COUNT_LINES.with(|lines| lines.borrow().contains_key(&(session_id, *item_id))))
+        .count();
+    let counted = lines(session_id).len();
+    if counted + new_items > MAX_SESSION_LINES {
+        return Err(Error::capacity_exceeded(
+            "TOO_MANY_ENTRIES",
+            MAX_SESSION_LINES as u64,
+            (counted + new_items) as u64,
+            format!("A count session holds at most {} items, {} are already counted", MAX_SESSION_LINES, counted),
+        ));
+    }
+
+    let now = time();
+    COUNT_LINES.with(|lines| {
+        let mut lines = lines.borrow_mut();
+        for entry in counts {
+            let line = CountLine {
+                item_id: entry.item_id,
+                counted_quantity: entry.counted_quantity,
+                counted_at: now,
+                counted_by: crate::env::caller(),
+            };
+            lines.insert((session_id, entry.item_id), line);
+        }
+    });
+    Ok((counted + new_items) as u64)
+}
+
+#[ic_cdk::update]
+fn post_count(session_id: u64, options: Option<MutationOptions>) -> Result<CountResult, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("post_count", options, || _post_count(session_id))
+}
+
+fn _post_count(session_id: u64) -> Result<CountResult, Error> {
+    let session = open_session(session_id)?;
+    if session.is_physical_inventory() {
+        return Err(Error::conflict(
+            "count_session",
+            Some(session_id),
+            "APPROVAL_REQUIRED",
+            format!("Physical inventory session with id={} is applied by approve_count", session_id),
+        ));
+    }
+    ensure_unfrozen(session.warehouse_id)?;
+    staffing::ensure_on_shift(session.warehouse_id, session.org_id, Duty::Counting)?;
+    apply(session)
+}
+
+#[ic_cdk::update]
+fn approve_count(session_id: u64, options: Option<MutationOptions>) -> Result<CountResult, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("approve_count", options, || _approve_count(session_id))
+}
+
+fn _approve_count(session_id: u64) -> Result<CountResult, Error> {
+    let session = open_session(session_id)?;
+    if !session.is_physical_inventory() {
+        return Err(Error::conflict(
+            "count_session",
+            Some(session_id),
+            "NOT_PHYSICAL_INVENTORY",
+            format!("Count session with id={} is applied by post_count", session_id),
+        ));
+    }
+    organization::ensure_role_or_controller(session.org_id, Role::Admin)?;
+    apply(session)
+}
+
+// Closes an open session without changing stock, lifting the freeze of a physical inventory
+#[ic_cdk::update]
+fn cancel_count(session_id: u64, options: Option<MutationOptions>) -> Result<CountSession, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("cancel_count", options, || _cancel_count(session_id))
+}
+
+fn _cancel_count(session_id: u64) -> Result<CountSession, Error> {
+    let session = open_session(session_id)?;
+    if session.is_physical_inventory() {
+        organization::ensure_role_or_controller(session.org_id, Role::Admin)?;
+    }
+    Ok(close(session, CountStatus::Cancelled))
+}
+
+// Fails while a physical inventory of the warehouse is open
+pub(crate) fn ensure_unfrozen(warehouse_id: WarehouseId) -> Result<(), Error> {
+    match FROZEN_WAREHOUSES.with(|frozen| frozen.borrow().get(&warehouse_id)) {
+        Some(session_id) => Err(Error::conflict(
+            "warehouse",
+            Some(warehouse_id.into()),
+            "WAREHOUSE_FROZEN",
+            format!(
+                "Warehouse with id={} is frozen by physical inventory session id={}",
+                warehouse_id, session_id
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+// Cancels the open physical inventory of a warehouse that is being removed with its tenant
+pub(crate) fn release_warehouse(warehouse_id: WarehouseId) {
+    let session = FROZEN_WAREHOUSES
+        .with(|frozen| frozen.borrow().get(&warehouse_id))
+        .and_then(|session_id| COUNT_SESSIONS.with(|sessions| sessions.borrow().get(&session_id)));
+    match session {
+        Some(session) => {
+            close(session, CountStatus::Cancelled);
+        }
+        None => {
+            FROZEN_WAREHOUSES.with(|frozen| frozen.borrow_mut().remove(&warehouse_id));
+        }
+    }
+}
+
+// Writes the corrections of an open session and marks it posted, lifting any freeze
+fn apply(session: CountSession) -> Result<CountResult, Error> {
+    // Everything is checked before the first write
+    let evaluation = evaluate(&session)?;
+
+    let mut adjustment_ids = Vec::new();
+    for (item, previous_quantity) in evaluation.corrected {
+        store::insert_item(item.clone());
+        let adjustment_id =
+            adjustment::record(&item, previous_quantity, AdjustmentReason::CountCorrection, Some(session.id));
+        adjustment_ids.push(adjustment_id);
+    }
+
+    Ok(CountResult {
+        session: close(session, CountStatus::Posted),
+        variances: evaluation.variances,
+        adjustment_ids,
+        unchanged_items: evaluation.unchanged_items,
+        uncounted_items: evaluation.uncounted_items,
+        skipped_item_ids: evaluation.skipped_item_ids,
+    })
+}
+
+fn evaluate(session: &CountSession) -> Result<Evaluation, Error> {
+    let mut counts: Vec<(ItemId, u64)> =
+        lines(session.id).into_iter().map(|line| (line.item_id, line.counted_quantity)).collect();
+    let counted = counts.len();
+    let scope_size = match &session.scope {
+        CountScope::AllItems => store::item_ids_in_warehouse(session.warehouse_id, usize::MAX).len(),
+        CountScope::Items(item_ids) => item_ids.iter().collect::<BTreeSet<_>>().len(),
+        CountScope::PhysicalInventory => {
+            // The warehouse is frozen, so the counted items are still in it
+            let item_ids = store::item_ids_in_warehouse(session.warehouse_id, usize::MAX);
+            let counted_ids: BTreeSet<ItemId> = counts.iter().map(|(item_id, _)| *item_id).collect();
+            counts.extend(item_ids.iter().filter(|item_id| !counted_ids.contains(item_id)).map(|item_id| (*item_id, 0)));
+            item_ids.len()
+        }
+    };
+
+    let mut evaluation = Evaluation {
+        variances: Vec::new(),
+        corrected: Vec::new(),
+        unchanged_items: 0,
+        uncounted_items: 0,
+        skipped_item_ids: Vec::new(),
+    };
+    for (item_id, counted_quantity) in counts {
+        let item = STOCK_STORAGE
+            .with(|storage| storage.borrow().get(&item_id))
+            .filter(|item| item.warehouse_id == session.warehouse_id);
+        let mut item = match item {
+            Some(item) => item,
+            None => {
+                evaluation.skipped_item_ids.push(item_id);
+                continue;
+            }
+        };
+        if item.quantity == counted_quantity {
+            evaluation.unchanged_items += 1;
+            continue;
+        }
+        evaluation.variances.push(Variance {
+            item_id,
+            system_quantity: item.quantity,
+            counted_quantity,
+            difference: counted_quantity as i128 - item.quantity as i128,
+        });
+        let previous_quantity = item.quantity;
+        item.quantity = counted_quantity;
+        item.updated_at = Some(time());
+        item.updated_by = Some(crate::env::caller());
+        item.version += 1;
+        _ensure_fits("item", &item)?;
+        evaluation.corrected.push((item, previous_quantity));
+    }
+
+    let counted_in_scope = counted.saturating_sub(evaluation.skipped_item_ids.len());
+    evaluation.uncounted_items = scope_size.saturating_sub(counted_in_scope) as u64;
+    Ok(evaluation)
+}
+
+fn close(mut session: CountSession, status: CountStatus) -> CountSession {
+    session.status = status;
+    session.posted_at = Some(time());
+    COUNT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session.id, session.clone()));
+    if session.is_physical_inventory() {
+        FROZEN_WAREHOUSES.with(|frozen| frozen.borrow_mut().remove(&session.warehouse_id));
+    }
+    session
+}
+
+impl CountSession {
+    fn covers(&self, item_id: ItemId) -> bool {
+        let in_scope = match &self.scope {
+            CountScope::AllItems | CountScope::PhysicalInventory => true,
+            CountScope::Items(item_ids) => item_ids.contains(&item_id),
+        };
+        in_scope && in_warehouse(item_id, self.warehouse_id)
+    }
+
+    fn is_physical_inventory(&self) -> bool {
+        matches!(self.scope, CountScope::PhysicalInventory)
+    }
+}
+
+pub(crate) fn validate_scope(scope: &CountScope) -> Result<(), Error> {
+    match scope {
+        CountScope::Items(item_ids) if item_ids.is_empty() || item_ids.len() > MAX_SCOPE_ITEMS => Err(Error::invalid_input(
+            "scope",
+            "INVALID_SCOPE",
+            format!("A scope lists 1 to {} items, got {}", MAX_SCOPE_ITEMS, item_ids.len()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn validate_counts(counts: &[CountEntry]) -> Result<(), Error> {
+    if counts.is_empty() || counts.len() > MAX_COUNTS_PER_CALL {
+        return Err(Error::invalid_input(
+            "counts",
+            "TOO_MANY_ENTRIES",
+            format!("counts must have 1 to {} entries, got {}", MAX_COUNTS_PER_CALL, counts.len()),
+        ));
+    }
+    Ok(())
+}
+
+// Sessions of other organizations do not exist for the caller
+fn session(session_id: u64) -> Result<CountSession, Error> {
+    COUNT_SESSIONS
+        .with(|sessions| sessions.borrow().get(&session_id))
+        .filter(|session| session.org_id == caller_org())
+        .ok_or_else(|| Error::not_found("count_session", session_id))
+}
+
+fn open_session(session_id: u64) -> Result<CountSession, Error> {
+    let session = session(session_id)?;
+    if session.status != CountStatus::Open {
+        return Err(Error::conflict(
+            "count_session",
+            Some(session_id),
+            "SESSION_NOT_OPEN",
+            format!("Count session with id={} is {:?}", session_id, session.status),
+        ));
+    }
+    Ok(session)
+}
+
+fn lines(session_id: u64) -> Vec<CountLine> {
+    COUNT_LINES.with(|lines| {
+        lines
+            .borrow()
+            .range((session_id, ItemId(0))..=(session_id, ItemId(u64::MAX)))
+            .map(|(_, line)| line)
+            .collect()
+    })
+}
+
+fn in_warehouse(item_id: ItemId, warehouse_id: WarehouseId) -> bool {
+    STOCK_STORAGE.with(|storage| storage.borrow().get(&item_id)).is_some_and(|item| item.warehouse_id == warehouse_id)
+}
+
+fn not_in_scope(item_id: ItemId) -> Error {
+    Error::invalid_input(
+        "item_id",
+        "NOT_IN_SCOPE",
+        format!("Item with id={} is not in the warehouse or scope being counted", item_id),
+    )
+}
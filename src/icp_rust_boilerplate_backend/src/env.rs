@@ -0,0 +1,36 @@
+// The caller, its controller status and the clock. In a canister they come from ic0,
+// which native tests cannot call; there the caller is set per test thread, is no
+// controller, and the clock stands still.
+#[cfg(not(test))]
+pub(crate) use ic_cdk::api::{is_controller, time};
+#[cfg(not(test))]
+pub(crate) use ic_cdk::caller;
+
+#[cfg(test)]
+pub(crate) use fake::{caller, is_controller, set_caller, time};
+
+#[cfg(test)]
+mod fake {
+    use candid::Principal;
+    use std::cell::Cell;
+
+    thread_local! {
+        static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+    }
+
+    pub(crate) fn caller() -> Principal {
+        CALLER.with(Cell::get)
+    }
+
+    pub(crate) fn set_caller(caller: Principal) {
+        CALLER.with(|current| current.set(caller));
+    }
+
+    pub(crate) fn is_controller(_principal: &Principal) -> bool {
+        false
+    }
+
+    pub(crate) fn time() -> u64 {
+        1
+    }
+}
@@ -0,0 +1,321 @@
+use crate::ids::ItemId;
+use candid::Principal;
+
+// Every variant carries a stable machine-readable `code` (SCREAMING_SNAKE_CASE, never renamed
+// once released), a human-readable `msg` and typed details, so clients can branch on the code
+// and render their own localized message from the details.
+#[derive(candid::CandidType, Clone, Debug, Deserialize, Serialize)]
+pub(crate) enum Error {
+    NotFound {
+        code: String,
+        msg: String,
+        entity: String,
+        id: Option<u64>,
+    },
+    NotEnoughStock {
+        code: String,
+        msg: String,
+        item_id: ItemId,
+        available: u64,
+        requested: u64,
+    },
+    Unauthorized {
+        code: String,
+        msg: String,
+        caller: Principal,
+    },
+    InvalidInput {
+        code: String,
+        msg: String,
+        field: String,
+    },
+    // The request clashes with the current state of a record, e.g. a duplicate name
+    Conflict {
+        code: String,
+        msg: String,
+        entity: String,
+        id: Option<u64>, // The record the request conflicts with
+    },
+    // A collection is full, e.g. the webhooks of an organization; `requested` is its size
+    // had the call been accepted
+    CapacityExceeded {
+        code: String,
+        msg: String,
+        limit: u64,
+        requested: u64,
+    },
+    // A lifecycle change that is not allowed from the record's current status
+    InvalidTransition {
+        code: String,
+        msg: String,
+        from: String,
+        to: String,
+    },
+    RecordTooLarge {
+        code: String,
+        msg: String,
+        size: u64,
+        max_size: u64,
+    },
+    // A controller switched the endpoint off, see killswitch.rs
+    EndpointDisabled {
+        code: String,
+        msg: String,
+        method: String,
+    },
+    // The canister is in read-only mode, see maintenance.rs
+    Maintenance {
+        code: String,
+        msg: String,
+        since: u64,
+    },
+    // An organization reached one of its quotas, see quota.rs
+    QuotaExceeded {
+        code: String,
+        msg: String,
+        org_id: u64,
+        quota: String, // Name of the Quotas field, e.g. max_items
+        limit: u64,
+        used: u64,
+    },
+    // The caller made too many update calls in a short time, see ratelimit.rs
+    RateLimited {
+        code: String,
+        msg: String,
+        retry_after_ns: u64,
+    },
+    // A quantity would exceed u64::MAX
+    Overflow {
+        code: String,
+        msg: String,
+        item_id: ItemId,
+        current: u64,
+        delta: u64,
+    },
+}
+
+impl Error {
+    // "CODE: msg", for places that can only report a string, such as a trap
+    pub(crate) fn summary(&self) -> String {
+        match self {
+            Error::NotFound { code, msg, .. }
+            | Error::NotEnoughStock { code, msg, .. }
+            | Error::Unauthorized { code, msg, .. }
+            | Error::InvalidInput { code, msg, .. }
+            | Error::Conflict { code, msg, .. }
+            | Error::CapacityExceeded { code, msg, .. }
+            | Error::InvalidTransition { code, msg, .. }
+            | Error::RecordTooLarge { code, msg, .. }
+            | Error::EndpointDisabled { code, msg, .. }
+            | Error::Maintenance { code, msg, .. }
+            | Error::QuotaExceeded { code, msg, .. }
+            | Error::RateLimited { code, msg, .. }
+            | Error::Overflow { code, msg, .. } => format!("{}: {}", code, msg),
+        }
+    }
+
+    // The same error with `context` before its message, e.g. "op 3: "
+    pub(crate) fn with_context(mut self, context: &str) -> Self {
+        match &mut self {
+            Error::NotFound { msg, .. }
+            | Error::NotEnoughStock { msg, .. }
+            | Error::Unauthorized { msg, .. }
+            | Error::InvalidInput { msg, .. }
+            | Error::Conflict { msg, .. }
+            | Error::CapacityExceeded { msg, .. }
+            | Error::InvalidTransition { msg, .. }
+            | Error::RecordTooLarge { msg, .. }
+            | Error::EndpointDisabled { msg, .. }
+            | Error::Maintenance { msg, .. }
+            | Error::QuotaExceeded { msg, .. }
+            | Error::RateLimited { msg, .. }
+            | Error::Overflow { msg, .. } => msg.insert_str(0, context),
+        }
+        self
+    }
+
+    // Name of the variant, for metrics
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Error::NotFound { .. } => "NotFound",
+            Error::NotEnoughStock { .. } => "NotEnoughStock",
+            Error::Unauthorized { .. } => "Unauthorized",
+            Error::InvalidInput { .. } => "InvalidInput",
+            Error::Conflict { .. } => "Conflict",
+            Error::CapacityExceeded { .. } => "CapacityExceeded",
+            Error::InvalidTransition { .. } => "InvalidTransition",
+            Error::RecordTooLarge { .. } => "RecordTooLarge",
+            Error::EndpointDisabled { .. } => "EndpointDisabled",
+            Error::Maintenance { .. } => "Maintenance",
+            Error::QuotaExceeded { .. } => "QuotaExceeded",
+            Error::RateLimited { .. } => "RateLimited",
+            Error::Overflow { .. } => "Overflow",
+        }
+    }
+
+    // `entity` is snake_case ("warehouse", "item", ...); the code becomes e.g. WAREHOUSE_NOT_FOUND
+    pub(crate) fn not_found(entity: &str, id: impl Into<u64>) -> Self {
+        let id = id.into();
+        Error::NotFound {
+            code: format!("{}_NOT_FOUND", entity.to_uppercase()),
+            msg: format!("{} with id={} not found", capitalize(entity), id),
+            entity: entity.to_string(),
+            id: Some(id),
+        }
+    }
+
+    // For lookups by something other than the id, e.g. a SKU
+    pub(crate) fn not_found_by(entity: &str, msg: String) -> Self {
+        Error::NotFound {
+            code: format!("{}_NOT_FOUND", entity.to_uppercase()),
+            msg,
+            entity: entity.to_string(),
+            id: None,
+        }
+    }
+
+    pub(crate) fn not_enough_stock(item_id: ItemId, available: u64, requested: u64) -> Self {
+        Error::NotEnoughStock {
+            code: "NOT_ENOUGH_STOCK".to_string(),
+            msg: format!(
+                "Not enough stock for item_id={}, available={}, requested={}",
+                item_id, available, requested
+            ),
+            item_id,
+            available,
+            requested,
+        }
+    }
+
+    pub(crate) fn overflow(item_id: ItemId, current: u64, delta: u64) -> Self {
+        Error::Overflow {
+            code: "QUANTITY_OVERFLOW".to_string(),
+            msg: format!(
+                "Quantity of item_id={} would overflow: current={}, added={}",
+                item_id, current, delta
+            ),
+            item_id,
+            current,
+            delta,
+        }
+    }
+
+    pub(crate) fn record_too_large(entity: &str, size: u64, max_size: u64) -> Self {
+        Error::RecordTooLarge {
+            code: format!("{}_TOO_LARGE", entity.to_uppercase()),
+            msg: format!(
+                "{} record would take {} bytes, the limit is {}",
+                capitalize(entity),
+                size,
+                max_size
+            ),
+            size,
+            max_size,
+        }
+    }
+
+    pub(crate) fn unauthorized(caller: Principal, msg: String) -> Self {
+        Error::Unauthorized {
+            code: "UNAUTHORIZED".to_string(),
+            msg,
+            caller,
+        }
+    }
+
+    // `code` names the problem with the field, e.g. EMPTY_VALUE or VALUE_TOO_LONG
+    pub(crate) fn invalid_input(field: &str, code: &str, msg: String) -> Self {
+        Error::InvalidInput {
+            code: code.to_string(),
+            msg,
+            field: field.to_string(),
+        }
+    }
+
+    // `id` is None when the caller expected a record that does not exist (current version 0)
+    pub(crate) fn version_mismatch(entity: &str, id: Option<u64>, expected: u64, current: u64) -> Self {
+        let record = match id {
+            Some(id) => format!("{} with id={}", capitalize(entity), id),
+            None => capitalize(entity),
+        };
+        Error::Conflict {
+            code: "VERSION_MISMATCH".to_string(),
+            msg: format!("{} is at version {}, expected {}", record, current, expected),
+            entity: entity.to_string(),
+            id,
+        }
+    }
+
+    pub(crate) fn endpoint_disabled(method: &str, reason: Option<String>) -> Self {
+        let msg = match reason {
+            Some(reason) => format!("{} is disabled: {}", method, reason),
+            None => format!("{} is disabled", method),
+        };
+        Error::EndpointDisabled {
+            code: "ENDPOINT_DISABLED".to_string(),
+            msg,
+            method: method.to_string(),
+        }
+    }
+
+    pub(crate) fn maintenance(since: u64, message: Option<String>) -> Self {
+        Error::Maintenance {
+            code: "MAINTENANCE".to_string(),
+            msg: message.unwrap_or_else(|| "The canister is read-only for maintenance".to_string()),
+            since,
+        }
+    }
+
+    pub(crate) fn rate_limited(calls: u64, window_ns: u64, retry_after_ns: u64) -> Self {
+        Error::RateLimited {
+            code: "RATE_LIMITED".to_string(),
+            msg: format!(
+                "At most {} update calls per {} seconds; retry in {} ms",
+                calls,
+                window_ns / 1_000_000_000,
+                retry_after_ns.div_ceil(1_000_000)
+            ),
+            retry_after_ns,
+        }
+    }
+
+    pub(crate) fn quota_exceeded(org_id: u64, quota: &str, limit: u64, used: u64) -> Self {
+        Error::QuotaExceeded {
+            code: "QUOTA_EXCEEDED".to_string(),
+            msg: format!(
+                "Organization with id={} has reached its {} quota of {} (using {})",
+                org_id, quota, limit, used
+            ),
+            org_id,
+            quota: quota.to_string(),
+            limit,
+            used,
+        }
+    }
+
+    pub(crate) fn capacity_exceeded(code: &str, limit: u64, requested: u64, msg: String) -> Self {
+        Error::CapacityExceeded {
+            code: code.to_string(),
+            msg,
+            limit,
+            requested,
+        }
+    }
+
+    pub(crate) fn conflict(entity: &str, id: Option<u64>, code: &str, msg: String) -> Self {
+        Error::Conflict {
+            code: code.to_string(),
+            msg,
+            entity: entity.to_string(),
+            id,
+        }
+    }
+}
+
+fn capitalize(entity: &str) -> String {
+    let words = entity.replace('_', " ");
+    let mut chars = words.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
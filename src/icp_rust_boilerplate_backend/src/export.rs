@@ -0,0 +1,286 @@
+// Stock of the caller's organization as a CSV or JSON document, for backups and analysis
+// off-chain. export_inventory returns the first chunk; while a chunk has a `next` token,
+// get_export_chunk with that token returns the following one. The chunks concatenated in
+// order are the document. Nothing is stored between calls, so a document read while the
+// stock changes can mix states from before and after the change.
+//
+// CSV has one row per item, and one with empty item columns per warehouse without items.
+// JSON is an array of warehouses, each with its items, and holds every CSV column, so the
+// CSV rows of a JSON export can be rebuilt from it. The inventory root (see merkle.rs) is
+// taken over the CSV rows.
+use crate::ids::{ItemId, WarehouseId};
+use crate::merkle::{self, PartialRoot};
+use crate::store;
+use crate::tenancy::caller_org;
+use crate::{Error, StockItem, Warehouse, WAREHOUSE_STORAGE};
+use crate::_get_warehouse;
+
+const MAX_CHUNK_RECORDS: usize = 500; // Warehouses and items per chunk
+const CSV_HEADER: &str = "warehouse_id,warehouse_name,item_id,item_name,quantity,unit,sku,barcodes,unit_cost,unit_price,currency,reorder_point\n";
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ExportFormat {
+    Csv,
+    Json,
+}
+
+// Where the next chunk starts; pass it back unchanged
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ExportToken {
+    format: ExportFormat,
+    only_warehouse_id: Option<WarehouseId>,
+    warehouse_id: WarehouseId,
+    warehouse_open: bool,       // The warehouse was begun in an earlier chunk
+    after_item_id: Option<ItemId>, // Last item of the warehouse in an earlier chunk
+    warehouses_sent: u64,
+    items_sent: u64,
+    subtrees: PartialRoot, // Over the rows of the earlier chunks
+}
+
+// `leaves` are the leaf hashes of the CSV rows of the chunk, in order. The last chunk has the
+// root over every row of the document, which equals the root of get_inventory_root when
+// the stock did not change during the export.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ExportChunk {
+    data: String,
+    leaves: Vec<String>,         // Lowercase hex
+    merkle_root: Option<String>, // Lowercase hex, on the last chunk
+    next: Option<ExportToken>,   // None on the last chunk
+}
+
+// The records of one chunk, as read by export_inventory and get_inventory_root
+pub(crate) struct Chunk {
+    pub(crate) data: String,
+    pub(crate) leaves: Vec<[u8; 32]>, // Of the CSV rows of the chunk, whatever the format
+    pub(crate) warehouses_sent: u64,  // In this chunk and the ones before it
+    pub(crate) items_sent: u64,
+    pub(crate) merkle_root: Option<[u8; 32]>, // On the last chunk
+    pub(crate) next: Option<ExportToken>,
+}
+
+impl From<Chunk> for ExportChunk {
+    fn from(chunk: Chunk) -> Self {
+        ExportChunk {
+            data: chunk.data,
+            leaves: chunk.leaves.iter().map(|leaf| merkle::to_hex(leaf)).collect(),
+            merkle_root: chunk.merkle_root.map(|root| merkle::to_hex(&root)),
+            next: chunk.next,
+        }
+    }
+}
+
+// With `warehouse_id`, only that warehouse and its items
+#[ic_cdk::query]
+fn export_inventory(format: ExportFormat, warehouse_id: Option<WarehouseId>) -> Result<ExportChunk, Error> {
+    if let Some(warehouse_id) = warehouse_id {
+        _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    }
+    let data = match format {
+        ExportFormat::Csv => CSV_HEADER.to_string(),
+        ExportFormat::Json => "[".to_string(),
+    };
+    Ok(chunk(data, first_token(format, warehouse_id)).into())
+}
+
+#[ic_cdk::query]
+fn get_export_chunk(token: ExportToken) -> Result<ExportChunk, Error> {
+    if let Some(warehouse_id) = token.only_warehouse_id {
+        _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    }
+    Ok(chunk(String::new(), token).into())
+}
+
+impl ExportToken {
+    pub(crate) fn only_warehouse_id(&self) -> Option<WarehouseId> {
+        self.only_warehouse_id
+    }
+}
+
+pub(crate) fn first_token(format: ExportFormat, warehouse_id: Option<WarehouseId>) -> ExportToken {
+    ExportToken {
+        format,
+        only_warehouse_id: warehouse_id,
+        warehouse_id: warehouse_id.unwrap_or_default(),
+        warehouse_open: false,
+        after_item_id: None,
+        warehouses_sent: 0,
+        items_sent: 0,
+        subtrees: PartialRoot::default(),
+    }
+}
+
+// Appends the records that follow `token` to `data`. The caller checks that a single
+// warehouse of the token belongs to the caller's organization.
+pub(crate) fn chunk(mut data: String, mut token: ExportToken) -> Chunk {
+    let mut subtrees = std::mem::take(&mut token.subtrees);
+    let mut leaves = Vec::new();
+    let warehouse_ids: Vec<WarehouseId> = match token.only_warehouse_id {
+        Some(warehouse_id) => vec![warehouse_id],
+        None => store::listed_warehouse_ids(caller_org())
+            .into_iter()
+            .filter(|id| *id >= token.warehouse_id)
+            .collect(),
+    };
+
+    let mut remaining = MAX_CHUNK_RECORDS;
+    let mut warehouses_sent = token.warehouses_sent;
+    let mut items_sent = token.items_sent;
+    for warehouse_id in warehouse_ids {
+        let Some(warehouse) = WAREHOUSE_STORAGE.with(|storage| storage.borrow().get(&warehouse_id)) else {
+            continue;
+        };
+        let resumed = warehouse_id == token.warehouse_id && token.warehouse_open;
+        let after_item_id = if resumed { token.after_item_id } else { None };
+
+        if !resumed {
+            if remaining == 0 {
+                return Chunk {
+                    data,
+                    leaves,
+                    warehouses_sent,
+                    items_sent,
+                    merkle_root: None,
+                    next: Some(ExportToken {
+                        warehouse_id,
+                        warehouse_open: false,
+                        after_item_id: None,
+                        warehouses_sent,
+                        items_sent,
+                        subtrees,
+                        ..token
+                    }),
+                };
+            }
+            if token.format == ExportFormat::Json {
+                let separator = if warehouses_sent > 0 { "," } else { "" };
+                // The object is left open for its items
+                data.push_str(&format!(
+                    "{}{{\"id\":{},\"name\":{},\"created_at\":{},\"version\":{},\"items\":[",
+                    separator,
+                    warehouse.id,
+                    serde_json::Value::from(warehouse.name.as_str()),
+                    warehouse.created_at,
+                    warehouse.version,
+                ));
+            }
+            warehouses_sent += 1;
+            remaining -= 1;
+        }
+
+        // One extra item tells whether the warehouse continues in the next chunk
+        let mut items = store::items_in_warehouse(warehouse_id, after_item_id, remaining + 1);
+        let more = items.len() > remaining;
+        items.truncate(remaining);
+        remaining -= items.len();
+        items_sent += items.len() as u64;
+
+        if after_item_id.is_none() && items.is_empty() {
+            let row = csv_row(&warehouse, None);
+            let leaf = merkle::leaf_hash(row.as_bytes());
+            subtrees.push(leaf);
+            leaves.push(leaf);
+            if token.format == ExportFormat::Csv {
+                data.push_str(&row);
+            }
+        }
+        for (index, item) in items.iter().enumerate() {
+            let row = csv_row(&warehouse, Some(item));
+            let leaf = merkle::leaf_hash(row.as_bytes());
+            subtrees.push(leaf);
+            leaves.push(leaf);
+            match token.format {
+                ExportFormat::Csv => data.push_str(&row),
+                ExportFormat::Json => {
+                    if index > 0 || after_item_id.is_some() {
+                        data.push(',');
+                    }
+                    data.push_str(&item_json(item).to_string());
+                }
+            }
+        }
+        if more {
+            return Chunk {
+                data,
+                leaves,
+                warehouses_sent,
+                items_sent,
+                merkle_root: None,
+                next: Some(ExportToken {
+                    warehouse_id,
+                    warehouse_open: true,
+                    after_item_id: items.last().map(|item| item.item_id).or(after_item_id),
+                    warehouses_sent,
+                    items_sent,
+                    subtrees,
+                    ..token
+                }),
+            };
+        }
+        if token.format == ExportFormat::Json {
+            data.push_str("]}");
+        }
+    }
+
+    if token.format == ExportFormat::Json {
+        data.push(']');
+    }
+    Chunk {
+        data,
+        leaves,
+        warehouses_sent,
+        items_sent,
+        merkle_root: Some(subtrees.root()),
+        next: None,
+    }
+}
+
+// Also the item representation of the HTTP gateway
+pub(crate) fn item_json(item: &StockItem) -> serde_json::Value {
+    serde_json::json!({
+        "item_id": item.item_id,
+        "warehouse_id": item.warehouse_id,
+        "item_name": item.item_name,
+        "quantity": item.quantity,
+        "unit": format!("{:?}", item.unit),
+        "sku": item.sku,
+        "barcodes": item.barcodes,
+        "unit_cost": item.unit_cost,
+        "unit_price": item.unit_price,
+        "currency": item.currency,
+        "category_id": item.category_id,
+        "bin_id": item.bin_id,
+        "reorder_point": item.reorder_point,
+        "version": item.version,
+    })
+}
+
+fn csv_row(warehouse: &Warehouse, item: Option<&StockItem>) -> String {
+    let optional = |value: Option<u64>| value.map(|value| value.to_string()).unwrap_or_default();
+    let mut fields = vec![warehouse.id.to_string(), warehouse.name.clone()];
+    match item {
+        Some(item) => fields.extend([
+            item.item_id.to_string(),
+            item.item_name.clone(),
+            item.quantity.to_string(),
+            format!("{:?}", item.unit),
+            item.sku.clone().unwrap_or_default(),
+            item.barcodes.join(" "),
+            optional(item.unit_cost),
+            optional(item.unit_price),
+            item.currency.clone().unwrap_or_default(),
+            optional(item.reorder_point),
+        ]),
+        None => fields.extend(std::iter::repeat_n(String::new(), 10)),
+    }
+    let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    fields.join(",") + "\n"
+}
+
+// Quoted when the value holds a comma, a quote or a line break, with quotes doubled
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
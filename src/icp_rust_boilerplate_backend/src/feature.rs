@@ -0,0 +1,121 @@
+// Feature flags for subsystems that are rolled out gradually. A flag is off unless it is
+// switched on for the whole deployment or for one organization; a per-organization
+// setting wins over the deployment-wide one, so a feature can also be held back for a
+// single tenant.
+use crate::tenancy::caller_org;
+use crate::{idempotency, Error, MutationOptions, _ensure_admin, FEATURE_FLAGS};
+
+// Scope of deployment-wide settings in FEATURE_FLAGS; organization ids never reach it
+const DEPLOYMENT: u64 = u64::MAX;
+
+// Stored by code(); never renumber a released feature
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum Feature {
+    Reservations,
+    Lots,
+    HttpApi,
+}
+
+const FEATURES: [Feature; 3] = [Feature::Reservations, Feature::Lots, Feature::HttpApi];
+
+impl Feature {
+    fn code(self) -> u8 {
+        match self {
+            Feature::Reservations => 1,
+            Feature::Lots => 2,
+            Feature::HttpApi => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Feature> {
+        FEATURES.into_iter().find(|feature| feature.code() == code)
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct FeatureState {
+    feature: Feature,
+    enabled: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct FeatureSetting {
+    feature: Feature,
+    org_id: Option<u64>, // None for the deployment-wide setting
+    enabled: bool,
+}
+
+// Effective state of every feature for the caller's organization
+#[ic_cdk::query]
+fn get_feature_flags() -> Vec<FeatureState> {
+    let org_id = caller_org();
+    FEATURES
+        .into_iter()
+        .map(|feature| FeatureState {
+            feature,
+            enabled: is_enabled(feature, org_id),
+        })
+        .collect()
+}
+
+// Every explicit setting, deployment-wide ones first
+#[ic_cdk::query]
+fn list_feature_settings() -> Result<Vec<FeatureSetting>, Error> {
+    _ensure_admin()?;
+    let mut settings: Vec<FeatureSetting> = FEATURE_FLAGS.with(|flags| {
+        flags
+            .borrow()
+            .iter()
+            .filter_map(|((code, scope), enabled)| {
+                Some(FeatureSetting {
+                    feature: Feature::from_code(code)?,
+                    org_id: (scope != DEPLOYMENT).then_some(scope),
+                    enabled: enabled == 1,
+                })
+            })
+            .collect()
+    });
+    settings.sort_by_key(|setting| setting.org_id.is_some());
+    Ok(settings)
+}
+
+// Sets a feature for the deployment (org_id None) or for one organization; `enabled`
+// None removes the setting, so the organization follows the deployment again
+#[ic_cdk::update]
+fn set_feature_flag(
+    feature: Feature,
+    org_id: Option<u64>,
+    enabled: Option<bool>,
+    options: Option<MutationOptions>,
+) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("set_feature_flag", options, || {
+        _ensure_admin()?;
+        let key = (feature.code(), org_id.unwrap_or(DEPLOYMENT));
+        FEATURE_FLAGS.with(|flags| match enabled {
+            Some(enabled) => flags.borrow_mut().insert(key, enabled as u8),
+            None => flags.borrow_mut().remove(&key),
+        });
+        Ok(())
+    })
+}
+
+// Drops the per-organization settings of every feature
+pub(crate) fn clear_org(org_id: u64) {
+    FEATURE_FLAGS.with(|flags| {
+        let mut flags = flags.borrow_mut();
+        for feature in FEATURES {
+            flags.remove(&(feature.code(), org_id));
+        }
+    });
+}
+
+pub(crate) fn is_enabled(feature: Feature, org_id: u64) -> bool {
+    FEATURE_FLAGS.with(|flags| {
+        let flags = flags.borrow();
+        flags
+            .get(&(feature.code(), org_id))
+            .or_else(|| flags.get(&(feature.code(), DEPLOYMENT)))
+            .is_some_and(|enabled| enabled == 1)
+    })
+}
@@ -0,0 +1,76 @@
+// Demand forecasts from the movement ledger. The outflows of an item (stock_out, not
+// corrections) over the last HISTORY_DAYS days are summed per day; the daily demand is
+// projected as their exponentially smoothed average, with the plain moving average of the
+// last MOVING_AVERAGE_DAYS days reported alongside. The suggested order brings the
+// available stock up to the projected demand plus the reorder point.
+use crate::ids::ItemId;
+use crate::{ledger, reservation, Error, _get_item};
+use crate::env::time;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const HISTORY_DAYS: u64 = 56;
+const MOVING_AVERAGE_DAYS: u64 = 28;
+const SMOOTHING: f64 = 0.3; // Weight of the newest day
+const MAX_HORIZON_DAYS: u64 = 365;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct DemandForecast {
+    item_id: ItemId,
+    horizon_days: u64,
+    moving_average_per_day: f64,
+    smoothed_per_day: f64,
+    projected_demand: u64, // Over the horizon, rounded up
+    available: u64,        // Quantity less active reservations
+    reorder_point: Option<u64>,
+    suggested_order_quantity: u64,
+}
+
+#[ic_cdk::query]
+fn forecast_demand(item_id: ItemId, horizon_days: u64) -> Result<DemandForecast, Error> {
+    let item = _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    if !(1..=MAX_HORIZON_DAYS).contains(&horizon_days) {
+        return Err(Error::invalid_input(
+            "horizon_days",
+            "VALUE_OUT_OF_RANGE",
+            format!("horizon_days must be between 1 and {}, got {}", MAX_HORIZON_DAYS, horizon_days),
+        ));
+    }
+
+    let daily = daily_outflows(item_id);
+    let recent = &daily[(HISTORY_DAYS - MOVING_AVERAGE_DAYS) as usize..];
+    let moving_average_per_day = recent.iter().sum::<f64>() / MOVING_AVERAGE_DAYS as f64;
+    let smoothed_per_day = daily
+        .iter()
+        .skip(1)
+        .fold(daily[0], |smoothed, day| SMOOTHING * day + (1.0 - SMOOTHING) * smoothed);
+
+    // Saturating float-to-int conversion
+    let projected_demand = (smoothed_per_day * horizon_days as f64).ceil() as u64;
+    let available = reservation::available(&item);
+    let suggested_order_quantity = projected_demand
+        .saturating_add(item.reorder_point.unwrap_or(0))
+        .saturating_sub(available);
+
+    Ok(DemandForecast {
+        item_id,
+        horizon_days,
+        moving_average_per_day,
+        smoothed_per_day,
+        projected_demand,
+        available,
+        reorder_point: item.reorder_point,
+        suggested_order_quantity,
+    })
+}
+
+// Outflow of each of the last HISTORY_DAYS days (the current one included), oldest first
+fn daily_outflows(item_id: ItemId) -> Vec<f64> {
+    let now = time();
+    let since = now.saturating_sub(HISTORY_DAYS * DAY_NS);
+    let mut daily = vec![0.0; HISTORY_DAYS as usize];
+    for (at, quantity) in ledger::item_outflows(item_id, since) {
+        let day = (at.saturating_sub(since) / DAY_NS).min(HISTORY_DAYS - 1);
+        daily[day as usize] += quantity as f64;
+    }
+    daily
+}
@@ -0,0 +1,149 @@
+// A read-only JSON API over HTTP, so browsers and curl can read inventory without a
+// Candid client:
+//
+//   GET /warehouses?offset=&limit=           warehouses in id order
+//   GET /warehouses/{id}/stock?cursor=&limit= items of a warehouse in id order
+//   GET /items/{id}
+//   GET /metrics                              Prometheus text exposition format
+//
+// Requests through the HTTP gateway come from the anonymous principal, so they see the
+// records of the default organization only. Responses are not certified, so they are
+// served on the raw domain. /metrics is public, as on the system canisters, since a
+// scraper cannot authenticate as a controller; it holds canister-wide counts and no records.
+use crate::ids::{ItemId, WarehouseId};
+use crate::error::Error;
+use crate::export::item_json;
+use crate::tenancy::caller_org;
+use crate::{metrics, settings, store, Warehouse, WAREHOUSE_STORAGE};
+use crate::{_get_item, _get_warehouse};
+
+#[derive(candid::CandidType, Deserialize)]
+pub(crate) struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Serialize)]
+pub(crate) struct HttpGatewayResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpGatewayResponse {
+    if request.method != "GET" {
+        return respond(405, error_json("METHOD_NOT_ALLOWED", "Only GET is supported"));
+    }
+    let (path, query) = request.url.split_once('?').unwrap_or((&request.url, ""));
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    if segments == ["metrics"] {
+        return HttpGatewayResponse {
+            status_code: 200,
+            headers: vec![("Content-Type".to_string(), "text/plain; version=0.0.4".to_string())],
+            body: metrics::render_prometheus(&metrics::report()).into_bytes(),
+        };
+    }
+
+    let result = match segments.as_slice() {
+        ["warehouses"] => warehouses(query),
+        ["warehouses", id, "stock"] => id_param(id).and_then(|id| stock(WarehouseId(id), query)),
+        ["items", id] => id_param(id).and_then(|id| item(ItemId(id))),
+        _ => return respond(404, error_json("NOT_FOUND", &format!("No route for {}", path))),
+    };
+    match result {
+        Ok(body) => respond(200, body),
+        Err(error) => {
+            let status = match error {
+                Error::NotFound { .. } => 404,
+                Error::InvalidInput { .. } => 400,
+                _ => 500,
+            };
+            let summary = error.summary();
+            let (code, message) = summary.split_once(": ").unwrap_or((&summary, ""));
+            respond(status, error_json(code, message))
+        }
+    }
+}
+
+fn warehouses(query: &str) -> Result<serde_json::Value, Error> {
+    let offset = number_param(query, "offset")?.unwrap_or(0);
+    let limit = settings::page_size(number_param(query, "limit")?)?;
+    let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+    let warehouses: Vec<serde_json::Value> = WAREHOUSE_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        store::listed_warehouses_page(caller_org(), offset, limit)
+            .iter()
+            .filter_map(|id| storage.get(id))
+            .map(|warehouse| warehouse_json(&warehouse))
+            .collect()
+    });
+    let next_offset = (warehouses.len() == limit).then(|| offset as u64 + limit as u64);
+    Ok(serde_json::json!({ "warehouses": warehouses, "next_offset": next_offset }))
+}
+
+fn stock(warehouse_id: WarehouseId, query: &str) -> Result<serde_json::Value, Error> {
+    let cursor = number_param(query, "cursor")?.map(ItemId);
+    let limit = settings::page_size(number_param(query, "limit")?)?;
+    _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+
+    // One extra item tells whether another page follows
+    let mut items = store::items_in_warehouse(warehouse_id, cursor, limit + 1);
+    let more = items.len() > limit;
+    items.truncate(limit);
+    let next_cursor = if more { items.last().map(|item| item.item_id) } else { None };
+    let items: Vec<serde_json::Value> = items.iter().map(item_json).collect();
+    Ok(serde_json::json!({ "items": items, "next_cursor": next_cursor }))
+}
+
+fn item(item_id: ItemId) -> Result<serde_json::Value, Error> {
+    _get_item(&item_id)
+        .map(|item| item_json(&item))
+        .ok_or_else(|| Error::not_found("item", item_id))
+}
+
+fn warehouse_json(warehouse: &Warehouse) -> serde_json::Value {
+    serde_json::json!({
+        "id": warehouse.id,
+        "name": warehouse.name,
+        "created_at": warehouse.created_at,
+        "updated_at": warehouse.updated_at,
+        "version": warehouse.version,
+    })
+}
+
+fn id_param(segment: &str) -> Result<u64, Error> {
+    segment
+        .parse()
+        .map_err(|_| Error::invalid_input("id", "INVALID_FORMAT", format!("Invalid id {:?}", segment)))
+}
+
+// The value of `name` in the query string, if present
+fn number_param(query: &str, name: &str) -> Result<Option<u64>, Error> {
+    let value = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value);
+    value
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| Error::invalid_input(name, "INVALID_FORMAT", format!("Invalid {} {:?}", name, value)))
+        })
+        .transpose()
+}
+
+fn error_json(code: &str, message: &str) -> serde_json::Value {
+    serde_json::json!({ "code": code, "message": message })
+}
+
+fn respond(status_code: u16, body: serde_json::Value) -> HttpGatewayResponse {
+    HttpGatewayResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: body.to_string().into_bytes(),
+    }
+}
@@ -0,0 +1,63 @@
+// Where warehouses are, for routing an order to the nearest warehouse that can fill it.
+// Distances are along the surface of a sphere with the Earth's mean radius (the haversine
+// formula), which is within half a percent of the distance on the ellipsoid.
+use crate::settings::page_size;
+use crate::tenancy::caller_org;
+use crate::{store, validation, Error, Warehouse, STOCK_STORAGE, WAREHOUSE_STORAGE};
+
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct NearbyWarehouse {
+    warehouse: Warehouse,
+    distance_km: f64,
+}
+
+// Listed warehouses of the caller's organization that have a location, nearest first.
+// With `item_name`, only those with an item of that name in stock.
+#[ic_cdk::query]
+fn find_nearest_warehouses(
+    latitude: f64,
+    longitude: f64,
+    limit: Option<u64>,
+    item_name: Option<String>,
+) -> Result<Vec<NearbyWarehouse>, Error> {
+    validation::validate_location(Some(latitude), Some(longitude))?;
+    let limit = page_size(limit)?;
+    let item_name = item_name.map(|name| validation::validate_name("item_name", &name)).transpose()?;
+
+    let mut nearby: Vec<NearbyWarehouse> = WAREHOUSE_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        store::listed_warehouse_ids(caller_org())
+            .iter()
+            .filter_map(|id| storage.get(id))
+            .filter(|warehouse| item_name.as_ref().is_none_or(|name| holds(warehouse, name)))
+            .filter_map(|warehouse| {
+                let location = warehouse.latitude.zip(warehouse.longitude)?;
+                Some(NearbyWarehouse {
+                    distance_km: distance_km((latitude, longitude), location),
+                    warehouse,
+                })
+            })
+            .collect()
+    });
+    nearby.sort_by(|a, b| a.distance_km.total_cmp(&b.distance_km).then(a.warehouse.id.cmp(&b.warehouse.id)));
+    nearby.truncate(limit);
+    Ok(nearby)
+}
+
+fn holds(warehouse: &Warehouse, item_name: &str) -> bool {
+    store::item_id_by_name(warehouse.id, item_name)
+        .and_then(|item_id| STOCK_STORAGE.with(|storage| storage.borrow().get(&item_id)))
+        .is_some_and(|item| item.quantity > 0)
+}
+
+// Great-circle distance between two (latitude, longitude) points in degrees
+fn distance_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (from_latitude, to_latitude) = (from.0.to_radians(), to.0.to_radians());
+    let latitude_delta = to_latitude - from_latitude;
+    let longitude_delta = (to.1 - from.1).to_radians();
+    let a = (latitude_delta / 2.0).sin().powi(2)
+        + from_latitude.cos() * to_latitude.cos() * (longitude_delta / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
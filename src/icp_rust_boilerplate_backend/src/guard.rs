@@ -0,0 +1,52 @@
+// Checks shared by every update endpoint. idempotency::run calls ensure_caller first and
+// before_first_run unless it replays a stored response, so an endpoint gets them by going
+// through run like every other update.
+use crate::tenancy::{self, NO_ORG};
+use crate::{killswitch, maintenance, quota, ratelimit, Error};
+use candid::Principal;
+
+const JOINING_METHODS: &[&str] = &["create_organization", "accept_invitation"];
+
+// Who may call `method` at all, replays included
+pub(crate) fn ensure_caller(method: &str) -> Result<(), Error> {
+    ensure_authenticated()?;
+    ensure_affiliated(method)
+}
+
+// Whether a call that will run may run now; it is charged to the rate limit and the quota
+pub(crate) fn before_first_run(method: &str) -> Result<(), Error> {
+    maintenance::ensure_writable()?;
+    killswitch::ensure_enabled(method)?;
+    ratelimit::take_call()?;
+    quota::record_call()
+}
+
+// The same checks without counting the call, for previews of an update
+pub(crate) fn check_update(method: &str) -> Result<(), Error> {
+    ensure_caller(method)?;
+    maintenance::ensure_writable()?;
+    killswitch::ensure_enabled(method)?;
+    ratelimit::ensure_call_available()?;
+    quota::ensure_call_quota()
+}
+
+// Anonymous callers may read; every write is attributed to a principal
+pub(crate) fn ensure_authenticated() -> Result<(), Error> {
+    let caller = crate::env::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::unauthorized(caller, "Anonymous callers cannot make updates".to_string()));
+    }
+    Ok(())
+}
+
+// Principals without a membership reach no records, and may only join an organization
+fn ensure_affiliated(method: &str) -> Result<(), Error> {
+    let caller = crate::env::caller();
+    if tenancy::caller_org() == NO_ORG && !JOINING_METHODS.contains(&method) {
+        return Err(Error::unauthorized(
+            caller,
+            format!("Caller {} belongs to no organization; create one or accept an invitation", caller),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,73 @@
+// A status for external monitoring. Every check reads counters, map lengths or the last
+// key of a map, so polling stays cheap at any data size; check_integrity (admin) does the
+// full comparison and names the records involved. Quantities are unsigned, so there is no
+// negative availability to look for.
+use crate::{maintenance, totals};
+use crate::{ITEMS_BY_CREATOR, ORG_WAREHOUSES, ITEM_ID_INCREMENT, ITEM_NAME_INDEX, PENDING_PURGES, PURGE_SCHEDULED, STOCK_STORAGE};
+use crate::{WAREHOUSE_ID_INCREMENT, WAREHOUSE_ITEMS, WAREHOUSE_NAME_INDEX, WAREHOUSE_STORAGE};
+use crate::env::time;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum HealthStatus {
+    Healthy,
+    Maintenance, // Checks pass, but update calls are refused
+    Degraded,    // At least one check failed
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct HealthCheck {
+    name: String,
+    ok: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Health {
+    status: HealthStatus,
+    checks: Vec<HealthCheck>,
+    checked_at: u64,
+}
+
+#[ic_cdk::query]
+fn health() -> Health {
+    let warehouses = WAREHOUSE_STORAGE.with(|storage| storage.borrow().len());
+    let items = STOCK_STORAGE.with(|storage| storage.borrow().len());
+    let max_warehouse_id = WAREHOUSE_STORAGE.with(|storage| storage.borrow().last_key_value().map_or(0, |(id, _)| id.0));
+    let max_item_id = STOCK_STORAGE.with(|storage| storage.borrow().last_key_value().map_or(0, |(id, _)| id.0));
+    let totals = totals::totals();
+    let purge_stalled = PENDING_PURGES.with(|pending| !pending.borrow().is_empty())
+        && !PURGE_SCHEDULED.with(|scheduled| *scheduled.borrow())
+        && !maintenance::is_enabled();
+
+    let checks = vec![
+        check("warehouse_id_counter", WAREHOUSE_ID_INCREMENT.with(|next| *next.borrow().get()) > max_warehouse_id),
+        check("item_id_counter", ITEM_ID_INCREMENT.with(|next| *next.borrow().get()) > max_item_id),
+        check("warehouse_count", totals.warehouse_count == warehouses),
+        check("item_count", totals.item_count == items),
+        check("warehouse_name_index", WAREHOUSE_NAME_INDEX.with(|index| index.borrow().len()) == warehouses),
+        check("org_warehouses_index", ORG_WAREHOUSES.with(|index| index.borrow().len()) == warehouses),
+        check("item_name_index", ITEM_NAME_INDEX.with(|index| index.borrow().len()) == items),
+        check("warehouse_items_index", WAREHOUSE_ITEMS.with(|index| index.borrow().len()) == items),
+        check("items_by_creator_index", ITEMS_BY_CREATOR.with(|index| index.borrow().len()) == items),
+        check("purge_timer", !purge_stalled),
+    ];
+
+    let status = if checks.iter().any(|check| !check.ok) {
+        HealthStatus::Degraded
+    } else if maintenance::is_enabled() {
+        HealthStatus::Maintenance
+    } else {
+        HealthStatus::Healthy
+    };
+    Health {
+        status,
+        checks,
+        checked_at: time(),
+    }
+}
+
+fn check(name: &str, ok: bool) -> HealthCheck {
+    HealthCheck {
+        name: name.to_string(),
+        ok,
+    }
+}
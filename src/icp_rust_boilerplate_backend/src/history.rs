@@ -0,0 +1,88 @@
+// Quantity of every stock item over time, for charts. store.rs records the level an item
+// is left at whenever a write creates it, changes its quantity or removes it (level 0).
+// Points are keyed by time, so of several writes in one message only the last is kept.
+use crate::ids::ItemId;
+use crate::settings::page_size;
+use crate::tenancy::caller_org;
+use crate::{Error, StockItem, ITEM_HISTORY};
+use candid::{Decode, Encode};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct HistoryEntry {
+    org_id: u64,
+    quantity: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct QuantityPoint {
+    at: u64,
+    quantity: u64, // Level after the change
+}
+
+impl Storable for HistoryEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for HistoryEntry {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Points from `from` to `to` (nanoseconds, inclusive), oldest first. For the next page
+// pass the time of the last point plus one as `from`. The history outlives the item.
+#[ic_cdk::query]
+fn get_item_history(item_id: ItemId, from: u64, to: u64, limit: u64) -> Result<Vec<QuantityPoint>, Error> {
+    let limit = page_size(Some(limit))?;
+    if from > to {
+        return Err(Error::invalid_input(
+            "to",
+            "INVALID_RANGE",
+            format!("to must not be before from, got from={} to={}", from, to),
+        ));
+    }
+    let org_id = caller_org();
+    Ok(ITEM_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range((item_id, from)..=(item_id, to))
+            .filter(|(_, entry)| entry.org_id == org_id)
+            .take(limit)
+            .map(|((_, at), entry)| QuantityPoint {
+                at,
+                quantity: entry.quantity,
+            })
+            .collect()
+    }))
+}
+
+// Called by store.rs for every item write
+pub(crate) fn record(old: Option<&StockItem>, new: Option<&StockItem>) {
+    let Some(item) = new.or(old) else {
+        return;
+    };
+    // Renames, moves and price changes are not points
+    if let (Some(old), Some(new)) = (old, new) {
+        if old.quantity == new.quantity {
+            return;
+        }
+    }
+    let quantity = new.map_or(0, |new| new.quantity);
+    ITEM_HISTORY.with(|history| {
+        history.borrow_mut().insert(
+            (item.item_id, time()),
+            HistoryEntry {
+                org_id: item.org_id,
+                quantity,
+            },
+        )
+    });
+}
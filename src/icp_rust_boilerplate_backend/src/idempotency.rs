@@ -0,0 +1,225 @@
+// Replay protection for update calls. A caller that retries with the same idempotency key
+// within the retention window (see settings.rs) gets the stored response back instead of
+// applying the change twice. A key is for one request: reusing it with other arguments is
+// rejected rather than answered with the response to the first ones.
+use crate::settings::idempotency_window_ns;
+use crate::{audit, guard, metrics, Error, IDEMPOTENCY_EXPIRY, IDEMPOTENCY_RESPONSES};
+use candid::{CandidType, Decode, Encode};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+const MAX_KEY_LENGTH: usize = 64; // In characters
+const MAX_RESPONSE_SIZE: usize = 3 * 1024; // Larger responses are remembered but not replayed
+const PRUNE_BATCH: usize = 100; // Expired entries dropped per update call
+
+// Trailing optional argument of every update endpoint
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct MutationOptions {
+    idempotency_key: Option<String>, // Scoped to the caller and the method
+    expected_version: Option<u64>,   // Version of the record the caller last read
+}
+
+pub(crate) fn expected_version(options: &Option<MutationOptions>) -> Option<u64> {
+    options.as_ref().and_then(|options| options.expected_version)
+}
+
+// For endpoints that do not change an existing warehouse or stock item
+pub(crate) fn reject_expected_version(options: &Option<MutationOptions>) -> Result<(), Error> {
+    match expected_version(options) {
+        Some(_) => Err(Error::invalid_input(
+            "expected_version",
+            "NOT_SUPPORTED",
+            "expected_version is not supported by this endpoint".to_string(),
+        )),
+        None => Ok(()),
+    }
+}
+
+// sha256 over (caller, method, idempotency key)
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct RequestKey([u8; 32]);
+
+impl Storable for RequestKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RequestKey(bytes.as_ref().try_into().unwrap())
+    }
+}
+
+impl BoundedStorable for RequestKey {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct StoredResponse {
+    created_at: u64,
+    response: Option<Vec<u8>>, // Candid-encoded Ok value, None if it exceeded MAX_RESPONSE_SIZE
+    args_hash: Option<Vec<u8>>, // sha256 of the encoded arguments; None for entries of earlier releases
+}
+
+impl Storable for StoredResponse {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StoredResponse {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Runs `apply` at most once per idempotency key, after the checks in guard.rs. A replay is
+// only checked for the caller: it changes nothing, so it is answered in maintenance mode too
+// and takes no rate limit token or quota call. Only successful results are stored: a failed
+// call changes nothing, so retrying it is safe. Every outcome is counted in metrics.rs.
+pub(crate) fn run<T, F>(method: &str, options: Option<MutationOptions>, apply: F) -> Result<T, Error>
+where
+    T: CandidType + DeserializeOwned,
+    F: FnOnce() -> Result<T, Error>,
+{
+    let result = audit::in_method(method, || run_once(method, options, apply));
+    metrics::record_call(method, result.as_ref().err());
+    result
+}
+
+fn run_once<T, F>(method: &str, options: Option<MutationOptions>, apply: F) -> Result<T, Error>
+where
+    T: CandidType + DeserializeOwned,
+    F: FnOnce() -> Result<T, Error>,
+{
+    guard::ensure_caller(method)?;
+    let now = time();
+
+    let request = match options.and_then(|options| options.idempotency_key) {
+        Some(key) => {
+            validate_key(&key)?;
+            let request = request_key(method, &key);
+            let args_hash = Sha256::digest(ic_cdk::api::call::arg_data_raw()).to_vec();
+            if let Some(replayed) = replay(method, &key, &request, &args_hash, now) {
+                return replayed;
+            }
+            Some((request, args_hash))
+        }
+        None => None,
+    };
+
+    guard::before_first_run(method)?;
+    prune_expired(now);
+    let value = apply()?;
+    let Some((request, args_hash)) = request else {
+        return Ok(value);
+    };
+
+    let encoded = candid::encode_one(&value).unwrap();
+    let stored = StoredResponse {
+        created_at: now,
+        response: (encoded.len() <= MAX_RESPONSE_SIZE).then_some(encoded),
+        args_hash: Some(args_hash),
+    };
+    let previous = IDEMPOTENCY_RESPONSES.with(|responses| responses.borrow_mut().insert(request.clone(), stored));
+    IDEMPOTENCY_EXPIRY.with(|expiry| {
+        let mut expiry = expiry.borrow_mut();
+        if let Some(previous) = previous {
+            expiry.remove(&(previous.created_at, request.clone()));
+        }
+        expiry.insert((now, request), ());
+    });
+
+    Ok(value)
+}
+
+// The stored response to the request, if it is still within the retention window
+fn replay<T: CandidType + DeserializeOwned>(
+    method: &str,
+    key: &str,
+    request: &RequestKey,
+    args_hash: &[u8],
+    now: u64,
+) -> Option<Result<T, Error>> {
+    let stored = IDEMPOTENCY_RESPONSES.with(|responses| responses.borrow().get(request))?;
+    if now >= stored.created_at.saturating_add(idempotency_window_ns()) {
+        return None;
+    }
+    if stored.args_hash.is_some_and(|stored_hash| stored_hash != args_hash) {
+        return Some(Err(Error::conflict(
+            "request",
+            None,
+            "IDEMPOTENCY_KEY_REUSED",
+            format!("Idempotency key {:?} was already used for a {} call with other arguments", key, method),
+        )));
+    }
+    Some(match stored.response {
+        Some(bytes) => Ok(candid::decode_one(&bytes).unwrap()),
+        None => Err(Error::conflict(
+            "request",
+            None,
+            "DUPLICATE_REQUEST",
+            format!(
+                "A {} call with idempotency key {:?} was already applied; its response is too large to replay",
+                method, key
+            ),
+        )),
+    })
+}
+
+pub(crate) fn validate_options(options: &Option<MutationOptions>) -> Result<(), Error> {
+    match options.as_ref().and_then(|options| options.idempotency_key.as_ref()) {
+        Some(key) => validate_key(key),
+        None => Ok(()),
+    }
+}
+
+fn validate_key(key: &str) -> Result<(), Error> {
+    let length = key.chars().count();
+    if length == 0 || length > MAX_KEY_LENGTH {
+        return Err(Error::invalid_input(
+            "idempotency_key",
+            "INVALID_IDEMPOTENCY_KEY",
+            format!("idempotency_key must be 1 to {} characters, got {}", MAX_KEY_LENGTH, length),
+        ));
+    }
+    Ok(())
+}
+
+fn request_key(method: &str, key: &str) -> RequestKey {
+    let caller = crate::env::caller();
+    let caller = caller.as_slice();
+
+    // Length prefixes keep ("ab", "c") and ("a", "bc") apart
+    let mut hasher = Sha256::new();
+    for part in [caller, method.as_bytes(), key.as_bytes()] {
+        hasher.update((part.len() as u32).to_be_bytes());
+        hasher.update(part);
+    }
+    RequestKey(hasher.finalize().into())
+}
+
+// Entries are dropped a few at a time on each update call, oldest first
+fn prune_expired(now: u64) {
+    let cutoff = now.saturating_sub(idempotency_window_ns());
+    let expired: Vec<(u64, RequestKey)> = IDEMPOTENCY_EXPIRY.with(|expiry| {
+        expiry
+            .borrow()
+            .iter()
+            .take(PRUNE_BATCH)
+            .map(|(entry, _)| entry)
+            .take_while(|(created_at, _)| *created_at <= cutoff)
+            .collect()
+    });
+
+    for entry in expired {
+        IDEMPOTENCY_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&entry));
+        IDEMPOTENCY_RESPONSES.with(|responses| responses.borrow_mut().remove(&entry.1));
+    }
+}
@@ -0,0 +1,69 @@
+// Ids of warehouses and stock items as distinct types, so one cannot be passed where the
+// other is expected. Both are transparent: Candid sees a nat64, so the interface and
+// every stored record keep their layout, and as stable map keys they have the bytes of
+// the u64 they wrap. The interface is deliberately unchanged: the .did shows nat64, not
+// WarehouseId or ItemId, because Candid 0.9 can only name records and variants, and an
+// alias of nat64 would change the type table of every encoded value.
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::fmt;
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct WarehouseId(pub(crate) u64);
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct ItemId(pub(crate) u64);
+
+impl fmt::Display for WarehouseId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for ItemId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<WarehouseId> for u64 {
+    fn from(id: WarehouseId) -> u64 {
+        id.0
+    }
+}
+
+impl From<ItemId> for u64 {
+    fn from(id: ItemId) -> u64 {
+        id.0
+    }
+}
+
+impl Storable for WarehouseId {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        WarehouseId(u64::from_bytes(bytes))
+    }
+}
+
+impl BoundedStorable for WarehouseId {
+    const MAX_SIZE: u32 = u64::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for ItemId {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        ItemId(u64::from_bytes(bytes))
+    }
+}
+
+impl BoundedStorable for ItemId {
+    const MAX_SIZE: u32 = u64::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = true;
+}
@@ -0,0 +1,447 @@
+use crate::ids::WarehouseId;
+use crate::{Error, MutationOptions, StockItem, StockItemPayload, IMPORT_MAPPINGS};
+use crate::{_add_item_to_warehouse, _ensure_admin, _ensure_fits};
+use crate::{idempotency, store, validation};
+use candid::{Decode, Encode};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+pub(crate) const MAX_CSV_BYTES: usize = 512 * 1024;
+pub(crate) const MAX_IMPORT_ROWS: usize = 1_000; // Data rows per call, excluding the header
+pub(crate) const MAX_MAPPING_ENTRIES: usize = 32; // Per list of columns, defaults and conversions
+
+// Fields of a stock item that a CSV column can be mapped onto
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum ImportField {
+    WarehouseId,
+    ItemName,
+    Quantity,
+    Unit,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ColumnMapping {
+    column: String,
+    field: ImportField,
+}
+
+// Value used when the mapped column is missing or empty in a row
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FieldDefault {
+    field: ImportField,
+    value: String,
+}
+
+// Quantities given in `unit` are multiplied by `factor` (e.g. "dozen" -> 12)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct UnitConversion {
+    unit: String,
+    factor: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ImportMapping {
+    id: u64,
+    name: String,
+    delimiter: String,
+    columns: Vec<ColumnMapping>,
+    defaults: Vec<FieldDefault>,
+    unit_conversions: Vec<UnitConversion>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ImportMappingPayload {
+    name: String,
+    delimiter: Option<String>, // Defaults to ","
+    columns: Vec<ColumnMapping>,
+    defaults: Vec<FieldDefault>,
+    unit_conversions: Vec<UnitConversion>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ImportRowResult {
+    row: u64, // 1-based line number in the CSV, the header being row 1
+    pub(crate) result: Result<StockItem, Error>,
+}
+
+impl Storable for ImportMapping {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ImportMapping {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_import_mapping(id: u64) -> Result<ImportMapping, Error> {
+    IMPORT_MAPPINGS
+        .with(|mappings| mappings.borrow().get(&id))
+        .ok_or_else(|| Error::not_found("import_mapping", id))
+}
+
+#[ic_cdk::query]
+fn list_import_mappings() -> Vec<ImportMapping> {
+    IMPORT_MAPPINGS.with(|mappings| mappings.borrow().iter().map(|(_, m)| m).collect())
+}
+
+#[ic_cdk::update]
+fn create_import_mapping(payload: ImportMappingPayload, options: Option<MutationOptions>) -> Result<ImportMapping, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("create_import_mapping", options, || create_mapping(payload))
+}
+
+fn create_mapping(payload: ImportMappingPayload) -> Result<ImportMapping, Error> {
+    _ensure_admin()?;
+
+    // Mappings are never re-numbered, so the next id follows the highest one in use
+    let id = IMPORT_MAPPINGS.with(|mappings| {
+        mappings
+            .borrow()
+            .last_key_value()
+            .map_or(1, |(last_id, _)| last_id + 1)
+    });
+    let mapping = build_mapping(id, payload, time(), None)?;
+    _ensure_fits("import_mapping", &mapping)?;
+
+    IMPORT_MAPPINGS.with(|mappings| mappings.borrow_mut().insert(id, mapping.clone()));
+    Ok(mapping)
+}
+
+#[ic_cdk::update]
+fn update_import_mapping(
+    id: u64,
+    payload: ImportMappingPayload,
+    options: Option<MutationOptions>,
+) -> Result<ImportMapping, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("update_import_mapping", options, || update_mapping(id, payload))
+}
+
+fn update_mapping(id: u64, payload: ImportMappingPayload) -> Result<ImportMapping, Error> {
+    _ensure_admin()?;
+
+    let existing = get_import_mapping(id)?;
+    let mapping = build_mapping(id, payload, existing.created_at, Some(time()))?;
+    _ensure_fits("import_mapping", &mapping)?;
+
+    IMPORT_MAPPINGS.with(|mappings| mappings.borrow_mut().insert(id, mapping.clone()));
+    Ok(mapping)
+}
+
+#[ic_cdk::update]
+fn delete_import_mapping(id: u64, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("delete_import_mapping", options, || delete_mapping(id))
+}
+
+fn delete_mapping(id: u64) -> Result<(), Error> {
+    _ensure_admin()?;
+
+    match IMPORT_MAPPINGS.with(|mappings| mappings.borrow_mut().remove(&id)) {
+        Some(_) => Ok(()),
+        None => Err(Error::not_found("import_mapping", id)),
+    }
+}
+
+// Ingests a CSV document (header row first) using a stored mapping.
+// Each data row is added like a call to add_item_to_warehouse and reported separately.
+#[ic_cdk::update]
+fn import_csv(mapping_id: u64, csv: String, options: Option<MutationOptions>) -> Result<Vec<ImportRowResult>, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("import_csv", options, || import_rows(mapping_id, &csv))
+}
+
+fn import_rows(mapping_id: u64, csv: &str) -> Result<Vec<ImportRowResult>, Error> {
+    check_csv_size(csv)?;
+    let mapping = get_import_mapping(mapping_id)?;
+    let rows = parse_csv(csv, mapping_delimiter(&mapping));
+    check_row_count(&rows)?;
+
+    let mut rows = rows.into_iter();
+    let header = match rows.next() {
+        Some((_, header)) => header,
+        None => return Ok(Vec::new()), // Nothing to import
+    };
+    let positions = column_positions(&mapping, &header)?;
+
+    Ok(rows.map(|(row, values)| import_row(&mapping, &positions, row, &values)).collect())
+}
+
+// Adds one data row like a call to add_item_to_warehouse
+pub(crate) fn import_row(
+    mapping: &ImportMapping,
+    positions: &[(ImportField, usize)],
+    row: u64,
+    values: &[String],
+) -> ImportRowResult {
+    ImportRowResult {
+        row,
+        result: row_to_payload(mapping, positions, values).and_then(|payload| _add_item_to_warehouse(payload, None)),
+    }
+}
+
+// Also run from inspect_message, where the size checks happen before the CSV is parsed
+pub(crate) fn validate_import(mapping_id: u64, csv: &str) -> Result<(), Error> {
+    check_csv_size(csv)?;
+    match IMPORT_MAPPINGS.with(|mappings| mappings.borrow().get(&mapping_id)) {
+        Some(mapping) => check_row_count(&parse_csv(csv, mapping_delimiter(&mapping))),
+        None => Ok(()), // Reported by the handler
+    }
+}
+
+fn check_csv_size(csv: &str) -> Result<(), Error> {
+    if csv.len() > MAX_CSV_BYTES {
+        return Err(Error::invalid_input(
+            "csv",
+            "VALUE_TOO_LONG",
+            format!("csv must be at most {} bytes, got {}", MAX_CSV_BYTES, csv.len()),
+        ));
+    }
+    Ok(())
+}
+
+fn check_row_count(rows: &[(u64, Vec<String>)]) -> Result<(), Error> {
+    let data_rows = rows.len().saturating_sub(1);
+    if data_rows > MAX_IMPORT_ROWS {
+        return Err(Error::invalid_input(
+            "csv",
+            "TOO_MANY_ROWS",
+            format!("csv must have at most {} data rows, got {}", MAX_IMPORT_ROWS, data_rows),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn mapping_delimiter(mapping: &ImportMapping) -> char {
+    mapping.delimiter.chars().next().unwrap_or(',')
+}
+
+pub(crate) fn build_mapping(
+    id: u64,
+    payload: ImportMappingPayload,
+    created_at: u64,
+    updated_at: Option<u64>,
+) -> Result<ImportMapping, Error> {
+    let name = validation::validate_name("name", &payload.name)?;
+    let delimiter = payload.delimiter.unwrap_or_else(|| ",".to_string());
+    if delimiter.chars().count() != 1 || delimiter == "\"" {
+        return Err(Error::invalid_input(
+            "delimiter",
+            "INVALID_FORMAT",
+            format!("Delimiter must be a single character other than '\"', got {:?}", delimiter),
+        ));
+    }
+
+    for (field, count) in [
+        ("columns", payload.columns.len()),
+        ("defaults", payload.defaults.len()),
+        ("unit_conversions", payload.unit_conversions.len()),
+    ] {
+        if count > MAX_MAPPING_ENTRIES {
+            return Err(Error::invalid_input(
+                field,
+                "TOO_MANY_ENTRIES",
+                format!("{} must have at most {} entries, got {}", field, MAX_MAPPING_ENTRIES, count),
+            ));
+        }
+    }
+    for column in &payload.columns {
+        validation::validate_name("column", &column.column)?;
+    }
+    for default in &payload.defaults {
+        validation::validate_name("default", &default.value)?;
+    }
+    for conversion in &payload.unit_conversions {
+        validation::validate_name("unit", &conversion.unit)?;
+    }
+
+    // Every required field needs either a column or a default value
+    for (field, label) in [
+        (ImportField::WarehouseId, "warehouse_id"),
+        (ImportField::ItemName, "item_name"),
+        (ImportField::Quantity, "quantity"),
+    ] {
+        let mapped = payload.columns.iter().any(|c| c.field == field)
+            || payload.defaults.iter().any(|d| d.field == field);
+        if !mapped {
+            return Err(Error::invalid_input(
+                "columns",
+                "MISSING_VALUE",
+                format!("Field {} has neither a column nor a default value", label),
+            ));
+        }
+    }
+
+    if let Some(conversion) = payload.unit_conversions.iter().find(|c| c.factor == 0) {
+        return Err(Error::invalid_input(
+            "unit_conversions",
+            "VALUE_NOT_POSITIVE",
+            format!("Unit {} has a conversion factor of zero", conversion.unit),
+        ));
+    }
+
+    Ok(ImportMapping {
+        id,
+        name,
+        delimiter,
+        columns: payload.columns,
+        defaults: payload.defaults,
+        unit_conversions: payload.unit_conversions,
+        created_at,
+        updated_at,
+    })
+}
+
+// Resolves the position of every mapped column in the header row
+pub(crate) fn column_positions(mapping: &ImportMapping, header: &[String]) -> Result<Vec<(ImportField, usize)>, Error> {
+    mapping
+        .columns
+        .iter()
+        .map(|c| {
+            header
+                .iter()
+                .position(|h| same_label(h, &c.column))
+                .map(|pos| (c.field, pos))
+                .ok_or_else(|| {
+                    Error::invalid_input(
+                        &c.column,
+                        "MISSING_COLUMN",
+                        format!("Column {} not found in the CSV header", c.column),
+                    )
+                })
+        })
+        .collect()
+}
+
+fn row_to_payload(
+    mapping: &ImportMapping,
+    positions: &[(ImportField, usize)],
+    values: &[String],
+) -> Result<StockItemPayload, Error> {
+    // Column value first, then the mapping default
+    let value = |field: ImportField| -> Option<String> {
+        positions
+            .iter()
+            .filter(|(f, _)| *f == field)
+            .filter_map(|(_, pos)| values.get(*pos))
+            .map(|v| v.trim())
+            .find(|v| !v.is_empty())
+            .map(str::to_string)
+            .or_else(|| {
+                mapping
+                    .defaults
+                    .iter()
+                    .find(|d| d.field == field)
+                    .map(|d| d.value.clone())
+            })
+    };
+    let required = |field: ImportField, label: &str| {
+        value(field)
+            .ok_or_else(|| Error::invalid_input(label, "MISSING_VALUE", format!("Missing value for {}", label)))
+    };
+
+    let warehouse_id = required(ImportField::WarehouseId, "warehouse_id")?;
+    let warehouse_id = warehouse_id.parse::<u64>().map_err(|_| {
+        Error::invalid_input(
+            "warehouse_id",
+            "INVALID_FORMAT",
+            format!("Invalid warehouse_id {:?}", warehouse_id),
+        )
+    })?;
+    let item_name = required(ImportField::ItemName, "item_name")?;
+    let quantity = required(ImportField::Quantity, "quantity")?;
+    let mut quantity = quantity.parse::<u64>().map_err(|_| {
+        Error::invalid_input("quantity", "INVALID_FORMAT", format!("Invalid quantity {:?}", quantity))
+    })?;
+
+    if let Some(unit) = value(ImportField::Unit) {
+        let conversion = mapping
+            .unit_conversions
+            .iter()
+            .find(|c| same_label(&c.unit, &unit))
+            .ok_or_else(|| {
+                Error::invalid_input("unit", "UNKNOWN_UNIT", format!("No conversion defined for unit {}", unit))
+            })?;
+        quantity = quantity.checked_mul(conversion.factor).ok_or_else(|| {
+            Error::invalid_input(
+                "quantity",
+                "VALUE_TOO_LARGE",
+                format!("Quantity {} {} is too large", quantity, unit),
+            )
+        })?;
+    }
+
+    Ok(StockItemPayload {
+        warehouse_id: WarehouseId(warehouse_id),
+        item_name,
+        quantity,
+        unit: None,
+    })
+}
+
+// Header and unit labels match regardless of case, whitespace and Unicode normal form
+fn same_label(a: &str, b: &str) -> bool {
+    store::name_key(a) == store::name_key(b)
+}
+
+// Minimal RFC 4180 reader: quoted fields, doubled quotes and CRLF line endings.
+// Returns the non-empty records with their 1-based line number.
+pub(crate) fn parse_csv(text: &str, delimiter: char) -> Vec<(u64, Vec<String>)> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                _ => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                }
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_quotes = true,
+            '\r' => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                if record.iter().any(|f| !f.is_empty()) {
+                    records.push((record_line, std::mem::take(&mut record)));
+                }
+                line += 1;
+                record_line = line;
+            }
+            c if c == delimiter => record.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+
+    record.push(field);
+    if record.iter().any(|f| !f.is_empty()) {
+        records.push((record_line, record));
+    }
+    records
+}
@@ -0,0 +1,311 @@
+// Ingress messages are checked here before execution, so anonymous, oversized or invalid
+// calls are rejected without paying for them. Handlers repeat every check:
+// inspect_message does not run for calls from other canisters.
+use crate::alerts;
+use crate::backup::{Backup, BACKUP_CHUNK_BYTES};
+use crate::batch::{self, InventoryOp};
+use crate::bins::{self, BinPayload};
+use crate::category::{self, CategoryPayload};
+use crate::codes::{self, ItemCodes};
+use crate::counting::{self, CountEntry, CountScope};
+use crate::feature::Feature;
+use crate::idempotency::{self, MutationOptions};
+use crate::ids::{ItemId, WarehouseId};
+use crate::import::{self, ImportMappingPayload, MAX_CSV_BYTES};
+use crate::integrity::{self, RepairPlan};
+use crate::ledger::MovementReason;
+use crate::{guard, killswitch, maintenance};
+use crate::organization::{OrganizationPayload, Role};
+use crate::pricing::{self, ItemPrice};
+use crate::pubsub::Topic;
+use crate::purchasing::{self, PurchaseOrderPayload};
+use crate::quota::Quotas;
+use crate::reports::ReportKind;
+use crate::reservation;
+use crate::sales::{self, SalesOrderPayload};
+use crate::settings::{self, Settings};
+use crate::staffing::{self, ShiftWindow, StaffRole};
+use crate::staging::{self, MAX_CHUNK_BYTES};
+use crate::units::{self, Conversion, Unit};
+use crate::{validation, Error, StockItemPayload, UpdateItemPayload, UpdateWarehousePayload, WarehousePayload};
+use candid::Principal;
+use ic_cdk::api::call::{accept_message, arg_data, arg_data_raw_size, method_name};
+
+const MAX_IMPORT_ARG_BYTES: usize = MAX_CSV_BYTES + 1024;
+const MAX_CHUNK_ARG_BYTES: usize = MAX_CHUNK_BYTES + 1024;
+const MAX_RESTORE_CHUNK_ARG_BYTES: usize = BACKUP_CHUNK_BYTES as usize + 1024;
+
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let method = method_name();
+    match check(&method) {
+        Ok(()) => accept_message(),
+        Err(error) => ic_cdk::trap(&error.summary()),
+    }
+}
+
+fn check(method: &str) -> Result<(), Error> {
+    let max_size = match method {
+        "import_csv" => MAX_IMPORT_ARG_BYTES,
+        "upload_chunk" => MAX_CHUNK_ARG_BYTES,
+        "upload_restore_chunk" => MAX_RESTORE_CHUNK_ARG_BYTES,
+        _ => settings::max_arg_bytes(),
+    };
+    let size = arg_data_raw_size();
+    if size > max_size {
+        return Err(Error::invalid_input(
+            "arguments",
+            "PAYLOAD_TOO_LARGE",
+            format!("Arguments of {} must be at most {} bytes, got {}", method, max_size, size),
+        ));
+    }
+
+    // Also checked by the handler; rejecting here spares callers the cost of the call.
+    // Maintenance mode, the kill switch and the rate limit are left to the handler, which
+    // answers replays of stored responses despite them (see idempotency.rs).
+    guard::ensure_caller(method)?;
+
+    // A message whose arguments do not decode traps here, which also rejects it
+    match method {
+        "add_warehouse" => {
+            let (payload, options): (WarehousePayload, Option<MutationOptions>) = arg_data();
+            validation::validate_warehouse_payload(payload)?;
+            idempotency::validate_options(&options)
+        }
+        "update_warehouse" => {
+            let (_, payload, options): (u64, UpdateWarehousePayload, Option<MutationOptions>) = arg_data();
+            validation::validate_update_warehouse_payload(payload)?;
+            idempotency::validate_options(&options)
+        }
+        "start_count" => {
+            let (_, scope, options): (u64, CountScope, Option<MutationOptions>) = arg_data();
+            counting::validate_scope(&scope)?;
+            idempotency::validate_options(&options)
+        }
+        "submit_counts" => {
+            let (_, counts, options): (u64, Vec<CountEntry>, Option<MutationOptions>) = arg_data();
+            counting::validate_counts(&counts)?;
+            idempotency::validate_options(&options)
+        }
+        "delete_warehouse" | "delete_import_mapping" | "delete_tenant_data" | "post_count"
+        | "approve_count" | "cancel_count" | "end_staff_assignment" | "delete_category" | "delete_bin" | "remove_webhook"
+        | "release_reservation" | "commit_reservation" | "submit_purchase_order" | "receive_purchase_order"
+        | "pick_sales_order" | "ship_sales_order" | "cancel_sales_order" | "begin_import" | "commit_import"
+        | "cancel_import" | "archive_warehouse" | "restore_warehouse" | "purge_warehouse"
+        | "restore_item" | "unsubscribe" | "unschedule_report" | "commit_restore" => {
+            let (_, options): (u64, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "stock_in" | "stock_out" => {
+            let (_, quantity, _, options): (u64, u64, MovementReason, Option<MutationOptions>) = arg_data();
+            validation::validate_quantity("quantity", quantity)?;
+            idempotency::validate_options(&options)
+        }
+        "add_item_to_warehouse" => {
+            let (payload, options): (StockItemPayload, Option<MutationOptions>) = arg_data();
+            validation::validate_stock_item_payload(payload)?;
+            idempotency::validate_options(&options)
+        }
+        "update_item" => {
+            let (_, payload, options): (u64, UpdateItemPayload, Option<MutationOptions>) = arg_data();
+            validation::validate_update_item_payload(payload)?;
+            idempotency::validate_options(&options)
+        }
+        "delete_item" => {
+            let (_, quantity, options): (u64, u64, Option<MutationOptions>) = arg_data();
+            validation::validate_quantity("quantity", quantity)?;
+            idempotency::validate_options(&options)
+        }
+        "transfer_item" => {
+            let (_, _, _, quantity, options): (u64, u64, u64, u64, Option<MutationOptions>) = arg_data();
+            validation::validate_quantity("quantity", quantity)?;
+            idempotency::validate_options(&options)
+        }
+        "set_item_price" => {
+            let (_, price, options): (u64, ItemPrice, Option<MutationOptions>) = arg_data();
+            pricing::validate_price(&price)?;
+            idempotency::validate_options(&options)
+        }
+        "set_item_codes" => {
+            let (_, item_codes, options): (u64, ItemCodes, Option<MutationOptions>) = arg_data();
+            codes::validate_codes(item_codes)?;
+            idempotency::validate_options(&options)
+        }
+        "create_category" => {
+            let (payload, options): (CategoryPayload, Option<MutationOptions>) = arg_data();
+            category::validate_payload(&payload)?;
+            idempotency::validate_options(&options)
+        }
+        "create_backup" => {
+            let (options,): (Option<MutationOptions>,) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "begin_restore" => {
+            let (_, options): (Backup, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "upload_restore_chunk" => {
+            let (_, _, _, options): (u64, u64, Vec<u8>, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "schedule_report" => {
+            let (_, options): (ReportKind, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "create_bin" => {
+            let (_, payload, options): (WarehouseId, BinPayload, Option<MutationOptions>) = arg_data();
+            bins::validate_payload(&payload)?;
+            idempotency::validate_options(&options)
+        }
+        "update_category" => {
+            let (_, payload, options): (u64, CategoryPayload, Option<MutationOptions>) = arg_data();
+            category::validate_payload(&payload)?;
+            idempotency::validate_options(&options)
+        }
+        "create_purchase_order" => {
+            let (payload, options): (PurchaseOrderPayload, Option<MutationOptions>) = arg_data();
+            purchasing::validate_payload(payload)?;
+            idempotency::validate_options(&options)
+        }
+        "update_purchase_order" => {
+            let (_, payload, options): (u64, PurchaseOrderPayload, Option<MutationOptions>) = arg_data();
+            purchasing::validate_payload(payload)?;
+            idempotency::validate_options(&options)
+        }
+        "add_items_to_warehouse" => {
+            let (_, items, options): (u64, Vec<StockItemPayload>, Option<MutationOptions>) = arg_data();
+            batch::validate_add_count(items.len())?;
+            idempotency::validate_options(&options)
+        }
+        "transfer_items" => {
+            let (_, _, lines, options): (WarehouseId, WarehouseId, Vec<(ItemId, u64)>, Option<MutationOptions>) = arg_data();
+            batch::validate_transfer_lines(&lines)?;
+            idempotency::validate_options(&options)
+        }
+        "upload_chunk" => {
+            let (_, bytes, options): (u64, Vec<u8>, Option<MutationOptions>) = arg_data();
+            staging::validate_chunk(&bytes)?;
+            idempotency::validate_options(&options)
+        }
+        "apply_batch" => {
+            let (ops, options): (Vec<InventoryOp>, Option<MutationOptions>) = arg_data();
+            batch::validate_ops(&ops)?;
+            idempotency::validate_options(&options)
+        }
+        "create_sales_order" => {
+            let (payload, options): (SalesOrderPayload, Option<MutationOptions>) = arg_data();
+            sales::validate_payload(payload)?;
+            idempotency::validate_options(&options)
+        }
+        "set_item_category" | "set_reorder_point" | "move_item_to_bin" => {
+            let (_, _, options): (u64, Option<u64>, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "set_unit_conversion" => {
+            let (conversion, options): (Conversion, Option<MutationOptions>) = arg_data();
+            units::validate_conversion(&conversion)?;
+            idempotency::validate_options(&options)
+        }
+        "remove_unit_conversion" => {
+            let (_, _, options): (Unit, Unit, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "register_webhook" => {
+            let (url, options): (String, Option<MutationOptions>) = arg_data();
+            alerts::validate_url(&url)?;
+            idempotency::validate_options(&options)
+        }
+        "subscribe" => {
+            let (_, topics, options): (Principal, Vec<Topic>, Option<MutationOptions>) = arg_data();
+            if topics.is_empty() {
+                return Err(Error::invalid_input(
+                    "topics",
+                    "INVALID_TOPICS",
+                    "topics must name at least one topic, each at most once".to_string(),
+                ));
+            }
+            idempotency::validate_options(&options)
+        }
+        "reserve_stock" => {
+            let (_, quantity, reference, options): (u64, u64, String, Option<MutationOptions>) = arg_data();
+            reservation::validate_reservation(quantity, &reference)?;
+            idempotency::validate_options(&options)
+        }
+        "assign_staff" => {
+            let (_, _, _, shift, options): (u64, Principal, StaffRole, ShiftWindow, Option<MutationOptions>) = arg_data();
+            staffing::validate_shift(&shift)?;
+            idempotency::validate_options(&options)
+        }
+        "create_import_mapping" => {
+            let (payload, options): (ImportMappingPayload, Option<MutationOptions>) = arg_data();
+            import::build_mapping(0, payload, 0, None)?;
+            idempotency::validate_options(&options)
+        }
+        "update_import_mapping" => {
+            let (_, payload, options): (u64, ImportMappingPayload, Option<MutationOptions>) = arg_data();
+            import::build_mapping(0, payload, 0, None)?;
+            idempotency::validate_options(&options)
+        }
+        "import_csv" => {
+            let (mapping_id, csv, options): (u64, String, Option<MutationOptions>) = arg_data();
+            import::validate_import(mapping_id, &csv)?;
+            idempotency::validate_options(&options)
+        }
+        "repair" => {
+            let (plan, options): (RepairPlan, Option<MutationOptions>) = arg_data();
+            integrity::validate_plan(&plan)?;
+            idempotency::validate_options(&options)
+        }
+        "assign_organization" => {
+            let (_, _, options): (Principal, Option<u64>, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "set_maintenance_mode" => {
+            let (_, message, options): (bool, Option<String>, Option<MutationOptions>) = arg_data();
+            maintenance::validate_message("message", &message)?;
+            idempotency::validate_options(&options)
+        }
+        "disable_endpoint" => {
+            let (method, reason, options): (String, Option<String>, Option<MutationOptions>) = arg_data();
+            killswitch::validate_method(&method)?;
+            maintenance::validate_message("reason", &reason)?;
+            idempotency::validate_options(&options)
+        }
+        "enable_endpoint" => {
+            let (method, options): (String, Option<MutationOptions>) = arg_data();
+            killswitch::validate_method(&method)?;
+            idempotency::validate_options(&options)
+        }
+        "set_feature_flag" => {
+            let (_, _, _, options): (Feature, Option<u64>, Option<bool>, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "update_settings" => {
+            let (settings, options): (Settings, Option<MutationOptions>) = arg_data();
+            settings::validate_settings(&settings)?;
+            idempotency::validate_options(&options)
+        }
+        "set_quotas" => {
+            let (_, _, options): (u64, Quotas, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "create_organization" => {
+            let (payload, options): (OrganizationPayload, Option<MutationOptions>) = arg_data();
+            validation::validate_name("name", &payload.name)?;
+            idempotency::validate_options(&options)
+        }
+        "invite_member" | "set_member_role" => {
+            let (_, _, options): (Principal, Role, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "accept_invitation" => {
+            let (_, options): (u64, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        "remove_member" => {
+            let (_, options): (Principal, Option<MutationOptions>) = arg_data();
+            idempotency::validate_options(&options)
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,331 @@
+use crate::ids::{ItemId, WarehouseId};
+use crate::{certified, purge, totals};
+use crate::store::{self, name_key};
+use crate::{idempotency, Error, MutationOptions, _ensure_admin};
+use crate::{ITEM_ID_INCREMENT, WAREHOUSE_ID_INCREMENT};
+use crate::{ITEM_NAME_INDEX, STOCK_STORAGE, WAREHOUSE_ITEMS, WAREHOUSE_NAME_INDEX, WAREHOUSE_STORAGE};
+use crate::env::time;
+
+const DEFAULT_BATCH_SIZE: u64 = 500;
+const MAX_BATCH_SIZE: u64 = 5_000;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CounterIssue {
+    counter: String,
+    msg: String,
+}
+
+// Every list holds at most `limit` ids; `truncated` is set when more problems exist
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct IntegrityReport {
+    orphaned_items: Vec<ItemId>, // Stock items whose warehouse_id points nowhere (and is not being purged)
+    dangling_warehouse_index_entries: Vec<WarehouseId>, // Name index entries without a matching warehouse
+    missing_warehouse_index_entries: Vec<WarehouseId>, // Warehouses absent from the name index
+    dangling_item_index_entries: Vec<ItemId>,
+    missing_item_index_entries: Vec<ItemId>,
+    dangling_warehouse_items_entries: Vec<ItemId>, // Item ids in WAREHOUSE_ITEMS
+    missing_warehouse_items_entries: Vec<ItemId>,
+    counter_issues: Vec<CounterIssue>,
+    truncated: bool,
+    checked_at: u64,
+}
+
+impl IntegrityReport {
+    fn has_index_issues(&self) -> bool {
+        !(self.dangling_warehouse_index_entries.is_empty()
+            && self.missing_warehouse_index_entries.is_empty()
+            && self.dangling_item_index_entries.is_empty()
+            && self.missing_item_index_entries.is_empty()
+            && self.dangling_warehouse_items_entries.is_empty()
+            && self.missing_warehouse_items_entries.is_empty())
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RepairPlan {
+    remove_orphaned_items: bool,
+    fix_indexes: bool,
+    fix_counters: bool,
+    batch_size: Option<u64>, // Problems fixed per category and call, defaults to 500
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct RepairReport {
+    orphaned_items_removed: u64,
+    index_entries_removed: u64,
+    index_entries_added: u64,
+    counters_fixed: u64,
+    complete: bool, // False when the batch limit was hit; call repair again
+}
+
+#[ic_cdk::query]
+fn check_integrity(limit: Option<u64>) -> Result<IntegrityReport, Error> {
+    _ensure_admin()?;
+    Ok(collect(batch_size(limit)?))
+}
+
+#[ic_cdk::update]
+fn repair(plan: RepairPlan, options: Option<MutationOptions>) -> Result<RepairReport, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("repair", options, || apply_repair(plan))
+}
+
+fn apply_repair(plan: RepairPlan) -> Result<RepairReport, Error> {
+    _ensure_admin()?;
+
+    let report = collect(batch_size(plan.batch_size)?);
+    let mut result = RepairReport::default();
+
+    if plan.remove_orphaned_items {
+        for item_id in &report.orphaned_items {
+            store::remove_item(*item_id);
+            result.orphaned_items_removed += 1;
+        }
+    }
+
+    if plan.fix_indexes {
+        // Dangling index entries are removed by key, missing ones re-derived from the record
+        WAREHOUSE_NAME_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            let stale: Vec<_> = index
+                .iter()
+                .filter(|((_, id), _)| report.dangling_warehouse_index_entries.contains(id))
+                .map(|(key, _)| key)
+                .collect();
+            for key in stale {
+                index.remove(&key);
+                result.index_entries_removed += 1;
+            }
+        });
+        ITEM_NAME_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            let stale: Vec<_> = index
+                .iter()
+                .filter(|((_, id), _)| report.dangling_item_index_entries.contains(id))
+                .map(|(key, _)| key)
+                .collect();
+            for key in stale {
+                index.remove(&key);
+                result.index_entries_removed += 1;
+            }
+        });
+
+        WAREHOUSE_ITEMS.with(|index| {
+            let mut index = index.borrow_mut();
+            let stale: Vec<_> = index
+                .iter()
+                .filter(|((_, id), _)| report.dangling_warehouse_items_entries.contains(id))
+                .map(|(key, _)| key)
+                .collect();
+            for key in stale {
+                index.remove(&key);
+                result.index_entries_removed += 1;
+            }
+        });
+
+        for id in &report.missing_warehouse_index_entries {
+            if let Some(warehouse) = WAREHOUSE_STORAGE.with(|s| s.borrow().get(id)) {
+                let key = ((warehouse.org_id, name_key(&warehouse.name)), *id);
+                WAREHOUSE_NAME_INDEX.with(|index| index.borrow_mut().insert(key, ()));
+                result.index_entries_added += 1;
+            }
+        }
+        for id in &report.missing_item_index_entries {
+            if let Some(item) = STOCK_STORAGE.with(|s| s.borrow().get(id)) {
+                let key = ((item.warehouse_id, name_key(&item.item_name)), *id);
+                ITEM_NAME_INDEX.with(|index| index.borrow_mut().insert(key, ()));
+                result.index_entries_added += 1;
+            }
+        }
+        for id in &report.missing_warehouse_items_entries {
+            if let Some(item) = STOCK_STORAGE.with(|s| s.borrow().get(id)) {
+                WAREHOUSE_ITEMS.with(|index| index.borrow_mut().insert((item.warehouse_id, *id), ()));
+                result.index_entries_added += 1;
+            }
+        }
+    }
+
+    if plan.fix_counters && !report.counter_issues.is_empty() {
+        result.counters_fixed = fix_counters();
+    }
+
+    // Anything skipped by the plan is still outstanding
+    let skipped = (!plan.remove_orphaned_items && !report.orphaned_items.is_empty())
+        || (!plan.fix_indexes && report.has_index_issues())
+        || (!plan.fix_counters && !report.counter_issues.is_empty());
+    result.complete = !report.truncated && !skipped;
+
+    Ok(result)
+}
+
+pub(crate) fn validate_plan(plan: &RepairPlan) -> Result<(), Error> {
+    batch_size(plan.batch_size).map(|_| ())
+}
+
+fn batch_size(requested: Option<u64>) -> Result<u64, Error> {
+    match requested.unwrap_or(DEFAULT_BATCH_SIZE) {
+        size @ 1..=MAX_BATCH_SIZE => Ok(size),
+        size => Err(Error::invalid_input(
+            "batch_size",
+            "VALUE_OUT_OF_RANGE",
+            format!("batch_size must be between 1 and {}, got {}", MAX_BATCH_SIZE, size),
+        )),
+    }
+}
+
+fn collect(limit: u64) -> IntegrityReport {
+    let limit = limit as usize;
+    let mut report = IntegrityReport {
+        checked_at: time(),
+        ..Default::default()
+    };
+    let mut max_warehouse_id = 0;
+    WAREHOUSE_STORAGE.with(|storage| {
+        for (id, warehouse) in storage.borrow().iter() {
+            max_warehouse_id = max_warehouse_id.max(id.0);
+            let indexed = WAREHOUSE_NAME_INDEX
+                .with(|index| index.borrow().contains_key(&((warehouse.org_id, name_key(&warehouse.name)), id)));
+            if !indexed {
+                push(&mut report.missing_warehouse_index_entries, id, limit, &mut report.truncated);
+            }
+        }
+    });
+
+    let mut max_item_id = 0;
+    STOCK_STORAGE.with(|storage| {
+        for (id, item) in storage.borrow().iter() {
+            max_item_id = max_item_id.max(id.0);
+            let warehouse_exists = WAREHOUSE_STORAGE.with(|s| s.borrow().contains_key(&item.warehouse_id));
+            if !warehouse_exists && !purge::is_pending(item.warehouse_id) {
+                push(&mut report.orphaned_items, id, limit, &mut report.truncated);
+            }
+            let key = ((item.warehouse_id, name_key(&item.item_name)), id);
+            if !ITEM_NAME_INDEX.with(|index| index.borrow().contains_key(&key)) {
+                push(&mut report.missing_item_index_entries, id, limit, &mut report.truncated);
+            }
+            if !WAREHOUSE_ITEMS.with(|index| index.borrow().contains_key(&(item.warehouse_id, id))) {
+                push(&mut report.missing_warehouse_items_entries, id, limit, &mut report.truncated);
+            }
+        }
+    });
+
+    WAREHOUSE_NAME_INDEX.with(|index| {
+        for (((org_id, key), id), _) in index.borrow().iter() {
+            let matches = WAREHOUSE_STORAGE
+                .with(|s| s.borrow().get(&id))
+                .is_some_and(|warehouse| warehouse.org_id == org_id && name_key(&warehouse.name) == key);
+            if !matches {
+                push(&mut report.dangling_warehouse_index_entries, id, limit, &mut report.truncated);
+            }
+        }
+    });
+
+    ITEM_NAME_INDEX.with(|index| {
+        for (((warehouse_id, key), id), _) in index.borrow().iter() {
+            let matches = STOCK_STORAGE.with(|s| s.borrow().get(&id)).is_some_and(|item| {
+                item.warehouse_id == warehouse_id && name_key(&item.item_name) == key
+            });
+            if !matches {
+                push(&mut report.dangling_item_index_entries, id, limit, &mut report.truncated);
+            }
+        }
+    });
+
+    WAREHOUSE_ITEMS.with(|index| {
+        for ((warehouse_id, id), _) in index.borrow().iter() {
+            let matches = STOCK_STORAGE
+                .with(|s| s.borrow().get(&id))
+                .is_some_and(|item| item.warehouse_id == warehouse_id);
+            if !matches {
+                push(&mut report.dangling_warehouse_items_entries, id, limit, &mut report.truncated);
+            }
+        }
+    });
+
+    report.counter_issues = counter_issues(max_warehouse_id, max_item_id);
+    report
+}
+
+// Adds `id` unless `list` already holds `limit` ids
+fn push<Id>(list: &mut Vec<Id>, id: Id, limit: usize, truncated: &mut bool) {
+    if list.len() < limit {
+        list.push(id);
+    } else {
+        *truncated = true;
+    }
+}
+
+// Next ids must be above every id in use
+fn counter_issues(max_warehouse_id: u64, max_item_id: u64) -> Vec<CounterIssue> {
+    let mut issues = Vec::new();
+
+    let next_warehouse_id = WAREHOUSE_ID_INCREMENT.with(|c| *c.borrow().get());
+    if next_warehouse_id <= max_warehouse_id {
+        issues.push(CounterIssue {
+            counter: "warehouse_id".to_string(),
+            msg: format!(
+                "Next id {} is not above the highest warehouse id {}",
+                next_warehouse_id, max_warehouse_id
+            ),
+        });
+    }
+    let next_item_id = ITEM_ID_INCREMENT.with(|c| *c.borrow().get());
+    if next_item_id <= max_item_id {
+        issues.push(CounterIssue {
+            counter: "item_id".to_string(),
+            msg: format!("Next id {} is not above the highest item id {}", next_item_id, max_item_id),
+        });
+    }
+
+    if !totals::verify() {
+        issues.push(CounterIssue {
+            counter: "totals".to_string(),
+            msg: "Cached item counts or quantities differ from a full recount".to_string(),
+        });
+    }
+
+    if !certified::verify() {
+        issues.push(CounterIssue {
+            counter: "state_digest".to_string(),
+            msg: "The certified digest differs from one computed over all records".to_string(),
+        });
+    }
+
+    issues
+}
+
+// Returns the number of counters that were changed
+fn fix_counters() -> u64 {
+    let mut fixed = 0;
+
+    let max_warehouse_id =
+        WAREHOUSE_STORAGE.with(|s| s.borrow().last_key_value().map_or(0, |(id, _)| id.0));
+    WAREHOUSE_ID_INCREMENT.with(|c| {
+        let mut next = c.borrow_mut();
+        if *next.get() <= max_warehouse_id {
+            next.set(max_warehouse_id + 1).unwrap();
+            fixed += 1;
+        }
+    });
+
+    if !totals::verify() {
+        totals::rebuild();
+        fixed += 1;
+    }
+
+    if !certified::verify() {
+        certified::rebuild();
+        fixed += 1;
+    }
+
+    let max_item_id = STOCK_STORAGE.with(|s| s.borrow().last_key_value().map_or(0, |(id, _)| id.0));
+    ITEM_ID_INCREMENT.with(|c| {
+        let mut next = c.borrow_mut();
+        if *next.get() <= max_item_id {
+            next.set(max_item_id + 1).unwrap();
+            fixed += 1;
+        }
+    });
+
+    fixed
+}
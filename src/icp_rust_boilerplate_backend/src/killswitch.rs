@@ -0,0 +1,124 @@
+// Endpoints that controllers have switched off at runtime, e.g. transfers during a
+// physical count. guard.rs consults DISABLED_ENDPOINTS before every update call, so a
+// disabled endpoint fails for every caller, controllers included.
+use crate::{idempotency, maintenance, Error, MutationOptions, _ensure_admin, DISABLED_ENDPOINTS};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+const MAX_METHOD_LENGTH: usize = 64;
+
+// The switch itself can never be switched off
+const ALWAYS_ENABLED: [&str; 2] = ["disable_endpoint", "enable_endpoint"];
+
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct MethodName(String);
+
+impl Storable for MethodName {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        MethodName(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for MethodName {
+    const MAX_SIZE: u32 = MAX_METHOD_LENGTH as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DisabledEndpoint {
+    method: String,
+    reason: Option<String>,
+    disabled_at: u64,
+    disabled_by: Principal,
+}
+
+impl Storable for DisabledEndpoint {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DisabledEndpoint {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn list_disabled_endpoints() -> Vec<DisabledEndpoint> {
+    DISABLED_ENDPOINTS.with(|endpoints| endpoints.borrow().iter().map(|(_, endpoint)| endpoint).collect())
+}
+
+#[ic_cdk::update]
+fn disable_endpoint(method: String, reason: Option<String>, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("disable_endpoint", options, || {
+        _ensure_admin()?;
+        validate_method(&method)?;
+        maintenance::validate_message("reason", &reason)?;
+        if ALWAYS_ENABLED.contains(&method.as_str()) {
+            return Err(Error::invalid_input(
+                "method",
+                "NOT_SUPPORTED",
+                format!("{} cannot be disabled", method),
+            ));
+        }
+
+        let endpoint = DisabledEndpoint {
+            method: method.clone(),
+            reason,
+            disabled_at: time(),
+            disabled_by: crate::env::caller(),
+        };
+        DISABLED_ENDPOINTS.with(|endpoints| endpoints.borrow_mut().insert(MethodName(method), endpoint));
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn enable_endpoint(method: String, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("enable_endpoint", options, || {
+        _ensure_admin()?;
+        validate_method(&method)?;
+        DISABLED_ENDPOINTS.with(|endpoints| endpoints.borrow_mut().remove(&MethodName(method)));
+        Ok(())
+    })
+}
+
+pub(crate) fn ensure_enabled(method: &str) -> Result<(), Error> {
+    if method.len() > MAX_METHOD_LENGTH {
+        return Ok(());
+    }
+    match DISABLED_ENDPOINTS.with(|endpoints| endpoints.borrow().get(&MethodName(method.to_string()))) {
+        Some(endpoint) => Err(Error::endpoint_disabled(method, endpoint.reason)),
+        None => Ok(()),
+    }
+}
+
+// Method names are snake_case identifiers
+pub(crate) fn validate_method(method: &str) -> Result<(), Error> {
+    let valid = !method.is_empty()
+        && method.len() <= MAX_METHOD_LENGTH
+        && method.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_');
+    if !valid {
+        return Err(Error::invalid_input(
+            "method",
+            "INVALID_METHOD_NAME",
+            format!(
+                "method must be 1 to {} lowercase letters, digits or underscores, got {:?}",
+                MAX_METHOD_LENGTH, method
+            ),
+        ));
+    }
+    Ok(())
+}
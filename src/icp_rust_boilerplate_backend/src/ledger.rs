@@ -0,0 +1,247 @@
+// Stock movements with a reason. stock_in and stock_out change an item's quantity and
+// append a StockMovement to a stable log, which is never rewritten; the log is indexed
+// by item and by warehouse so either history can be paged without reading the rest.
+// Other stock changes (receipts, shipments, counts) are traced by the audit log.
+use crate::ids::{ItemId, WarehouseId};
+use crate::settings::page_size;
+use crate::tenancy::caller_org;
+use crate::{counting, idempotency, reservation, staffing, store, validation, Error, MutationOptions, StockItem};
+use crate::{ITEM_MOVEMENTS, MOVEMENTS, WAREHOUSE_MOVEMENTS};
+use crate::{_add_quantity, _check_version, _ensure_not_archived, _get_item, _get_warehouse, _remove_quantity};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum MovementReason {
+    Purchase,
+    Sale,
+    Damage,
+    Correction,
+    Transfer,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum MovementDirection {
+    In,
+    Out,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StockMovement {
+    org_id: u64,
+    warehouse_id: WarehouseId,
+    item_id: ItemId,
+    direction: MovementDirection,
+    quantity: u64,
+    reason: MovementReason,
+    resulting_quantity: u64,
+    at: u64,
+    by: Principal,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct MovementRecord {
+    seq: u64, // Position in the log, from 0
+    movement: StockMovement,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct MovementPage {
+    movements: Vec<MovementRecord>,
+    next: Option<u64>, // Pass as `from` to continue; None after the last movement
+}
+
+impl Storable for StockMovement {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[ic_cdk::update]
+fn stock_in(
+    item_id: ItemId,
+    quantity: u64,
+    reason: MovementReason,
+    options: Option<MutationOptions>,
+) -> Result<StockItem, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("stock_in", options, || {
+        _move_stock(item_id, quantity, MovementDirection::In, reason, expected_version)
+    })
+}
+
+// Leaves the item at zero rather than removing it, so its history stays attached
+#[ic_cdk::update]
+fn stock_out(
+    item_id: ItemId,
+    quantity: u64,
+    reason: MovementReason,
+    options: Option<MutationOptions>,
+) -> Result<StockItem, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("stock_out", options, || {
+        _move_stock(item_id, quantity, MovementDirection::Out, reason, expected_version)
+    })
+}
+
+// Movements of an item in log order, from position `from` on. The history outlives the item.
+#[ic_cdk::query]
+fn get_item_movements(item_id: ItemId, from: Option<u64>, limit: Option<u64>) -> Result<MovementPage, Error> {
+    let limit = page_size(limit)?;
+    Ok(ITEM_MOVEMENTS.with(|index| page(&index.borrow(), item_id, from.unwrap_or(0), limit)))
+}
+
+// Movements of every item of a warehouse in log order, from position `from` on
+#[ic_cdk::query]
+fn get_warehouse_movements(warehouse_id: WarehouseId, from: Option<u64>, limit: Option<u64>) -> Result<MovementPage, Error> {
+    let limit = page_size(limit)?;
+    Ok(WAREHOUSE_MOVEMENTS.with(|index| page(&index.borrow(), warehouse_id, from.unwrap_or(0), limit)))
+}
+
+fn _move_stock(
+    item_id: ItemId,
+    quantity: u64,
+    direction: MovementDirection,
+    reason: MovementReason,
+    expected_version: Option<u64>,
+) -> Result<StockItem, Error> {
+    validation::validate_quantity("quantity", quantity)?;
+    let mut item = _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    _check_version("item", item_id, item.version, expected_version)?;
+    counting::ensure_unfrozen(item.warehouse_id)?;
+
+    match direction {
+        MovementDirection::In => {
+            let warehouse =
+                _get_warehouse(&item.warehouse_id).ok_or_else(|| Error::not_found("warehouse", item.warehouse_id))?;
+            _ensure_not_archived(&warehouse)?;
+            staffing::ensure_on_shift(warehouse.id, warehouse.org_id, staffing::Duty::Receiving)?;
+            item.quantity = _add_quantity(item_id, item.quantity, quantity)?;
+        }
+        MovementDirection::Out => {
+            reservation::ensure_available(&item, quantity)?;
+            item.quantity = _remove_quantity(item_id, item.quantity, quantity)?;
+        }
+    }
+    let now = time();
+    item.updated_at = Some(now);
+    item.updated_by = Some(crate::env::caller());
+    item.version += 1;
+    store::insert_item(item.clone());
+
+    let movement = StockMovement {
+        org_id: item.org_id,
+        warehouse_id: item.warehouse_id,
+        item_id,
+        direction,
+        quantity,
+        reason,
+        resulting_quantity: item.quantity,
+        at: now,
+        by: crate::env::caller(),
+    };
+    let seq = MOVEMENTS
+        .with(|log| log.borrow().append(&movement))
+        .expect("movement log is out of stable memory");
+    ITEM_MOVEMENTS.with(|index| index.borrow_mut().insert((item_id, seq), ()));
+    WAREHOUSE_MOVEMENTS.with(|index| index.borrow_mut().insert((item.warehouse_id, seq), ()));
+    Ok(item)
+}
+
+// Quantity of each item of the warehouse that flowed out since `since`
+pub(crate) fn quantities_out(warehouse_id: WarehouseId, since: u64) -> BTreeMap<ItemId, u64> {
+    let from = first_seq_since(since);
+    let mut quantities = BTreeMap::new();
+    let seqs: Vec<u64> = WAREHOUSE_MOVEMENTS.with(|index| {
+        index
+            .borrow()
+            .range((warehouse_id, from)..=(warehouse_id, u64::MAX))
+            .map(|((_, seq), _)| seq)
+            .collect()
+    });
+    MOVEMENTS.with(|log| {
+        let log = log.borrow();
+        for movement in seqs.into_iter().filter_map(|seq| log.get(seq)) {
+            if is_outflow(&movement) {
+                let quantity: &mut u64 = quantities.entry(movement.item_id).or_default();
+                *quantity = quantity.saturating_add(movement.quantity);
+            }
+        }
+    });
+    quantities
+}
+
+// Time and quantity of every outflow of the item since `since`, oldest first
+pub(crate) fn item_outflows(item_id: ItemId, since: u64) -> Vec<(u64, u64)> {
+    let from = first_seq_since(since);
+    let seqs: Vec<u64> = ITEM_MOVEMENTS.with(|index| {
+        index
+            .borrow()
+            .range((item_id, from)..=(item_id, u64::MAX))
+            .map(|((_, seq), _)| seq)
+            .collect()
+    });
+    MOVEMENTS.with(|log| {
+        let log = log.borrow();
+        seqs.into_iter()
+            .filter_map(|seq| log.get(seq))
+            .filter(is_outflow)
+            .map(|movement| (movement.at, movement.quantity))
+            .collect()
+    })
+}
+
+// Stock that left through stock_out; corrections fix the count rather than consume stock
+fn is_outflow(movement: &StockMovement) -> bool {
+    movement.direction == MovementDirection::Out && movement.reason != MovementReason::Correction
+}
+
+// Position of the first movement at or after `at`; the log is appended in time order
+fn first_seq_since(at: u64) -> u64 {
+    MOVEMENTS.with(|log| {
+        let log = log.borrow();
+        let (mut low, mut high) = (0, log.len());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if log.get(middle).is_some_and(|movement| movement.at < at) {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        low
+    })
+}
+
+// Up to `limit` movements under `key` of the caller's organization from position `from` on
+fn page<K: BoundedStorable + Copy + Default + Ord, M: ic_stable_structures::Memory>(
+    index: &StableBTreeMap<(K, u64), (), M>,
+    key: K,
+    from: u64,
+    limit: usize,
+) -> MovementPage {
+    let org_id = caller_org();
+    // One extra position tells whether another page follows
+    let mut seqs: Vec<u64> = index
+        .range((key, from)..=(key, u64::MAX))
+        .take(limit + 1)
+        .map(|((_, seq), _)| seq)
+        .collect();
+    let next = (seqs.len() > limit).then(|| seqs[limit]);
+    seqs.truncate(limit);
+    let movements = MOVEMENTS.with(|log| {
+        let log = log.borrow();
+        seqs.into_iter()
+            .filter_map(|seq| log.get(seq).map(|movement| MovementRecord { seq, movement }))
+            .filter(|record| record.movement.org_id == org_id)
+            .collect()
+    });
+    MovementPage { movements, next }
+}
@@ -1,58 +1,286 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use env::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable};
 use std::{borrow::Cow, cell::RefCell};
-use std::collections::HashSet; // Import HashSet
+
+mod abc;
+mod adjustment;
+mod aging;
+mod alerts;
+mod audit;
+mod backup;
+mod batch;
+mod bins;
+mod capacity;
+mod category;
+mod certified;
+mod codes;
+mod counting;
+mod env;
+mod error;
+mod export;
+mod feature;
+mod forecast;
+mod geo;
+mod gateway;
+mod guard;
+mod health;
+mod history;
+mod ids;
+mod idempotency;
+mod import;
+mod inspect;
+mod integrity;
+mod killswitch;
+mod ledger;
+mod maintenance;
+mod merkle;
+mod metrics;
+mod migrations;
+mod organization;
+mod pricing;
+mod pubsub;
+mod purchasing;
+#[cfg(feature = "debug")]
+mod profile;
+mod projection;
+mod quota;
+mod ratelimit;
+mod rebalance;
+mod reorder;
+mod reports;
+mod reservation;
+mod sales;
+mod purge;
+mod search;
+mod settings;
+mod simulation;
+mod staffing;
+mod staging;
+mod store;
+mod tenancy;
+mod tenant_data;
+mod totals;
+mod trash;
+mod units;
+mod validation;
+
+use abc::AbcAnalysis;
+use adjustment::Adjustment;
+use aging::AgingReport;
+use alerts::Webhook;
+use audit::{AuditEntry, AuditFilter, AuditPage, ChangePage};
+use backup::{Backup, RestoreSession};
+use batch::{InventoryOp, OpResult, ReadRequest, ReadResponse};
+use bins::{Bin, BinPayload};
+use capacity::StorageStatus;
+use category::{Category, CategoryPayload};
+use certified::{CertifiedStockItem, CertifiedWarehouse, StateCertificate, StateDigest};
+use codes::ItemCodes;
+use counting::{CountEntry, CountLine, CountResult, CountScope, CountSession, CountSessionView, VarianceReport};
+use error::Error;
+use export::{ExportChunk, ExportFormat, ExportToken};
+use feature::{Feature, FeatureSetting, FeatureState};
+use forecast::DemandForecast;
+use gateway::{HttpGatewayResponse, HttpRequest};
+use geo::NearbyWarehouse;
+use health::Health;
+use history::{HistoryEntry, QuantityPoint};
+use ids::{ItemId, WarehouseId};
+use idempotency::{MutationOptions, RequestKey, StoredResponse};
+use import::{ImportMapping, ImportMappingPayload, ImportRowResult};
+use integrity::{IntegrityReport, RepairPlan, RepairReport};
+use killswitch::{DisabledEndpoint, MethodName};
+use ledger::{MovementPage, MovementReason, StockMovement};
+use maintenance::MaintenanceMode;
+use merkle::InventoryRoot;
+use metrics::{CanisterStats, Metrics, MetricsReport};
+use organization::{Invitation, Member, Organization, OrganizationPayload, Role};
+use pricing::{ItemPrice, PriceChange, Valuation};
+use pubsub::{InventoryEvent, Subscription, Topic};
+use purchasing::{PurchaseOrder, PurchaseOrderPayload, PurchaseOrderStatus};
+#[cfg(feature = "debug")]
+use profile::{ProfileOp, ProfileReport};
+use projection::{Projected, Projection};
+use quota::{CallCount, QuotaUsage, Quotas};
+use ratelimit::TokenBucket;
+use rebalance::RebalanceTransfer;
+use reports::{Report, ReportKind, ReportSchedule};
+use reservation::Reservation;
+use sales::{SalesOrder, SalesOrderPayload, SalesOrderStatus};
+use search::{NameSearch, TextSearch};
+use settings::Settings;
+use simulation::Simulation;
+use migrations::UpgradeArgs;
+use staffing::{ShiftWindow, StaffAssignment, StaffRole};
+use staging::{Chunk, ImportProgress, ImportSession};
+use store::{ItemNameKey, NameKey, WarehouseNameKey};
+use tenancy::MemberKey;
+use tenant_data::{ExportCursor, TenantDeletion, TenantExportPage};
+use totals::{InventoryTotals, WarehouseTotals};
+use trash::TrashedItem;
+use units::{Conversion, ConversionTable, Unit};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct Warehouse {
-    id: u64,
+    id: WarehouseId,
     name: String,
     created_at: u64,
+    version: u64, // Starts at 1 and is bumped on every change
+    org_id: u64,  // Owning organization, see tenancy.rs
+    created_by: Principal,
+    updated_at: Option<u64>,
+    updated_by: Option<Principal>, // Caller of the last change
+    archived_at: Option<u64>,      // Set while archived, see archive_warehouse
+    address: Option<String>,
+    latitude: Option<f64>, // Degrees, set together with `longitude`; see geo.rs
+    longitude: Option<f64>,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct StockItem {
-    item_id: u64,
-    warehouse_id: u64,
+    item_id: ItemId,
+    warehouse_id: WarehouseId,
+    item_name: String,
+    quantity: u64,
+    unit: Unit, // Of `quantity`, see units.rs
+    created_at: u64,
+    updated_at: Option<u64>,
+    version: u64, // Starts at 1 and is bumped on every change
+    org_id: u64,  // Always the org of the warehouse
+    unit_cost: Option<u64>, // See pricing.rs
+    unit_price: Option<u64>,
+    currency: Option<String>,
+    sku: Option<String>, // See codes.rs
+    barcodes: Vec<String>,
+    category_id: Option<u64>, // See category.rs
+    bin_id: Option<u64>,      // See bins.rs
+    reorder_point: Option<u64>, // See reorder.rs
+    created_by: Principal,
+    updated_by: Option<Principal>, // Caller of the last change
+}
+
+// Fallback layouts for records stored by older releases: the original fields plus every
+// field added since, as optional fields, so any older layout decodes. Missing values
+// take their defaults (version 1, DEFAULT_ORG, counted in pieces, not priced, no codes or
+// category, bin or reorder point, no address or location, created by the anonymous principal).
+// Migration 1 (migrations.rs) rewrites them in the current layout.
+#[derive(candid::CandidType, Deserialize)]
+struct WarehouseV1 {
+    id: u64,
+    name: String,
+    created_at: u64,
+    version: Option<u64>,
+    org_id: Option<u64>,
+    created_by: Option<Principal>,
+    updated_at: Option<u64>,
+    updated_by: Option<Principal>,
+    archived_at: Option<u64>,
+    address: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct StockItemV1 {
+    item_id: ItemId,
+    warehouse_id: WarehouseId,
     item_name: String,
     quantity: u64,
+    unit: Option<Unit>,
     created_at: u64,
     updated_at: Option<u64>,
+    version: Option<u64>,
+    org_id: Option<u64>,
+    unit_cost: Option<u64>,
+    unit_price: Option<u64>,
+    currency: Option<String>,
+    sku: Option<String>,
+    barcodes: Option<Vec<String>>,
+    category_id: Option<u64>,
+    bin_id: Option<u64>,
+    reorder_point: Option<u64>,
+    created_by: Option<Principal>,
+    updated_by: Option<Principal>,
 }
 
 impl Storable for Warehouse {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|_| {
+            let legacy = Decode!(bytes.as_ref(), WarehouseV1).unwrap();
+            Warehouse {
+                id: WarehouseId(legacy.id),
+                name: legacy.name,
+                created_at: legacy.created_at,
+                version: legacy.version.unwrap_or(1),
+                org_id: legacy.org_id.unwrap_or(tenancy::DEFAULT_ORG),
+                created_by: legacy.created_by.unwrap_or_else(Principal::anonymous),
+                updated_at: legacy.updated_at,
+                updated_by: legacy.updated_by,
+                archived_at: legacy.archived_at,
+                address: legacy.address,
+                latitude: legacy.latitude,
+                longitude: legacy.longitude,
+            }
+        })
     }
 }
 
+// Every value reserves the full bound in its map node, eleven to a node, so it is kept close
+// to the largest valid record: 1422 bytes for a warehouse and 984 for an item, with every
+// name and address at its limit in four-byte characters (see largest_records_fit_their_bound).
+// Releases up to schema version 1 had 1 KiB, which a long address no longer fits.
 impl BoundedStorable for Warehouse {
-    const MAX_SIZE: u32 = 1024;
+    const MAX_SIZE: u32 = 2 * 1024;
     const IS_FIXED_SIZE: bool = false;
 }
 
 impl Storable for StockItem {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|_| {
+            let legacy = Decode!(bytes.as_ref(), StockItemV1).unwrap();
+            StockItem {
+                item_id: legacy.item_id,
+                warehouse_id: legacy.warehouse_id,
+                item_name: legacy.item_name,
+                quantity: legacy.quantity,
+                unit: legacy.unit.unwrap_or_default(),
+                created_at: legacy.created_at,
+                updated_at: legacy.updated_at,
+                version: legacy.version.unwrap_or(1),
+                org_id: legacy.org_id.unwrap_or(tenancy::DEFAULT_ORG),
+                unit_cost: legacy.unit_cost,
+                unit_price: legacy.unit_price,
+                currency: legacy.currency,
+                sku: legacy.sku,
+                barcodes: legacy.barcodes.unwrap_or_default(),
+                category_id: legacy.category_id,
+                bin_id: legacy.bin_id,
+                reorder_point: legacy.reorder_point,
+                created_by: legacy.created_by.unwrap_or_else(Principal::anonymous),
+                updated_by: legacy.updated_by,
+            }
+        })
     }
 }
 
+// See the bound of Warehouse
 impl BoundedStorable for StockItem {
-    const MAX_SIZE: u32 = 1024;
+    const MAX_SIZE: u32 = 2 * 1024;
     const IS_FIXED_SIZE: bool = false;
 }
 
@@ -61,366 +289,1632 @@ thread_local! {
         MemoryManager::init(DefaultMemoryImpl::default())
     );
 
-    static WAREHOUSE_ID_COUNTER: RefCell<HashSet<u64>> = RefCell::new(HashSet::new()); // Store deleted IDs
-    static WAREHOUSE_ID_INCREMENT: RefCell<u64> = RefCell::new(1);  // Store current counter for new IDs
+    static PURGE_SCHEDULED: RefCell<bool> = const { RefCell::new(false) }; // A purge timer is pending
+
+    // Heap-only name search indexes, rebuilt from the stable maps in post_upgrade
+    static WAREHOUSE_SEARCH: RefCell<NameSearch<WarehouseId>> = RefCell::new(NameSearch::default());
+    static ITEM_TEXT: RefCell<TextSearch> = RefCell::new(TextSearch::default());
+
+    // Heap-only call counters, see metrics.rs
+    static METRICS: RefCell<Metrics> = RefCell::new(Metrics::default());
+
+    // The low-stock alert timer, see alerts.rs
+    static ALERT_TIMER: RefCell<Option<ic_cdk_timers::TimerId>> = const { RefCell::new(None) };
+    static ALERTS_RUNNING: RefCell<bool> = const { RefCell::new(false) };
+    static TRASH_TIMER: RefCell<Option<ic_cdk_timers::TimerId>> = const { RefCell::new(None) };
+    static RESERVATION_TIMER: RefCell<Option<ic_cdk_timers::TimerId>> = const { RefCell::new(None) };
+    static PUBSUB_TIMER: RefCell<Option<ic_cdk_timers::TimerId>> = const { RefCell::new(None) };
+    static REPORT_TIMER: RefCell<Option<ic_cdk_timers::TimerId>> = const { RefCell::new(None) };
+
+    // Method named in audit entries, set for the duration of an update call or timer job
+    static AUDIT_METHOD: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    // In memories 2 and 3 up to schema version 1, see migrations.rs
+    static WAREHOUSE_STORAGE: RefCell<StableBTreeMap<WarehouseId, Warehouse, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81)))
+    ));
+
+    static STOCK_STORAGE: RefCell<StableBTreeMap<ItemId, StockItem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82)))
+    ));
+
+    static IMPORT_MAPPINGS: RefCell<StableBTreeMap<u64, ImportMapping, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    // MemoryId 5 held the warehouse name index before it was keyed by organization; unused since
+
+    // ((warehouse id, normalized name), item id) -> ()
+    static ITEM_NAME_INDEX: RefCell<StableBTreeMap<ItemNameKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    // Responses of update calls made with an idempotency key
+    static IDEMPOTENCY_RESPONSES: RefCell<StableBTreeMap<RequestKey, StoredResponse, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    // (created_at, request key) -> (), oldest first, for pruning IDEMPOTENCY_RESPONSES
+    static IDEMPOTENCY_EXPIRY: RefCell<StableBTreeMap<(u64, RequestKey), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    // (warehouse id, item id) -> (), so a warehouse's stock is a key range
+    static WAREHOUSE_ITEMS: RefCell<StableBTreeMap<(WarehouseId, ItemId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    // Deleted warehouses whose stock items are still being removed
+    static PENDING_PURGES: RefCell<StableBTreeMap<WarehouseId, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static TOTALS: RefCell<StableCell<InventoryTotals, Memory>> =
+        RefCell::new(StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+            InventoryTotals::default(),
+    ).unwrap());
+
+    // Warehouse id -> item count and quantity; warehouses without items have no entry
+    static WAREHOUSE_TOTALS: RefCell<StableBTreeMap<WarehouseId, WarehouseTotals, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    // Principal -> organization id, for principals that are members. Kept in step with
+    // MEMBER_ROLES by tenancy::set_membership.
+    static MEMBERSHIPS: RefCell<StableBTreeMap<MemberKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    // ((org id, normalized name), warehouse id) -> ()
+    static WAREHOUSE_NAME_INDEX: RefCell<StableBTreeMap<WarehouseNameKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    // Org id -> counts and quantity of that organization
+    static ORG_TOTALS: RefCell<StableBTreeMap<u64, InventoryTotals, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    static ORGANIZATIONS: RefCell<StableBTreeMap<u64, Organization, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    // (org id, principal) -> role, so an organization's members are a key range
+    static MEMBER_ROLES: RefCell<StableBTreeMap<(u64, MemberKey), Role, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    // (invited principal, org id) -> pending invitation
+    static INVITATIONS: RefCell<StableBTreeMap<(MemberKey, u64), Invitation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    // Org id -> quotas; organizations without an entry are unlimited
+    static ORG_QUOTAS: RefCell<StableBTreeMap<u64, Quotas, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    // Org id -> update calls made on the current day
+    static ORG_CALLS: RefCell<StableBTreeMap<u64, CallCount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    static MAINTENANCE: RefCell<StableCell<MaintenanceMode, Memory>> =
+        RefCell::new(StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+            MaintenanceMode::default(),
+    ).unwrap());
+
+    // (feature code, org id or u64::MAX for the deployment) -> 1 if enabled, 0 if disabled
+    static FEATURE_FLAGS: RefCell<StableBTreeMap<(u8, u64), u8, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    // Method name -> why and by whom it was switched off
+    static DISABLED_ENDPOINTS: RefCell<StableBTreeMap<MethodName, DisabledEndpoint, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    static SETTINGS: RefCell<StableCell<Settings, Memory>> =
+        RefCell::new(StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+            Settings::default(),
+    ).unwrap());
+
+    // Sum of the hashes of all warehouses and stock items, see certified.rs
+    static STATE_DIGEST: RefCell<StableCell<StateDigest, Memory>> =
+        RefCell::new(StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+            StateDigest::default(),
+    ).unwrap());
+
+    static COUNT_SESSIONS: RefCell<StableBTreeMap<u64, CountSession, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    // (session id, item id) -> latest count of the item
+    static COUNT_LINES: RefCell<StableBTreeMap<(u64, ItemId), CountLine, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    static ADJUSTMENTS: RefCell<StableBTreeMap<u64, Adjustment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // Warehouse id -> the physical inventory session that freezes it
+    static FROZEN_WAREHOUSES: RefCell<StableBTreeMap<WarehouseId, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    // (item id, sequence number) -> price set on the item, oldest first
+    static PRICE_HISTORY: RefCell<StableBTreeMap<(ItemId, u64), PriceChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    static STAFF_ASSIGNMENTS: RefCell<StableBTreeMap<u64, StaffAssignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    // (warehouse id, assignment id) -> (), including ended assignments
+    static WAREHOUSE_STAFF: RefCell<StableBTreeMap<(WarehouseId, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    // Id counters are stable so that ids stay unique across upgrades. MemoryIds 33 and 35
+    // held deleted ids queued for reuse; emptied by post_upgrade and unused since.
+    static WAREHOUSE_ID_INCREMENT: RefCell<StableCell<u64, Memory>> = // Store current counter for new IDs
+        RefCell::new(StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+            1,
+    ).unwrap());
+
+    static ITEM_ID_INCREMENT: RefCell<StableCell<u64, Memory>> = // Store current counter for new IDs
+        RefCell::new(StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))),
+            1,
+    ).unwrap());
+
+    // (creator, item id) -> ()
+    static ITEMS_BY_CREATOR: RefCell<StableBTreeMap<(MemberKey, ItemId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+
+    // (organization id, warehouse id) -> ()
+    static ORG_WAREHOUSES: RefCell<StableBTreeMap<(u64, WarehouseId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    // Every warehouse and stock item write, oldest first, see audit.rs
+    static AUDIT_LOG: RefCell<StableLog<AuditEntry, Memory, Memory>> =
+        RefCell::new(StableLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ).unwrap());
+
+    // (warehouse id, SKU) -> item id, see codes.rs
+    static SKU_INDEX: RefCell<StableBTreeMap<(WarehouseId, NameKey), ItemId, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+
+    // (warehouse id, barcode) -> item id
+    static BARCODE_INDEX: RefCell<StableBTreeMap<(WarehouseId, NameKey), ItemId, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+
+    static CATEGORIES: RefCell<StableBTreeMap<u64, Category, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+
+    // (category id, item id) -> ()
+    static ITEMS_BY_CATEGORY: RefCell<StableBTreeMap<(u64, ItemId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    // Organization id -> unit conversions, see units.rs
+    static UNIT_CONVERSIONS: RefCell<StableBTreeMap<u64, ConversionTable, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+
+    // (warehouse id, item id) -> () for items at or below their reorder point
+    static LOW_STOCK: RefCell<StableBTreeMap<(WarehouseId, ItemId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    static WEBHOOKS: RefCell<StableBTreeMap<u64, Webhook, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+
+    static RESERVATIONS: RefCell<StableBTreeMap<u64, Reservation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    // Item id -> quantity held by its active reservations
+    static RESERVED_QUANTITIES: RefCell<StableBTreeMap<ItemId, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+
+    // (item id, reservation id) -> () for active reservations
+    static ITEM_RESERVATIONS: RefCell<StableBTreeMap<(ItemId, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+
+    // (expires at, reservation id) -> () for active reservations that expire
+    static RESERVATION_EXPIRY: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84)))
+    ));
+
+    static PURCHASE_ORDERS: RefCell<StableBTreeMap<u64, PurchaseOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+
+    static SALES_ORDERS: RefCell<StableBTreeMap<u64, SalesOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+
+    static IMPORT_SESSIONS: RefCell<StableBTreeMap<u64, ImportSession, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+
+    // (import id, chunk number) -> uploaded bytes, see staging.rs
+    static IMPORT_CHUNKS: RefCell<StableBTreeMap<(u64, u32), Chunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+
+    // (bucket, record id) -> leaf hash of the record, see certified.rs
+    static RECORD_HASHES: RefCell<StableBTreeMap<(u64, u64), StateDigest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+
+    // Bucket -> root over the leaf hashes in it; empty buckets have no entry
+    static BUCKET_HASHES: RefCell<StableBTreeMap<u64, StateDigest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+
+    // Caller -> token bucket, see ratelimit.rs
+    static RATE_LIMITS: RefCell<StableBTreeMap<MemberKey, TokenBucket, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+
+    static RATE_LIMIT_EXPIRY: RefCell<StableBTreeMap<(u64, MemberKey), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+
+    // (org_id, warehouse id) of archived warehouses, which listings leave out
+    static ARCHIVED_WAREHOUSES: RefCell<StableBTreeMap<(u64, WarehouseId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+
+    // Item id -> item emptied by delete_item, see trash.rs; in memory 60 up to schema version 1
+    static TRASH: RefCell<StableBTreeMap<ItemId, TrashedItem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83)))
+    ));
+
+    static TRASH_EXPIRY: RefCell<StableBTreeMap<(u64, ItemId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61)))
+    ));
+
+    // Every stock_in and stock_out, oldest first, see ledger.rs
+    static MOVEMENTS: RefCell<StableLog<StockMovement, Memory, Memory>> =
+        RefCell::new(StableLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ).unwrap());
+
+    // (item id, position in MOVEMENTS)
+    static ITEM_MOVEMENTS: RefCell<StableBTreeMap<(ItemId, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64)))
+    ));
+
+    // (warehouse id, position in MOVEMENTS)
+    static WAREHOUSE_MOVEMENTS: RefCell<StableBTreeMap<(WarehouseId, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65)))
+    ));
+
+    // (item id, time) -> quantity the item was left at, see history.rs
+    static ITEM_HISTORY: RefCell<StableBTreeMap<(ItemId, u64), HistoryEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+
+    // (org_id, subscription id) -> subscribing canister, see pubsub.rs
+    static SUBSCRIPTIONS: RefCell<StableBTreeMap<(u64, u64), Subscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67)))
+    ));
+
+    // Sequence number -> event not yet delivered to every subscription
+    static EVENTS: RefCell<StableBTreeMap<u64, InventoryEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68)))
+    ));
+
+    // Sequence number of the next event
+    static EVENT_SEQ: RefCell<StableCell<u64, Memory>> =
+        RefCell::new(StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69))),
+            1,
+    ).unwrap());
+
+    // Bin id -> bin, see bins.rs
+    static BINS: RefCell<StableBTreeMap<u64, Bin, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70)))
+    ));
+
+    // (warehouse id, bin id) -> ()
+    static WAREHOUSE_BINS: RefCell<StableBTreeMap<(WarehouseId, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71)))
+    ));
+
+    // (bin id, item id) -> ()
+    static ITEMS_BY_BIN: RefCell<StableBTreeMap<(u64, ItemId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72)))
+    ));
+
+    // Schedule id -> scheduled report job, see reports.rs
+    static REPORT_SCHEDULES: RefCell<StableBTreeMap<u64, ReportSchedule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73)))
+    ));
+
+    static REPORTS: RefCell<StableBTreeMap<u64, Report, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74)))
+    ));
+
+    // (org_id, report id) -> ()
+    static ORG_REPORTS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75)))
+    ));
 
-    static ITEM_ID_COUNTER: RefCell<Vec<u64>> = RefCell::new(Vec::new()); // Store reusable IDs
-    static ITEM_ID_INCREMENT: RefCell<u64> = RefCell::new(1);  // Store current counter for new IDs
+    // Copy of the other memories made by create_backup, see backup.rs
+    static BACKUP_DATA: RefCell<Memory> =
+        RefCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76))));
 
-    static WAREHOUSE_STORAGE: RefCell<StableBTreeMap<u64, Warehouse, Memory>> =
+    static BACKUPS: RefCell<StableBTreeMap<u64, Backup, Memory>> =
         RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77)))
     ));
 
-    static STOCK_STORAGE: RefCell<StableBTreeMap<u64, StockItem, Memory>> =
+    // Backup being restored, uploaded chunk by chunk, see backup.rs
+    static RESTORE_DATA: RefCell<Memory> =
+        RefCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78))));
+
+    static RESTORES: RefCell<StableBTreeMap<u64, RestoreSession, Memory>> =
         RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79)))
     ));
+
+    // Layout of the stable state, see migrations.rs; 0 for the releases before versioning
+    static SCHEMA_VERSION: RefCell<StableCell<u64, Memory>> =
+        RefCell::new(StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80))),
+            0,
+    ).unwrap());
+}
+
+// Opens a stable structure of the thread_local above again, reading what its memory holds
+// now; see reopen_stable_state
+macro_rules! reopen {
+    (
+        maps { $($map:ident: $map_id:literal,)* }
+        cells { $($cell:ident: $cell_id:literal => $default:expr,)* }
+        logs { $($log:ident: $index_id:literal, $data_id:literal,)* }
+    ) => {
+        // The memories reopened, also those a backup holds, see backup.rs
+        #[cfg(test)]
+        pub(crate) const REOPENED_MEMORIES: &[u8] = &[$($map_id,)* $($cell_id,)* $($index_id, $data_id,)*];
+
+        pub(crate) fn reopen_stable_state() {
+            let memory = |id: u8| MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
+            $($map.with(|map| *map.borrow_mut() = StableBTreeMap::init(memory($map_id)));)*
+            $($cell.with(|cell| *cell.borrow_mut() = StableCell::init(memory($cell_id), $default).unwrap());)*
+            $($log.with(|log| *log.borrow_mut() = StableLog::init(memory($index_id), memory($data_id)).unwrap());)*
+        }
+    };
+}
+
+// Every structure of a memory the backup holds, after commit_restore (backup.rs) wrote the
+// memories underneath them. The v1 maps of migrations.rs have no structure of their own.
+reopen! {
+    maps {
+        WAREHOUSE_STORAGE: 81,
+        STOCK_STORAGE: 82,
+        IMPORT_MAPPINGS: 4,
+        ITEM_NAME_INDEX: 6,
+        IDEMPOTENCY_RESPONSES: 7,
+        IDEMPOTENCY_EXPIRY: 8,
+        WAREHOUSE_ITEMS: 9,
+        PENDING_PURGES: 10,
+        WAREHOUSE_TOTALS: 12,
+        MEMBERSHIPS: 13,
+        WAREHOUSE_NAME_INDEX: 14,
+        ORG_TOTALS: 15,
+        ORGANIZATIONS: 16,
+        MEMBER_ROLES: 17,
+        INVITATIONS: 18,
+        ORG_QUOTAS: 19,
+        ORG_CALLS: 20,
+        FEATURE_FLAGS: 22,
+        DISABLED_ENDPOINTS: 23,
+        COUNT_SESSIONS: 26,
+        COUNT_LINES: 27,
+        ADJUSTMENTS: 28,
+        FROZEN_WAREHOUSES: 29,
+        PRICE_HISTORY: 30,
+        STAFF_ASSIGNMENTS: 31,
+        WAREHOUSE_STAFF: 32,
+        ITEMS_BY_CREATOR: 37,
+        ORG_WAREHOUSES: 38,
+        SKU_INDEX: 41,
+        BARCODE_INDEX: 42,
+        CATEGORIES: 43,
+        ITEMS_BY_CATEGORY: 44,
+        UNIT_CONVERSIONS: 45,
+        LOW_STOCK: 46,
+        WEBHOOKS: 47,
+        RESERVATIONS: 48,
+        RESERVED_QUANTITIES: 49,
+        ITEM_RESERVATIONS: 50,
+        RESERVATION_EXPIRY: 84,
+        PURCHASE_ORDERS: 51,
+        SALES_ORDERS: 52,
+        IMPORT_SESSIONS: 53,
+        IMPORT_CHUNKS: 54,
+        RECORD_HASHES: 55,
+        BUCKET_HASHES: 56,
+        RATE_LIMITS: 57,
+        RATE_LIMIT_EXPIRY: 58,
+        ARCHIVED_WAREHOUSES: 59,
+        TRASH: 83,
+        TRASH_EXPIRY: 61,
+        ITEM_MOVEMENTS: 64,
+        WAREHOUSE_MOVEMENTS: 65,
+        ITEM_HISTORY: 66,
+        SUBSCRIPTIONS: 67,
+        EVENTS: 68,
+        BINS: 70,
+        WAREHOUSE_BINS: 71,
+        ITEMS_BY_BIN: 72,
+        REPORT_SCHEDULES: 73,
+        REPORTS: 74,
+        ORG_REPORTS: 75,
+    }
+    cells {
+        TOTALS: 11 => InventoryTotals::default(),
+        MAINTENANCE: 21 => MaintenanceMode::default(),
+        SETTINGS: 24 => Settings::default(),
+        STATE_DIGEST: 25 => StateDigest::default(),
+        WAREHOUSE_ID_INCREMENT: 34 => 1,
+        ITEM_ID_INCREMENT: 36 => 1,
+        EVENT_SEQ: 69 => 1,
+        SCHEMA_VERSION: 80 => 0,
+    }
+    logs {
+        AUDIT_LOG: 39, 40,
+        MOVEMENTS: 62, 63,
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PagedWarehouses {
+    warehouses: Vec<Warehouse>,
+    total_count: u64,         // Warehouses of the caller's organization
+    next_offset: Option<u64>, // None on the last page
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct StockPage {
+    items: Vec<StockItem>,
+    next_cursor: Option<ItemId>, // Pass as `cursor` for the next page; None on the last page
 }
 
 #[derive(candid::CandidType, Serialize, Deserialize, Default)]
 struct WarehousePayload {
     name: String,
+    get_existing: Option<bool>, // With true, a warehouse already using the name is returned instead of a conflict
+    address: Option<String>,
+    latitude: Option<f64>, // Both or neither
+    longitude: Option<f64>,
+}
+
+// Fields left as None keep their current value
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct UpdateWarehousePayload {
+    name: Option<String>,
+    address: Option<String>,
+    latitude: Option<f64>, // Both or neither
+    longitude: Option<f64>,
 }
 
 #[derive(candid::CandidType, Serialize, Deserialize, Default)]
 struct StockItemPayload {
-    warehouse_id: u64,
+    warehouse_id: WarehouseId,
     item_name: String,
     quantity: u64,
+    unit: Option<Unit>, // Of `quantity`; None means the unit of the stored item, or pieces for a new one
 }
 
-// Function to get the next available warehouse ID
-fn get_next_warehouse_id() -> u64 {
-    // First, try to find the smallest reusable ID
-    let reusable_id = WAREHOUSE_ID_COUNTER.with(|counter| {
-        let counter_ref = counter.borrow(); // Immutable borrow
-        counter_ref.iter().min().copied() // Get the smallest available ID
-    });
+// Fields left as None keep their current value
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct UpdateItemPayload {
+    item_name: Option<String>,
+    quantity: Option<u64>,     // The new quantity, not a difference; delete_item removes stock
+    warehouse_id: Option<WarehouseId>, // Moves the whole record, keeping its id and price history
+}
 
-    // If a reusable ID exists, remove it from the set and return it
-    if let Some(id) = reusable_id {
-        WAREHOUSE_ID_COUNTER.with(|counter| {
-            let mut counter_mut = counter.borrow_mut(); // Mutable borrow
-            counter_mut.remove(&id); // Remove the reused ID
-        });
-        return id; // Return the reusable ID
-    }
+// Takes the argument of post_upgrade, since the interface declares one for both; a new
+// canister has nothing to migrate
+#[ic_cdk::init]
+fn init(_args: Option<UpgradeArgs>) {
+    migrations::mark_current();
+}
 
-    // If no reusable ID exists, increment the main counter
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<UpgradeArgs>) {
+    load_state(&args.unwrap_or_default());
+}
+
+// Brings the stable state to the layout of this release and rebuilds what derives from it,
+// after an upgrade and after commit_restore (backup.rs) replaced the state
+pub(crate) fn load_state(args: &UpgradeArgs) {
+    migrations::run(args);
+    ensure_id_counters();
+    store::ensure_indexes();
+    totals::ensure_totals();
+    certified::ensure_digest();
+    search::rebuild();
+    purge::resume();
+    alerts::resume();
+    trash::resume();
+    reservation::resume();
+    pubsub::resume();
+    reports::resume();
+}
+
+// Ids are never reused, so an id held outside the canister cannot come to name another
+// record after a deletion
+fn get_next_warehouse_id() -> WarehouseId {
     WAREHOUSE_ID_INCREMENT.with(|counter| {
-        let mut id_counter = counter.borrow_mut(); // Mutable borrow
-        let next_id = *id_counter; // Get the current ID
-        *id_counter += 1; // Increment for next use
-        next_id // Return the current ID
+        let mut counter = counter.borrow_mut();
+        let next_id = *counter.get(); // Get the current ID
+        counter.set(next_id + 1).unwrap(); // Increment for next use
+        WarehouseId(next_id) // Return the current ID
     })
 }
 
-// Function to get the next available stock item ID, allowing ID reuse
-fn get_next_item_id() -> u64 {
-    // Check if there are any reusable IDs in the ITEM_ID_COUNTER
-    if let Some(reused_id) = ITEM_ID_COUNTER.with(|counter| {
+fn get_next_item_id() -> ItemId {
+    ITEM_ID_INCREMENT.with(|counter| {
         let mut counter = counter.borrow_mut();
-        if !counter.is_empty() {
-            return counter.pop(); // Return the last reusable ID
-        }
-        None // No reusable ID available
-    }) {
-        return reused_id; // Return the reused ID if available
-    }
+        let next_id = *counter.get(); // Get the current ID
+        counter.set(next_id + 1).unwrap(); // Increment for next use
+        ItemId(next_id) // Return the current ID
+    })
+}
+
+// The id get_next_item_id will return, for plans that must not write
+fn peek_next_item_id() -> ItemId {
+    ITEM_ID_INCREMENT.with(|counter| ItemId(*counter.borrow().get()))
+}
 
-    // If no reusable IDs are available, increment the counter for new IDs starting from 1
+// Releases before the counters were stable restarted them at 1 on every upgrade. Raising
+// each counter above the highest stored id makes the first upgrade to this release safe
+// and keeps them monotonic if a counter was ever left behind. Ids that earlier releases
+// queued for reuse are counted as issued, and the queues are emptied.
+fn ensure_id_counters() {
+    let max_queued_warehouse_id = retire_reuse_queue(33);
+    let max_queued_item_id = retire_reuse_queue(35);
+    let max_warehouse_id = WAREHOUSE_STORAGE
+        .with(|s| s.borrow().last_key_value().map_or(0, |(id, _)| id.0))
+        .max(max_queued_warehouse_id);
+    WAREHOUSE_ID_INCREMENT.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        if *counter.get() <= max_warehouse_id {
+            counter.set(max_warehouse_id + 1).unwrap();
+        }
+    });
+    let max_item_id = STOCK_STORAGE
+        .with(|s| s.borrow().last_key_value().map_or(0, |(id, _)| id.0))
+        .max(max_queued_item_id);
     ITEM_ID_INCREMENT.with(|counter| {
-        let mut id = counter.borrow_mut();
-        let next_id = *id;   // Get the current ID
-        *id += 1;            // Increment for next use
-        next_id              // Return the current ID
-    })
+        let mut counter = counter.borrow_mut();
+        if *counter.get() <= max_item_id {
+            counter.set(max_item_id + 1).unwrap();
+        }
+    });
+}
+
+// Empties a queue of reusable ids, returning the highest id it held or 0
+fn retire_reuse_queue(memory_id: u8) -> u64 {
+    let mut queue: StableBTreeMap<u64, (), Memory> =
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id))));
+    let max_id = queue.last_key_value().map_or(0, |(id, _)| id);
+    let ids: Vec<u64> = queue.iter().map(|(id, _)| id).collect();
+    for id in ids {
+        queue.remove(&id);
+    }
+    max_id
 }
 
 #[ic_cdk::query]
-fn get_warehouse(id: u64) -> Result<Warehouse, Error> {
+fn get_warehouse(id: WarehouseId) -> Result<Warehouse, Error> {
     match _get_warehouse(&id) {
         Some(warehouse) => Ok(warehouse),
-        None => Err(Error::NotFound {
-            msg: format!("A warehouse with id={} not found", id),
-        }),
+        None => Err(Error::not_found("warehouse", id)),
     }
 }
 
+// Warehouses of the caller's organization in id order, without their stock or the
+// archived ones
+#[ic_cdk::query]
+fn list_warehouses(offset: u64, limit: u64) -> Result<PagedWarehouses, Error> {
+    let limit = settings::page_size(Some(limit))?;
+    let org_id = tenancy::caller_org();
+    // Saturating, so counters that drifted apart cannot trap the query
+    let total_count = totals::org_totals(org_id)
+        .warehouse_count
+        .saturating_sub(store::archived_count(org_id));
+    let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+    let warehouses: Vec<Warehouse> = WAREHOUSE_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        store::listed_warehouses_page(org_id, offset, limit)
+            .iter()
+            .filter_map(|id| storage.get(id))
+            .collect()
+    });
+    let next = offset as u64 + warehouses.len() as u64;
+    Ok(PagedWarehouses {
+        next_offset: (next < total_count).then_some(next),
+        warehouses,
+        total_count,
+    })
+}
+
 #[ic_cdk::update]
-fn add_warehouse(payload: WarehousePayload) -> Result<Warehouse, Error> {
+fn add_warehouse(payload: WarehousePayload, options: Option<MutationOptions>) -> Result<Warehouse, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("add_warehouse", options, || _add_warehouse(payload))
+}
+
+fn _add_warehouse(payload: WarehousePayload) -> Result<Warehouse, Error> {
+    let payload = validation::validate_warehouse_payload(payload)?;
+
+    let org_id = tenancy::caller_org();
+
+    // Names are unique within an organization, regardless of case and spacing
+    if let Some(existing_id) = store::warehouse_id_by_name(org_id, &payload.name) {
+        if payload.get_existing == Some(true) {
+            return _get_warehouse(&existing_id).ok_or_else(|| Error::not_found("warehouse", existing_id));
+        }
+        return Err(Error::conflict(
+            "warehouse",
+            Some(existing_id.into()),
+            "DUPLICATE_WAREHOUSE_NAME",
+            format!("Warehouse with id={} already uses the name {:?}", existing_id, payload.name),
+        ));
+    }
+    quota::ensure_warehouse_quota(org_id)?;
+
     let id = get_next_warehouse_id();  // Get the next available ID
 
     let warehouse = Warehouse {
         id,
         name: payload.name,
         created_at: time(),
+        version: 1,
+        org_id,
+        created_by: env::caller(),
+        updated_at: None,
+        updated_by: None,
+        archived_at: None,
+        address: payload.address,
+        latitude: payload.latitude,
+        longitude: payload.longitude,
     };
+    _ensure_fits("warehouse", &warehouse)?;
 
-    WAREHOUSE_STORAGE.with(|storage| {
-        storage.borrow_mut().insert(id, warehouse.clone());
-    });
+    store::insert_warehouse(warehouse.clone());
 
     Ok(warehouse)
 }
 
 #[ic_cdk::update]
-fn delete_warehouse(warehouse_id: u64) -> Result<(), Error> {
-    // Step 1: Remove the warehouse and check if it was found
-    let warehouse_found = WAREHOUSE_STORAGE.with(|storage| {
-        let mut storage_mut = storage.borrow_mut(); // Get mutable borrow
-        storage_mut.remove(&warehouse_id).is_some() // Remove and check if it existed
-    });
+fn update_warehouse(
+    warehouse_id: WarehouseId,
+    payload: UpdateWarehousePayload,
+    options: Option<MutationOptions>,
+) -> Result<Warehouse, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("update_warehouse", options, || {
+        _update_warehouse(warehouse_id, payload, expected_version)
+    })
+}
 
-    if !warehouse_found {
-        return Err(Error::NotFound {
-            msg: format!("Warehouse with id={} not found", warehouse_id),
-        });
+fn _update_warehouse(
+    warehouse_id: WarehouseId,
+    payload: UpdateWarehousePayload,
+    expected_version: Option<u64>,
+) -> Result<Warehouse, Error> {
+    let payload = validation::validate_update_warehouse_payload(payload)?;
+
+    let mut warehouse = match _get_warehouse(&warehouse_id) {
+        Some(warehouse) => warehouse,
+        None => return Err(Error::not_found("warehouse", warehouse_id)),
+    };
+    _check_version("warehouse", warehouse_id, warehouse.version, expected_version)?;
+
+    let name = payload.name.filter(|name| *name != warehouse.name);
+    let address = payload.address.filter(|address| warehouse.address.as_ref() != Some(address));
+    let location = payload
+        .latitude
+        .zip(payload.longitude)
+        .filter(|location| warehouse.latitude.zip(warehouse.longitude) != Some(*location));
+    if name.is_none() && address.is_none() && location.is_none() {
+        return Ok(warehouse); // Nothing to change: no new version
     }
 
-    // Add the deleted ID to the HashSet for reuse
-    WAREHOUSE_ID_COUNTER.with(|counter| {
-        let mut counter_mut = counter.borrow_mut();
-        counter_mut.insert(warehouse_id);
-    });
+    if let Some(name) = name {
+        // A change of case or spacing only keeps the warehouse's own lookup key
+        if let Some(existing_id) = store::warehouse_id_by_name(warehouse.org_id, &name).filter(|id| *id != warehouse_id) {
+            return Err(Error::conflict(
+                "warehouse",
+                Some(existing_id.into()),
+                "DUPLICATE_WAREHOUSE_NAME",
+                format!("Warehouse with id={} already uses the name {:?}", existing_id, name),
+            ));
+        }
+        warehouse.name = name;
+    }
+    if address.is_some() {
+        warehouse.address = address;
+    }
+    if let Some((latitude, longitude)) = location {
+        warehouse.latitude = Some(latitude);
+        warehouse.longitude = Some(longitude);
+    }
 
-    // Step 2: Now delete all stock items associated with the warehouse
-    STOCK_STORAGE.with(|storage| {
-        let mut stock_storage = storage.borrow_mut(); // Get mutable borrow
+    warehouse.updated_at = Some(time());
+    warehouse.updated_by = Some(env::caller());
+    warehouse.version += 1;
+    _ensure_fits("warehouse", &warehouse)?;
 
-        // Collect stock item IDs to remove
-        let item_ids_to_remove: Vec<u64> = stock_storage.iter()
-            .filter(|(_, item)| item.warehouse_id == warehouse_id)
-            .map(|(id, _)| id)
-            .collect();
+    store::insert_warehouse(warehouse.clone());
 
-        // Remove the stock items
-        for item_id in item_ids_to_remove {
-            stock_storage.remove(&item_id);
-        }
-    });
+    Ok(warehouse)
+}
 
-    Ok(())
+// Archives the warehouse, as archive_warehouse does; purge_warehouse deletes it
+#[ic_cdk::update]
+fn delete_warehouse(warehouse_id: WarehouseId, options: Option<MutationOptions>) -> Result<(), Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("delete_warehouse", options, || {
+        _archive_warehouse(warehouse_id, true, expected_version).map(|_| ())
+    })
 }
 
+// Hides the warehouse from listings and refuses new stock in it, until it is restored.
+// Its stock can still be read, moved out and removed.
+#[ic_cdk::update]
+fn archive_warehouse(warehouse_id: WarehouseId, options: Option<MutationOptions>) -> Result<Warehouse, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("archive_warehouse", options, || _archive_warehouse(warehouse_id, true, expected_version))
+}
 
-#[ic_cdk::query]
-fn get_all_warehouses_with_stocks() -> Vec<(Warehouse, Vec<StockItem>)> {
-    let mut result = Vec::new();
-
-    WAREHOUSE_STORAGE.with(|warehouse_storage| {
-        let warehouses = warehouse_storage.borrow();
-        for (warehouse_id, warehouse) in warehouses.iter() {
-            let stocks: Vec<StockItem> = STOCK_STORAGE.with(|stock_storage| {
-                stock_storage.borrow()
-                    .iter()
-                    .filter_map(|(_, item)| {
-                        if item.warehouse_id == warehouse_id {
-                            Some(item.clone())
-                        } else {
-                            None
-                        }
-                    })
-                    .collect()
-            });
-            result.push((warehouse.clone(), stocks));
-        }
-    });
+#[ic_cdk::update]
+fn restore_warehouse(warehouse_id: WarehouseId, options: Option<MutationOptions>) -> Result<Warehouse, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("restore_warehouse", options, || _archive_warehouse(warehouse_id, false, expected_version))
+}
+
+fn _archive_warehouse(warehouse_id: WarehouseId, archive: bool, expected_version: Option<u64>) -> Result<Warehouse, Error> {
+    let mut warehouse = match _get_warehouse(&warehouse_id) {
+        Some(warehouse) => warehouse,
+        None => return Err(Error::not_found("warehouse", warehouse_id)),
+    };
+    _check_version("warehouse", warehouse_id, warehouse.version, expected_version)?;
+    if warehouse.archived_at.is_some() == archive {
+        let (code, state) = if archive { ("ALREADY_ARCHIVED", "already") } else { ("NOT_ARCHIVED", "not") };
+        return Err(Error::conflict(
+            "warehouse",
+            Some(warehouse_id.into()),
+            code,
+            format!("Warehouse with id={} is {} archived", warehouse_id, state),
+        ));
+    }
+    counting::ensure_unfrozen(warehouse_id)?;
+
+    let now = time();
+    warehouse.archived_at = archive.then_some(now);
+    warehouse.updated_at = Some(now);
+    warehouse.updated_by = Some(env::caller());
+    warehouse.version += 1;
+    store::insert_warehouse(warehouse.clone());
 
-    result
+    Ok(warehouse)
 }
 
+// Deletes the warehouse and its stock, archived or not
 #[ic_cdk::update]
-fn add_item_to_warehouse(payload: StockItemPayload) -> Result<StockItem, Error> {
-    // Check if the warehouse exists
-    let warehouse_exists = WAREHOUSE_STORAGE.with(|storage| {
-        storage.borrow().get(&payload.warehouse_id).is_some()
-    });
+fn purge_warehouse(warehouse_id: WarehouseId, options: Option<MutationOptions>) -> Result<(), Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("purge_warehouse", options, || _purge_warehouse(warehouse_id, expected_version))
+}
 
-    if !warehouse_exists {
-        return Err(Error::NotFound {
-            msg: format!("Warehouse with id={} not found", payload.warehouse_id),
-        });
-    }
+fn _purge_warehouse(warehouse_id: WarehouseId, expected_version: Option<u64>) -> Result<(), Error> {
+    // Step 1: Check the warehouse can be deleted, then remove it
+    _plan_delete_warehouse(warehouse_id, expected_version)?;
+    store::remove_warehouse(warehouse_id);
+
+    // Step 2: Delete its stock items
+    purge::purge_warehouse(warehouse_id);
+
+    Ok(())
+}
+
+// The checks of purge_warehouse; shared with simulate_delete_warehouse
+fn _plan_delete_warehouse(warehouse_id: WarehouseId, expected_version: Option<u64>) -> Result<Warehouse, Error> {
+    _ensure_admin()?;
+    // Check the warehouse exists and is the version the caller saw
+    let warehouse = match _get_warehouse(&warehouse_id) {
+        Some(warehouse) => warehouse,
+        None => return Err(Error::not_found("warehouse", warehouse_id)),
+    };
+    _check_version("warehouse", warehouse_id, warehouse.version, expected_version)?;
+    counting::ensure_unfrozen(warehouse_id)?;
+    Ok(warehouse)
+}
+
+#[ic_cdk::update]
+fn add_item_to_warehouse(payload: StockItemPayload, options: Option<MutationOptions>) -> Result<StockItem, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("add_item_to_warehouse", options, || {
+        _add_item_to_warehouse(payload, expected_version)
+    })
+}
+
+// Shared by add_item_to_warehouse and the CSV importer.
+// `expected_version` refers to the item being topped up; 0 means the item must not exist yet.
+fn _add_item_to_warehouse(payload: StockItemPayload, expected_version: Option<u64>) -> Result<StockItem, Error> {
+    let payload = validation::validate_stock_item_payload(payload)?;
+
+    // Check if the warehouse exists
+    let warehouse = match _get_warehouse(&payload.warehouse_id) {
+        Some(warehouse) => warehouse,
+        None => return Err(Error::not_found("warehouse", payload.warehouse_id)),
+    };
+    counting::ensure_unfrozen(warehouse.id)?;
+    _ensure_not_archived(&warehouse)?;
+    staffing::ensure_on_shift(warehouse.id, warehouse.org_id, staffing::Duty::Receiving)?;
 
     // Check if an item with the same name already exists in the warehouse
-    let existing_item_id = STOCK_STORAGE.with(|storage| {
-        storage.borrow().iter()
-            .find(|(_, item)| item.warehouse_id == payload.warehouse_id && item.item_name == payload.item_name)
-            .map(|(id, _)| id) // Return the existing item ID
-    });
+    let existing_item = store::item_id_by_name(payload.warehouse_id, &payload.item_name)
+        .and_then(|item_id| STOCK_STORAGE.with(|storage| storage.borrow().get(&item_id)));
 
-    let item = if let Some(item_id) = existing_item_id {
-        // If the item exists, update the quantity
-        STOCK_STORAGE.with(|storage| {
-            let mut stock_storage = storage.borrow_mut();
-            if let Some(existing_item) = stock_storage.get(&item_id) {
-                let mut existing_item = existing_item.clone(); // Clone to modify
-                existing_item.quantity += payload.quantity; // Increment the quantity
-                existing_item.updated_at = Some(time()); // Update the timestamp
-                stock_storage.insert(item_id, existing_item.clone()); // Reinsert updated item
-                return Ok(existing_item); // Return the updated item wrapped in Ok
-            } else {
-                return Err(Error::NotFound {
-                    msg: format!("Item with id={} not found", item_id),
-                });
-            }
-        })?
+    // The index ignores case, so "widget" tops up "Widget" and the stored spelling is kept
+    let item = if let Some(mut existing_item) = existing_item {
+        _check_version("item", existing_item.item_id, existing_item.version, expected_version)?;
+
+        // If the item exists, update the quantity, in the unit it is stored in
+        let quantity = match payload.unit {
+            Some(unit) => units::convert(warehouse.org_id, payload.quantity, unit, existing_item.unit)?,
+            None => payload.quantity,
+        };
+        existing_item.quantity = _add_quantity(existing_item.item_id, existing_item.quantity, quantity)?;
+        existing_item.updated_at = Some(time()); // Update the timestamp
+        existing_item.updated_by = Some(env::caller());
+        existing_item.version += 1;
+        existing_item
     } else {
+        if let Some(expected) = expected_version.filter(|expected| *expected != 0) {
+            return Err(Error::version_mismatch("item", None, expected, 0));
+        }
+
         // If no existing item, create a new one
+        quota::ensure_item_quota(warehouse.org_id)?;
+        _ensure_room(warehouse.id, 1)?;
         let item_id = get_next_item_id();
         StockItem {
             item_id,
             warehouse_id: payload.warehouse_id,
             item_name: payload.item_name,
             quantity: payload.quantity,
+            unit: payload.unit.unwrap_or_default(),
             created_at: time(),
             updated_at: None,
+            version: 1,
+            org_id: warehouse.org_id,
+            unit_cost: None,
+            unit_price: None,
+            currency: None,
+            sku: None,
+            barcodes: Vec::new(),
+            category_id: None,
+            bin_id: None,
+            reorder_point: settings::default_reorder_point(),
+            created_by: env::caller(),
+            updated_by: None,
         }
     };
 
     // Insert the new or updated item into storage
-    STOCK_STORAGE.with(|storage| {
-        let mut stock_storage = storage.borrow_mut();
-        stock_storage.insert(item.item_id, item.clone());
-    });
+    _ensure_fits("item", &item)?;
+    store::insert_item(item.clone());
+
+    Ok(item)
+}
+
+#[ic_cdk::update]
+fn update_item(item_id: ItemId, payload: UpdateItemPayload, options: Option<MutationOptions>) -> Result<StockItem, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("update_item", options, || _update_item(item_id, payload, expected_version))
+}
+
+fn _update_item(item_id: ItemId, payload: UpdateItemPayload, expected_version: Option<u64>) -> Result<StockItem, Error> {
+    let payload = validation::validate_update_item_payload(payload)?;
+
+    let mut item = match _get_item(&item_id) {
+        Some(item) => item,
+        None => return Err(Error::not_found("item", item_id)),
+    };
+    _check_version("item", item_id, item.version, expected_version)?;
+
+    let item_name = payload.item_name.filter(|name| *name != item.item_name);
+    let quantity = payload.quantity.filter(|quantity| *quantity != item.quantity);
+    let warehouse_id = payload.warehouse_id.filter(|warehouse_id| *warehouse_id != item.warehouse_id);
+    if item_name.is_none() && quantity.is_none() && warehouse_id.is_none() {
+        return Ok(item); // Nothing to change: no new version
+    }
+
+    // Changing the stock of a warehouse is refused while it is being counted
+    if quantity.is_some() || warehouse_id.is_some() {
+        counting::ensure_unfrozen(item.warehouse_id)?;
+    }
+    if let Some(warehouse_id) = warehouse_id {
+        let warehouse = match _get_warehouse(&warehouse_id) {
+            Some(warehouse) => warehouse,
+            None => return Err(Error::not_found("warehouse", warehouse_id)),
+        };
+        counting::ensure_unfrozen(warehouse.id)?;
+        _ensure_not_archived(&warehouse)?;
+        staffing::ensure_on_shift(warehouse.id, warehouse.org_id, staffing::Duty::Receiving)?;
+        _ensure_room(warehouse.id, 1)?;
+        item.warehouse_id = warehouse_id;
+        item.bin_id = None;
+    }
+    if let Some(quantity) = quantity {
+        if quantity > item.quantity {
+            if let Some(warehouse) = _get_warehouse(&item.warehouse_id) {
+                _ensure_not_archived(&warehouse)?;
+            }
+        }
+        staffing::ensure_on_shift(item.warehouse_id, item.org_id, staffing::Duty::Receiving)?;
+        reservation::ensure_available(&item, item.quantity.saturating_sub(quantity))?;
+        item.quantity = quantity;
+    }
+    if let Some(item_name) = item_name {
+        item.item_name = item_name;
+    }
+
+    codes::ensure_codes_free(&item)?;
+
+    // Names are unique within a warehouse; a change of case or spacing keeps the item's own key
+    if let Some(existing_id) = store::item_id_by_name(item.warehouse_id, &item.item_name).filter(|id| *id != item_id) {
+        return Err(Error::conflict(
+            "item",
+            Some(existing_id.into()),
+            "DUPLICATE_ITEM_NAME",
+            format!(
+                "Item with id={} in warehouse_id={} is already named {:?}",
+                existing_id, item.warehouse_id, item.item_name
+            ),
+        ));
+    }
+
+    item.updated_at = Some(time());
+    item.updated_by = Some(env::caller());
+    item.version += 1;
+    _ensure_fits("item", &item)?;
+
+    // The indexes follow the new name and warehouse
+    store::insert_item(item.clone());
 
     Ok(item)
 }
 
 // Function to check stock
 #[ic_cdk::query]
-fn check_stock(item_id: u64) -> Result<StockItem, Error> {
-    match STOCK_STORAGE.with(|storage| storage.borrow().get(&item_id)) {
+fn check_stock(item_id: ItemId) -> Result<StockItem, Error> {
+    match _get_item(&item_id) {
         Some(stock_item) => Ok(stock_item.clone()), // Return a clone
-        None => Err(Error::NotFound {
-            msg: format!("Item with id={} not found", item_id),
-        }),
+        None => Err(Error::not_found("item", item_id)),
     }
 }
 
 #[ic_cdk::update]
-fn delete_item(item_id: u64, quantity: u64) -> Result<StockItem, Error> {
-    STOCK_STORAGE.with(|storage| {
-        // Borrow the storage as mutable
-        let mut stock = storage.borrow_mut();
-        
-        // Check if the item exists
-        if let Some(item) = stock.get(&item_id) {
-            let mut item = item.clone(); // Clone to modify
-
-            // Check if the quantity to delete is valid
-            if quantity > item.quantity {
-                return Err(Error::NotEnoughStock {
-                    msg: format!(
-                        "Not enough stock to delete: available={}, requested={}",
-                        item.quantity, quantity
-                    ),
-                });
-            }
+fn delete_item(item_id: ItemId, quantity: u64, options: Option<MutationOptions>) -> Result<StockItem, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("delete_item", options, || _delete_item(item_id, quantity, expected_version))
+}
 
-            // Decrement the quantity
-            item.quantity -= quantity;
-            item.updated_at = Some(time()); // Update the timestamp
-            
-            // If quantity is zero, remove the item
-            if item.quantity == 0 {
-                stock.remove(&item_id);
-            } else {
-                // If there are remaining items, update the stock
-                stock.insert(item_id, item.clone()); // Reinsert the updated item
-            }
-            
-            Ok(item) // Return the updated item
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Item with id={} not found", item_id),
-            })
+fn _delete_item(item_id: ItemId, quantity: u64, expected_version: Option<u64>) -> Result<StockItem, Error> {
+    let item = _plan_delete_item(item_id, quantity, expected_version)?;
+
+    // If quantity is zero, remove the item; restore_item can bring it back for a while
+    if item.quantity == 0 {
+        if let Some(before) = store::remove_item(item_id) {
+            trash::put(before);
         }
-    })
+    } else {
+        // If there are remaining items, update the stock
+        store::insert_item(item.clone()); // Reinsert the updated item
+    }
+
+    Ok(item) // Return the updated item
+}
+
+// Returns the item as delete_item would leave it, without writing it
+fn _plan_delete_item(item_id: ItemId, quantity: u64, expected_version: Option<u64>) -> Result<StockItem, Error> {
+    validation::validate_quantity("quantity", quantity)?;
+
+    // Check if the item exists
+    let mut item = match _get_item(&item_id) {
+        Some(item) => item,
+        None => return Err(Error::not_found("item", item_id)),
+    };
+
+    _check_version("item", item_id, item.version, expected_version)?;
+    counting::ensure_unfrozen(item.warehouse_id)?;
+
+    // Decrement the quantity, failing if the quantity to delete is not available
+    reservation::ensure_available(&item, quantity)?;
+    item.quantity = _remove_quantity(item_id, item.quantity, quantity)?;
+    item.updated_at = Some(time()); // Update the timestamp
+    item.updated_by = Some(env::caller());
+    item.version += 1;
+    Ok(item)
 }
 
 // Function to transfer items between warehouses
 #[ic_cdk::update]
-fn transfer_item(item_id: u64, from_warehouse_id: u64, to_warehouse_id: u64, quantity: u64) -> Result<(), Error> {
-    // Scope for mutable borrow
-    STOCK_STORAGE.with(|storage| {
-        let mut stock = storage.borrow_mut();
-        
-        if let Some(mut item) = stock.remove(&item_id) {
-            if item.warehouse_id != from_warehouse_id {
-                stock.insert(item_id, item.clone());
-                return Err(Error::NotFound {
-                    msg: format!(
-                        "Item with id={} not found in warehouse_id={}",
-                        item_id, from_warehouse_id
-                    ),
-                });
-            }
+fn transfer_item(
+    item_id: ItemId,
+    from_warehouse_id: WarehouseId,
+    to_warehouse_id: WarehouseId,
+    quantity: u64,
+    options: Option<MutationOptions>,
+) -> Result<(), Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("transfer_item", options, || {
+        _transfer_item(item_id, from_warehouse_id, to_warehouse_id, quantity, expected_version)
+    })
+}
 
-            if item.quantity < quantity {
-                stock.insert(item_id, item.clone());
-                return Err(Error::NotEnoughStock {
-                    msg: format!(
-                        "Not enough stock for item_id={}, available={}, requested={}",
-                        item_id, item.quantity, quantity
-                    ),
-                });
-            }
+// `expected_version` refers to the source item
+fn _transfer_item(
+    item_id: ItemId,
+    from_warehouse_id: WarehouseId,
+    to_warehouse_id: WarehouseId,
+    quantity: u64,
+    expected_version: Option<u64>,
+) -> Result<(), Error> {
+    let (item, new_item) = _plan_transfer_item(item_id, from_warehouse_id, to_warehouse_id, quantity, expected_version)?;
 
-            item.quantity -= quantity;
-            item.updated_at = Some(time());
+    store::insert_item(item);
+    // Stock moved into a new record keeps its price; the record's history starts with it
+    let created = new_item.version == 1;
+    if created {
+        get_next_item_id(); // The id the plan peeked at
+    }
+    if created && (new_item.unit_cost.is_some() || new_item.unit_price.is_some()) {
+        pricing::record(&new_item);
+    }
+    let (org_id, source_id) = (new_item.org_id, item_id);
+    store::insert_item(new_item);
+    pubsub::record_transfer(org_id, source_id, from_warehouse_id, to_warehouse_id, quantity);
 
-            stock.insert(item_id, item.clone());
+    Ok(())
+}
 
-            // Create a new item record for the destination warehouse
-            let new_item = StockItem {
-                item_id: get_next_item_id(),
-                warehouse_id: to_warehouse_id,
-                item_name: item.item_name.clone(),
-                quantity,
-                created_at: time(),
-                updated_at: None,
-            };
+// Returns the source and destination records as transfer_item would write them; the
+// destination is the item of the same name there, or a new record when there is none
+fn _plan_transfer_item(
+    item_id: ItemId,
+    from_warehouse_id: WarehouseId,
+    to_warehouse_id: WarehouseId,
+    quantity: u64,
+    expected_version: Option<u64>,
+) -> Result<(StockItem, StockItem), Error> {
+    validation::validate_quantity("quantity", quantity)?;
+    if from_warehouse_id == to_warehouse_id {
+        return Err(Error::invalid_input(
+            "to_warehouse_id",
+            "SAME_WAREHOUSE",
+            "to_warehouse_id must differ from from_warehouse_id".to_string(),
+        ));
+    }
 
-            stock.insert(new_item.item_id, new_item);
-            
-            Ok(())
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Item with id={} not found", item_id),
-            })
+    // Both warehouses must exist, otherwise the moved stock would be unreachable
+    for warehouse_id in [from_warehouse_id, to_warehouse_id] {
+        let warehouse = match _get_warehouse(&warehouse_id) {
+            Some(warehouse) => warehouse,
+            None => return Err(Error::not_found("warehouse", warehouse_id)),
+        };
+        counting::ensure_unfrozen(warehouse_id)?;
+        if warehouse_id == to_warehouse_id {
+            _ensure_not_archived(&warehouse)?;
         }
-    })
+    }
+
+    let mut item = match _get_item(&item_id) {
+        Some(item) => item,
+        None => return Err(Error::not_found("item", item_id)),
+    };
+
+    if item.warehouse_id != from_warehouse_id {
+        return Err(Error::conflict(
+            "item",
+            Some(item_id.into()),
+            "ITEM_NOT_IN_WAREHOUSE",
+            format!(
+                "Item with id={} is stored in warehouse_id={}, not warehouse_id={}",
+                item_id, item.warehouse_id, from_warehouse_id
+            ),
+        ));
+    }
+
+    _check_version("item", item_id, item.version, expected_version)?;
+
+    reservation::ensure_available(&item, quantity)?;
+    item.quantity = _remove_quantity(item_id, item.quantity, quantity)?;
+    item.updated_at = Some(time());
+    item.updated_by = Some(env::caller());
+    item.version += 1;
+
+    // Stock joins the destination item of the same name in any case, in the unit that item
+    // is stored in
+    let existing_item = store::item_id_by_name(to_warehouse_id, &item.item_name)
+        .and_then(|existing_id| STOCK_STORAGE.with(|storage| storage.borrow().get(&existing_id)));
+    let new_item = if let Some(mut existing_item) = existing_item {
+        let moved = units::convert(item.org_id, quantity, item.unit, existing_item.unit)?;
+        existing_item.quantity = _add_quantity(existing_item.item_id, existing_item.quantity, moved)?;
+        existing_item.updated_at = Some(time());
+        existing_item.updated_by = Some(env::caller());
+        existing_item.version += 1;
+        existing_item
+    } else {
+        quota::ensure_item_quota(item.org_id)?;
+        _ensure_room(to_warehouse_id, 1)?;
+        // Create a new item record for the destination warehouse
+        let new_item = StockItem {
+            item_id: peek_next_item_id(), // Taken by _transfer_item
+            warehouse_id: to_warehouse_id,
+            item_name: item.item_name.clone(),
+            quantity,
+            unit: item.unit,
+            created_at: time(),
+            updated_at: None,
+            version: 1,
+            org_id: item.org_id,
+            unit_cost: item.unit_cost,
+            unit_price: item.unit_price,
+            currency: item.currency.clone(),
+            sku: item.sku.clone(),
+            barcodes: item.barcodes.clone(),
+            category_id: item.category_id,
+            bin_id: None, // Bins belong to the source warehouse
+            reorder_point: item.reorder_point,
+            created_by: env::caller(),
+            updated_by: None,
+        };
+        codes::ensure_codes_free(&new_item)?;
+        new_item
+    };
+
+    // Nothing is written unless both records fit
+    _ensure_fits("item", &item)?;
+    _ensure_fits("item", &new_item)?;
+
+    Ok((item, new_item))
 }
 
+// Items of the caller's organization created by `principal`, in id order; pass the last
+// item_id of a page as `after_item_id` to get the next one
 #[ic_cdk::query]
-fn get_warehouse_stock(warehouse_id: u64) -> Vec<StockItem> {
-    STOCK_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .filter_map(|(_, item)| {
-                if item.warehouse_id == warehouse_id {
-                    Some(item.clone()) // Return a clone
-                } else {
-                    None
-                }
-            })
-            .collect()
-    })
+fn get_items_created_by(principal: Principal, after_item_id: Option<ItemId>, limit: Option<u64>) -> Result<Vec<StockItem>, Error> {
+    let limit = settings::page_size(limit)?;
+    Ok(store::items_created_by(principal, tenancy::caller_org(), after_item_id, limit))
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
-    NotEnoughStock { msg: String },
+// Items of a warehouse in id order, one page at a time
+#[ic_cdk::query]
+fn get_warehouse_stock_page(warehouse_id: WarehouseId, cursor: Option<ItemId>, limit: Option<u64>) -> Result<StockPage, Error> {
+    let limit = settings::page_size(limit)?;
+    if _get_warehouse(&warehouse_id).is_none() {
+        return Err(Error::not_found("warehouse", warehouse_id));
+    }
+    // One extra item tells whether another page follows
+    let mut items = store::items_in_warehouse(warehouse_id, cursor, limit + 1);
+    let more = items.len() > limit;
+    items.truncate(limit);
+    Ok(StockPage {
+        next_cursor: if more { items.last().map(|item| item.item_id) } else { None },
+        items,
+    })
 }
 
+
 // Helper functions
-fn _get_warehouse(id: &u64) -> Option<Warehouse> {
-    WAREHOUSE_STORAGE.with(|service| service.borrow().get(id))
+// Lookups made on behalf of the caller: records of other organizations are not visible
+fn _get_warehouse(id: &WarehouseId) -> Option<Warehouse> {
+    WAREHOUSE_STORAGE
+        .with(|service| service.borrow().get(id))
+        .filter(|warehouse| warehouse.org_id == tenancy::caller_org())
+}
+
+fn _get_item(id: &ItemId) -> Option<StockItem> {
+    STOCK_STORAGE
+        .with(|storage| storage.borrow().get(id))
+        .filter(|item| item.org_id == tenancy::caller_org())
+}
+
+// Archived warehouses take no new stock
+fn _ensure_not_archived(warehouse: &Warehouse) -> Result<(), Error> {
+    if warehouse.archived_at.is_some() {
+        return Err(Error::conflict(
+            "warehouse",
+            Some(warehouse.id.into()),
+            "WAREHOUSE_ARCHIVED",
+            format!("Warehouse with id={} is archived", warehouse.id),
+        ));
+    }
+    Ok(())
+}
+
+// A warehouse holds at most max_items_per_warehouse items; lowering the setting below the
+// current count only blocks new ones
+fn _ensure_room(warehouse_id: WarehouseId, new_items: u64) -> Result<(), Error> {
+    let limit = settings::max_items_per_warehouse();
+    let requested = totals::warehouse_totals(warehouse_id).item_count.saturating_add(new_items);
+    if requested > limit {
+        return Err(Error::capacity_exceeded(
+            "WAREHOUSE_FULL",
+            limit,
+            requested,
+            format!("Warehouse with id={} holds at most {} items", warehouse_id, limit),
+        ));
+    }
+    Ok(())
+}
+
+// Stock math never wraps: additions fail with Overflow, subtractions with NotEnoughStock
+fn _add_quantity(item_id: ItemId, current: u64, delta: u64) -> Result<u64, Error> {
+    current
+        .checked_add(delta)
+        .ok_or_else(|| Error::overflow(item_id, current, delta))
+}
+
+fn _remove_quantity(item_id: ItemId, current: u64, delta: u64) -> Result<u64, Error> {
+    current
+        .checked_sub(delta)
+        .ok_or_else(|| Error::not_enough_stock(item_id, current, delta))
+}
+
+// Checked before every insert: StableBTreeMap traps on values above their MAX_SIZE
+fn _ensure_fits<T: BoundedStorable>(entity: &str, record: &T) -> Result<(), Error> {
+    let size = record.to_bytes().len() as u64;
+    if size > T::MAX_SIZE as u64 {
+        return Err(Error::record_too_large(entity, size, T::MAX_SIZE as u64));
+    }
+    Ok(())
+}
+
+// Rejects the write when the caller's copy of the record is stale
+fn _check_version(entity: &str, id: impl Into<u64>, current: u64, expected: Option<u64>) -> Result<(), Error> {
+    match expected {
+        Some(expected) if expected != current => {
+            Err(Error::version_mismatch(entity, Some(id.into()), expected, current))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Admin-only endpoints are restricted to the canister controllers
+fn _ensure_admin() -> Result<(), Error> {
+    let caller = env::caller();
+    if env::is_controller(&caller) {
+        Ok(())
+    } else {
+        Err(Error::unauthorized(caller, format!("Caller {} is not an admin", caller)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_quantity_fails_with_overflow_past_u64_max() {
+        let item_id = ItemId(7);
+        assert_eq!(_add_quantity(item_id, 0, 0).unwrap(), 0);
+        assert_eq!(_add_quantity(item_id, 0, u64::MAX).unwrap(), u64::MAX);
+        assert_eq!(_add_quantity(item_id, u64::MAX - 1, 1).unwrap(), u64::MAX);
+        assert_eq!(_add_quantity(item_id, u64::MAX, 0).unwrap(), u64::MAX);
+        assert!(matches!(
+            _add_quantity(item_id, u64::MAX, 1),
+            Err(Error::Overflow { current: u64::MAX, delta: 1, .. })
+        ));
+        assert!(matches!(
+            _add_quantity(item_id, 1, u64::MAX),
+            Err(Error::Overflow { current: 1, delta: u64::MAX, .. })
+        ));
+    }
+
+    #[test]
+    fn remove_quantity_fails_with_not_enough_stock_below_zero() {
+        let item_id = ItemId(7);
+        assert_eq!(_remove_quantity(item_id, 0, 0).unwrap(), 0);
+        assert_eq!(_remove_quantity(item_id, 5, 5).unwrap(), 0);
+        assert_eq!(_remove_quantity(item_id, u64::MAX, u64::MAX).unwrap(), 0);
+        assert_eq!(_remove_quantity(item_id, u64::MAX, 1).unwrap(), u64::MAX - 1);
+        assert!(matches!(
+            _remove_quantity(item_id, 0, 1),
+            Err(Error::NotEnoughStock { available: 0, requested: 1, .. })
+        ));
+        assert!(matches!(
+            _remove_quantity(item_id, 5, 6),
+            Err(Error::NotEnoughStock { available: 5, requested: 6, .. })
+        ));
+        assert!(matches!(
+            _remove_quantity(item_id, u64::MAX - 1, u64::MAX),
+            Err(Error::NotEnoughStock { available, requested: u64::MAX, .. }) if available == u64::MAX - 1
+        ));
+    }
+
+    // Every field at its largest valid value: names and addresses of four-byte characters,
+    // codes of MAX_CODE_LENGTH, the largest principals and no optional field left out
+    fn largest_warehouse() -> Warehouse {
+        Warehouse {
+            id: WarehouseId(u64::MAX),
+            name: "\u{1F4E6}".repeat(validation::MAX_NAME_LENGTH),
+            created_at: u64::MAX,
+            version: u64::MAX,
+            org_id: u64::MAX,
+            created_by: Principal::from_slice(&[0xff; 29]),
+            updated_at: Some(u64::MAX),
+            updated_by: Some(Principal::from_slice(&[0xff; 29])),
+            archived_at: Some(u64::MAX),
+            address: Some("\u{1F4E6}".repeat(validation::MAX_ADDRESS_LENGTH)),
+            latitude: Some(-90.0),
+            longitude: Some(-180.0),
+        }
+    }
+
+    fn largest_item() -> StockItem {
+        StockItem {
+            item_id: ItemId(u64::MAX),
+            warehouse_id: WarehouseId(u64::MAX),
+            item_name: "\u{1F4E6}".repeat(validation::MAX_NAME_LENGTH),
+            quantity: u64::MAX,
+            unit: Unit::BoxOf(u64::MAX),
+            created_at: u64::MAX,
+            updated_at: Some(u64::MAX),
+            version: u64::MAX,
+            org_id: u64::MAX,
+            unit_cost: Some(u64::MAX),
+            unit_price: Some(u64::MAX),
+            currency: Some("XXX".to_string()),
+            sku: Some("S".repeat(codes::MAX_CODE_LENGTH)),
+            barcodes: vec!["B".repeat(codes::MAX_CODE_LENGTH); codes::MAX_BARCODES],
+            category_id: Some(u64::MAX),
+            bin_id: Some(u64::MAX),
+            reorder_point: Some(u64::MAX),
+            created_by: Principal::from_slice(&[0xff; 29]),
+            updated_by: Some(Principal::from_slice(&[0xff; 29])),
+        }
+    }
+
+    #[test]
+    fn largest_records_fit_their_bound() {
+        assert!(largest_warehouse().to_bytes().len() <= Warehouse::MAX_SIZE as usize);
+        assert!(largest_item().to_bytes().len() <= StockItem::MAX_SIZE as usize);
+    }
+
+    // Organization 1 with the caller as a member, warehouses 1 and 2 and the archived
+    // warehouse 3, and item 1 of 10 pieces in warehouse 1; new items get ids from 3 on.
+    // Records go straight into the maps: store.rs also certifies them, which only works
+    // inside a canister.
+    pub(crate) fn transfer_fixture() {
+        let member = Principal::from_slice(&[1; 29]);
+        env::set_caller(member);
+        tenancy::set_membership(member, Some((1, Role::Member)));
+        for id in 1..=3 {
+            let warehouse = Warehouse {
+                id: WarehouseId(id),
+                name: format!("Warehouse {}", id),
+                created_at: 0,
+                version: 1,
+                org_id: 1,
+                created_by: member,
+                updated_at: None,
+                updated_by: None,
+                archived_at: (id == 3).then_some(0),
+                address: None,
+                latitude: None,
+                longitude: None,
+            };
+            WAREHOUSE_STORAGE.with(|storage| storage.borrow_mut().insert(warehouse.id, warehouse));
+        }
+        insert_stock(ItemId(1), WarehouseId(1), 10);
+        ITEM_ID_INCREMENT.with(|counter| counter.borrow_mut().set(3)).unwrap();
+    }
+
+    fn insert_stock(item_id: ItemId, warehouse_id: WarehouseId, quantity: u64) {
+        let item = StockItem {
+            item_id,
+            warehouse_id,
+            item_name: "Bolt".to_string(),
+            quantity,
+            unit: Unit::Piece,
+            created_at: 0,
+            updated_at: None,
+            version: 1,
+            org_id: 1,
+            unit_cost: None,
+            unit_price: None,
+            currency: None,
+            sku: None,
+            barcodes: Vec::new(),
+            category_id: None,
+            bin_id: None,
+            reorder_point: None,
+            created_by: env::caller(),
+            updated_by: None,
+        };
+        let name_key = (warehouse_id, store::name_key(&item.item_name));
+        ITEM_NAME_INDEX.with(|index| index.borrow_mut().insert((name_key, item_id), ()));
+        STOCK_STORAGE.with(|storage| storage.borrow_mut().insert(item_id, item));
+    }
+
+    // Every stored warehouse and item, and the next item id
+    fn stored_state() -> (Vec<Vec<u8>>, u64) {
+        let warehouses: Vec<Vec<u8>> = WAREHOUSE_STORAGE
+            .with(|storage| storage.borrow().iter().map(|(_, warehouse)| warehouse.to_bytes().into_owned()).collect());
+        let items: Vec<Vec<u8>> = STOCK_STORAGE
+            .with(|storage| storage.borrow().iter().map(|(_, item)| item.to_bytes().into_owned()).collect());
+        let next_item_id = ITEM_ID_INCREMENT.with(|counter| *counter.borrow().get());
+        ([warehouses, items].concat(), next_item_id)
+    }
+
+    // Plans moving `quantity` of item 1 out of warehouse 1, and checks that the failed plan
+    // left the state as it was
+    fn failed_plan(to_warehouse_id: WarehouseId, quantity: u64) -> Error {
+        let before = stored_state();
+        let result = _plan_transfer_item(ItemId(1), WarehouseId(1), to_warehouse_id, quantity, None);
+        assert!(stored_state() == before);
+        match result {
+            Ok(_) => panic!("the transfer plan succeeded"),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn transfer_plan_fails_within_one_warehouse() {
+        transfer_fixture();
+        assert!(matches!(
+            failed_plan(WarehouseId(1), 1),
+            Error::InvalidInput { code, .. } if code == "SAME_WAREHOUSE"
+        ));
+    }
+
+    #[test]
+    fn transfer_plan_fails_without_destination() {
+        transfer_fixture();
+        assert!(matches!(
+            failed_plan(WarehouseId(4), 1),
+            Error::NotFound { entity, id: Some(4), .. } if entity == "warehouse"
+        ));
+    }
+
+    #[test]
+    fn transfer_plan_fails_into_archived_warehouse() {
+        transfer_fixture();
+        assert!(matches!(
+            failed_plan(WarehouseId(3), 1),
+            Error::Conflict { code, id: Some(3), .. } if code == "WAREHOUSE_ARCHIVED"
+        ));
+    }
+
+    #[test]
+    fn transfer_plan_fails_above_stock() {
+        transfer_fixture();
+        assert!(matches!(
+            failed_plan(WarehouseId(2), 11),
+            Error::NotEnoughStock { available: 10, requested: 11, .. }
+        ));
+    }
+
+    #[test]
+    fn transfer_plan_fails_on_a_taken_code_without_using_an_id() {
+        transfer_fixture();
+        STOCK_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let mut item = storage.get(&ItemId(1)).unwrap();
+            item.sku = Some("SKU-1".to_string());
+            storage.insert(ItemId(1), item);
+        });
+        let sku_key = (WarehouseId(2), store::code_key("SKU-1"));
+        SKU_INDEX.with(|index| index.borrow_mut().insert(sku_key, ItemId(2)));
+        assert!(matches!(
+            failed_plan(WarehouseId(2), 1),
+            Error::Conflict { code, id: Some(2), .. } if code == "DUPLICATE_ITEM_CODE"
+        ));
+    }
+
+    #[test]
+    fn transfer_plan_fails_on_overflow_at_destination() {
+        transfer_fixture();
+        insert_stock(ItemId(2), WarehouseId(2), u64::MAX);
+        assert!(matches!(
+            failed_plan(WarehouseId(2), 1),
+            Error::Overflow { current: u64::MAX, delta: 1, .. }
+        ));
+    }
 }
 
 // need this to generate candid
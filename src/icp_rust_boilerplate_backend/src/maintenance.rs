@@ -0,0 +1,87 @@
+// Read-only mode for backups and migrations. While it is on, update calls from anyone but
+// a controller fail with Error::Maintenance and the purge timer pauses; queries keep working.
+use crate::{idempotency, purge, Error, MutationOptions, _ensure_admin, MAINTENANCE};
+use candid::{Decode, Encode};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+const MAX_MESSAGE_LENGTH: usize = 200; // In characters
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct MaintenanceMode {
+    enabled: bool,
+    message: Option<String>, // Shown to callers, e.g. "Back at 14:00 UTC"
+    since: u64,              // When the mode last changed
+}
+
+impl Storable for MaintenanceMode {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MaintenanceMode {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_maintenance_mode() -> MaintenanceMode {
+    mode()
+}
+
+#[ic_cdk::update]
+fn set_maintenance_mode(enabled: bool, message: Option<String>, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("set_maintenance_mode", options, || {
+        _ensure_admin()?;
+        validate_message("message", &message)?;
+        MAINTENANCE
+            .with(|cell| {
+                cell.borrow_mut().set(MaintenanceMode {
+                    enabled,
+                    message,
+                    since: time(),
+                })
+            })
+            .unwrap();
+        if !enabled {
+            purge::resume();
+        }
+        Ok(())
+    })
+}
+
+// Also while a restored state waits for the upgrade that loads it
+pub(crate) fn is_enabled() -> bool {
+    mode().enabled
+}
+
+pub(crate) fn ensure_writable() -> Result<(), Error> {
+    let mode = mode();
+    if mode.enabled && !crate::env::is_controller(&crate::env::caller()) {
+        return Err(Error::maintenance(mode.since, mode.message));
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_message(field: &str, message: &Option<String>) -> Result<(), Error> {
+    let length = message.as_ref().map_or(0, |message| message.chars().count());
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(Error::invalid_input(
+            field,
+            "VALUE_TOO_LONG",
+            format!("{} must be at most {} characters, got {}", field, MAX_MESSAGE_LENGTH, length),
+        ));
+    }
+    Ok(())
+}
+
+fn mode() -> MaintenanceMode {
+    MAINTENANCE.with(|cell| cell.borrow().get().clone())
+}
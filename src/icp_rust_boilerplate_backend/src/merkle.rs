@@ -0,0 +1,167 @@
+use crate::export::{self, ExportFormat, ExportToken};
+use crate::ids::WarehouseId;
+use crate::_get_warehouse;
+use crate::env::time;
+use sha2::{Digest, Sha256};
+
+// Root over the exported records, so a downloaded export can be checked against the canister.
+//
+// Leaves are sha256(0x00 || row) for every row of the CSV export after the header, in order,
+// line break included: every warehouse in id order, each followed by its items in id order
+// (see export.rs). Parents are sha256(0x01 || left || right); an unpaired last node is
+// carried up to the next level unchanged. The root of an empty export is sha256("").
+//
+// The root is read in pages of the export's chunk size. While a page has a `next` token,
+// get_inventory_root with that token continues it; the last page has the root. The token
+// is an export token, which carries the subtrees completed so far, so a token that was
+// changed gives a wrong root rather than an error. The chunks of an export have the same
+// root and the leaf of every row, see export.rs.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct InventoryRoot {
+    merkle_root: Option<String>, // Lowercase hex, on the last page
+    warehouse_count: u64, // On this page and the ones before it
+    item_count: u64,
+    computed_at: u64,
+    next: Option<ExportToken>,
+}
+
+// The complete subtrees over the leaves so far, largest first, see push
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct PartialRoot(Vec<Subtree>);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Subtree {
+    leaves: u64, // A power of two
+    hash: Vec<u8>,
+}
+
+impl PartialRoot {
+    // Equal subtrees are joined as they complete, which pairs the leaves as merkle_root does
+    pub(crate) fn push(&mut self, leaf: [u8; 32]) {
+        let mut node = Subtree { leaves: 1, hash: leaf.to_vec() };
+        while self.0.last().is_some_and(|last| last.leaves == node.leaves) {
+            let left = self.0.pop().unwrap();
+            node = Subtree {
+                leaves: left.leaves * 2,
+                hash: parent(&digest(&left.hash), &digest(&node.hash)).to_vec(),
+            };
+        }
+        self.0.push(node);
+    }
+
+    // The carried-up nodes of merkle_root are the smaller subtrees, joined from the right
+    pub(crate) fn root(&self) -> [u8; 32] {
+        let mut subtrees = self.0.iter().rev().map(|subtree| digest(&subtree.hash));
+        let Some(last) = subtrees.next() else {
+            return Sha256::digest([]).into();
+        };
+        subtrees.fold(last, |right, left| parent(&left, &right))
+    }
+}
+
+// With `warehouse_id`, only that warehouse and its items; a warehouse outside the caller's
+// organization has the root of an empty export
+#[ic_cdk::query]
+fn get_inventory_root(warehouse_id: Option<WarehouseId>, token: Option<ExportToken>) -> InventoryRoot {
+    inventory_root(warehouse_id, token)
+}
+
+pub(crate) fn inventory_root(warehouse_id: Option<WarehouseId>, token: Option<ExportToken>) -> InventoryRoot {
+    let token = token.unwrap_or_else(|| export::first_token(ExportFormat::Csv, warehouse_id));
+    if token.only_warehouse_id().is_some_and(|id| _get_warehouse(&id).is_none()) {
+        return InventoryRoot {
+            merkle_root: Some(to_hex(&PartialRoot::default().root())),
+            warehouse_count: 0,
+            item_count: 0,
+            computed_at: time(),
+            next: None,
+        };
+    }
+
+    let chunk = export::chunk(String::new(), token);
+    InventoryRoot {
+        merkle_root: chunk.merkle_root.map(|root| to_hex(&root)),
+        warehouse_count: chunk.warehouses_sent,
+        item_count: chunk.items_sent,
+        computed_at: time(),
+        next: chunk.next,
+    }
+}
+
+fn digest(hash: &[u8]) -> [u8; 32] {
+    hash.try_into().unwrap_or_default()
+}
+
+pub(crate) fn leaf_hash(record: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0x00]);
+    hasher.update(record);
+    hasher.finalize().into()
+}
+
+pub(crate) fn merkle_root(mut level: Vec<[u8; 32]>) -> [u8; 32] {
+    if level.is_empty() {
+        return Sha256::digest([]).into();
+    }
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => parent(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+// Siblings from the leaf at `index` up to the root of `merkle_root(level)`, each with
+// whether it is the left child; a level where the node is carried up has no sibling
+pub(crate) fn merkle_path(mut level: Vec<[u8; 32]>, mut index: usize) -> Vec<([u8; 32], bool)> {
+    let mut path = Vec::new();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            path.push((level[sibling], sibling < index));
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => parent(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+        index /= 2;
+    }
+    path
+}
+
+pub(crate) fn parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_root_matches_merkle_root() {
+        for count in 0..70u8 {
+            let leaves: Vec<[u8; 32]> = (0..count).map(|leaf| leaf_hash(&[leaf])).collect();
+            let mut partial = PartialRoot::default();
+            leaves.iter().for_each(|leaf| partial.push(*leaf));
+            assert_eq!(partial.root(), merkle_root(leaves), "{} leaves", count);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+// Operational metrics for controllers. Call and error counts are kept on the heap for
+// every update that goes through idempotency::run, so they start over on upgrade;
+// queries are not counted because their state changes are discarded. Instructions are
+// the counter at the end of the handler, before the reply is encoded.
+use crate::{Error, _ensure_admin, METRICS};
+use crate::{ADJUSTMENTS, AUDIT_LOG, COUNT_SESSIONS, IDEMPOTENCY_RESPONSES, ORGANIZATIONS, PENDING_PURGES, PRICE_HISTORY};
+use crate::{totals, MEMORY_MANAGER, STOCK_STORAGE, WAREHOUSE_STORAGE};
+use ic_cdk::api::stable::{stable64_size, WASM_PAGE_SIZE_IN_BYTES};
+use crate::env::time;
+use ic_cdk::api::{canister_balance128, instruction_counter};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    since: u64, // First counted call
+    methods: BTreeMap<String, MethodCounter>,
+    errors: BTreeMap<&'static str, u64>, // By Error variant
+}
+
+// Stable structures by the memory they live in, see the thread_local in lib.rs
+pub(crate) const MEMORIES: &[(&str, u8)] = &[
+    ("warehouse_storage_v1", 2), // Emptied by migration 2, see migrations.rs
+    ("stock_storage_v1", 3),
+    ("import_mappings", 4),
+    ("item_name_index", 6),
+    ("idempotency_responses", 7),
+    ("idempotency_expiry", 8),
+    ("warehouse_items", 9),
+    ("pending_purges", 10),
+    ("totals", 11),
+    ("warehouse_totals", 12),
+    ("memberships", 13),
+    ("warehouse_name_index", 14),
+    ("org_totals", 15),
+    ("organizations", 16),
+    ("member_roles", 17),
+    ("invitations", 18),
+    ("org_quotas", 19),
+    ("org_calls", 20),
+    ("maintenance", 21),
+    ("feature_flags", 22),
+    ("disabled_endpoints", 23),
+    ("settings", 24),
+    ("state_digest", 25),
+    ("count_sessions", 26),
+    ("count_lines", 27),
+    ("adjustments", 28),
+    ("frozen_warehouses", 29),
+    ("price_history", 30),
+    ("staff_assignments", 31),
+    ("warehouse_staff", 32),
+    ("warehouse_id_increment", 34),
+    ("item_id_increment", 36),
+    ("items_by_creator", 37),
+    ("org_warehouses", 38),
+    ("audit_log_index", 39),
+    ("audit_log_data", 40),
+    ("sku_index", 41),
+    ("barcode_index", 42),
+    ("categories", 43),
+    ("items_by_category", 44),
+    ("unit_conversions", 45),
+    ("low_stock", 46),
+    ("webhooks", 47),
+    ("reservations", 48),
+    ("reserved_quantities", 49),
+    ("item_reservations", 50),
+    ("purchase_orders", 51),
+    ("sales_orders", 52),
+    ("import_sessions", 53),
+    ("import_chunks", 54),
+    ("record_hashes", 55),
+    ("bucket_hashes", 56),
+    ("rate_limits", 57),
+    ("rate_limit_expiry", 58),
+    ("archived_warehouses", 59),
+    ("trash_v1", 60),
+    ("trash_expiry", 61),
+    ("movements_index", 62),
+    ("movements_data", 63),
+    ("item_movements", 64),
+    ("warehouse_movements", 65),
+    ("item_history", 66),
+    ("subscriptions", 67),
+    ("events", 68),
+    ("event_seq", 69),
+    ("bins", 70),
+    ("warehouse_bins", 71),
+    ("items_by_bin", 72),
+    ("report_schedules", 73),
+    ("reports", 74),
+    ("org_reports", 75),
+    ("backup_data", 76),
+    ("backups", 77),
+    ("restore_data", 78),
+    ("restores", 79),
+    ("schema_version", 80),
+    ("warehouse_storage", 81),
+    ("stock_storage", 82),
+    ("trash", 83),
+    ("reservation_expiry", 84),
+];
+
+#[derive(Default)]
+struct MethodCounter {
+    calls: u64,
+    errors: u64,
+    instructions: u128,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct MethodMetrics {
+    method: String,
+    calls: u64,
+    errors: u64,
+    average_instructions: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Count {
+    name: String,
+    count: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct MetricsReport {
+    since: u64,
+    methods: Vec<MethodMetrics>,
+    errors: Vec<Count>,  // Error variant -> calls that returned it
+    entries: Vec<Count>, // Stored records per map
+    stable_memory_bytes: u64,
+    heap_memory_bytes: u64,
+    cycles_balance: u128,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CanisterStats {
+    warehouse_count: u64,
+    item_count: u64,
+    total_quantity: u128,
+    memory_pages: Vec<Count>, // 64 KiB pages per stable structure
+    stable_memory_pages: u64, // Including the memory manager's own pages
+    heap_memory_bytes: u64,
+    cycles_balance: u128,
+}
+
+// Sizes of the whole canister, from the cached totals, so it stays cheap at any data size
+#[ic_cdk::query]
+fn get_stats() -> Result<CanisterStats, Error> {
+    _ensure_admin()?;
+    let totals = totals::totals();
+    Ok(CanisterStats {
+        warehouse_count: totals.warehouse_count,
+        item_count: totals.item_count,
+        total_quantity: totals.total_quantity,
+        memory_pages: MEMORIES
+            .iter()
+            .map(|(name, id)| count_of(name, MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(*id)).size())))
+            .collect(),
+        stable_memory_pages: stable64_size(),
+        heap_memory_bytes: heap_memory_bytes(),
+        cycles_balance: canister_balance128(),
+    })
+}
+
+#[ic_cdk::query]
+fn get_metrics() -> Result<MetricsReport, Error> {
+    _ensure_admin()?;
+    Ok(report())
+}
+
+// The same report in the Prometheus text exposition format
+#[ic_cdk::query]
+fn get_metrics_text() -> Result<String, Error> {
+    _ensure_admin()?;
+    Ok(render_prometheus(&report()))
+}
+
+pub(crate) fn record_call(method: &str, error: Option<&Error>) {
+    let instructions = instruction_counter();
+    METRICS.with(|metrics| {
+        let mut metrics = metrics.borrow_mut();
+        if metrics.since == 0 {
+            metrics.since = time();
+        }
+        let counter = metrics.methods.entry(method.to_string()).or_default();
+        counter.calls += 1;
+        counter.instructions += instructions as u128;
+        if let Some(error) = error {
+            counter.errors += 1;
+            *metrics.errors.entry(error.kind()).or_default() += 1;
+        }
+    });
+}
+
+pub(crate) fn report() -> MetricsReport {
+    let (since, methods, errors) = METRICS.with(|metrics| {
+        let metrics = metrics.borrow();
+        let methods = metrics
+            .methods
+            .iter()
+            .map(|(method, counter)| MethodMetrics {
+                method: method.clone(),
+                calls: counter.calls,
+                errors: counter.errors,
+                average_instructions: (counter.instructions / counter.calls.max(1) as u128) as u64,
+            })
+            .collect();
+        let errors = metrics.errors.iter().map(|(kind, count)| count_of(kind, *count)).collect();
+        (metrics.since, methods, errors)
+    });
+
+    MetricsReport {
+        since,
+        methods,
+        errors,
+        entries: vec![
+            count_of("warehouses", WAREHOUSE_STORAGE.with(|map| map.borrow().len())),
+            count_of("stock_items", STOCK_STORAGE.with(|map| map.borrow().len())),
+            count_of("organizations", ORGANIZATIONS.with(|map| map.borrow().len())),
+            count_of("idempotency_responses", IDEMPOTENCY_RESPONSES.with(|map| map.borrow().len())),
+            count_of("pending_purges", PENDING_PURGES.with(|map| map.borrow().len())),
+            count_of("count_sessions", COUNT_SESSIONS.with(|map| map.borrow().len())),
+            count_of("adjustments", ADJUSTMENTS.with(|map| map.borrow().len())),
+            count_of("price_changes", PRICE_HISTORY.with(|map| map.borrow().len())),
+            count_of("audit_entries", AUDIT_LOG.with(|log| log.borrow().len())),
+        ],
+        stable_memory_bytes: stable64_size() * WASM_PAGE_SIZE_IN_BYTES as u64,
+        heap_memory_bytes: heap_memory_bytes(),
+        cycles_balance: canister_balance128(),
+    }
+}
+
+type Samples = Vec<(String, u128)>; // Label set, e.g. {method="x"}, and value
+
+// Each family is written as one group: its HELP and TYPE lines, then its samples
+pub(crate) fn render_prometheus(report: &MetricsReport) -> String {
+    let mut out = String::new();
+    let labelled = |label: &str, value: &str| format!("{{{}=\"{}\"}}", label, value);
+    let methods = |value: fn(&MethodMetrics) -> u64| {
+        report
+            .methods
+            .iter()
+            .map(|method| (labelled("method", &method.method), value(method) as u128))
+            .collect::<Vec<_>>()
+    };
+    let counts = |label: &str, counts: &[Count]| {
+        counts
+            .iter()
+            .map(|count| (labelled(label, &count.name), count.count as u128))
+            .collect::<Vec<_>>()
+    };
+
+    let families: Vec<(&str, &str, &str, Samples)> = vec![
+        ("inventory_calls_total", "counter", "Update calls per method.", methods(|method| method.calls)),
+        (
+            "inventory_call_errors_total",
+            "counter",
+            "Update calls per method that returned an error.",
+            methods(|method| method.errors),
+        ),
+        (
+            "inventory_call_instructions_average",
+            "gauge",
+            "Average instructions per update call.",
+            methods(|method| method.average_instructions),
+        ),
+        ("inventory_errors_total", "counter", "Update calls per returned error variant.", counts("variant", &report.errors)),
+        ("inventory_entries", "gauge", "Stored records per map.", counts("map", &report.entries)),
+        (
+            "inventory_stable_memory_bytes",
+            "gauge",
+            "Stable memory size.",
+            vec![(String::new(), report.stable_memory_bytes as u128)],
+        ),
+        (
+            "inventory_heap_memory_bytes",
+            "gauge",
+            "Wasm heap size.",
+            vec![(String::new(), report.heap_memory_bytes as u128)],
+        ),
+        ("inventory_cycles_balance", "gauge", "Cycles balance of the canister.", vec![(String::new(), report.cycles_balance)]),
+    ];
+    for (name, kind, help, samples) in families {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        for (labels, value) in samples {
+            writeln!(out, "{}{} {}", name, labels, value).unwrap();
+        }
+    }
+    out
+}
+
+fn count_of(name: &str, count: u64) -> Count {
+    Count {
+        name: name.to_string(),
+        count,
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_memory_bytes() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE_IN_BYTES as u64
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_memory_bytes() -> u64 {
+    0
+}
@@ -0,0 +1,263 @@
+// Versioned migrations of the stored records. SCHEMA_VERSION holds the version of the layout
+// the stable state is in; post_upgrade runs every migration above it, in order, before
+// anything else reads the state, and an upgrade to a release older than the stored version
+// traps and is rolled back. To change the layout of a record: keep decoding the previous
+// layout in Storable::from_bytes, add a migration that rewrites the records, and bump
+// SCHEMA_VERSION_CURRENT. Migrations run within the instruction limit of post_upgrade.
+use crate::ids::{ItemId, WarehouseId};
+use crate::organization::{self, Role};
+use crate::store::{self, NameKey};
+use crate::tenancy::{self, DEFAULT_ORG, NO_ORG};
+use crate::trash::TrashedItem;
+use crate::{counting, pricing, reservation, units};
+use crate::{Memory, StockItem, Warehouse, MEMORY_MANAGER, SCHEMA_VERSION, STOCK_STORAGE, TRASH, WAREHOUSE_STORAGE};
+use candid::Principal;
+use crate::env::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::thread::LocalKey;
+
+pub(crate) const SCHEMA_VERSION_CURRENT: u64 = 5;
+const REWRITE_BATCH: usize = 1000; // Records read at a time while rewriting a map
+const V1_RECORD_BYTES: u32 = 1024; // Bound of warehouses and items up to version 1
+
+// Argument of post_upgrade, for the migrations that cannot decide on their own
+#[derive(candid::CandidType, Deserialize, Default)]
+pub(crate) struct UpgradeArgs {
+    // Owner of the organization that adopts the records made before tenancy (migration 4).
+    // Required when there are such records.
+    pub(crate) pre_tenancy_owner: Option<Principal>,
+}
+
+// Migration to version N is MIGRATIONS[N - 1]
+const MIGRATIONS: &[fn(&UpgradeArgs)] = &[
+    // 1: records of the releases before versioning, which decode only through WarehouseV1
+    // and StockItemV1, are rewritten in the current layout where it fits the old bound
+    |_| {
+        rewrite(&mut v1_map::<WarehouseId, Warehouse, V1_RECORD_BYTES>(2));
+        rewrite(&mut v1_map::<ItemId, StockItem, V1_RECORD_BYTES>(3));
+    },
+    // 2: warehouses, items and deleted items move to maps with a larger bound
+    |_| {
+        move_into(&mut v1_map::<WarehouseId, Warehouse, V1_RECORD_BYTES>(2), &WAREHOUSE_STORAGE);
+        move_into(&mut v1_map::<ItemId, StockItem, V1_RECORD_BYTES>(3), &STOCK_STORAGE);
+        move_into(&mut v1_map::<ItemId, TrashedItem, { V1_RECORD_BYTES + 128 }>(60), &TRASH);
+    },
+    // 3: items whose names collide under the current name key are merged; the name index
+    // is built first when it is missing
+    |_| {
+        store::ensure_indexes();
+        merge_duplicate_items();
+    },
+    // 4: the records made before tenancy, which every principal without a membership could
+    // reach, get an organization with an Owner
+    adopt_pre_tenancy_records,
+    // 5: name keys are case-folded rather than lowercased, so the name indexes are rebuilt
+    // and the items that now collide merged
+    |_| {
+        store::rebuild_name_indexes();
+        merge_duplicate_items();
+    },
+];
+
+// A value read from a map of an older release, under the bound that map was created with
+struct Bounded<T, const MAX_SIZE: u32>(T);
+
+impl<T: Storable, const MAX_SIZE: u32> Storable for Bounded<T, MAX_SIZE> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Bounded(T::from_bytes(bytes))
+    }
+}
+
+impl<T: Storable, const MAX_SIZE: u32> BoundedStorable for Bounded<T, MAX_SIZE> {
+    const MAX_SIZE: u32 = MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_schema_version() -> u64 {
+    schema_version()
+}
+
+// A new canister starts in the current layout
+pub(crate) fn mark_current() {
+    set_schema_version(SCHEMA_VERSION_CURRENT);
+}
+
+pub(crate) fn run(args: &UpgradeArgs) {
+    let stored = schema_version();
+    if stored > SCHEMA_VERSION_CURRENT {
+        ic_cdk::trap(&format!(
+            "Stable state is in schema version {}, this release only knows up to {}",
+            stored, SCHEMA_VERSION_CURRENT
+        ));
+    }
+    for version in stored + 1..=SCHEMA_VERSION_CURRENT {
+        MIGRATIONS[version as usize - 1](args);
+        set_schema_version(version);
+    }
+}
+
+fn schema_version() -> u64 {
+    SCHEMA_VERSION.with(|cell| *cell.borrow().get())
+}
+
+fn set_schema_version(version: u64) {
+    SCHEMA_VERSION.with(|cell| cell.borrow_mut().set(version)).unwrap();
+}
+
+fn v1_map<K, V, const MAX_SIZE: u32>(memory_id: u8) -> StableBTreeMap<K, Bounded<V, MAX_SIZE>, Memory>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: Storable,
+{
+    StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id))))
+}
+
+// Decodes every value of `map` and stores it again, in the layout of the current release;
+// a value that would no longer fit the bound is left as it is
+fn rewrite<K, V>(map: &mut StableBTreeMap<K, V, Memory>)
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let mut after: Option<K> = None;
+    loop {
+        let start = after.clone().map_or(Bound::Unbounded, Bound::Excluded);
+        let batch: Vec<(K, V)> = map.range((start, Bound::Unbounded)).take(REWRITE_BATCH).collect();
+        let Some((last, _)) = batch.last() else {
+            return;
+        };
+        after = Some(last.clone());
+        for (key, value) in batch {
+            if value.to_bytes().len() <= V::MAX_SIZE as usize {
+                map.insert(key, value);
+            }
+        }
+    }
+}
+
+// Moves every value of `from` into `to`, in the layout of the current release
+fn move_into<K, V, const MAX_SIZE: u32>(
+    from: &mut StableBTreeMap<K, Bounded<V, MAX_SIZE>, Memory>,
+    to: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+) where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    loop {
+        let batch: Vec<(K, Bounded<V, MAX_SIZE>)> = from.iter().take(REWRITE_BATCH).collect();
+        if batch.is_empty() {
+            return;
+        }
+        to.with(|to| {
+            let mut to = to.borrow_mut();
+            for (key, value) in batch {
+                from.remove(&key);
+                to.insert(key, value.0);
+            }
+        });
+    }
+}
+
+// Items of one warehouse whose names share a store::name_key, left by releases that kept
+// them apart, are merged into the one with the lowest id, which keeps its spelling.
+// A duplicate stays as it is when merging would lose something: reserved stock, a SKU or
+// barcodes, a unit without a conversion, or a frozen warehouse. The price history of a
+// merged item is carried over to the one it is merged into.
+fn merge_duplicate_items() {
+    // Keyed from the records rather than the name index, so stale index keys cannot split
+    // or join groups
+    let mut groups: BTreeMap<(WarehouseId, NameKey), Vec<ItemId>> = BTreeMap::new();
+    STOCK_STORAGE.with(|storage| {
+        for (id, item) in storage.borrow().iter() {
+            groups.entry((item.warehouse_id, store::name_key(&item.item_name))).or_default().push(id);
+        }
+    });
+
+    for group in groups.into_values().filter(|group| group.len() > 1) {
+        let get = |id: &ItemId| STOCK_STORAGE.with(|storage| storage.borrow().get(id));
+        let Some(mut survivor) = get(&group[0]) else {
+            continue;
+        };
+        if counting::ensure_unfrozen(survivor.warehouse_id).is_err() {
+            continue;
+        }
+        let mut merged = Vec::new();
+        for duplicate in group[1..].iter().filter_map(get) {
+            if reservation::available(&duplicate) < duplicate.quantity
+                || duplicate.sku.is_some()
+                || !duplicate.barcodes.is_empty()
+            {
+                continue;
+            }
+            let quantity = units::convert(survivor.org_id, duplicate.quantity, duplicate.unit, survivor.unit)
+                .ok()
+                .and_then(|quantity| survivor.quantity.checked_add(quantity));
+            if let Some(quantity) = quantity {
+                survivor.quantity = quantity;
+                merged.push(duplicate.item_id);
+            }
+        }
+        if merged.is_empty() {
+            continue;
+        }
+        survivor.updated_at = Some(time());
+        survivor.updated_by = Some(crate::env::caller());
+        survivor.version += 1;
+        for id in merged {
+            pricing::move_history(id, survivor.item_id);
+            store::remove_item(id);
+        }
+        pricing::record(&survivor); // The price in effect from the merge on is the survivor's
+        store::insert_item(survivor);
+    }
+}
+
+// DEFAULT_ORG gets a record, `pre_tenancy_owner` becomes its Owner and the principals
+// that created its warehouses and items its Members. Principals that joined another
+// organization meanwhile stay there; without an Owner, controllers assign its members.
+// A canister without such records is left as it is.
+fn adopt_pre_tenancy_records(args: &UpgradeArgs) {
+    let mut creators: BTreeSet<Principal> = WAREHOUSE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, warehouse)| warehouse.org_id == DEFAULT_ORG)
+            .map(|(_, warehouse)| warehouse.created_by)
+            .collect()
+    });
+    if creators.is_empty() {
+        return;
+    }
+    STOCK_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        let items = storage.iter().filter(|(_, item)| item.org_id == DEFAULT_ORG);
+        creators.extend(items.map(|(_, item)| item.created_by));
+    });
+
+    let owner = match args.pre_tenancy_owner {
+        Some(owner) if owner != Principal::anonymous() => owner,
+        _ => ic_cdk::trap(
+            "Records made before tenancy need an Owner: upgrade with \
+             (opt record { pre_tenancy_owner = opt principal \"<owner>\" })",
+        ),
+    };
+    organization::insert_default_organization(owner);
+    if tenancy::org_of(owner) == NO_ORG {
+        tenancy::set_membership(owner, Some((DEFAULT_ORG, Role::Owner)));
+    }
+    for creator in creators {
+        if creator != Principal::anonymous() && tenancy::org_of(creator) == NO_ORG {
+            tenancy::set_membership(creator, Some((DEFAULT_ORG, Role::Member)));
+        }
+    }
+}
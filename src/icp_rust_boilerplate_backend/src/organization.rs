@@ -0,0 +1,379 @@
+// Organizations and their members. A principal belongs to at most one organization at a
+// time (see tenancy.rs), joins one by creating it or by accepting an invitation, and holds
+// one role in it. Every organization keeps at least one Owner.
+use crate::tenancy::{self, MemberKey, DEFAULT_ORG, NO_ORG};
+use crate::{idempotency, validation, Error, MutationOptions};
+use crate::{INVITATIONS, MEMBER_ROLES, ORGANIZATIONS};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Organization {
+    id: u64,
+    name: String,
+    created_at: u64,
+    created_by: Principal,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct OrganizationPayload {
+    pub(crate) name: String,
+}
+
+// Owners manage everything including roles, Admins manage Members, Members only work with stock
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Role {
+    Member,
+    Admin,
+    Owner,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Member {
+    principal: Principal,
+    role: Role,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Invitation {
+    org_id: u64,
+    principal: Principal,
+    role: Role,
+    invited_by: Principal,
+    invited_at: u64,
+}
+
+impl Storable for Organization {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Organization {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Role {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Role {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Invitation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Invitation {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// The caller's organization
+#[ic_cdk::query]
+fn get_organization() -> Result<Organization, Error> {
+    let org_id = tenancy::caller_org();
+    organization(org_id).ok_or_else(|| Error::not_found("organization", org_id))
+}
+
+// Members of the caller's organization, in principal order
+#[ic_cdk::query]
+fn get_organization_members() -> Result<Vec<Member>, Error> {
+    let org_id = tenancy::caller_org();
+    if organization(org_id).is_none() {
+        return Err(Error::not_found("organization", org_id));
+    }
+    Ok(members(org_id))
+}
+
+#[ic_cdk::query]
+fn get_my_invitations() -> Vec<Invitation> {
+    let caller = MemberKey(crate::env::caller());
+    INVITATIONS.with(|invitations| {
+        invitations
+            .borrow()
+            .range((caller.clone(), 0)..=(caller, u64::MAX))
+            .map(|(_, invitation)| invitation)
+            .collect()
+    })
+}
+
+// The caller becomes the Owner of a new organization
+#[ic_cdk::update]
+fn create_organization(payload: OrganizationPayload, options: Option<MutationOptions>) -> Result<Organization, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("create_organization", options, || _create_organization(payload))
+}
+
+fn _create_organization(payload: OrganizationPayload) -> Result<Organization, Error> {
+    let name = validation::validate_name("name", &payload.name)?;
+    let caller = crate::env::caller();
+    ensure_unaffiliated(caller)?;
+
+    // Ids start at 1; DEFAULT_ORG only has a record when it holds records made before tenancy
+    let id = ORGANIZATIONS
+        .with(|organizations| organizations.borrow().last_key_value().map(|(id, _)| id))
+        .unwrap_or(DEFAULT_ORG)
+        + 1;
+    let organization = Organization {
+        id,
+        name,
+        created_at: time(),
+        created_by: caller,
+    };
+    ORGANIZATIONS.with(|organizations| organizations.borrow_mut().insert(id, organization.clone()));
+    tenancy::set_membership(caller, Some((id, Role::Owner)));
+
+    Ok(organization)
+}
+
+// Invites a principal into the caller's organization; a later invitation replaces an earlier one
+#[ic_cdk::update]
+fn invite_member(principal: Principal, role: Role, options: Option<MutationOptions>) -> Result<Invitation, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("invite_member", options, || _invite_member(principal, role))
+}
+
+fn _invite_member(principal: Principal, role: Role) -> Result<Invitation, Error> {
+    let caller = crate::env::caller();
+    let (org_id, caller_role) = ensure_role(caller, Role::Admin)?;
+    if role > caller_role {
+        return Err(Error::unauthorized(
+            caller,
+            format!("Caller {} cannot invite with role {:?}", caller, role),
+        ));
+    }
+    if tenancy::org_of(principal) == org_id {
+        return Err(already_member(principal, org_id));
+    }
+
+    let invitation = Invitation {
+        org_id,
+        principal,
+        role,
+        invited_by: caller,
+        invited_at: time(),
+    };
+    INVITATIONS.with(|invitations| {
+        invitations
+            .borrow_mut()
+            .insert((MemberKey(principal), org_id), invitation.clone())
+    });
+
+    Ok(invitation)
+}
+
+// The caller joins the organization that invited them, with the role of the invitation
+#[ic_cdk::update]
+fn accept_invitation(org_id: u64, options: Option<MutationOptions>) -> Result<Organization, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("accept_invitation", options, || _accept_invitation(org_id))
+}
+
+fn _accept_invitation(org_id: u64) -> Result<Organization, Error> {
+    let caller = crate::env::caller();
+    let key = (MemberKey(caller), org_id);
+    let invitation = INVITATIONS
+        .with(|invitations| invitations.borrow().get(&key))
+        .ok_or_else(|| Error::not_found("invitation", org_id))?;
+    let organization = organization(org_id).ok_or_else(|| Error::not_found("organization", org_id))?;
+    ensure_unaffiliated(caller)?;
+
+    INVITATIONS.with(|invitations| invitations.borrow_mut().remove(&key));
+    tenancy::set_membership(caller, Some((org_id, invitation.role)));
+
+    Ok(organization)
+}
+
+// Removes a member, or withdraws a pending invitation, from the caller's organization.
+// Any member may remove themselves; the last Owner cannot.
+#[ic_cdk::update]
+fn remove_member(principal: Principal, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("remove_member", options, || _remove_member(principal))
+}
+
+fn _remove_member(principal: Principal) -> Result<(), Error> {
+    let caller = crate::env::caller();
+    let (org_id, caller_role) = if principal == caller {
+        ensure_role(caller, Role::Member)?
+    } else {
+        ensure_role(caller, Role::Admin)?
+    };
+
+    let invitation = (MemberKey(principal), org_id);
+    let role = match role_of(org_id, principal) {
+        Some(role) => role,
+        None => {
+            return match INVITATIONS.with(|invitations| invitations.borrow_mut().remove(&invitation)) {
+                Some(_) => Ok(()),
+                None => Err(not_a_member(principal, org_id)),
+            }
+        }
+    };
+    if principal != caller && role > caller_role {
+        return Err(Error::unauthorized(
+            caller,
+            format!("Caller {} cannot remove a member with role {:?}", caller, role),
+        ));
+    }
+    if role == Role::Owner {
+        ensure_other_owner(org_id, principal)?;
+    }
+
+    tenancy::set_membership(principal, None);
+    Ok(())
+}
+
+// Owners only
+#[ic_cdk::update]
+fn set_member_role(principal: Principal, role: Role, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("set_member_role", options, || _set_member_role(principal, role))
+}
+
+fn _set_member_role(principal: Principal, role: Role) -> Result<(), Error> {
+    let (org_id, _) = ensure_role(crate::env::caller(), Role::Owner)?;
+    let current = role_of(org_id, principal).ok_or_else(|| not_a_member(principal, org_id))?;
+    if current == Role::Owner && role != Role::Owner {
+        ensure_other_owner(org_id, principal)?;
+    }
+
+    tenancy::set_membership(principal, Some((org_id, role)));
+    Ok(())
+}
+
+// The record of DEFAULT_ORG, written by migration 4 for the records made before tenancy
+pub(crate) fn insert_default_organization(created_by: Principal) {
+    let organization = Organization {
+        id: DEFAULT_ORG,
+        name: "Default organization".to_string(),
+        created_at: time(),
+        created_by,
+    };
+    ORGANIZATIONS.with(|organizations| organizations.borrow_mut().insert(DEFAULT_ORG, organization));
+}
+
+pub(crate) fn organization(org_id: u64) -> Option<Organization> {
+    ORGANIZATIONS.with(|organizations| organizations.borrow().get(&org_id))
+}
+
+pub(crate) fn role_of(org_id: u64, principal: Principal) -> Option<Role> {
+    MEMBER_ROLES.with(|roles| roles.borrow().get(&(org_id, MemberKey(principal))))
+}
+
+pub(crate) fn members(org_id: u64) -> Vec<Member> {
+    MEMBER_ROLES.with(|roles| {
+        roles
+            .borrow()
+            .range((org_id, MemberKey::default())..)
+            .take_while(|((org, _), _)| *org == org_id)
+            .map(|((_, MemberKey(principal)), role)| Member { principal, role })
+            .collect()
+    })
+}
+
+// Controllers, and members of the organization itself with at least the `required` role
+pub(crate) fn ensure_role_or_controller(org_id: u64, required: Role) -> Result<(), Error> {
+    let caller = crate::env::caller();
+    if crate::env::is_controller(&caller) || role_of(org_id, caller).is_some_and(|role| role >= required) {
+        return Ok(());
+    }
+    Err(Error::unauthorized(
+        caller,
+        format!("Caller {} needs the {:?} role in organization with id={}", caller, required, org_id),
+    ))
+}
+
+// Removes the organization record, its members and its pending invitations
+pub(crate) fn remove_organization(org_id: u64) {
+    for member in members(org_id) {
+        tenancy::set_membership(member.principal, None);
+    }
+    INVITATIONS.with(|invitations| {
+        let mut invitations = invitations.borrow_mut();
+        let pending: Vec<_> = invitations
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|(_, org)| *org == org_id)
+            .collect();
+        for key in pending {
+            invitations.remove(&key);
+        }
+    });
+    ORGANIZATIONS.with(|organizations| organizations.borrow_mut().remove(&org_id));
+}
+
+// The caller's organization and role, if the role is at least `required`
+fn ensure_role(caller: Principal, required: Role) -> Result<(u64, Role), Error> {
+    let org_id = tenancy::org_of(caller);
+    match role_of(org_id, caller) {
+        Some(role) if role >= required => Ok((org_id, role)),
+        _ => Err(Error::unauthorized(
+            caller,
+            format!("Caller {} needs the {:?} role in an organization", caller, required),
+        )),
+    }
+}
+
+// Principals leave their organization before joining another one
+fn ensure_unaffiliated(principal: Principal) -> Result<(), Error> {
+    match tenancy::org_of(principal) {
+        NO_ORG => Ok(()),
+        org_id => Err(already_member(principal, org_id)),
+    }
+}
+
+fn ensure_other_owner(org_id: u64, principal: Principal) -> Result<(), Error> {
+    let other_owner = members(org_id)
+        .iter()
+        .any(|member| member.role == Role::Owner && member.principal != principal);
+    if other_owner {
+        Ok(())
+    } else {
+        Err(Error::conflict(
+            "organization",
+            Some(org_id),
+            "LAST_OWNER",
+            format!("Principal {} is the last Owner of organization with id={}", principal, org_id),
+        ))
+    }
+}
+
+fn already_member(principal: Principal, org_id: u64) -> Error {
+    Error::conflict(
+        "organization",
+        Some(org_id),
+        "ALREADY_MEMBER",
+        format!("Principal {} already belongs to organization with id={}", principal, org_id),
+    )
+}
+
+fn not_a_member(principal: Principal, org_id: u64) -> Error {
+    Error::invalid_input(
+        "principal",
+        "NOT_A_MEMBER",
+        format!("Principal {} is not a member of organization with id={}", principal, org_id),
+    )
+}
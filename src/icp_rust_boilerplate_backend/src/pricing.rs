@@ -0,0 +1,224 @@
+// Unit cost and price of stock items, with every change kept in PRICE_HISTORY so reports
+// can look up the price that was in effect at a given time. Amounts are integers in the
+// smallest unit of the item's currency, or of the organization's when the item names
+// none; None means not priced. The history of an item is removed with the item.
+use crate::ids::{ItemId, WarehouseId};
+use crate::tenancy::caller_org;
+use crate::{idempotency, store, Error, MutationOptions, StockItem, PRICE_HISTORY};
+use crate::{_check_version, _ensure_fits, _get_item, _get_warehouse};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) struct ItemPrice {
+    unit_cost: Option<u64>,
+    unit_price: Option<u64>,
+    currency: Option<String>, // ISO 4217 code, e.g. "EUR"
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PriceChange {
+    unit_cost: Option<u64>,
+    unit_price: Option<u64>,
+    currency: Option<String>,
+    changed_at: u64,
+    changed_by: Principal,
+}
+
+impl Storable for PriceChange {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PriceChange {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Stock value at unit cost, one total per currency. Items without a cost are counted in
+// `unpriced_items` instead.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct Valuation {
+    totals: Vec<CurrencyTotal>,
+    unpriced_items: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CurrencyTotal {
+    currency: Option<String>, // None for items in the organization's currency
+    value: u128,              // Sum of quantity × unit_cost
+    items: u64,
+}
+
+// Oldest change first
+#[ic_cdk::query]
+fn get_price_history(item_id: ItemId) -> Result<Vec<PriceChange>, Error> {
+    _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    Ok(history(item_id))
+}
+
+// The price set last before or at `at` (nanoseconds since the epoch), or None if the
+// item was not priced yet
+#[ic_cdk::query]
+fn get_price_at(item_id: ItemId, at: u64) -> Result<Option<PriceChange>, Error> {
+    _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    Ok(history(item_id).into_iter().take_while(|change| change.changed_at <= at).last())
+}
+
+#[ic_cdk::query]
+fn get_warehouse_valuation(warehouse_id: WarehouseId) -> Result<Valuation, Error> {
+    _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    Ok(valuation(&[warehouse_id]))
+}
+
+// Every warehouse of the caller's organization
+#[ic_cdk::query]
+fn get_total_valuation() -> Valuation {
+    valuation(&store::warehouse_ids_in_org(caller_org()))
+}
+
+// `expected_version` refers to the item
+#[ic_cdk::update]
+fn set_item_price(item_id: ItemId, price: ItemPrice, options: Option<MutationOptions>) -> Result<StockItem, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("set_item_price", options, || _set_item_price(item_id, price, expected_version))
+}
+
+fn _set_item_price(item_id: ItemId, price: ItemPrice, expected_version: Option<u64>) -> Result<StockItem, Error> {
+    validate_price(&price)?;
+    let mut item = _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    _check_version("item", item_id, item.version, expected_version)?;
+    if price == price_of(&item) {
+        return Ok(item);
+    }
+
+    item.unit_cost = price.unit_cost;
+    item.unit_price = price.unit_price;
+    item.currency = price.currency;
+    item.updated_at = Some(time());
+    item.updated_by = Some(crate::env::caller());
+    item.version += 1;
+    _ensure_fits("item", &item)?;
+
+    store::insert_item(item.clone());
+    record(&item);
+    Ok(item)
+}
+
+// Appends the current price of `item` to its history
+pub(crate) fn record(item: &StockItem) {
+    let change = PriceChange {
+        unit_cost: item.unit_cost,
+        unit_price: item.unit_price,
+        currency: item.currency.clone(),
+        changed_at: time(),
+        changed_by: crate::env::caller(),
+    };
+    PRICE_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let seq = history
+            .range((item.item_id, 0)..=(item.item_id, u64::MAX))
+            .last()
+            .map_or(0, |((_, seq), _)| seq + 1);
+        history.insert((item.item_id, seq), change);
+    });
+}
+
+pub(crate) fn validate_price(price: &ItemPrice) -> Result<(), Error> {
+    validate_currency(&price.currency)
+}
+
+pub(crate) fn validate_currency(currency: &Option<String>) -> Result<(), Error> {
+    match currency {
+        Some(currency) if currency.len() != 3 || !currency.bytes().all(|b| b.is_ascii_uppercase()) => {
+            Err(Error::invalid_input(
+                "currency",
+                "INVALID_CURRENCY",
+                format!("currency must be an ISO 4217 code of three capital letters, got {:?}", currency),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn clear_item(item_id: ItemId) {
+    let keys: Vec<(ItemId, u64)> = PRICE_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range((item_id, 0)..=(item_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    PRICE_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        for key in keys {
+            history.remove(&key);
+        }
+    });
+}
+
+// Moves the history of `from` into the history of `to`, keeping the changes of both in
+// the order they were made
+pub(crate) fn move_history(from: ItemId, to: ItemId) {
+    let mut changes = history(to);
+    changes.extend(history(from));
+    changes.sort_by_key(|change| change.changed_at); // Stable: `to` first on a tie
+    clear_item(from);
+    clear_item(to);
+    PRICE_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        for (seq, change) in changes.into_iter().enumerate() {
+            history.insert((to, seq as u64), change);
+        }
+    });
+}
+
+fn price_of(item: &StockItem) -> ItemPrice {
+    ItemPrice {
+        unit_cost: item.unit_cost,
+        unit_price: item.unit_price,
+        currency: item.currency.clone(),
+    }
+}
+
+pub(crate) fn valuation(warehouse_ids: &[WarehouseId]) -> Valuation {
+    let mut totals: BTreeMap<Option<String>, (u128, u64)> = BTreeMap::new();
+    let mut unpriced_items = 0;
+    for warehouse_id in warehouse_ids {
+        for item in store::items_in_warehouse(*warehouse_id, None, usize::MAX) {
+            match item.unit_cost {
+                Some(unit_cost) => {
+                    let (value, items) = totals.entry(item.currency).or_default();
+                    *value += item.quantity as u128 * unit_cost as u128;
+                    *items += 1;
+                }
+                None => unpriced_items += 1,
+            }
+        }
+    }
+    Valuation {
+        totals: totals
+            .into_iter()
+            .map(|(currency, (value, items))| CurrencyTotal { currency, value, items })
+            .collect(),
+        unpriced_items,
+    }
+}
+
+fn history(item_id: ItemId) -> Vec<PriceChange> {
+    PRICE_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range((item_id, 0)..=(item_id, u64::MAX))
+            .map(|(_, change)| change)
+            .collect()
+    })
+}
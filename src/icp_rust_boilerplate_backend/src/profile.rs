@@ -0,0 +1,105 @@
+// Only built with `--features debug`. Runs as a query, so operations that write
+// (such as AddItem) are measured without their changes being kept.
+use crate::ids::WarehouseId;
+use crate::store;
+use crate::totals;
+use crate::{_add_item_to_warehouse, _ensure_admin, merkle, Error, StockItemPayload};
+use crate::STOCK_STORAGE;
+use ic_cdk::api::instruction_counter;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum ProfileOp {
+    WarehouseStock(WarehouseId),
+    ItemByName { warehouse_id: WarehouseId, item_name: String },
+    Totals,
+    InventoryRoot,
+    AddItem(StockItemPayload),
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ProfilePhase {
+    name: String,
+    instructions: u64,
+    records: u64, // Records found or written by the phase
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ProfileReport {
+    phases: Vec<ProfilePhase>,
+    total_instructions: u64,
+}
+
+#[ic_cdk::query]
+fn profile(op: ProfileOp) -> Result<ProfileReport, Error> {
+    _ensure_admin()?;
+
+    let start = instruction_counter();
+    let phases = match op {
+        // Full-table filter against the warehouse index
+        ProfileOp::WarehouseStock(warehouse_id) => vec![
+            measure("full_scan", || {
+                STOCK_STORAGE.with(|storage| {
+                    storage
+                        .borrow()
+                        .iter()
+                        .filter(|(_, item)| item.warehouse_id == warehouse_id)
+                        .count() as u64
+                })
+            }),
+            measure("indexed", || {
+                let item_ids = store::item_ids_in_warehouse(warehouse_id, usize::MAX);
+                STOCK_STORAGE.with(|storage| {
+                    let storage = storage.borrow();
+                    item_ids.iter().filter_map(|id| storage.get(id)).count() as u64
+                })
+            }),
+        ],
+        ProfileOp::ItemByName { warehouse_id, item_name } => vec![
+            measure("full_scan", || {
+                let name = store::name_key(&item_name);
+                STOCK_STORAGE.with(|storage| {
+                    storage
+                        .borrow()
+                        .iter()
+                        .filter(|(_, item)| {
+                            item.warehouse_id == warehouse_id
+                                && store::name_key(&item.item_name) == name
+                        })
+                        .count() as u64
+                })
+            }),
+            measure("indexed", || {
+                store::item_id_by_name(warehouse_id, &item_name).is_some() as u64
+            }),
+        ],
+        ProfileOp::Totals => vec![
+            measure("recount", || totals::verify() as u64),
+            measure("cached", || {
+                totals::totals();
+                1
+            }),
+        ],
+        ProfileOp::InventoryRoot => vec![measure("inventory_root", || {
+            merkle::inventory_root(None, None);
+            1
+        })],
+        ProfileOp::AddItem(payload) => vec![measure("add_item_to_warehouse", || {
+            _add_item_to_warehouse(payload, None).is_ok() as u64
+        })],
+    };
+
+    Ok(ProfileReport {
+        phases,
+        total_instructions: instruction_counter() - start,
+    })
+}
+
+fn measure(name: &str, phase: impl FnOnce() -> u64) -> ProfilePhase {
+    let start = instruction_counter();
+    let records = phase();
+    ProfilePhase {
+        name: name.to_string(),
+        instructions: instruction_counter() - start,
+        records,
+    }
+}
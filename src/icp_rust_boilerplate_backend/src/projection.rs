@@ -0,0 +1,64 @@
+// Lighter listings for clients that only need to build a selection list. The Ids
+// projection is answered from index keys alone, without decoding any record.
+use crate::ids::WarehouseId;
+use crate::tenancy::caller_org;
+use crate::{store, STOCK_STORAGE, WAREHOUSE_STORAGE, _get_warehouse};
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum Projection {
+    Ids,
+    IdsAndNames,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RecordRef {
+    id: u64,
+    name: String,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum Projected {
+    Ids(Vec<u64>),
+    IdsAndNames(Vec<RecordRef>),
+}
+
+// Warehouses of the caller's organization that are not archived, in id order
+#[ic_cdk::query]
+fn get_warehouses_projected(projection: Projection) -> Projected {
+    let ids = store::listed_warehouse_ids(caller_org());
+    match projection {
+        Projection::Ids => Projected::Ids(ids.into_iter().map(u64::from).collect()),
+        Projection::IdsAndNames => Projected::IdsAndNames(WAREHOUSE_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            ids.iter()
+                .filter_map(|id| storage.get(id))
+                .map(|warehouse| RecordRef {
+                    id: warehouse.id.into(),
+                    name: warehouse.name,
+                })
+                .collect()
+        })),
+    }
+}
+
+// The stock of one warehouse in item id order
+#[ic_cdk::query]
+fn get_warehouse_stock_projected(warehouse_id: WarehouseId, projection: Projection) -> Projected {
+    let ids = match _get_warehouse(&warehouse_id) {
+        Some(_) => store::item_ids_in_warehouse(warehouse_id, usize::MAX),
+        None => Vec::new(),
+    };
+    match projection {
+        Projection::Ids => Projected::Ids(ids.into_iter().map(u64::from).collect()),
+        Projection::IdsAndNames => Projected::IdsAndNames(STOCK_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            ids.iter()
+                .filter_map(|id| storage.get(id))
+                .map(|item| RecordRef {
+                    id: item.item_id.into(),
+                    name: item.item_name,
+                })
+                .collect()
+        })),
+    }
+}
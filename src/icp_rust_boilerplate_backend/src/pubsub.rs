@@ -0,0 +1,411 @@
+// Inventory events pushed to subscribing canisters. An organization's Admins subscribe a
+// canister to some topics; store.rs and transfer_item publish an event for every item
+// created, every quantity change and every transfer, but only while a subscription of
+// the organization wants the topic. Every DELIVERY_INTERVAL a timer sends each due
+// subscription the events it has not had yet, oldest first, as a one-way call to
+//
+//     on_inventory_event : (vec InventoryEvent) -> ()
+//
+// One-way calls get no reply, so an event counts as delivered once the call is enqueued;
+// a call that cannot be enqueued is retried with exponential backoff, and the position
+// and attempt state are kept with the subscription in stable memory so an upgrade does
+// not reset them. Events every subscription has had are removed, and so are events older
+// than EVENT_RETENTION_NS, which a subscriber failing for that long skips. The timer
+// pauses in maintenance mode, like the purge.
+use crate::ids::{ItemId, WarehouseId};
+use crate::organization::{self, Role};
+use crate::tenancy::caller_org;
+use crate::{idempotency, maintenance, Error, MutationOptions, StockItem, EVENTS, EVENT_SEQ, PUBSUB_TIMER, SUBSCRIPTIONS};
+use crate::_ensure_fits;
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::time::Duration;
+
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_BACKOFF_NS: u64 = 60 * 60 * 1_000_000_000;
+const EVENT_RETENTION_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_SUBSCRIPTIONS: usize = 5; // Per organization
+const MAX_BATCH: usize = 100; // Events per call
+const MAX_SCAN: usize = 1000; // Events looked at per subscription and tick
+const PRUNE_BATCH: usize = 1000; // Events removed per tick
+const MAX_ERROR_LENGTH: usize = 200;
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) enum Topic {
+    ItemCreated,
+    QuantityChanged,
+    TransferCompleted,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum EventKind {
+    ItemCreated {
+        item_id: ItemId,
+        warehouse_id: WarehouseId,
+        quantity: u64,
+    },
+    // Also sent with quantity 0 when an item is removed
+    QuantityChanged {
+        item_id: ItemId,
+        warehouse_id: WarehouseId,
+        previous: u64,
+        quantity: u64,
+    },
+    TransferCompleted {
+        item_id: ItemId, // The source item
+        from_warehouse_id: WarehouseId,
+        to_warehouse_id: WarehouseId,
+        quantity: u64,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct InventoryEvent {
+    seq: u64, // Increases by one per event across all organizations
+    org_id: u64,
+    at: u64,
+    kind: EventKind,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Subscription {
+    id: u64, // Unique within the organization
+    org_id: u64,
+    canister_id: Principal,
+    topics: Vec<Topic>,
+    created_at: u64,
+    created_by: Principal,
+    cursor: u64,   // Sequence number of the first event not yet delivered
+    failures: u32, // Consecutive calls that could not be enqueued
+    next_attempt_at: u64,
+    last_delivered_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl EventKind {
+    fn topic(&self) -> Topic {
+        match self {
+            EventKind::ItemCreated { .. } => Topic::ItemCreated,
+            EventKind::QuantityChanged { .. } => Topic::QuantityChanged,
+            EventKind::TransferCompleted { .. } => Topic::TransferCompleted,
+        }
+    }
+}
+
+impl Storable for InventoryEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InventoryEvent {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Subscription {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Subscription {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Subscriptions of the caller's organization, for its Admins and Owners
+#[ic_cdk::query]
+fn list_subscriptions() -> Result<Vec<Subscription>, Error> {
+    let org_id = caller_org();
+    organization::ensure_role_or_controller(org_id, Role::Admin)?;
+    Ok(subscriptions_in_org(org_id))
+}
+
+// Delivery starts with the next event published, earlier ones are not sent
+#[ic_cdk::update]
+fn subscribe(canister_id: Principal, topics: Vec<Topic>, options: Option<MutationOptions>) -> Result<Subscription, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("subscribe", options, || _subscribe(canister_id, topics))
+}
+
+fn _subscribe(canister_id: Principal, topics: Vec<Topic>) -> Result<Subscription, Error> {
+    validate_canister_id(canister_id)?;
+    if topics.is_empty() || topics.iter().enumerate().any(|(i, topic)| topics[..i].contains(topic)) {
+        return Err(Error::invalid_input(
+            "topics",
+            "INVALID_TOPICS",
+            "topics must name at least one topic, each at most once".to_string(),
+        ));
+    }
+    let org_id = caller_org();
+    organization::ensure_role_or_controller(org_id, Role::Admin)?;
+    let existing = subscriptions_in_org(org_id);
+    if let Some(subscription) = existing.iter().find(|subscription| subscription.canister_id == canister_id) {
+        return Err(Error::conflict(
+            "subscription",
+            Some(subscription.id),
+            "ALREADY_SUBSCRIBED",
+            format!("Canister {} is already subscribed with id={}", canister_id, subscription.id),
+        ));
+    }
+    if existing.len() >= MAX_SUBSCRIPTIONS {
+        return Err(Error::capacity_exceeded(
+            "TOO_MANY_ENTRIES",
+            MAX_SUBSCRIPTIONS as u64,
+            existing.len() as u64 + 1,
+            format!("An organization has at most {} subscriptions", MAX_SUBSCRIPTIONS),
+        ));
+    }
+
+    // The next id follows the highest one in the organization
+    let id = existing.last().map_or(1, |last| last.id + 1);
+    let subscription = Subscription {
+        id,
+        org_id,
+        canister_id,
+        topics,
+        created_at: time(),
+        created_by: crate::env::caller(),
+        cursor: next_seq(),
+        failures: 0,
+        next_attempt_at: 0,
+        last_delivered_at: None,
+        last_error: None,
+    };
+    _ensure_fits("subscription", &subscription)?;
+
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().insert((org_id, id), subscription.clone()));
+    resume();
+    Ok(subscription)
+}
+
+// Events not yet delivered are dropped for this subscriber
+#[ic_cdk::update]
+fn unsubscribe(id: u64, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("unsubscribe", options, || _unsubscribe(id))
+}
+
+fn _unsubscribe(id: u64) -> Result<(), Error> {
+    let org_id = caller_org();
+    organization::ensure_role_or_controller(org_id, Role::Admin)?;
+    SUBSCRIPTIONS
+        .with(|subscriptions| subscriptions.borrow_mut().remove(&(org_id, id)))
+        .ok_or_else(|| Error::not_found("subscription", id))?;
+    Ok(())
+}
+
+// Only other canisters can be called; user principals and the management canister cannot
+fn validate_canister_id(canister_id: Principal) -> Result<(), Error> {
+    // Canister ids are opaque principals, whose last byte is 0x01
+    if canister_id.as_slice().last() != Some(&0x01) || canister_id == ic_cdk::id() {
+        return Err(Error::invalid_input(
+            "canister_id",
+            "INVALID_CANISTER_ID",
+            format!("canister_id must be the id of another canister, got {}", canister_id),
+        ));
+    }
+    Ok(())
+}
+
+// Called by store.rs for every write of a stock item; a removal is a change to 0
+pub(crate) fn record_item(old: Option<&StockItem>, new: Option<&StockItem>) {
+    let (org_id, kind) = match (old, new) {
+        (None, Some(new)) => (
+            new.org_id,
+            EventKind::ItemCreated {
+                item_id: new.item_id,
+                warehouse_id: new.warehouse_id,
+                quantity: new.quantity,
+            },
+        ),
+        (Some(old), new) if old.quantity != new.map_or(0, |new| new.quantity) => (
+            old.org_id,
+            EventKind::QuantityChanged {
+                item_id: old.item_id,
+                warehouse_id: new.map_or(old.warehouse_id, |new| new.warehouse_id),
+                previous: old.quantity,
+                quantity: new.map_or(0, |new| new.quantity),
+            },
+        ),
+        _ => return,
+    };
+    publish(org_id, kind);
+}
+
+pub(crate) fn record_transfer(org_id: u64, item_id: ItemId, from_warehouse_id: WarehouseId, to_warehouse_id: WarehouseId, quantity: u64) {
+    publish(
+        org_id,
+        EventKind::TransferCompleted {
+            item_id,
+            from_warehouse_id,
+            to_warehouse_id,
+            quantity,
+        },
+    );
+}
+
+fn publish(org_id: u64, kind: EventKind) {
+    let topic = kind.topic();
+    let wanted = subscriptions_in_org(org_id)
+        .iter()
+        .any(|subscription| subscription.topics.contains(&topic));
+    if !wanted {
+        return;
+    }
+    let seq = next_seq();
+    EVENT_SEQ.with(|cell| cell.borrow_mut().set(seq + 1)).unwrap();
+    let event = InventoryEvent {
+        seq,
+        org_id,
+        at: time(),
+        kind,
+    };
+    EVENTS.with(|events| events.borrow_mut().insert(seq, event));
+}
+
+// Removes the subscriptions of an organization; its events are pruned by the timer
+pub(crate) fn clear_org(org_id: u64) {
+    for subscription in subscriptions_in_org(org_id) {
+        SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().remove(&(org_id, subscription.id)));
+    }
+}
+
+// Timers do not survive upgrades; started again while there is a subscription
+pub(crate) fn resume() {
+    let idle = PUBSUB_TIMER.with(|timer| timer.borrow().is_none());
+    if idle && SUBSCRIPTIONS.with(|subscriptions| !subscriptions.borrow().is_empty()) {
+        let timer = ic_cdk_timers::set_timer_interval(DELIVERY_INTERVAL, deliver_due);
+        PUBSUB_TIMER.with(|current| *current.borrow_mut() = Some(timer));
+    }
+}
+
+fn deliver_due() {
+    if SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().is_empty()) {
+        if let Some(timer) = PUBSUB_TIMER.with(|timer| timer.borrow_mut().take()) {
+            ic_cdk_timers::clear_timer(timer);
+        }
+        prune(time());
+        return;
+    }
+    if maintenance::is_enabled() {
+        return;
+    }
+
+    let now = time();
+    let due: Vec<Subscription> = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| subscription.next_attempt_at <= now)
+            .collect()
+    });
+    for subscription in due {
+        deliver(subscription, now);
+    }
+    prune(now);
+}
+
+// Sends the next batch of events the subscription wants
+fn deliver(mut subscription: Subscription, now: u64) {
+    let mut batch = Vec::new();
+    let mut cursor = subscription.cursor;
+    EVENTS.with(|events| {
+        for (seq, event) in events.borrow().range(subscription.cursor..).take(MAX_SCAN) {
+            cursor = seq + 1;
+            if event.org_id == subscription.org_id && subscription.topics.contains(&event.kind.topic()) {
+                batch.push(event);
+                if batch.len() == MAX_BATCH {
+                    break;
+                }
+            }
+        }
+    });
+    if cursor == subscription.cursor {
+        return;
+    }
+
+    if !batch.is_empty() {
+        let result = ic_cdk::notify(subscription.canister_id, "on_inventory_event", (batch,));
+        if let Err(code) = result {
+            subscription.failures = subscription.failures.saturating_add(1);
+            let backoff = DELIVERY_INTERVAL.as_nanos() as u64 * 2u64.saturating_pow(subscription.failures);
+            subscription.next_attempt_at = now + backoff.min(MAX_BACKOFF_NS);
+            let mut error = format!("{:?}", code);
+            error.truncate(MAX_ERROR_LENGTH);
+            subscription.last_error = Some(error);
+            save(subscription);
+            return;
+        }
+        subscription.failures = 0;
+        subscription.last_delivered_at = Some(now);
+    }
+    subscription.cursor = cursor;
+    subscription.next_attempt_at = now;
+    save(subscription);
+}
+
+fn save(subscription: Subscription) {
+    SUBSCRIPTIONS.with(|subscriptions| {
+        let mut subscriptions = subscriptions.borrow_mut();
+        // Removed while the batch was being sent
+        let key = (subscription.org_id, subscription.id);
+        if subscriptions.contains_key(&key) {
+            subscriptions.insert(key, subscription);
+        }
+    });
+}
+
+// Removes events every subscription has had, and events past the retention window
+fn prune(now: u64) {
+    let delivered = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .iter()
+            .map(|(_, subscription)| subscription.cursor)
+            .min()
+            .unwrap_or(u64::MAX)
+    });
+    let cutoff = now.saturating_sub(EVENT_RETENTION_NS);
+    let expired: Vec<u64> = EVENTS.with(|events| {
+        events
+            .borrow()
+            .iter()
+            .take(PRUNE_BATCH)
+            .take_while(|(seq, event)| *seq < delivered || event.at < cutoff)
+            .map(|(seq, _)| seq)
+            .collect()
+    });
+    EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        for seq in expired {
+            events.remove(&seq);
+        }
+    });
+}
+
+fn next_seq() -> u64 {
+    EVENT_SEQ.with(|cell| *cell.borrow().get())
+}
+
+fn subscriptions_in_org(org_id: u64) -> Vec<Subscription> {
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .range((org_id, 0)..=(org_id, u64::MAX))
+            .map(|(_, subscription)| subscription)
+            .collect()
+    })
+}
@@ -0,0 +1,319 @@
+// Purchase orders for stock bought from a supplier. An order is edited while it is a
+// draft, fixed once submitted, and received into its warehouse in one call: every line
+// adds its quantity to the item of that name, which is created when the warehouse has
+// none. Either all lines are received or, if any cannot be, none is. Quantities are in
+// the unit the item is stored in; costs are in the smallest unit of the order currency.
+use crate::ids::{ItemId, WarehouseId};
+use crate::store::name_key;
+use crate::tenancy::caller_org;
+use crate::{counting, idempotency, pricing, quota, settings, staffing, store, validation, Error, MutationOptions, StockItem};
+use crate::{PURCHASE_ORDERS, STOCK_STORAGE};
+use crate::{_add_quantity, _check_version, _ensure_fits, _ensure_not_archived, _ensure_room, _get_warehouse, get_next_item_id};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+pub(crate) const MAX_LINES: usize = 50; // Per order
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) enum PurchaseOrderStatus {
+    Draft,
+    Submitted,
+    Received,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PurchaseOrderLine {
+    item_name: String,
+    quantity: u64,
+    unit_cost: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PurchaseOrderPayload {
+    supplier: String,
+    warehouse_id: WarehouseId,
+    currency: Option<String>, // ISO 4217 code; None for the organization's currency
+    lines: Vec<PurchaseOrderLine>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PurchaseOrder {
+    id: u64,
+    org_id: u64,
+    warehouse_id: WarehouseId,
+    supplier: String,
+    currency: Option<String>,
+    lines: Vec<PurchaseOrderLine>,
+    status: PurchaseOrderStatus,
+    version: u64,
+    created_at: u64,
+    created_by: Principal,
+    updated_at: Option<u64>,
+    submitted_at: Option<u64>,
+    received_at: Option<u64>,
+    received_by: Option<Principal>,
+}
+
+impl Storable for PurchaseOrder {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PurchaseOrder {
+    const MAX_SIZE: u32 = 32 * 1024; // MAX_LINES lines with names of MAX_NAME_LENGTH
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_purchase_order(id: u64) -> Result<PurchaseOrder, Error> {
+    purchase_order(id).ok_or_else(|| Error::not_found("purchase_order", id))
+}
+
+// Orders of the caller's organization in id order, optionally only those in `status`
+#[ic_cdk::query]
+fn list_purchase_orders(status: Option<PurchaseOrderStatus>) -> Vec<PurchaseOrder> {
+    let org_id = caller_org();
+    PURCHASE_ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| order.org_id == org_id && status.is_none_or(|status| order.status == status))
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn create_purchase_order(payload: PurchaseOrderPayload, options: Option<MutationOptions>) -> Result<PurchaseOrder, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("create_purchase_order", options, || _create_purchase_order(payload))
+}
+
+fn _create_purchase_order(payload: PurchaseOrderPayload) -> Result<PurchaseOrder, Error> {
+    let payload = validate_payload(payload)?;
+    let warehouse = _get_warehouse(&payload.warehouse_id).ok_or_else(|| Error::not_found("warehouse", payload.warehouse_id))?;
+
+    // Orders are never re-numbered, so the next id follows the highest one in use
+    let id = PURCHASE_ORDERS.with(|orders| orders.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let order = PurchaseOrder {
+        id,
+        org_id: warehouse.org_id,
+        warehouse_id: warehouse.id,
+        supplier: payload.supplier,
+        currency: payload.currency,
+        lines: payload.lines,
+        status: PurchaseOrderStatus::Draft,
+        version: 1,
+        created_at: time(),
+        created_by: crate::env::caller(),
+        updated_at: None,
+        submitted_at: None,
+        received_at: None,
+        received_by: None,
+    };
+    _ensure_fits("purchase_order", &order)?;
+
+    PURCHASE_ORDERS.with(|orders| orders.borrow_mut().insert(id, order.clone()));
+    Ok(order)
+}
+
+// Replaces the supplier, warehouse, currency and lines of a draft
+#[ic_cdk::update]
+fn update_purchase_order(id: u64, payload: PurchaseOrderPayload, options: Option<MutationOptions>) -> Result<PurchaseOrder, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("update_purchase_order", options, || _update_purchase_order(id, payload, expected_version))
+}
+
+fn _update_purchase_order(id: u64, payload: PurchaseOrderPayload, expected_version: Option<u64>) -> Result<PurchaseOrder, Error> {
+    let payload = validate_payload(payload)?;
+    let mut order = in_status(id, PurchaseOrderStatus::Draft)?;
+    _check_version("purchase_order", id, order.version, expected_version)?;
+    let warehouse = _get_warehouse(&payload.warehouse_id).ok_or_else(|| Error::not_found("warehouse", payload.warehouse_id))?;
+
+    order.warehouse_id = warehouse.id;
+    order.supplier = payload.supplier;
+    order.currency = payload.currency;
+    order.lines = payload.lines;
+    order.updated_at = Some(time());
+    order.version += 1;
+    _ensure_fits("purchase_order", &order)?;
+
+    PURCHASE_ORDERS.with(|orders| orders.borrow_mut().insert(id, order.clone()));
+    Ok(order)
+}
+
+// Fixes the order; it can no longer be edited, only received
+#[ic_cdk::update]
+fn submit_purchase_order(id: u64, options: Option<MutationOptions>) -> Result<PurchaseOrder, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("submit_purchase_order", options, || _submit_purchase_order(id, expected_version))
+}
+
+fn _submit_purchase_order(id: u64, expected_version: Option<u64>) -> Result<PurchaseOrder, Error> {
+    let mut order = in_status(id, PurchaseOrderStatus::Draft)?;
+    _check_version("purchase_order", id, order.version, expected_version)?;
+    _get_warehouse(&order.warehouse_id).ok_or_else(|| Error::not_found("warehouse", order.warehouse_id))?;
+
+    order.status = PurchaseOrderStatus::Submitted;
+    order.submitted_at = Some(time());
+    order.updated_at = order.submitted_at;
+    order.version += 1;
+
+    PURCHASE_ORDERS.with(|orders| orders.borrow_mut().insert(id, order.clone()));
+    Ok(order)
+}
+
+// Adds every line to the stock of the order's warehouse
+#[ic_cdk::update]
+fn receive_purchase_order(id: u64, options: Option<MutationOptions>) -> Result<PurchaseOrder, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("receive_purchase_order", options, || _receive_purchase_order(id, expected_version))
+}
+
+fn _receive_purchase_order(id: u64, expected_version: Option<u64>) -> Result<PurchaseOrder, Error> {
+    let mut order = in_status(id, PurchaseOrderStatus::Submitted)?;
+    _check_version("purchase_order", id, order.version, expected_version)?;
+    let warehouse = _get_warehouse(&order.warehouse_id).ok_or_else(|| Error::not_found("warehouse", order.warehouse_id))?;
+    counting::ensure_unfrozen(warehouse.id)?;
+    _ensure_not_archived(&warehouse)?;
+    staffing::ensure_on_shift(warehouse.id, warehouse.org_id, staffing::Duty::Receiving)?;
+
+    // Every line is checked before the first write; new items get their id afterwards
+    let mut items = Vec::with_capacity(order.lines.len());
+    for line in &order.lines {
+        let existing = store::item_id_by_name(warehouse.id, &line.item_name)
+            .and_then(|item_id| STOCK_STORAGE.with(|storage| storage.borrow().get(&item_id)));
+        let item = match existing {
+            // The index ignores case, so "widget" is received into "Widget"
+            Some(mut item) => {
+                item.quantity = _add_quantity(item.item_id, item.quantity, line.quantity)?;
+                item.updated_at = Some(time());
+                item.updated_by = Some(crate::env::caller());
+                item.version += 1;
+                item
+            }
+            None => new_item(&order, line),
+        };
+        _ensure_fits("item", &item)?;
+        items.push(item);
+    }
+    let new_items = items.iter().filter(|item| item.item_id == ItemId(0)).count() as u64;
+    if new_items > 0 {
+        quota::ensure_item_quota_for(warehouse.org_id, new_items)?;
+        _ensure_room(warehouse.id, new_items)?;
+    }
+
+    for mut item in items {
+        if item.item_id == ItemId(0) {
+            item.item_id = get_next_item_id();
+        }
+        store::insert_item(item);
+    }
+
+    order.status = PurchaseOrderStatus::Received;
+    order.received_at = Some(time());
+    order.received_by = Some(crate::env::caller());
+    order.updated_at = order.received_at;
+    order.version += 1;
+
+    PURCHASE_ORDERS.with(|orders| orders.borrow_mut().insert(id, order.clone()));
+    Ok(order)
+}
+
+// Normalizes the supplier and item names; item names are unique within an order
+pub(crate) fn validate_payload(mut payload: PurchaseOrderPayload) -> Result<PurchaseOrderPayload, Error> {
+    payload.supplier = validation::validate_name("supplier", &payload.supplier)?;
+    pricing::validate_currency(&payload.currency)?;
+    if payload.lines.is_empty() || payload.lines.len() > MAX_LINES {
+        return Err(Error::invalid_input(
+            "lines",
+            "VALUE_OUT_OF_RANGE",
+            format!("lines must have 1 to {} entries, got {}", MAX_LINES, payload.lines.len()),
+        ));
+    }
+
+    let mut names = BTreeSet::new();
+    for line in payload.lines.iter_mut() {
+        line.item_name = validation::validate_name("item_name", &line.item_name)?;
+        validation::validate_quantity("quantity", line.quantity)?;
+        if !names.insert(name_key(&line.item_name)) {
+            return Err(Error::invalid_input(
+                "lines",
+                "DUPLICATE_LINE",
+                format!("{:?} is on more than one line", line.item_name),
+            ));
+        }
+    }
+    Ok(payload)
+}
+
+// Removes the purchase orders of an organization whose warehouses are all gone
+pub(crate) fn clear_org(org_id: u64) {
+    let ids: Vec<u64> = PURCHASE_ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .iter()
+            .filter(|(_, order)| order.org_id == org_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    PURCHASE_ORDERS.with(|orders| {
+        let mut orders = orders.borrow_mut();
+        for id in ids {
+            orders.remove(&id);
+        }
+    });
+}
+
+// The item a line creates, still without an id
+fn new_item(order: &PurchaseOrder, line: &PurchaseOrderLine) -> StockItem {
+    StockItem {
+        item_id: ItemId(0),
+        warehouse_id: order.warehouse_id,
+        item_name: line.item_name.clone(),
+        quantity: line.quantity,
+        unit: Default::default(),
+        created_at: time(),
+        updated_at: None,
+        version: 1,
+        org_id: order.org_id,
+        unit_cost: None,
+        unit_price: None,
+        currency: None,
+        sku: None,
+        barcodes: Vec::new(),
+        category_id: None,
+        bin_id: None,
+        reorder_point: settings::default_reorder_point(),
+        created_by: crate::env::caller(),
+        updated_by: None,
+    }
+}
+
+fn in_status(id: u64, status: PurchaseOrderStatus) -> Result<PurchaseOrder, Error> {
+    let order = purchase_order(id).ok_or_else(|| Error::not_found("purchase_order", id))?;
+    if order.status != status {
+        return Err(Error::conflict(
+            "purchase_order",
+            Some(id),
+            "INVALID_STATUS",
+            format!("Purchase order with id={} is {:?}, not {:?}", id, order.status, status),
+        ));
+    }
+    Ok(order)
+}
+
+// A purchase order of the caller's organization
+fn purchase_order(id: u64) -> Option<PurchaseOrder> {
+    PURCHASE_ORDERS
+        .with(|orders| orders.borrow().get(&id))
+        .filter(|order| order.org_id == caller_org())
+}
@@ -0,0 +1,67 @@
+// Removes the stock of deleted warehouses. Small warehouses are emptied within
+// delete_warehouse; larger ones are finished in batches by a timer, one message per batch.
+// Deleted items and bins of the warehouse are dropped once its last item is gone.
+use crate::ids::WarehouseId;
+use crate::{audit, bins, maintenance, store, trash, PENDING_PURGES, PURGE_SCHEDULED};
+use std::time::Duration;
+
+const PURGE_BATCH: usize = 500; // Items removed per message
+
+pub(crate) fn purge_warehouse(warehouse_id: WarehouseId) {
+    if purge_batch(warehouse_id) {
+        release(warehouse_id);
+    } else {
+        PENDING_PURGES.with(|pending| pending.borrow_mut().insert(warehouse_id, ()));
+        schedule();
+    }
+}
+
+pub(crate) fn is_pending(warehouse_id: WarehouseId) -> bool {
+    PENDING_PURGES.with(|pending| pending.borrow().contains_key(&warehouse_id))
+}
+
+// Timers do not survive upgrades or maintenance mode
+pub(crate) fn resume() {
+    if PENDING_PURGES.with(|pending| !pending.borrow().is_empty()) {
+        schedule();
+    }
+}
+
+// Returns true once the warehouse has no items left
+fn purge_batch(warehouse_id: WarehouseId) -> bool {
+    let item_ids = store::item_ids_in_warehouse(warehouse_id, PURGE_BATCH + 1);
+    for item_id in item_ids.iter().take(PURGE_BATCH) {
+        store::remove_item(*item_id);
+    }
+    item_ids.len() <= PURGE_BATCH
+}
+
+fn release(warehouse_id: WarehouseId) {
+    trash::clear_warehouse(warehouse_id);
+    bins::clear_warehouse(warehouse_id);
+}
+
+fn schedule() {
+    if PURGE_SCHEDULED.with(|scheduled| scheduled.replace(true)) {
+        return;
+    }
+    ic_cdk_timers::set_timer(Duration::ZERO, run_pending);
+}
+
+fn run_pending() {
+    PURGE_SCHEDULED.with(|scheduled| scheduled.replace(false));
+
+    // Resumed by set_maintenance_mode once writes are allowed again
+    if maintenance::is_enabled() {
+        return;
+    }
+
+    let next = PENDING_PURGES.with(|pending| pending.borrow().iter().next().map(|(id, _)| id));
+    if let Some(warehouse_id) = next {
+        if audit::in_method("purge_warehouse", || purge_batch(warehouse_id)) {
+            PENDING_PURGES.with(|pending| pending.borrow_mut().remove(&warehouse_id));
+            release(warehouse_id);
+        }
+        resume();
+    }
+}
@@ -0,0 +1,173 @@
+// Per-organization limits, set by controllers. Warehouse and item counts come from the
+// organization totals; update calls are counted per UTC day. An organization without an
+// entry in ORG_QUOTAS, or a limit left as None, is unlimited.
+use crate::tenancy::caller_org;
+use crate::totals::org_totals;
+use crate::{idempotency, Error, MutationOptions, _ensure_admin, ORG_CALLS, ORG_QUOTAS};
+use candid::{Decode, Encode};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Quotas {
+    max_warehouses: Option<u64>,
+    max_items: Option<u64>,
+    max_attachment_bytes: Option<u64>, // Reserved: no attachment storage exists yet
+    max_calls_per_day: Option<u64>,    // Update calls; queries are not counted
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct QuotaUsage {
+    org_id: u64,
+    quotas: Quotas,
+    warehouses: u64,
+    items: u64,
+    attachment_bytes: u64,
+    calls_today: u64,
+    day_started_at: u64, // Calls are counted from this time until DAY_NS later
+}
+
+// Update calls of one organization on one day
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CallCount {
+    day: u64, // Days since the epoch
+    calls: u64,
+}
+
+impl Storable for Quotas {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Quotas {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for CallCount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CallCount {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Usage of the caller's organization, or of any organization for controllers
+#[ic_cdk::query]
+fn get_quota_usage(org_id: Option<u64>) -> Result<QuotaUsage, Error> {
+    let org_id = match org_id {
+        Some(org_id) if org_id != caller_org() => {
+            _ensure_admin()?;
+            org_id
+        }
+        _ => caller_org(),
+    };
+
+    let totals = org_totals(org_id);
+    let today = time() / DAY_NS;
+    Ok(QuotaUsage {
+        org_id,
+        quotas: quotas(org_id),
+        warehouses: totals.warehouse_count,
+        items: totals.item_count,
+        attachment_bytes: 0,
+        calls_today: calls_on(org_id, today),
+        day_started_at: today * DAY_NS,
+    })
+}
+
+// Replaces the quotas of an organization; lowering a limit below the current usage only
+// blocks further growth
+#[ic_cdk::update]
+fn set_quotas(org_id: u64, quotas: Quotas, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("set_quotas", options, || {
+        _ensure_admin()?;
+        ORG_QUOTAS.with(|map| map.borrow_mut().insert(org_id, quotas));
+        Ok(())
+    })
+}
+
+pub(crate) fn clear_org(org_id: u64) {
+    ORG_QUOTAS.with(|map| map.borrow_mut().remove(&org_id));
+    ORG_CALLS.with(|map| map.borrow_mut().remove(&org_id));
+}
+
+pub(crate) fn quotas(org_id: u64) -> Quotas {
+    ORG_QUOTAS.with(|map| map.borrow().get(&org_id).unwrap_or_default())
+}
+
+pub(crate) fn ensure_warehouse_quota(org_id: u64) -> Result<(), Error> {
+    ensure_within(org_id, "max_warehouses", quotas(org_id).max_warehouses, org_totals(org_id).warehouse_count)
+}
+
+pub(crate) fn ensure_item_quota(org_id: u64) -> Result<(), Error> {
+    ensure_within(org_id, "max_items", quotas(org_id).max_items, org_totals(org_id).item_count)
+}
+
+// Room for `count` more items at once
+pub(crate) fn ensure_item_quota_for(org_id: u64, count: u64) -> Result<(), Error> {
+    let used = org_totals(org_id).item_count.saturating_add(count.saturating_sub(1));
+    ensure_within(org_id, "max_items", quotas(org_id).max_items, used)
+}
+
+// Counts an update call against the caller's organization. Controllers are neither
+// limited nor counted, so they can always raise a quota.
+pub(crate) fn record_call() -> Result<(), Error> {
+    if crate::env::is_controller(&crate::env::caller()) {
+        return Ok(());
+    }
+    ensure_call_quota()?;
+    let org_id = caller_org();
+    let today = time() / DAY_NS;
+    let calls = calls_on(org_id, today);
+    ORG_CALLS.with(|map| {
+        map.borrow_mut().insert(
+            org_id,
+            CallCount {
+                day: today,
+                calls: calls + 1,
+            },
+        )
+    });
+    Ok(())
+}
+
+// Whether the caller's organization may make one more update call today
+pub(crate) fn ensure_call_quota() -> Result<(), Error> {
+    if crate::env::is_controller(&crate::env::caller()) {
+        return Ok(());
+    }
+    let org_id = caller_org();
+    ensure_within(org_id, "max_calls_per_day", quotas(org_id).max_calls_per_day, calls_on(org_id, time() / DAY_NS))
+}
+
+fn calls_on(org_id: u64, day: u64) -> u64 {
+    ORG_CALLS
+        .with(|map| map.borrow().get(&org_id))
+        .filter(|count| count.day == day)
+        .map_or(0, |count| count.calls)
+}
+
+// One more unit on top of `used` must stay within `limit`
+fn ensure_within(org_id: u64, quota: &str, limit: Option<u64>, used: u64) -> Result<(), Error> {
+    match limit {
+        Some(limit) if used >= limit => Err(Error::quota_exceeded(org_id, quota, limit, used)),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,113 @@
+// A token bucket per caller, so one client cannot spend the canister's cycles on update
+// calls. A bucket holds up to `rate_limit_calls` calls and refills completely over
+// `rate_limit_window_secs` (see settings.rs); every update takes one call from it.
+// Controllers are not limited. A bucket idle for a whole window is full again, which is
+// the same as having none, so such buckets are dropped as other calls come in.
+use crate::settings;
+use crate::tenancy::MemberKey;
+use crate::{Error, RATE_LIMITS, RATE_LIMIT_EXPIRY};
+use candid::{Decode, Encode};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+const MILLI: u128 = 1_000; // Tokens are kept in thousandths of a call
+const PRUNE_BATCH: usize = 10; // Idle buckets dropped per call
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TokenBucket {
+    milli_tokens: u64,
+    updated_at: u64,
+}
+
+impl Storable for TokenBucket {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TokenBucket {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Takes one call from the caller's bucket
+pub(crate) fn take_call() -> Result<(), Error> {
+    let caller = crate::env::caller();
+    if crate::env::is_controller(&caller) {
+        return Ok(());
+    }
+    let now = time();
+    prune_idle(now);
+
+    let key = MemberKey(caller);
+    let previous = RATE_LIMITS.with(|buckets| buckets.borrow().get(&key));
+    let milli_tokens = available(previous.as_ref(), now)?;
+    RATE_LIMIT_EXPIRY.with(|expiry| {
+        let mut expiry = expiry.borrow_mut();
+        if let Some(previous) = &previous {
+            expiry.remove(&(previous.updated_at, key.clone()));
+        }
+        expiry.insert((now, key.clone()), ());
+    });
+    RATE_LIMITS.with(|buckets| {
+        buckets.borrow_mut().insert(
+            key,
+            TokenBucket {
+                milli_tokens: (milli_tokens - MILLI) as u64,
+                updated_at: now,
+            },
+        )
+    });
+    Ok(())
+}
+
+// Whether the caller could make one more update call now
+pub(crate) fn ensure_call_available() -> Result<(), Error> {
+    let caller = crate::env::caller();
+    if crate::env::is_controller(&caller) {
+        return Ok(());
+    }
+    let bucket = RATE_LIMITS.with(|buckets| buckets.borrow().get(&MemberKey(caller)));
+    available(bucket.as_ref(), time()).map(|_| ())
+}
+
+// Thousandths of a call in the bucket after refilling it up to `now`, if at least one call
+fn available(bucket: Option<&TokenBucket>, now: u64) -> Result<u128, Error> {
+    let (calls, window_ns) = settings::rate_limit();
+    let capacity = calls as u128 * MILLI;
+    let milli_tokens = match bucket {
+        Some(bucket) => {
+            let elapsed = now.saturating_sub(bucket.updated_at) as u128;
+            (bucket.milli_tokens as u128 + elapsed * capacity / window_ns as u128).min(capacity)
+        }
+        None => capacity,
+    };
+    if milli_tokens < MILLI {
+        let retry_after_ns = ((MILLI - milli_tokens) * window_ns as u128).div_ceil(capacity) as u64;
+        return Err(Error::rate_limited(calls, window_ns, retry_after_ns));
+    }
+    Ok(milli_tokens)
+}
+
+fn prune_idle(now: u64) {
+    let (_, window_ns) = settings::rate_limit();
+    let cutoff = now.saturating_sub(window_ns);
+    let idle: Vec<(u64, MemberKey)> = RATE_LIMIT_EXPIRY.with(|expiry| {
+        expiry
+            .borrow()
+            .iter()
+            .take(PRUNE_BATCH)
+            .take_while(|((updated_at, _), _)| *updated_at < cutoff)
+            .map(|(entry, _)| entry)
+            .collect()
+    });
+    for entry in idle {
+        RATE_LIMIT_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&entry));
+        RATE_LIMITS.with(|buckets| buckets.borrow_mut().remove(&entry.1));
+    }
+}
@@ -0,0 +1,99 @@
+// Suggested transfers between the warehouses of an organization. Each item is meant to hold
+// its reorder point plus what flowed out of it over the last COVER_DAYS days, the demand
+// expected over as many days ahead. Items of the same name (and unit) above that level
+// supply items below it, largest shortfall first and largest surplus first. Nothing is
+// moved: the suggestions are the arguments of transfer_items.
+use crate::batch::MAX_TRANSFERS;
+use crate::ids::{ItemId, WarehouseId};
+use crate::store::{name_key, NameKey};
+use crate::tenancy::caller_org;
+use crate::{counting, ledger, reservation, store, StockItem};
+use crate::env::time;
+use std::collections::BTreeMap;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const COVER_DAYS: u64 = 30;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RebalanceTransfer {
+    from_warehouse_id: WarehouseId,
+    to_warehouse_id: WarehouseId,
+    lines: Vec<(ItemId, u64)>, // Items of the source warehouse and the quantity to move
+}
+
+// Stock of one item above or below its level
+struct Imbalance {
+    item: StockItem,
+    quantity: u64,
+}
+
+// Over the listed warehouses of the caller's organization that are not being counted,
+// ordered by source and destination warehouse
+#[ic_cdk::query]
+fn get_rebalance_suggestions() -> Vec<RebalanceTransfer> {
+    let since = time().saturating_sub(COVER_DAYS * DAY_NS);
+    let mut by_name: BTreeMap<NameKey, Vec<(StockItem, u64)>> = BTreeMap::new();
+    for warehouse_id in store::listed_warehouse_ids(caller_org()) {
+        if counting::ensure_unfrozen(warehouse_id).is_err() {
+            continue;
+        }
+        let outflows = ledger::quantities_out(warehouse_id, since);
+        for item in store::items_in_warehouse(warehouse_id, None, usize::MAX) {
+            let level = item
+                .reorder_point
+                .unwrap_or(0)
+                .saturating_add(outflows.get(&item.item_id).copied().unwrap_or(0));
+            by_name.entry(name_key(&item.item_name)).or_default().push((item, level));
+        }
+    }
+
+    let mut moves: BTreeMap<(WarehouseId, WarehouseId), Vec<(ItemId, u64)>> = BTreeMap::new();
+    for items in by_name.into_values() {
+        let (mut surpluses, mut shortfalls) = (Vec::new(), Vec::new());
+        for (item, level) in items {
+            let available = reservation::available(&item);
+            let (list, quantity) = match available.cmp(&level) {
+                std::cmp::Ordering::Greater => (&mut surpluses, available - level),
+                std::cmp::Ordering::Less => (&mut shortfalls, level - available),
+                std::cmp::Ordering::Equal => continue,
+            };
+            list.push(Imbalance { item, quantity });
+        }
+        shortfalls.sort_by_key(|shortfall| (std::cmp::Reverse(shortfall.quantity), shortfall.item.warehouse_id));
+        surpluses.sort_by_key(|surplus| (std::cmp::Reverse(surplus.quantity), surplus.item.warehouse_id));
+
+        for shortfall in &mut shortfalls {
+            for surplus in surpluses.iter_mut().filter(|surplus| surplus.item.unit == shortfall.item.unit) {
+                if shortfall.quantity == 0 {
+                    break;
+                }
+                let quantity = surplus.quantity.min(shortfall.quantity);
+                if quantity == 0 {
+                    continue;
+                }
+                surplus.quantity -= quantity;
+                shortfall.quantity -= quantity;
+                // Names are unique within a warehouse, so the item is on no other line of the pair
+                moves
+                    .entry((surplus.item.warehouse_id, shortfall.item.warehouse_id))
+                    .or_default()
+                    .push((surplus.item.item_id, quantity));
+            }
+        }
+    }
+
+    // transfer_items takes at most MAX_TRANSFERS lines per call
+    moves
+        .into_iter()
+        .flat_map(|((from_warehouse_id, to_warehouse_id), lines)| {
+            lines
+                .chunks(MAX_TRANSFERS)
+                .map(|lines| RebalanceTransfer {
+                    from_warehouse_id,
+                    to_warehouse_id,
+                    lines: lines.to_vec(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
@@ -0,0 +1,65 @@
+// Reorder points: the quantity at or below which an item should be restocked. store.rs
+// keeps LOW_STOCK in step with every write, so the query reads only the items below their
+// point rather than every item.
+use crate::ids::{ItemId, WarehouseId};
+use crate::tenancy::caller_org;
+use crate::{idempotency, store, Error, MutationOptions, StockItem, LOW_STOCK, STOCK_STORAGE};
+use crate::{_check_version, _ensure_fits, _get_item, _get_warehouse};
+use crate::env::time;
+
+// Items at or below their reorder point, in one warehouse or in every warehouse of the
+// caller's organization, ordered by warehouse and item id
+#[ic_cdk::query]
+fn get_low_stock_items(warehouse_id: Option<WarehouseId>) -> Result<Vec<StockItem>, Error> {
+    let warehouse_ids = match warehouse_id {
+        Some(warehouse_id) => {
+            _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+            vec![warehouse_id]
+        }
+        None => store::warehouse_ids_in_org(caller_org()),
+    };
+    Ok(low_stock(&warehouse_ids))
+}
+
+// `expected_version` refers to the item; None removes the reorder point
+#[ic_cdk::update]
+fn set_reorder_point(item_id: ItemId, reorder_point: Option<u64>, options: Option<MutationOptions>) -> Result<StockItem, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("set_reorder_point", options, || _set_reorder_point(item_id, reorder_point, expected_version))
+}
+
+fn _set_reorder_point(item_id: ItemId, reorder_point: Option<u64>, expected_version: Option<u64>) -> Result<StockItem, Error> {
+    let mut item = _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    _check_version("item", item_id, item.version, expected_version)?;
+    if item.reorder_point == reorder_point {
+        return Ok(item);
+    }
+
+    item.reorder_point = reorder_point;
+    item.updated_at = Some(time());
+    item.updated_by = Some(crate::env::caller());
+    item.version += 1;
+    _ensure_fits("item", &item)?;
+
+    store::insert_item(item.clone());
+    Ok(item)
+}
+
+pub(crate) fn is_low(item: &StockItem) -> bool {
+    item.reorder_point.is_some_and(|point| item.quantity <= point)
+}
+
+// Also called by the alert timer, so it does not filter by the caller's organization
+pub(crate) fn low_stock(warehouse_ids: &[WarehouseId]) -> Vec<StockItem> {
+    let item_ids: Vec<ItemId> = LOW_STOCK.with(|index| {
+        let index = index.borrow();
+        warehouse_ids
+            .iter()
+            .flat_map(|id| index.range((*id, ItemId(0))..=(*id, ItemId(u64::MAX))).map(|((_, item_id), _)| item_id))
+            .collect()
+    });
+    STOCK_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        item_ids.iter().filter_map(|id| storage.get(id)).collect()
+    })
+}
@@ -0,0 +1,296 @@
+// Reports produced on a schedule. Admins and Owners, or controllers, schedule each kind of
+// report once per organization; a timer ticking every REPORT_TICK runs the jobs that are
+// due and stores what they produce, so the aggregation happens off the query path and a
+// report can be read back by id after an upgrade. A new schedule runs on the next tick.
+// The newest MAX_REPORTS reports of an organization are kept. The timer pauses in
+// maintenance mode, like the purge.
+use crate::organization::{self, Role};
+use crate::pricing::{self, Valuation};
+use crate::settings::page_size;
+use crate::tenancy::caller_org;
+use crate::totals::{org_totals, InventoryTotals};
+use crate::{idempotency, ledger, maintenance, reorder, store, Error, MutationOptions};
+use crate::{ORG_REPORTS, REPORTS, REPORT_SCHEDULES, REPORT_TIMER};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::time::Duration;
+
+const REPORT_TICK: Duration = Duration::from_secs(15 * 60);
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_REPORTS: usize = 100; // Per organization
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ReportKind {
+    DailySummary,
+    WeeklyValuation,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ReportSchedule {
+    id: u64,
+    org_id: u64,
+    kind: ReportKind,
+    next_run_at: u64,
+    last_report_id: Option<u64>,
+    created_at: u64,
+    created_by: Principal,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Report {
+    id: u64,
+    org_id: u64,
+    kind: ReportKind,
+    generated_at: u64,
+    data: ReportData,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum ReportData {
+    DailySummary(DailySummary),
+    WeeklyValuation(Valuation),
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct DailySummary {
+    totals: InventoryTotals,
+    low_stock_items: u64,
+    quantity_out: u128, // Outflows through stock_out over the last day, corrections left out
+}
+
+impl ReportKind {
+    fn period_ns(self) -> u64 {
+        match self {
+            ReportKind::DailySummary => DAY_NS,
+            ReportKind::WeeklyValuation => 7 * DAY_NS,
+        }
+    }
+}
+
+impl Storable for ReportSchedule {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ReportSchedule {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Report {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Room for a valuation in every ISO 4217 currency
+impl BoundedStorable for Report {
+    const MAX_SIZE: u32 = 16 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Schedules of the caller's organization, for its Admins and Owners
+#[ic_cdk::query]
+fn list_report_schedules() -> Result<Vec<ReportSchedule>, Error> {
+    let org_id = caller_org();
+    organization::ensure_role_or_controller(org_id, Role::Admin)?;
+    Ok(schedules_in_org(org_id))
+}
+
+#[ic_cdk::query]
+fn get_report(report_id: u64) -> Result<Report, Error> {
+    REPORTS
+        .with(|reports| reports.borrow().get(&report_id))
+        .filter(|report| report.org_id == caller_org())
+        .ok_or_else(|| Error::not_found("report", report_id))
+}
+
+// Reports of the caller's organization, newest first
+#[ic_cdk::query]
+fn list_reports(limit: Option<u64>) -> Result<Vec<Report>, Error> {
+    let limit = page_size(limit)?;
+    let mut report_ids = report_ids_in_org(caller_org());
+    report_ids.reverse();
+    report_ids.truncate(limit);
+    Ok(REPORTS.with(|reports| {
+        let reports = reports.borrow();
+        report_ids.iter().filter_map(|id| reports.get(id)).collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn schedule_report(kind: ReportKind, options: Option<MutationOptions>) -> Result<ReportSchedule, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("schedule_report", options, || _schedule_report(kind))
+}
+
+fn _schedule_report(kind: ReportKind) -> Result<ReportSchedule, Error> {
+    let org_id = caller_org();
+    organization::ensure_role_or_controller(org_id, Role::Admin)?;
+    if let Some(existing) = schedules_in_org(org_id).into_iter().find(|schedule| schedule.kind == kind) {
+        return Err(Error::conflict(
+            "report_schedule",
+            Some(existing.id),
+            "DUPLICATE_SCHEDULE",
+            format!("Schedule with id={} already produces {:?} reports", existing.id, kind),
+        ));
+    }
+
+    // Schedules are never re-numbered, so the next id follows the highest one in use
+    let id = REPORT_SCHEDULES.with(|schedules| schedules.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let now = time();
+    let schedule = ReportSchedule {
+        id,
+        org_id,
+        kind,
+        next_run_at: now,
+        last_report_id: None,
+        created_at: now,
+        created_by: crate::env::caller(),
+    };
+
+    REPORT_SCHEDULES.with(|schedules| schedules.borrow_mut().insert(id, schedule.clone()));
+    resume();
+    Ok(schedule)
+}
+
+// Reports already produced are kept
+#[ic_cdk::update]
+fn unschedule_report(id: u64, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("unschedule_report", options, || _unschedule_report(id))
+}
+
+fn _unschedule_report(id: u64) -> Result<(), Error> {
+    let schedule = REPORT_SCHEDULES
+        .with(|schedules| schedules.borrow().get(&id))
+        .filter(|schedule| schedule.org_id == caller_org())
+        .ok_or_else(|| Error::not_found("report_schedule", id))?;
+    organization::ensure_role_or_controller(schedule.org_id, Role::Admin)?;
+
+    REPORT_SCHEDULES.with(|schedules| schedules.borrow_mut().remove(&id));
+    Ok(())
+}
+
+// Removes the schedules and reports of an organization
+pub(crate) fn clear_org(org_id: u64) {
+    for schedule in schedules_in_org(org_id) {
+        REPORT_SCHEDULES.with(|schedules| schedules.borrow_mut().remove(&schedule.id));
+    }
+    for report_id in report_ids_in_org(org_id) {
+        remove_report(org_id, report_id);
+    }
+}
+
+// Timers do not survive upgrades; started again while there is a schedule
+pub(crate) fn resume() {
+    let idle = REPORT_TIMER.with(|timer| timer.borrow().is_none());
+    if idle && REPORT_SCHEDULES.with(|schedules| !schedules.borrow().is_empty()) {
+        let timer = ic_cdk_timers::set_timer_interval(REPORT_TICK, run_due);
+        REPORT_TIMER.with(|current| *current.borrow_mut() = Some(timer));
+    }
+}
+
+fn run_due() {
+    if REPORT_SCHEDULES.with(|schedules| schedules.borrow().is_empty()) {
+        if let Some(timer) = REPORT_TIMER.with(|timer| timer.borrow_mut().take()) {
+            ic_cdk_timers::clear_timer(timer);
+        }
+        return;
+    }
+    if maintenance::is_enabled() {
+        return;
+    }
+
+    let now = time();
+    let due: Vec<ReportSchedule> = REPORT_SCHEDULES.with(|schedules| {
+        schedules
+            .borrow()
+            .iter()
+            .map(|(_, schedule)| schedule)
+            .filter(|schedule| schedule.next_run_at <= now)
+            .collect()
+    });
+    for mut schedule in due {
+        let report_id = store_report(schedule.org_id, schedule.kind, now);
+        // A run missed during maintenance is not caught up
+        let period = schedule.kind.period_ns();
+        schedule.next_run_at = (schedule.next_run_at + period).max(now + period);
+        schedule.last_report_id = Some(report_id);
+        REPORT_SCHEDULES.with(|schedules| schedules.borrow_mut().insert(schedule.id, schedule));
+    }
+}
+
+fn store_report(org_id: u64, kind: ReportKind, now: u64) -> u64 {
+    let warehouse_ids = store::warehouse_ids_in_org(org_id);
+    let data = match kind {
+        ReportKind::DailySummary => {
+            let since = now.saturating_sub(DAY_NS);
+            ReportData::DailySummary(DailySummary {
+                totals: org_totals(org_id),
+                low_stock_items: reorder::low_stock(&warehouse_ids).len() as u64,
+                quantity_out: warehouse_ids
+                    .iter()
+                    .flat_map(|id| ledger::quantities_out(*id, since).into_values())
+                    .map(u128::from)
+                    .sum(),
+            })
+        }
+        ReportKind::WeeklyValuation => ReportData::WeeklyValuation(pricing::valuation(&warehouse_ids)),
+    };
+
+    let id = REPORTS.with(|reports| reports.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let report = Report {
+        id,
+        org_id,
+        kind,
+        generated_at: now,
+        data,
+    };
+    REPORTS.with(|reports| reports.borrow_mut().insert(id, report));
+    ORG_REPORTS.with(|index| index.borrow_mut().insert((org_id, id), ()));
+
+    let report_ids = report_ids_in_org(org_id);
+    for old_id in report_ids.iter().take(report_ids.len().saturating_sub(MAX_REPORTS)) {
+        remove_report(org_id, *old_id);
+    }
+    id
+}
+
+fn remove_report(org_id: u64, report_id: u64) {
+    REPORTS.with(|reports| reports.borrow_mut().remove(&report_id));
+    ORG_REPORTS.with(|index| index.borrow_mut().remove(&(org_id, report_id)));
+}
+
+fn schedules_in_org(org_id: u64) -> Vec<ReportSchedule> {
+    REPORT_SCHEDULES.with(|schedules| {
+        schedules
+            .borrow()
+            .iter()
+            .map(|(_, schedule)| schedule)
+            .filter(|schedule| schedule.org_id == org_id)
+            .collect()
+    })
+}
+
+// Oldest first
+fn report_ids_in_org(org_id: u64) -> Vec<u64> {
+    ORG_REPORTS.with(|index| {
+        index
+            .borrow()
+            .range((org_id, 0)..=(org_id, u64::MAX))
+            .map(|((_, report_id), _)| report_id)
+            .collect()
+    })
+}
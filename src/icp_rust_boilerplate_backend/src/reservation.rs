@@ -0,0 +1,335 @@
+// Stock held for an order without taking it out of the warehouse yet. A reservation stays
+// active until it is committed, which removes the stock like delete_item, or released. One
+// made through reserve_stock is also released once reservation_ttl_secs (settings.rs) have
+// passed, by a timer that looks every EXPIRY_INTERVAL; sales orders hold theirs until shipped
+// or cancelled.
+// The reserved part of an item cannot be removed or transferred by anything else; counts
+// still correct the quantity, so a commit can fail after a count found less stock.
+use crate::ids::{ItemId, WarehouseId};
+use crate::tenancy::caller_org;
+use crate::{counting, idempotency, maintenance, settings, store, validation, Error, MutationOptions, StockItem};
+use crate::{ITEM_RESERVATIONS, RESERVATIONS, RESERVATION_EXPIRY, RESERVATION_TIMER, RESERVED_QUANTITIES};
+use crate::{_ensure_fits, _get_item, _remove_quantity};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::time::Duration;
+
+const MAX_REFERENCE_LENGTH: usize = 100; // In characters
+const MAX_ACTIVE_RESERVATIONS: usize = 1_000; // Per item
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+const EXPIRY_BATCH: usize = 500; // Reservations released per tick
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ReservationStatus {
+    Active,
+    Committed,
+    Released, // By the caller, on expiry, or because the item was removed
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Reservation {
+    pub(crate) id: u64,
+    org_id: u64,
+    item_id: ItemId,
+    warehouse_id: WarehouseId,
+    quantity: u64,
+    reference: String, // The caller's order number or similar
+    status: ReservationStatus,
+    created_at: u64,
+    created_by: Principal,
+    closed_at: Option<u64>,
+    closed_by: Option<Principal>,
+    expires_at: Option<u64>, // None for reservations held by a sales order
+}
+
+impl Storable for Reservation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Reservation {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_reservation(reservation_id: u64) -> Result<Reservation, Error> {
+    reservation(reservation_id).ok_or_else(|| Error::not_found("reservation", reservation_id))
+}
+
+// Active reservations of an item, oldest first
+#[ic_cdk::query]
+fn get_item_reservations(item_id: ItemId) -> Result<Vec<Reservation>, Error> {
+    _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    Ok(active_reservations(item_id))
+}
+
+// The quantity minus what active reservations hold
+#[ic_cdk::query]
+fn get_available_quantity(item_id: ItemId) -> Result<u64, Error> {
+    let item = _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    Ok(available(&item))
+}
+
+#[ic_cdk::update]
+fn reserve_stock(item_id: ItemId, quantity: u64, reference: String, options: Option<MutationOptions>) -> Result<Reservation, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("reserve_stock", options, || {
+        let expires_at = time().saturating_add(settings::reservation_ttl_ns());
+        reserve(item_id, quantity, reference, Some(expires_at))
+    })
+}
+
+#[ic_cdk::update]
+fn release_reservation(reservation_id: u64, options: Option<MutationOptions>) -> Result<Reservation, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("release_reservation", options, || release(reservation_id))
+}
+
+// Removes the reserved stock from the item; returns the item as left
+#[ic_cdk::update]
+fn commit_reservation(reservation_id: u64, options: Option<MutationOptions>) -> Result<StockItem, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("commit_reservation", options, || commit(reservation_id))
+}
+
+pub(crate) fn reserve(item_id: ItemId, quantity: u64, reference: String, expires_at: Option<u64>) -> Result<Reservation, Error> {
+    let reference = validate_reservation(quantity, &reference)?;
+    let item = ensure_reservable(item_id, quantity)?;
+
+    let id = RESERVATIONS.with(|reservations| reservations.borrow().last_key_value().map_or(1, |(id, _)| id + 1));
+    let reservation = Reservation {
+        id,
+        org_id: item.org_id,
+        item_id,
+        warehouse_id: item.warehouse_id,
+        quantity,
+        reference,
+        status: ReservationStatus::Active,
+        created_at: time(),
+        created_by: crate::env::caller(),
+        closed_at: None,
+        closed_by: None,
+        expires_at,
+    };
+    _ensure_fits("reservation", &reservation)?;
+
+    RESERVATIONS.with(|reservations| reservations.borrow_mut().insert(id, reservation.clone()));
+    ITEM_RESERVATIONS.with(|index| index.borrow_mut().insert((item_id, id), ()));
+    RESERVED_QUANTITIES.with(|reserved| {
+        let mut reserved = reserved.borrow_mut();
+        let total = reserved.get(&item_id).unwrap_or(0) + quantity;
+        reserved.insert(item_id, total);
+    });
+    if let Some(expires_at) = expires_at {
+        RESERVATION_EXPIRY.with(|expiry| expiry.borrow_mut().insert((expires_at, id), ()));
+        resume();
+    }
+    Ok(reservation)
+}
+
+pub(crate) fn release(reservation_id: u64) -> Result<Reservation, Error> {
+    let reservation = active(reservation_id)?;
+    Ok(close(reservation, ReservationStatus::Released))
+}
+
+pub(crate) fn commit(reservation_id: u64) -> Result<StockItem, Error> {
+    let (reservation, item) = plan_commit(reservation_id)?;
+    close(reservation, ReservationStatus::Committed);
+    if item.quantity == 0 {
+        store::remove_item(item.item_id);
+    } else {
+        store::insert_item(item.clone());
+    }
+    Ok(item)
+}
+
+// The item, if `quantity` of it can be reserved now
+pub(crate) fn ensure_reservable(item_id: ItemId, quantity: u64) -> Result<StockItem, Error> {
+    let item = _get_item(&item_id).ok_or_else(|| Error::not_found("item", item_id))?;
+    ensure_available(&item, quantity)?;
+    let active = active_reservations(item_id).len();
+    if active >= MAX_ACTIVE_RESERVATIONS {
+        return Err(Error::capacity_exceeded(
+            "TOO_MANY_ENTRIES",
+            MAX_ACTIVE_RESERVATIONS as u64,
+            active as u64 + 1,
+            format!("An item has at most {} active reservations", MAX_ACTIVE_RESERVATIONS),
+        ));
+    }
+    Ok(item)
+}
+
+// Checks that the reservation can be committed; returns it with the item as it will be left
+pub(crate) fn plan_commit(reservation_id: u64) -> Result<(Reservation, StockItem), Error> {
+    let reservation = active(reservation_id)?;
+    let mut item = _get_item(&reservation.item_id).ok_or_else(|| Error::not_found("item", reservation.item_id))?;
+    counting::ensure_unfrozen(item.warehouse_id)?;
+
+    item.quantity = _remove_quantity(item.item_id, item.quantity, reservation.quantity)?;
+    item.updated_at = Some(time());
+    item.updated_by = Some(crate::env::caller());
+    item.version += 1;
+    Ok((reservation, item))
+}
+
+// Fails with NotEnoughStock unless `quantity` can be taken without touching reserved stock
+pub(crate) fn ensure_available(item: &StockItem, quantity: u64) -> Result<(), Error> {
+    let available = available(item);
+    if quantity > available {
+        return Err(Error::not_enough_stock(item.item_id, available, quantity));
+    }
+    Ok(())
+}
+
+pub(crate) fn available(item: &StockItem) -> u64 {
+    item.quantity.saturating_sub(reserved(item.item_id))
+}
+
+// Releases the active reservations of an item that is being removed
+pub(crate) fn clear_item(item_id: ItemId) {
+    for reservation in active_reservations(item_id) {
+        close(reservation, ReservationStatus::Released);
+    }
+}
+
+// Removes the closed reservations of an organization whose items are all gone
+pub(crate) fn clear_org(org_id: u64) {
+    let ids: Vec<u64> = RESERVATIONS.with(|reservations| {
+        reservations
+            .borrow()
+            .iter()
+            .filter(|(_, reservation)| reservation.org_id == org_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    RESERVATIONS.with(|reservations| {
+        let mut reservations = reservations.borrow_mut();
+        for id in ids {
+            reservations.remove(&id);
+        }
+    });
+}
+
+// Timers do not survive upgrades; started again while a reservation can expire
+pub(crate) fn resume() {
+    let idle = RESERVATION_TIMER.with(|timer| timer.borrow().is_none());
+    if idle && RESERVATION_EXPIRY.with(|expiry| !expiry.borrow().is_empty()) {
+        let timer = ic_cdk_timers::set_timer_interval(EXPIRY_INTERVAL, release_expired);
+        RESERVATION_TIMER.with(|current| *current.borrow_mut() = Some(timer));
+    }
+}
+
+fn release_expired() {
+    if RESERVATION_EXPIRY.with(|expiry| expiry.borrow().is_empty()) {
+        if let Some(timer) = RESERVATION_TIMER.with(|timer| timer.borrow_mut().take()) {
+            ic_cdk_timers::clear_timer(timer);
+        }
+        return;
+    }
+    if maintenance::is_enabled() {
+        return;
+    }
+
+    let now = time();
+    let expired: Vec<u64> = RESERVATION_EXPIRY.with(|expiry| {
+        expiry
+            .borrow()
+            .iter()
+            .take(EXPIRY_BATCH)
+            .take_while(|((expires_at, _), _)| *expires_at <= now)
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    for id in expired {
+        // Not through reservation(): the timer belongs to no organization
+        if let Some(reservation) = RESERVATIONS.with(|reservations| reservations.borrow().get(&id)) {
+            close(reservation, ReservationStatus::Released);
+        }
+    }
+}
+
+// Returns the trimmed reference
+pub(crate) fn validate_reservation(quantity: u64, reference: &str) -> Result<String, Error> {
+    validation::validate_quantity("quantity", quantity)?;
+    let reference = reference.trim();
+    if reference.is_empty() {
+        return Err(Error::invalid_input("reference", "EMPTY_VALUE", "reference must not be empty".to_string()));
+    }
+    let length = reference.chars().count();
+    if length > MAX_REFERENCE_LENGTH {
+        return Err(Error::invalid_input(
+            "reference",
+            "VALUE_TOO_LONG",
+            format!("reference must be at most {} characters, got {}", MAX_REFERENCE_LENGTH, length),
+        ));
+    }
+    Ok(reference.to_string())
+}
+
+fn close(mut reservation: Reservation, status: ReservationStatus) -> Reservation {
+    reservation.status = status;
+    reservation.closed_at = Some(time());
+    reservation.closed_by = Some(crate::env::caller());
+    RESERVATIONS.with(|reservations| reservations.borrow_mut().insert(reservation.id, reservation.clone()));
+    ITEM_RESERVATIONS.with(|index| index.borrow_mut().remove(&(reservation.item_id, reservation.id)));
+    if let Some(expires_at) = reservation.expires_at {
+        RESERVATION_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&(expires_at, reservation.id)));
+    }
+    RESERVED_QUANTITIES.with(|reserved| {
+        let mut reserved = reserved.borrow_mut();
+        let total = reserved.get(&reservation.item_id).unwrap_or(0).saturating_sub(reservation.quantity);
+        if total == 0 {
+            reserved.remove(&reservation.item_id);
+        } else {
+            reserved.insert(reservation.item_id, total);
+        }
+    });
+    reservation
+}
+
+fn active(reservation_id: u64) -> Result<Reservation, Error> {
+    let reservation = reservation(reservation_id).ok_or_else(|| Error::not_found("reservation", reservation_id))?;
+    if reservation.status != ReservationStatus::Active {
+        return Err(Error::conflict(
+            "reservation",
+            Some(reservation_id),
+            "RESERVATION_CLOSED",
+            format!("Reservation with id={} is already {:?}", reservation_id, reservation.status),
+        ));
+    }
+    Ok(reservation)
+}
+
+// A reservation of the caller's organization
+fn reservation(reservation_id: u64) -> Option<Reservation> {
+    RESERVATIONS
+        .with(|reservations| reservations.borrow().get(&reservation_id))
+        .filter(|reservation| reservation.org_id == caller_org())
+}
+
+fn reserved(item_id: ItemId) -> u64 {
+    RESERVED_QUANTITIES.with(|reserved| reserved.borrow().get(&item_id).unwrap_or(0))
+}
+
+// Only active reservations are in ITEM_RESERVATIONS
+fn active_reservations(item_id: ItemId) -> Vec<Reservation> {
+    let ids: Vec<u64> = ITEM_RESERVATIONS.with(|index| {
+        index
+            .borrow()
+            .range((item_id, 0)..=(item_id, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    RESERVATIONS.with(|reservations| {
+        let reservations = reservations.borrow();
+        ids.iter().filter_map(|id| reservations.get(id)).collect()
+    })
+}
@@ -0,0 +1,266 @@
+// Sales orders for stock going out to a customer. A pending order names items and
+// quantities; picking reserves every line at once, so the stock stays in the warehouse
+// but nothing else can take it; shipping commits the reservations, which removes the
+// stock. An order is cancelled before it ships, which releases whatever it reserved.
+use crate::ids::ItemId;
+use crate::tenancy::caller_org;
+use crate::{idempotency, reservation, validation, Error, MutationOptions, SALES_ORDERS};
+use crate::{_check_version, _ensure_fits, _get_item};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+pub(crate) const MAX_LINES: usize = 50; // Per order
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) enum SalesOrderStatus {
+    Pending,
+    Picked,
+    Shipped,
+    Cancelled,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SalesOrderPayload {
+    customer: String,
+    lines: Vec<OrderLine>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct OrderLine {
+    item_id: ItemId,
+    quantity: u64, // In the unit the item is stored in
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SalesOrderLine {
+    item_id: ItemId,
+    quantity: u64,
+    reservation_id: Option<u64>, // Set when the order is picked
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SalesOrder {
+    id: u64,
+    org_id: u64,
+    customer: String,
+    lines: Vec<SalesOrderLine>,
+    status: SalesOrderStatus,
+    version: u64,
+    created_at: u64,
+    created_by: Principal,
+    updated_at: Option<u64>,
+    updated_by: Option<Principal>,
+}
+
+impl Storable for SalesOrder {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SalesOrder {
+    const MAX_SIZE: u32 = 4096; // MAX_LINES lines and a customer of MAX_NAME_LENGTH
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_sales_order(id: u64) -> Result<SalesOrder, Error> {
+    sales_order(id).ok_or_else(|| Error::not_found("sales_order", id))
+}
+
+// Orders of the caller's organization in id order, optionally only those in `status`
+#[ic_cdk::query]
+fn list_sales_orders(status: Option<SalesOrderStatus>) -> Vec<SalesOrder> {
+    let org_id = caller_org();
+    SALES_ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| order.org_id == org_id && status.is_none_or(|status| order.status == status))
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn create_sales_order(payload: SalesOrderPayload, options: Option<MutationOptions>) -> Result<SalesOrder, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("create_sales_order", options, || _create_sales_order(payload))
+}
+
+fn _create_sales_order(payload: SalesOrderPayload) -> Result<SalesOrder, Error> {
+    let payload = validate_payload(payload)?;
+    for line in &payload.lines {
+        _get_item(&line.item_id).ok_or_else(|| Error::not_found("item", line.item_id))?;
+    }
+
+    // Orders are never re-numbered, so the next id follows the highest one in use
+    let id = SALES_ORDERS.with(|orders| orders.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let order = SalesOrder {
+        id,
+        org_id: caller_org(),
+        customer: payload.customer,
+        lines: payload
+            .lines
+            .into_iter()
+            .map(|line| SalesOrderLine {
+                item_id: line.item_id,
+                quantity: line.quantity,
+                reservation_id: None,
+            })
+            .collect(),
+        status: SalesOrderStatus::Pending,
+        version: 1,
+        created_at: time(),
+        created_by: crate::env::caller(),
+        updated_at: None,
+        updated_by: None,
+    };
+    _ensure_fits("sales_order", &order)?;
+
+    SALES_ORDERS.with(|orders| orders.borrow_mut().insert(id, order.clone()));
+    Ok(order)
+}
+
+// Reserves the stock of every line, or of none when one line cannot be reserved
+#[ic_cdk::update]
+fn pick_sales_order(id: u64, options: Option<MutationOptions>) -> Result<SalesOrder, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("pick_sales_order", options, || _pick_sales_order(id, expected_version))
+}
+
+fn _pick_sales_order(id: u64, expected_version: Option<u64>) -> Result<SalesOrder, Error> {
+    let mut order = in_status(id, &[SalesOrderStatus::Pending])?;
+    _check_version("sales_order", id, order.version, expected_version)?;
+    for line in &order.lines {
+        reservation::ensure_reservable(line.item_id, line.quantity)?;
+    }
+
+    let reference = format!("sales order {}", id);
+    for line in order.lines.iter_mut() {
+        let reserved = reservation::reserve(line.item_id, line.quantity, reference.clone(), None)?;
+        line.reservation_id = Some(reserved.id);
+    }
+    save(&mut order, SalesOrderStatus::Picked);
+    Ok(order)
+}
+
+// Removes the reserved stock of every line from the warehouses
+#[ic_cdk::update]
+fn ship_sales_order(id: u64, options: Option<MutationOptions>) -> Result<SalesOrder, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("ship_sales_order", options, || _ship_sales_order(id, expected_version))
+}
+
+fn _ship_sales_order(id: u64, expected_version: Option<u64>) -> Result<SalesOrder, Error> {
+    let mut order = in_status(id, &[SalesOrderStatus::Picked])?;
+    _check_version("sales_order", id, order.version, expected_version)?;
+    // A reservation is gone if its item was removed, and a count may have found less stock
+    let reservation_ids: Vec<u64> = order.lines.iter().filter_map(|line| line.reservation_id).collect();
+    for reservation_id in &reservation_ids {
+        reservation::plan_commit(*reservation_id)?;
+    }
+
+    for reservation_id in reservation_ids {
+        reservation::commit(reservation_id)?;
+    }
+    save(&mut order, SalesOrderStatus::Shipped);
+    Ok(order)
+}
+
+// Releases what a picked order reserved
+#[ic_cdk::update]
+fn cancel_sales_order(id: u64, options: Option<MutationOptions>) -> Result<SalesOrder, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("cancel_sales_order", options, || _cancel_sales_order(id, expected_version))
+}
+
+fn _cancel_sales_order(id: u64, expected_version: Option<u64>) -> Result<SalesOrder, Error> {
+    let mut order = in_status(id, &[SalesOrderStatus::Pending, SalesOrderStatus::Picked])?;
+    _check_version("sales_order", id, order.version, expected_version)?;
+
+    for reservation_id in order.lines.iter().filter_map(|line| line.reservation_id) {
+        // Already released if the item was removed in the meantime
+        reservation::release(reservation_id).ok();
+    }
+    save(&mut order, SalesOrderStatus::Cancelled);
+    Ok(order)
+}
+
+// Normalizes the customer name; an item is on at most one line
+pub(crate) fn validate_payload(mut payload: SalesOrderPayload) -> Result<SalesOrderPayload, Error> {
+    payload.customer = validation::validate_name("customer", &payload.customer)?;
+    if payload.lines.is_empty() || payload.lines.len() > MAX_LINES {
+        return Err(Error::invalid_input(
+            "lines",
+            "VALUE_OUT_OF_RANGE",
+            format!("lines must have 1 to {} entries, got {}", MAX_LINES, payload.lines.len()),
+        ));
+    }
+
+    let mut item_ids = BTreeSet::new();
+    for line in &payload.lines {
+        validation::validate_quantity("quantity", line.quantity)?;
+        if !item_ids.insert(line.item_id) {
+            return Err(Error::invalid_input(
+                "lines",
+                "DUPLICATE_LINE",
+                format!("Item with id={} is on more than one line", line.item_id),
+            ));
+        }
+    }
+    Ok(payload)
+}
+
+// Removes the sales orders of an organization whose items are all gone
+pub(crate) fn clear_org(org_id: u64) {
+    let ids: Vec<u64> = SALES_ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .iter()
+            .filter(|(_, order)| order.org_id == org_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    SALES_ORDERS.with(|orders| {
+        let mut orders = orders.borrow_mut();
+        for id in ids {
+            orders.remove(&id);
+        }
+    });
+}
+
+fn save(order: &mut SalesOrder, status: SalesOrderStatus) {
+    order.status = status;
+    order.updated_at = Some(time());
+    order.updated_by = Some(crate::env::caller());
+    order.version += 1;
+    SALES_ORDERS.with(|orders| orders.borrow_mut().insert(order.id, order.clone()));
+}
+
+fn in_status(id: u64, allowed: &[SalesOrderStatus]) -> Result<SalesOrder, Error> {
+    let order = sales_order(id).ok_or_else(|| Error::not_found("sales_order", id))?;
+    if !allowed.contains(&order.status) {
+        return Err(Error::conflict(
+            "sales_order",
+            Some(id),
+            "INVALID_STATUS",
+            format!("Sales order with id={} is {:?}", id, order.status),
+        ));
+    }
+    Ok(order)
+}
+
+// A sales order of the caller's organization
+fn sales_order(id: u64) -> Option<SalesOrder> {
+    SALES_ORDERS
+        .with(|orders| orders.borrow().get(&id))
+        .filter(|order| order.org_id == caller_org())
+}
@@ -0,0 +1,208 @@
+// Name search served from heap-resident indexes. The stable maps stay the source of
+// truth: the heap indexes are rebuilt from them in post_upgrade and then kept current
+// by store.rs, so they never need to be persisted. Warehouses are found by name prefix;
+// items by the words they contain, through an index of the grams of each word of a name.
+use crate::ids::{ItemId, WarehouseId};
+use crate::store::{self, name_key, NameKey};
+use crate::settings::page_size;
+use crate::tenancy::caller_org;
+use crate::validation::MAX_NAME_LENGTH;
+use crate::{Error, StockItem, Warehouse, ITEM_TEXT, STOCK_STORAGE, WAREHOUSE_SEARCH, WAREHOUSE_STORAGE};
+use std::collections::BTreeSet;
+
+const GRAM_LENGTH: usize = 3;
+
+// Warehouses of the caller's organization whose name starts with `prefix`, ignoring case and spacing, in name order
+#[ic_cdk::query]
+fn search_warehouses(prefix: String, limit: Option<u64>) -> Result<Vec<Warehouse>, Error> {
+    let ids = WAREHOUSE_SEARCH.with(|index| index.borrow().ids_with_prefix(caller_org(), &prefix, limit))?;
+    Ok(WAREHOUSE_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        ids.iter().filter_map(|id| storage.get(id)).collect()
+    }))
+}
+
+// Stock items in any listed warehouse of the caller's organization whose name contains every
+// word of `query`, each within one word of the name, ignoring case: "wid 30" finds "Widget
+// 3000". In id order. The search stops at `limit` matches; when every word is at most
+// GRAM_LENGTH characters, those are the first found in the index, not the lowest ids.
+#[ic_cdk::query]
+fn search_items(query: String, limit: Option<u64>) -> Result<Vec<StockItem>, Error> {
+    let limit = page_size(limit)?;
+    let length = query.chars().count();
+    if length > MAX_NAME_LENGTH {
+        return Err(Error::invalid_input(
+            "query",
+            "VALUE_TOO_LONG",
+            format!("query must be at most {} characters, got {}", MAX_NAME_LENGTH, length),
+        ));
+    }
+    let query_words = words(name_key(&query).as_str());
+    if query_words.is_empty() {
+        return Err(Error::invalid_input(
+            "query",
+            "MISSING_VALUE",
+            "query must contain a letter or a digit".to_string(),
+        ));
+    }
+
+    let org_id = caller_org();
+    let listed: BTreeSet<WarehouseId> = store::listed_warehouse_ids(org_id).into_iter().collect();
+    // The longest word has the fewest candidates; the others are checked by lookup. Sharing
+    // grams does not make a substring, so every candidate is checked against its name.
+    let driving = query_words.iter().max_by_key(|word| word.len()).unwrap();
+    let mut hits: Vec<StockItem> = ITEM_TEXT.with(|index| {
+        let index = index.borrow();
+        STOCK_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            let mut seen = BTreeSet::new();
+            index
+                .candidates(org_id, driving)
+                .filter(|id| seen.insert(*id))
+                .filter(|id| query_words.iter().all(|word| index.has_grams(org_id, word, *id)))
+                .filter_map(|id| storage.get(&id))
+                .filter(|item| listed.contains(&item.warehouse_id))
+                .filter(|item| {
+                    let name = words(name_key(&item.item_name).as_str());
+                    query_words.iter().all(|word| name.iter().any(|part| part.windows(word.len()).any(|window| window == word)))
+                })
+                .take(limit)
+                .collect()
+        })
+    });
+    hits.sort_unstable_by_key(|item| item.item_id);
+    Ok(hits)
+}
+
+// ((org id, name key), id); a BTreeSet keeps an organization's names with a given prefix adjacent
+pub(crate) type SearchKey = (u64, NameKey);
+
+// For warehouses or for stock items, by the type of their ids
+#[derive(Default)]
+pub(crate) struct NameSearch<Id>(BTreeSet<(SearchKey, Id)>);
+
+impl<Id: Copy + Default + Ord> NameSearch<Id> {
+    fn ids_with_prefix(&self, org_id: u64, prefix: &str, limit: Option<u64>) -> Result<Vec<Id>, Error> {
+        let limit = page_size(limit)?;
+        let prefix = name_key(prefix);
+        Ok(self
+            .0
+            .range(((org_id, prefix.clone()), Id::default())..)
+            .take_while(|((org, key), _)| *org == org_id && key.starts_with(&prefix))
+            .map(|(_, id)| *id)
+            .take(limit)
+            .collect())
+    }
+}
+
+pub(crate) fn replace_warehouse(old: Option<SearchKey>, new: SearchKey, id: WarehouseId) {
+    WAREHOUSE_SEARCH.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(old) = old {
+            index.0.remove(&(old, id));
+        }
+        index.0.insert((new, id));
+    });
+}
+
+pub(crate) fn remove_warehouse(key: SearchKey, id: WarehouseId) {
+    WAREHOUSE_SEARCH.with(|index| index.borrow_mut().0.remove(&(key, id)));
+}
+
+// (org id, gram, item id); a gram is the up to GRAM_LENGTH characters of a word of the name
+// from one of its positions, padded with '\0', so a shorter gram is a prefix of a longer one
+type Gram = [char; GRAM_LENGTH];
+
+#[derive(Default)]
+pub(crate) struct TextSearch(BTreeSet<(u64, Gram, ItemId)>);
+
+impl TextSearch {
+    // Items with a gram that begins with the first GRAM_LENGTH characters of `word`: a
+    // superset of the items with a word containing it. A longer word names a single gram,
+    // whose items come in id order; a shorter one's come gram by gram, and can repeat.
+    fn candidates<'a>(&'a self, org_id: u64, word: &[char]) -> impl Iterator<Item = ItemId> + 'a {
+        let prefix = &word[..word.len().min(GRAM_LENGTH)];
+        let (mut low, mut high) = (['\0'; GRAM_LENGTH], [char::MAX; GRAM_LENGTH]);
+        low[..prefix.len()].copy_from_slice(prefix);
+        high[..prefix.len()].copy_from_slice(prefix);
+        self.0
+            .range((org_id, low, ItemId(0))..=(org_id, high, ItemId(u64::MAX)))
+            .map(|(_, _, id)| *id)
+    }
+
+    // Whether the item has every gram of `word`; a word no longer than a gram is left to the
+    // check against the name
+    fn has_grams(&self, org_id: u64, word: &[char], id: ItemId) -> bool {
+        word.len() <= GRAM_LENGTH
+            || word
+                .windows(GRAM_LENGTH)
+                .all(|window| self.0.contains(&(org_id, window.try_into().unwrap(), id)))
+    }
+
+    fn insert(&mut self, (org_id, key): &SearchKey, id: ItemId) {
+        for gram in grams(key) {
+            self.0.insert((*org_id, gram, id));
+        }
+    }
+
+    fn remove(&mut self, (org_id, key): &SearchKey, id: ItemId) {
+        for gram in grams(key) {
+            self.0.remove(&(*org_id, gram, id));
+        }
+    }
+}
+
+// Runs of letters and digits
+fn words(text: &str) -> Vec<Vec<char>> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.chars().collect())
+        .collect()
+}
+
+fn grams(key: &NameKey) -> Vec<Gram> {
+    let mut grams = Vec::new();
+    for word in words(key.as_str()) {
+        for start in 0..word.len() {
+            let mut gram = ['\0'; GRAM_LENGTH];
+            let end = (start + GRAM_LENGTH).min(word.len());
+            gram[..end - start].copy_from_slice(&word[start..end]);
+            grams.push(gram);
+        }
+    }
+    grams
+}
+
+pub(crate) fn replace_item(old: Option<SearchKey>, new: SearchKey, id: ItemId) {
+    ITEM_TEXT.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(old) = old {
+            index.remove(&old, id);
+        }
+        index.insert(&new, id);
+    });
+}
+
+pub(crate) fn remove_item(key: SearchKey, id: ItemId) {
+    ITEM_TEXT.with(|index| index.borrow_mut().remove(&key, id));
+}
+
+// Heap state is lost on upgrade, so this runs in post_upgrade
+pub(crate) fn rebuild() {
+    let warehouses = WAREHOUSE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(id, warehouse)| ((warehouse.org_id, name_key(&warehouse.name)), id))
+            .collect()
+    });
+    WAREHOUSE_SEARCH.with(|index| *index.borrow_mut() = NameSearch(warehouses));
+
+    let mut items = TextSearch::default();
+    STOCK_STORAGE.with(|storage| {
+        for (id, item) in storage.borrow().iter() {
+            items.insert(&(item.org_id, name_key(&item.item_name)), id);
+        }
+    });
+    ITEM_TEXT.with(|index| *index.borrow_mut() = items);
+}
@@ -0,0 +1,199 @@
+// Operational tunables that controllers can change without an upgrade. Every value is
+// checked against its range on update, so the code reading them can rely on it.
+use crate::validation::MAX_NAME_LENGTH;
+use crate::{alerts, idempotency, Error, MutationOptions, _ensure_admin, SETTINGS};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::time::Duration;
+
+const MAX_PAGE_SIZE: u64 = 1_000;
+const MAX_RETENTION_HOURS: u64 = 7 * 24;
+const MIN_INTERVAL_SECS: u64 = 60;
+const MAX_INTERVAL_SECS: u64 = 7 * 24 * 60 * 60;
+const MAX_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+const DEFAULT_ARG_BYTES: u64 = 16 * 1024;
+const MIN_ARG_BYTES: u64 = 1024;
+const MAX_ARG_BYTES: u64 = 2 * 1024 * 1024; // The ingress message limit
+const DEFAULT_RATE_LIMIT_CALLS: u64 = 100;
+const DEFAULT_RATE_LIMIT_WINDOW_SECS: u64 = 60;
+const MAX_RATE_LIMIT_CALLS: u64 = 100_000;
+const MAX_RATE_LIMIT_WINDOW_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_TRASH_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_WAREHOUSE_ITEMS: u64 = 10_000;
+const MAX_WAREHOUSE_ITEMS: u64 = 1_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Settings {
+    default_page_size: u64,             // Results per page when the caller does not ask for a size
+    max_page_size: u64,                 // Largest page a caller may ask for
+    idempotency_retention_hours: u64,   // How long an idempotency key is remembered
+    low_stock_alert_interval_secs: u64, // Time between low-stock scans
+    reservation_ttl_secs: u64,          // Lifetime of a reserve_stock reservation; sales orders hold theirs
+    max_arg_bytes: Option<u64>,         // Largest ingress arguments, see inspect.rs; None is 16 KiB
+    rate_limit_calls: Option<u64>,      // Update calls per caller and window, see ratelimit.rs; None is 100
+    rate_limit_window_secs: Option<u64>, // None is 60
+    trash_ttl_secs: Option<u64>,        // How long restore_item can bring back a deleted item; None is 7 days
+    max_name_length: Option<u64>,       // Longest name in characters, at most 100; None is 100
+    default_reorder_point: Option<u64>, // Reorder point of newly created items, see reorder.rs; None sets none
+    max_items_per_warehouse: Option<u64>, // Distinct items a warehouse can hold; None is 10,000
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            default_page_size: 20,
+            max_page_size: 100,
+            idempotency_retention_hours: 24,
+            low_stock_alert_interval_secs: 60 * 60,
+            reservation_ttl_secs: 15 * 60,
+            max_arg_bytes: None,
+            rate_limit_calls: None,
+            rate_limit_window_secs: None,
+            trash_ttl_secs: None,
+            max_name_length: None,
+            default_reorder_point: None,
+            max_items_per_warehouse: None,
+        }
+    }
+}
+
+impl Storable for Settings {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Settings {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_settings() -> Result<Settings, Error> {
+    let caller = crate::env::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::unauthorized(caller, "Anonymous callers cannot read settings".to_string()));
+    }
+    Ok(settings())
+}
+
+// Replaces every setting at once; read them first to change a single value
+#[ic_cdk::update]
+fn update_settings(settings: Settings, options: Option<MutationOptions>) -> Result<Settings, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("update_settings", options, || {
+        _ensure_admin()?;
+        validate_settings(&settings)?;
+        let previous = SETTINGS.with(|cell| cell.borrow_mut().set(settings.clone())).unwrap();
+        if previous.low_stock_alert_interval_secs != settings.low_stock_alert_interval_secs {
+            alerts::restart();
+        }
+        Ok(settings)
+    })
+}
+
+pub(crate) fn settings() -> Settings {
+    SETTINGS.with(|cell| cell.borrow().get().clone())
+}
+
+// The number of results to return for a requested page size
+pub(crate) fn page_size(requested: Option<u64>) -> Result<usize, Error> {
+    let settings = settings();
+    match requested.unwrap_or(settings.default_page_size) {
+        size if (1..=settings.max_page_size).contains(&size) => Ok(size as usize),
+        size => Err(Error::invalid_input(
+            "limit",
+            "VALUE_OUT_OF_RANGE",
+            format!("limit must be between 1 and {}, got {}", settings.max_page_size, size),
+        )),
+    }
+}
+
+pub(crate) fn idempotency_window_ns() -> u64 {
+    settings().idempotency_retention_hours * 60 * 60 * 1_000_000_000
+}
+
+pub(crate) fn max_arg_bytes() -> usize {
+    settings().max_arg_bytes.unwrap_or(DEFAULT_ARG_BYTES) as usize
+}
+
+// Calls per window and the window in nanoseconds
+pub(crate) fn rate_limit() -> (u64, u64) {
+    let settings = settings();
+    (
+        settings.rate_limit_calls.unwrap_or(DEFAULT_RATE_LIMIT_CALLS),
+        settings.rate_limit_window_secs.unwrap_or(DEFAULT_RATE_LIMIT_WINDOW_SECS) * 1_000_000_000,
+    )
+}
+
+pub(crate) fn low_stock_alert_interval() -> Duration {
+    Duration::from_secs(settings().low_stock_alert_interval_secs)
+}
+
+pub(crate) fn reservation_ttl_ns() -> u64 {
+    settings().reservation_ttl_secs * 1_000_000_000
+}
+
+pub(crate) fn trash_ttl_ns() -> u64 {
+    settings().trash_ttl_secs.unwrap_or(DEFAULT_TRASH_TTL_SECS) * 1_000_000_000
+}
+
+pub(crate) fn max_name_length() -> usize {
+    settings().max_name_length.map_or(MAX_NAME_LENGTH, |length| length as usize)
+}
+
+pub(crate) fn default_reorder_point() -> Option<u64> {
+    settings().default_reorder_point
+}
+
+pub(crate) fn max_items_per_warehouse() -> u64 {
+    settings().max_items_per_warehouse.unwrap_or(DEFAULT_WAREHOUSE_ITEMS)
+}
+
+pub(crate) fn validate_settings(settings: &Settings) -> Result<(), Error> {
+    ensure_range("max_page_size", settings.max_page_size, 1, MAX_PAGE_SIZE)?;
+    ensure_range("default_page_size", settings.default_page_size, 1, settings.max_page_size)?;
+    ensure_range("idempotency_retention_hours", settings.idempotency_retention_hours, 1, MAX_RETENTION_HOURS)?;
+    ensure_range(
+        "low_stock_alert_interval_secs",
+        settings.low_stock_alert_interval_secs,
+        MIN_INTERVAL_SECS,
+        MAX_INTERVAL_SECS,
+    )?;
+    ensure_range("reservation_ttl_secs", settings.reservation_ttl_secs, MIN_INTERVAL_SECS, MAX_TTL_SECS)?;
+    if let Some(max_arg_bytes) = settings.max_arg_bytes {
+        ensure_range("max_arg_bytes", max_arg_bytes, MIN_ARG_BYTES, MAX_ARG_BYTES)?;
+    }
+    if let Some(calls) = settings.rate_limit_calls {
+        ensure_range("rate_limit_calls", calls, 1, MAX_RATE_LIMIT_CALLS)?;
+    }
+    if let Some(window_secs) = settings.rate_limit_window_secs {
+        ensure_range("rate_limit_window_secs", window_secs, 1, MAX_RATE_LIMIT_WINDOW_SECS)?;
+    }
+    if let Some(ttl_secs) = settings.trash_ttl_secs {
+        ensure_range("trash_ttl_secs", ttl_secs, MIN_INTERVAL_SECS, MAX_TTL_SECS)?;
+    }
+    if let Some(items) = settings.max_items_per_warehouse {
+        ensure_range("max_items_per_warehouse", items, 1, MAX_WAREHOUSE_ITEMS)?;
+    }
+    if let Some(length) = settings.max_name_length {
+        ensure_range("max_name_length", length, 1, MAX_NAME_LENGTH as u64)?;
+    }
+    Ok(())
+}
+
+fn ensure_range(field: &str, value: u64, min: u64, max: u64) -> Result<(), Error> {
+    if value < min || value > max {
+        return Err(Error::invalid_input(
+            field,
+            "VALUE_OUT_OF_RANGE",
+            format!("{} must be between {} and {}, got {}", field, min, max, value),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,213 @@
+// Previews of the riskier mutations. Each simulate_* query takes the arguments of its
+// update, runs the same checks and reports the records the update would write, or the
+// error it would return. Nothing is committed: the checks do not write, and queries
+// discard state anyway. The idempotency key of `options` is ignored, so a preview does not
+// tell whether the update would replay an earlier response.
+//
+// The batch previews are the exception to checks that do not write: each operation sees
+// the writes of the ones before it, so they are applied in turn, without certifying the
+// state, and the query discards them.
+use crate::batch::{self, InventoryOp, OpResult};
+use crate::ids::{ItemId, WarehouseId};
+use crate::idempotency::{self, MutationOptions};
+use crate::{certified, guard, settings, store, Error, StockItem};
+use crate::{_get_item, _plan_delete_item, _plan_delete_warehouse, _plan_transfer_item};
+use std::collections::BTreeMap;
+
+// `quantity_before` is None for a record that would be created, `quantity_after` for one
+// that would be removed
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ItemChange {
+    item_id: ItemId,
+    warehouse_id: WarehouseId,
+    item_name: String,
+    quantity_before: Option<u64>,
+    quantity_after: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct Simulation {
+    items: Vec<ItemChange>,
+    removed_warehouse_ids: Vec<WarehouseId>,
+    omitted_item_count: u64, // Removed items beyond the first page, left out of `items`
+}
+
+#[ic_cdk::query]
+fn simulate_transfer_item(
+    item_id: ItemId,
+    from_warehouse_id: WarehouseId,
+    to_warehouse_id: WarehouseId,
+    quantity: u64,
+    options: Option<MutationOptions>,
+) -> Result<Simulation, Error> {
+    preview("transfer_item", &options)?;
+    let before = _get_item(&item_id).map(|item| item.quantity);
+    let (item, destination) = _plan_transfer_item(
+        item_id,
+        from_warehouse_id,
+        to_warehouse_id,
+        quantity,
+        idempotency::expected_version(&options),
+    )?;
+    // None when the stock would go into a new record
+    let destination_before = _get_item(&destination.item_id).map(|item| item.quantity);
+    Ok(Simulation {
+        items: vec![
+            change(&item, before, Some(item.quantity)),
+            change(&destination, destination_before, Some(destination.quantity)),
+        ],
+        ..Default::default()
+    })
+}
+
+#[ic_cdk::query]
+fn simulate_delete_item(item_id: ItemId, quantity: u64, options: Option<MutationOptions>) -> Result<Simulation, Error> {
+    preview("delete_item", &options)?;
+    let before = _get_item(&item_id).map(|item| item.quantity);
+    let item = _plan_delete_item(item_id, quantity, idempotency::expected_version(&options))?;
+    let after = Some(item.quantity).filter(|quantity| *quantity > 0);
+    Ok(Simulation {
+        items: vec![change(&item, before, after)],
+        ..Default::default()
+    })
+}
+
+// Previews purge_warehouse: lists every item of the warehouse, which the update purges in
+// batches. delete_warehouse only archives.
+#[ic_cdk::query]
+fn simulate_delete_warehouse(warehouse_id: WarehouseId, options: Option<MutationOptions>) -> Result<Simulation, Error> {
+    preview("purge_warehouse", &options)?;
+    _plan_delete_warehouse(warehouse_id, idempotency::expected_version(&options))?;
+    // Lists one page of the removed items and only counts the rest
+    let items: Vec<ItemChange> = store::items_in_warehouse(warehouse_id, None, settings::page_size(None)?)
+        .iter()
+        .map(|item| change(item, Some(item.quantity), None))
+        .collect();
+    let omitted_item_count = store::item_count_in_warehouse(warehouse_id) - items.len() as u64;
+    Ok(Simulation {
+        items,
+        removed_warehouse_ids: vec![warehouse_id],
+        omitted_item_count,
+    })
+}
+
+#[ic_cdk::query]
+fn simulate_apply_batch(ops: Vec<InventoryOp>, options: Option<MutationOptions>) -> Result<Simulation, Error> {
+    preview("apply_batch", &options)?;
+    batch::validate_ops(&ops)?;
+    apply_in_turn(ops, "op")
+}
+
+#[ic_cdk::query]
+fn simulate_transfer_items(
+    from_warehouse_id: WarehouseId,
+    to_warehouse_id: WarehouseId,
+    lines: Vec<(ItemId, u64)>,
+    options: Option<MutationOptions>,
+) -> Result<Simulation, Error> {
+    preview("transfer_items", &options)?;
+    batch::validate_transfer_lines(&lines)?;
+    let ops = lines
+        .into_iter()
+        .map(|(item_id, quantity)| InventoryOp::Transfer { item_id, from_warehouse_id, to_warehouse_id, quantity })
+        .collect();
+    apply_in_turn(ops, "line")
+}
+
+// Applies the operations in order and reports every item they touched once, from its
+// quantity before the first to after the last. A failing operation fails the preview, with
+// e.g. "op 3: " before its message.
+fn apply_in_turn(ops: Vec<InventoryOp>, label: &str) -> Result<Simulation, Error> {
+    certified::uncertified(|| apply_uncertified(ops, label))
+}
+
+fn apply_uncertified(ops: Vec<InventoryOp>, label: &str) -> Result<Simulation, Error> {
+    // Item id -> quantity before the first operation, None for an item an operation created
+    let mut before: BTreeMap<ItemId, Option<u64>> = BTreeMap::new();
+    let mut first_seen: BTreeMap<ItemId, StockItem> = BTreeMap::new(); // For items removed later
+    for (index, op) in ops.into_iter().enumerate() {
+        // A transfer may create the destination item, found by name once it has run
+        let (existing, destination) = match &op {
+            InventoryOp::AddItem(payload) => {
+                (store::item_id_by_name(payload.warehouse_id, &payload.item_name).into_iter().collect(), None)
+            }
+            InventoryOp::AdjustQuantity { item_id, .. } | InventoryOp::DeleteItem { item_id, .. } => (vec![*item_id], None),
+            InventoryOp::Transfer { item_id, to_warehouse_id, .. } => {
+                let name = _get_item(item_id).map(|item| item.item_name);
+                let mut ids = vec![*item_id];
+                ids.extend(name.as_ref().and_then(|name| store::item_id_by_name(*to_warehouse_id, name)));
+                (ids, name.map(|name| (*to_warehouse_id, name)))
+            }
+        };
+        for id in existing {
+            if let (false, Some(item)) = (before.contains_key(&id), _get_item(&id)) {
+                before.insert(id, Some(item.quantity));
+                first_seen.insert(id, item);
+            }
+        }
+
+        let result = batch::apply(op).map_err(|error| error.with_context(&format!("{} {}: ", label, index)))?;
+        let created = match result {
+            OpResult::AddItem(item) => Some(item.item_id),
+            _ => destination.and_then(|(warehouse_id, name)| store::item_id_by_name(warehouse_id, &name)),
+        };
+        if let Some(id) = created {
+            before.entry(id).or_insert(None);
+        }
+    }
+
+    let items = before
+        .into_iter()
+        .filter_map(|(id, quantity_before)| {
+            let current = _get_item(&id);
+            let record = current.clone().or_else(|| first_seen.remove(&id))?;
+            Some(change(&record, quantity_before, current.map(|item| item.quantity)))
+        })
+        .collect();
+    Ok(Simulation {
+        items,
+        ..Default::default()
+    })
+}
+
+// The checks the update makes before its handler runs
+fn preview(method: &str, options: &Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::validate_options(options)?;
+    guard::check_update(method)
+}
+
+fn change(item: &StockItem, quantity_before: Option<u64>, quantity_after: Option<u64>) -> ItemChange {
+    ItemChange {
+        item_id: item.item_id,
+        warehouse_id: item.warehouse_id,
+        item_name: item.item_name.clone(),
+        quantity_before,
+        quantity_after,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::transfer_fixture;
+
+    #[test]
+    fn batch_preview_reports_a_successful_transfer() {
+        transfer_fixture();
+        let transfer = InventoryOp::Transfer {
+            item_id: ItemId(1),
+            from_warehouse_id: WarehouseId(1),
+            to_warehouse_id: WarehouseId(2),
+            quantity: 4,
+        };
+        let Ok(simulation) = simulate_apply_batch(vec![transfer], None) else {
+            panic!("the preview failed");
+        };
+        let changes: Vec<_> = simulation
+            .items
+            .iter()
+            .map(|change| (change.warehouse_id, change.quantity_before, change.quantity_after))
+            .collect();
+        assert!(changes == vec![(WarehouseId(1), Some(10), Some(6)), (WarehouseId(2), None, Some(4))]);
+    }
+}
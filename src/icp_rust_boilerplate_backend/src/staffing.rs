@@ -0,0 +1,235 @@
+// Staff of a warehouse and their daily shifts. Admins and Owners of the organization, or
+// controllers, assign members of the organization with a staff role and a shift window in
+// UTC. Once a warehouse has an active assignment, receiving stock and counting there are
+// limited to staff on shift with a role for that duty; Admins, Owners and controllers are
+// exempt. Ended assignments are kept so the staff of any past moment can be looked up.
+use crate::ids::WarehouseId;
+use crate::organization::{self, Role};
+use crate::{idempotency, tenancy, Error, MutationOptions, STAFF_ASSIGNMENTS, WAREHOUSE_STAFF};
+use crate::_get_warehouse;
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+const NS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+const MAX_ACTIVE_ASSIGNMENTS: usize = 200; // Per warehouse
+
+// Supervisors can do everything the other roles can
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) enum StaffRole {
+    Receiver,
+    Counter,
+    Supervisor,
+}
+
+// Operations limited to staff on shift
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Duty {
+    Receiving,
+    Counting,
+}
+
+// Minutes after midnight UTC; a window whose end is before its start runs past midnight
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ShiftWindow {
+    start_minute: u16,
+    end_minute: u16,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StaffAssignment {
+    id: u64,
+    org_id: u64,
+    warehouse_id: WarehouseId,
+    principal: Principal,
+    role: StaffRole,
+    shift: ShiftWindow,
+    assigned_at: u64,
+    assigned_by: Principal,
+    ended_at: Option<u64>,
+    ended_by: Option<Principal>,
+}
+
+impl Storable for StaffAssignment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StaffAssignment {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Assignments that were active at `at` (nanoseconds since the epoch), or now
+#[ic_cdk::query]
+fn get_warehouse_staff(warehouse_id: WarehouseId, at: Option<u64>) -> Result<Vec<StaffAssignment>, Error> {
+    _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    let at = at.unwrap_or_else(time);
+    Ok(assignments(warehouse_id)
+        .into_iter()
+        .filter(|assignment| assignment.assigned_at <= at && assignment.ended_at.is_none_or(|ended| at < ended))
+        .collect())
+}
+
+#[ic_cdk::update]
+fn assign_staff(
+    warehouse_id: WarehouseId,
+    principal: Principal,
+    role: StaffRole,
+    shift: ShiftWindow,
+    options: Option<MutationOptions>,
+) -> Result<StaffAssignment, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("assign_staff", options, || _assign_staff(warehouse_id, principal, role, shift))
+}
+
+fn _assign_staff(warehouse_id: WarehouseId, principal: Principal, role: StaffRole, shift: ShiftWindow) -> Result<StaffAssignment, Error> {
+    validate_shift(&shift)?;
+    let warehouse = _get_warehouse(&warehouse_id).ok_or_else(|| Error::not_found("warehouse", warehouse_id))?;
+    organization::ensure_role_or_controller(warehouse.org_id, Role::Admin)?;
+    if principal == Principal::anonymous() || tenancy::org_of(principal) != warehouse.org_id {
+        return Err(Error::invalid_input(
+            "principal",
+            "NOT_A_MEMBER",
+            format!("Principal {} is not a member of organization with id={}", principal, warehouse.org_id),
+        ));
+    }
+    let active = assignments(warehouse_id).iter().filter(|assignment| assignment.ended_at.is_none()).count();
+    if active >= MAX_ACTIVE_ASSIGNMENTS {
+        return Err(Error::capacity_exceeded(
+            "TOO_MANY_ENTRIES",
+            MAX_ACTIVE_ASSIGNMENTS as u64,
+            active as u64 + 1,
+            format!("A warehouse has at most {} active staff assignments", MAX_ACTIVE_ASSIGNMENTS),
+        ));
+    }
+
+    let assignment = STAFF_ASSIGNMENTS.with(|assignments| {
+        let mut assignments = assignments.borrow_mut();
+        let id = assignments.last_key_value().map_or(1, |(id, _)| id + 1);
+        let assignment = StaffAssignment {
+            id,
+            org_id: warehouse.org_id,
+            warehouse_id,
+            principal,
+            role,
+            shift,
+            assigned_at: time(),
+            assigned_by: crate::env::caller(),
+            ended_at: None,
+            ended_by: None,
+        };
+        assignments.insert(id, assignment.clone());
+        assignment
+    });
+    WAREHOUSE_STAFF.with(|index| index.borrow_mut().insert((warehouse_id, assignment.id), ()));
+    Ok(assignment)
+}
+
+#[ic_cdk::update]
+fn end_staff_assignment(assignment_id: u64, options: Option<MutationOptions>) -> Result<StaffAssignment, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("end_staff_assignment", options, || _end_staff_assignment(assignment_id))
+}
+
+fn _end_staff_assignment(assignment_id: u64) -> Result<StaffAssignment, Error> {
+    let mut assignment = STAFF_ASSIGNMENTS
+        .with(|assignments| assignments.borrow().get(&assignment_id))
+        .filter(|assignment| assignment.org_id == tenancy::caller_org())
+        .ok_or_else(|| Error::not_found("staff_assignment", assignment_id))?;
+    organization::ensure_role_or_controller(assignment.org_id, Role::Admin)?;
+    if assignment.ended_at.is_some() {
+        return Err(Error::conflict(
+            "staff_assignment",
+            Some(assignment_id),
+            "ASSIGNMENT_ENDED",
+            format!("Staff assignment with id={} has already ended", assignment_id),
+        ));
+    }
+
+    assignment.ended_at = Some(time());
+    assignment.ended_by = Some(crate::env::caller());
+    STAFF_ASSIGNMENTS.with(|assignments| assignments.borrow_mut().insert(assignment_id, assignment.clone()));
+    Ok(assignment)
+}
+
+// Passes when the warehouse has no active staff, or the caller is on shift for `duty`
+pub(crate) fn ensure_on_shift(warehouse_id: WarehouseId, org_id: u64, duty: Duty) -> Result<(), Error> {
+    let active: Vec<StaffAssignment> =
+        assignments(warehouse_id).into_iter().filter(|assignment| assignment.ended_at.is_none()).collect();
+    if active.is_empty() || organization::ensure_role_or_controller(org_id, Role::Admin).is_ok() {
+        return Ok(());
+    }
+    let caller = crate::env::caller();
+    let minute = ((time() / NS_PER_MINUTE) % MINUTES_PER_DAY as u64) as u16;
+    let on_shift = active.iter().any(|assignment| {
+        assignment.principal == caller && assignment.role.covers(duty) && assignment.shift.contains(minute)
+    });
+    if on_shift {
+        return Ok(());
+    }
+    Err(Error::unauthorized(
+        caller,
+        format!("Caller {} is not on shift for {:?} at warehouse_id={}", caller, duty, warehouse_id),
+    ))
+}
+
+pub(crate) fn validate_shift(shift: &ShiftWindow) -> Result<(), Error> {
+    for (field, minute) in [("start_minute", shift.start_minute), ("end_minute", shift.end_minute)] {
+        if minute >= MINUTES_PER_DAY {
+            return Err(Error::invalid_input(
+                field,
+                "VALUE_OUT_OF_RANGE",
+                format!("{} must be below {}, got {}", field, MINUTES_PER_DAY, minute),
+            ));
+        }
+    }
+    if shift.start_minute == shift.end_minute {
+        return Err(Error::invalid_input(
+            "end_minute",
+            "INVALID_RANGE",
+            "A shift must end at a different minute than it starts".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+impl StaffRole {
+    fn covers(self, duty: Duty) -> bool {
+        matches!(
+            (self, duty),
+            (StaffRole::Supervisor, _) | (StaffRole::Receiver, Duty::Receiving) | (StaffRole::Counter, Duty::Counting)
+        )
+    }
+}
+
+impl ShiftWindow {
+    fn contains(&self, minute: u16) -> bool {
+        if self.start_minute < self.end_minute {
+            self.start_minute <= minute && minute < self.end_minute
+        } else {
+            minute >= self.start_minute || minute < self.end_minute
+        }
+    }
+}
+
+fn assignments(warehouse_id: WarehouseId) -> Vec<StaffAssignment> {
+    let ids: Vec<u64> = WAREHOUSE_STAFF.with(|index| {
+        index
+            .borrow()
+            .range((warehouse_id, 0)..=(warehouse_id, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    STAFF_ASSIGNMENTS.with(|assignments| {
+        let assignments = assignments.borrow();
+        ids.iter().filter_map(|id| assignments.get(id)).collect()
+    })
+}
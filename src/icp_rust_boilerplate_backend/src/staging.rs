@@ -0,0 +1,341 @@
+// CSV imports too large for one message. The file is uploaded in chunks into stable
+// memory after begin_import, then commit_import adds its rows with the stored mapping,
+// like import_csv. A commit call handles at most MAX_IMPORT_ROWS rows, so it is repeated
+// until it reports `complete`; the first call closes the upload. Chunks may split lines
+// and characters anywhere: rows are only cut apart at line breaks outside quotes. Only the
+// principal that began an import sees it, and its chunks are dropped once it completes.
+use crate::import::{self, ImportRowResult, MAX_CSV_BYTES, MAX_IMPORT_ROWS};
+use crate::{idempotency, Error, MutationOptions, IMPORT_CHUNKS, IMPORT_SESSIONS};
+use crate::_ensure_fits;
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+pub(crate) const MAX_CHUNK_BYTES: usize = 128 * 1024;
+const MAX_UPLOAD_BYTES: u64 = 16 * 1024 * 1024; // Per import
+const MAX_OPEN_IMPORTS: usize = 5; // Per principal, not yet complete
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ImportStatus {
+    Uploading,
+    Committing,
+    Complete,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ImportSession {
+    id: u64,
+    mapping_id: u64,
+    status: ImportStatus,
+    created_at: u64,
+    created_by: Principal,
+    bytes: u64,
+    chunks: u32,
+    header: Option<Vec<String>>, // Read by the first commit call
+    next_offset: u64,            // Byte where the rows not yet imported start
+    next_line: u64,              // Line number at next_offset
+    rows_imported: u64,
+    rows_failed: u64,
+}
+
+// The rows handled by one commit_import call
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ImportProgress {
+    session: ImportSession,
+    results: Vec<ImportRowResult>,
+    complete: bool,
+}
+
+#[derive(Clone)]
+pub(crate) struct Chunk(Vec<u8>);
+
+impl Storable for ImportSession {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ImportSession {
+    const MAX_SIZE: u32 = 8192; // Mostly the header row
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Chunk {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Chunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for Chunk {
+    const MAX_SIZE: u32 = MAX_CHUNK_BYTES as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_import(import_id: u64) -> Result<ImportSession, Error> {
+    session(import_id)
+}
+
+#[ic_cdk::update]
+fn begin_import(mapping_id: u64, options: Option<MutationOptions>) -> Result<ImportSession, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("begin_import", options, || _begin_import(mapping_id))
+}
+
+fn _begin_import(mapping_id: u64) -> Result<ImportSession, Error> {
+    import::get_import_mapping(mapping_id)?;
+    let caller = crate::env::caller();
+    let open = IMPORT_SESSIONS.with(|sessions| {
+        sessions
+            .borrow()
+            .iter()
+            .filter(|(_, session)| session.created_by == caller && session.status != ImportStatus::Complete)
+            .count()
+    });
+    if open >= MAX_OPEN_IMPORTS {
+        return Err(Error::capacity_exceeded(
+            "TOO_MANY_ENTRIES",
+            MAX_OPEN_IMPORTS as u64,
+            open as u64 + 1,
+            format!("A principal has at most {} imports in progress; commit or cancel one first", MAX_OPEN_IMPORTS),
+        ));
+    }
+
+    // Imports are never re-numbered, so the next id follows the highest one in use
+    let id = IMPORT_SESSIONS.with(|sessions| sessions.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let session = ImportSession {
+        id,
+        mapping_id,
+        status: ImportStatus::Uploading,
+        created_at: time(),
+        created_by: caller,
+        bytes: 0,
+        chunks: 0,
+        header: None,
+        next_offset: 0,
+        next_line: 1,
+        rows_imported: 0,
+        rows_failed: 0,
+    };
+    IMPORT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(id, session.clone()));
+    Ok(session)
+}
+
+// Appends `bytes` to the file; chunks are kept in upload order
+#[ic_cdk::update]
+fn upload_chunk(import_id: u64, bytes: Vec<u8>, options: Option<MutationOptions>) -> Result<ImportSession, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("upload_chunk", options, || _upload_chunk(import_id, bytes))
+}
+
+fn _upload_chunk(import_id: u64, bytes: Vec<u8>) -> Result<ImportSession, Error> {
+    validate_chunk(&bytes)?;
+    let mut session = in_status(import_id, ImportStatus::Uploading)?;
+    let total = session.bytes + bytes.len() as u64;
+    if total > MAX_UPLOAD_BYTES {
+        return Err(Error::invalid_input(
+            "bytes",
+            "VALUE_TOO_LONG",
+            format!("An import must be at most {} bytes, this chunk makes it {}", MAX_UPLOAD_BYTES, total),
+        ));
+    }
+
+    IMPORT_CHUNKS.with(|chunks| chunks.borrow_mut().insert((import_id, session.chunks), Chunk(bytes)));
+    session.bytes = total;
+    session.chunks += 1;
+    IMPORT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(import_id, session.clone()));
+    Ok(session)
+}
+
+// Imports the next rows; the first call reads the header and ends the upload
+#[ic_cdk::update]
+fn commit_import(import_id: u64, options: Option<MutationOptions>) -> Result<ImportProgress, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("commit_import", options, || _commit_import(import_id))
+}
+
+fn _commit_import(import_id: u64) -> Result<ImportProgress, Error> {
+    let mut session = session(import_id)?;
+    if session.status == ImportStatus::Complete {
+        return Err(closed(&session));
+    }
+    let mapping = import::get_import_mapping(session.mapping_id)?;
+    let delimiter = import::mapping_delimiter(&mapping);
+
+    // Every check that can fail comes before the first row is added
+    let (text, end) = next_rows(&session)?;
+    let mut rows = import::parse_csv(&text, delimiter).into_iter();
+    let lines_read = text.matches('\n').count() as u64;
+    let header = match session.header.clone() {
+        Some(header) => header,
+        None => match rows.next() {
+            Some((_, header)) => header,
+            None => Vec::new(), // An empty file imports nothing
+        },
+    };
+    let positions = if header.is_empty() { Vec::new() } else { import::column_positions(&mapping, &header)? };
+    session.header = Some(header);
+    _ensure_fits("import", &session)?;
+
+    let first_line = session.next_line;
+    let results: Vec<ImportRowResult> = rows
+        .map(|(line, values)| import::import_row(&mapping, &positions, first_line + line - 1, &values))
+        .collect();
+
+    let failed = results.iter().filter(|row| row.result.is_err()).count() as u64;
+    session.rows_failed += failed;
+    session.rows_imported += results.len() as u64 - failed;
+    session.next_offset = end;
+    session.next_line += lines_read;
+    session.status = if end == session.bytes { ImportStatus::Complete } else { ImportStatus::Committing };
+    if session.status == ImportStatus::Complete {
+        clear_chunks(import_id, session.chunks);
+    }
+    IMPORT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(import_id, session.clone()));
+
+    Ok(ImportProgress {
+        complete: session.status == ImportStatus::Complete,
+        session,
+        results,
+    })
+}
+
+// Drops an import that is not complete, keeping the rows already committed
+#[ic_cdk::update]
+fn cancel_import(import_id: u64, options: Option<MutationOptions>) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("cancel_import", options, || _cancel_import(import_id))
+}
+
+fn _cancel_import(import_id: u64) -> Result<(), Error> {
+    let session = session(import_id)?;
+    if session.status == ImportStatus::Complete {
+        return Err(closed(&session));
+    }
+
+    clear_chunks(import_id, session.chunks);
+    IMPORT_SESSIONS.with(|sessions| sessions.borrow_mut().remove(&import_id));
+    Ok(())
+}
+
+pub(crate) fn validate_chunk(bytes: &[u8]) -> Result<(), Error> {
+    if bytes.is_empty() || bytes.len() > MAX_CHUNK_BYTES {
+        return Err(Error::invalid_input(
+            "bytes",
+            "VALUE_OUT_OF_RANGE",
+            format!("A chunk must have 1 to {} bytes, got {}", MAX_CHUNK_BYTES, bytes.len()),
+        ));
+    }
+    Ok(())
+}
+
+// The text of the next whole rows, at most MAX_IMPORT_ROWS of them (one more while the
+// header is unread) and MAX_CSV_BYTES, and the offset where they end
+fn next_rows(session: &ImportSession) -> Result<(String, u64), Error> {
+    let start = session.next_offset;
+    let window = read(session, start, MAX_CSV_BYTES);
+    let at_end = start + window.len() as u64 == session.bytes;
+    let max_rows = MAX_IMPORT_ROWS + usize::from(session.header.is_none());
+
+    // '"' and '\n' are ASCII, so scanning bytes finds the same breaks as scanning characters
+    let mut in_quotes = false;
+    let mut rows = 0;
+    let mut cut = None;
+    for (position, byte) in window.iter().enumerate() {
+        match byte {
+            b'"' => in_quotes = !in_quotes,
+            b'\n' if !in_quotes => {
+                rows += 1;
+                cut = Some(position + 1);
+                if rows == max_rows {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    let length = match cut {
+        Some(length) if rows == max_rows || !at_end => length,
+        _ if at_end => window.len(), // The last row needs no line break
+        _ => {
+            return Err(Error::invalid_input(
+                "bytes",
+                "ROW_TOO_LONG",
+                format!("The row at line {} is longer than {} bytes", session.next_line, MAX_CSV_BYTES),
+            ))
+        }
+    };
+
+    let text = String::from_utf8(window[..length].to_vec()).map_err(|error| {
+        Error::invalid_input(
+            "bytes",
+            "INVALID_FORMAT",
+            format!("The file is not valid UTF-8 at byte {}", start + error.utf8_error().valid_up_to() as u64),
+        )
+    })?;
+    Ok((text, start + length as u64))
+}
+
+// Up to `limit` bytes of the file from `offset`
+fn read(session: &ImportSession, offset: u64, limit: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut chunk_start = 0u64;
+    IMPORT_CHUNKS.with(|chunks| {
+        for ((_, _), Chunk(chunk)) in chunks.borrow().range((session.id, 0)..(session.id, session.chunks)) {
+            let chunk_end = chunk_start + chunk.len() as u64;
+            if chunk_end > offset {
+                let from = offset.saturating_sub(chunk_start) as usize;
+                let wanted = limit - bytes.len();
+                bytes.extend(chunk[from..].iter().take(wanted));
+                if bytes.len() == limit {
+                    break;
+                }
+            }
+            chunk_start = chunk_end;
+        }
+    });
+    bytes
+}
+
+fn clear_chunks(import_id: u64, count: u32) {
+    IMPORT_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for seq in 0..count {
+            chunks.remove(&(import_id, seq));
+        }
+    });
+}
+
+fn in_status(import_id: u64, status: ImportStatus) -> Result<ImportSession, Error> {
+    let session = session(import_id)?;
+    if session.status != status {
+        return Err(closed(&session));
+    }
+    Ok(session)
+}
+
+fn closed(session: &ImportSession) -> Error {
+    Error::conflict(
+        "import",
+        Some(session.id),
+        "INVALID_STATUS",
+        format!("Import with id={} is {:?}", session.id, session.status),
+    )
+}
+
+// An import begun by the caller
+fn session(import_id: u64) -> Result<ImportSession, Error> {
+    IMPORT_SESSIONS
+        .with(|sessions| sessions.borrow().get(&import_id))
+        .filter(|session| session.created_by == crate::env::caller())
+        .ok_or_else(|| Error::not_found("import", import_id))
+}
@@ -0,0 +1,460 @@
+// All writes to warehouses and stock items go through this module so that the
+// secondary indexes, the cached totals and the certified digest stay in sync with the
+// primary maps, and every write is appended to the audit log.
+use crate::ids::{ItemId, WarehouseId};
+use crate::{audit, certified, history, pricing, pubsub, reorder, reservation, search, totals};
+use crate::validation::normalize_name;
+use crate::certified::RecordKey;
+use crate::tenancy::MemberKey;
+use crate::{ARCHIVED_WAREHOUSES, BARCODE_INDEX, ITEMS_BY_BIN, ITEMS_BY_CATEGORY, LOW_STOCK, SKU_INDEX};
+use crate::{StockItem, Warehouse, ITEMS_BY_CREATOR, ORG_WAREHOUSES, ITEM_NAME_INDEX, STOCK_STORAGE, WAREHOUSE_ITEMS, WAREHOUSE_NAME_INDEX, WAREHOUSE_STORAGE};
+use candid::Principal;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use unicode_normalization::UnicodeNormalization;
+
+// Lookup form of a name: normalized and case-folded, so "Widget" and " widget" share a key
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct NameKey(String);
+
+impl Storable for NameKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        NameKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for NameKey {
+    const MAX_SIZE: u32 = 1024; // Names are at most MAX_NAME_LENGTH characters, codes shorter
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Keys of WAREHOUSE_NAME_INDEX and ITEM_NAME_INDEX
+pub(crate) type WarehouseNameKey = ((u64, NameKey), WarehouseId);
+pub(crate) type ItemNameKey = ((WarehouseId, NameKey), ItemId);
+
+// Folding through the upper case also matches "STRASSE" with "straße", which lowercasing
+// alone keeps apart; the folded form is recomposed, since folding can decompose
+pub(crate) fn name_key(name: &str) -> NameKey {
+    NameKey(normalize_name(name).to_uppercase().to_lowercase().nfc().collect())
+}
+
+// Lookup form of a SKU or barcode
+pub(crate) fn code_key(code: &str) -> NameKey {
+    NameKey(code.trim().to_ascii_uppercase())
+}
+
+impl NameKey {
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub(crate) fn starts_with(&self, prefix: &NameKey) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
+}
+
+// Both name indexes are multimaps keyed by (scope, name, id): records created before the
+// indexes existed may share a name and must all stay reachable.
+pub(crate) fn warehouse_id_by_name(org_id: u64, name: &str) -> Option<WarehouseId> {
+    let key = (org_id, name_key(name));
+    WAREHOUSE_NAME_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((key.clone(), WarehouseId(0))..=(key, WarehouseId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .next()
+    })
+}
+
+pub(crate) fn item_id_by_sku(warehouse_id: WarehouseId, sku: &str) -> Option<ItemId> {
+    SKU_INDEX.with(|index| index.borrow().get(&(warehouse_id, code_key(sku))))
+}
+
+pub(crate) fn item_id_by_barcode(warehouse_id: WarehouseId, barcode: &str) -> Option<ItemId> {
+    BARCODE_INDEX.with(|index| index.borrow().get(&(warehouse_id, code_key(barcode))))
+}
+
+pub(crate) fn item_id_by_name(warehouse_id: WarehouseId, name: &str) -> Option<ItemId> {
+    let key = (warehouse_id, name_key(name));
+    ITEM_NAME_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((key.clone(), ItemId(0))..=(key, ItemId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .next()
+    })
+}
+
+// Ids of an organization's warehouses, archived ones included, in id order
+pub(crate) fn warehouse_ids_in_org(org_id: u64) -> Vec<WarehouseId> {
+    ORG_WAREHOUSES.with(|index| {
+        index
+            .borrow()
+            .range((org_id, WarehouseId(0))..=(org_id, WarehouseId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+// Ids of an organization's warehouses that are not archived, in id order
+pub(crate) fn listed_warehouse_ids(org_id: u64) -> Vec<WarehouseId> {
+    listed_warehouses_page(org_id, 0, usize::MAX)
+}
+
+// Up to `limit` ids of an organization's warehouses that are not archived in id order,
+// skipping the first `offset`. Skipped entries are only index keys, no records are read.
+pub(crate) fn listed_warehouses_page(org_id: u64, offset: usize, limit: usize) -> Vec<WarehouseId> {
+    ARCHIVED_WAREHOUSES.with(|archived| {
+        let archived = archived.borrow();
+        ORG_WAREHOUSES.with(|index| {
+            index
+                .borrow()
+                .range((org_id, WarehouseId(0))..=(org_id, WarehouseId(u64::MAX)))
+                .filter(|(key, _)| !archived.contains_key(key))
+                .skip(offset)
+                .take(limit)
+                .map(|((_, id), _)| id)
+                .collect()
+        })
+    })
+}
+
+pub(crate) fn archived_count(org_id: u64) -> u64 {
+    ARCHIVED_WAREHOUSES.with(|archived| archived.borrow().range((org_id, WarehouseId(0))..=(org_id, WarehouseId(u64::MAX))).count() as u64)
+}
+
+// Up to `limit` item ids of a warehouse, in id order, without scanning other warehouses
+pub(crate) fn item_ids_in_warehouse(warehouse_id: WarehouseId, limit: usize) -> Vec<ItemId> {
+    WAREHOUSE_ITEMS.with(|index| {
+        index
+            .borrow()
+            .range((warehouse_id, ItemId(0))..=(warehouse_id, ItemId(u64::MAX)))
+            .map(|((_, item_id), _)| item_id)
+            .take(limit)
+            .collect()
+    })
+}
+
+// Counts the index keys only, without reading an item record
+pub(crate) fn item_count_in_warehouse(warehouse_id: WarehouseId) -> u64 {
+    WAREHOUSE_ITEMS.with(|index| index.borrow().range((warehouse_id, ItemId(0))..=(warehouse_id, ItemId(u64::MAX))).count() as u64)
+}
+
+// Up to `limit` items of a warehouse with an id above `start_after`, in id order.
+// Only the returned records are read, so the cost follows `limit`, not the stock size.
+pub(crate) fn items_in_warehouse(warehouse_id: WarehouseId, start_after: Option<ItemId>, limit: usize) -> Vec<StockItem> {
+    let from = match start_after {
+        Some(ItemId(u64::MAX)) => return Vec::new(),
+        Some(ItemId(id)) => ItemId(id + 1),
+        None => ItemId(0),
+    };
+    WAREHOUSE_ITEMS.with(|index| {
+        STOCK_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            index
+                .borrow()
+                .range((warehouse_id, from)..=(warehouse_id, ItemId(u64::MAX)))
+                .filter_map(|((_, item_id), _)| storage.get(&item_id))
+                .take(limit)
+                .collect()
+        })
+    })
+}
+
+// Up to `limit` items created by `principal` in organization `org_id` with an id above
+// `start_after`, in id order
+pub(crate) fn items_created_by(principal: Principal, org_id: u64, start_after: Option<ItemId>, limit: usize) -> Vec<StockItem> {
+    let from = match start_after {
+        Some(ItemId(u64::MAX)) => return Vec::new(),
+        Some(ItemId(id)) => ItemId(id + 1),
+        None => ItemId(0),
+    };
+    ITEMS_BY_CREATOR.with(|index| {
+        STOCK_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            index
+                .borrow()
+                .range((MemberKey(principal), from)..=(MemberKey(principal), ItemId(u64::MAX)))
+                .filter_map(|((_, item_id), _)| storage.get(&item_id))
+                .filter(|item| item.org_id == org_id)
+                .take(limit)
+                .collect()
+        })
+    })
+}
+
+// Inserts or replaces a warehouse
+pub(crate) fn insert_warehouse(warehouse: Warehouse) {
+    let id = warehouse.id;
+    let warehouse_org = warehouse.org_id;
+    let new_key = (warehouse_org, name_key(&warehouse.name));
+    let new_leaf = certified::leaf(&warehouse);
+
+    let old = WAREHOUSE_STORAGE.with(|storage| storage.borrow_mut().insert(id, warehouse.clone()));
+    certified::record_change(RecordKey::Warehouse(id), old.as_ref().map(certified::leaf), Some(new_leaf));
+    audit::record_warehouse(old.as_ref(), Some(&warehouse));
+    WAREHOUSE_NAME_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(old) = &old {
+            index.remove(&((old.org_id, name_key(&old.name)), id));
+        }
+        index.insert((new_key.clone(), id), ());
+    });
+    ORG_WAREHOUSES.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(old) = &old {
+            index.remove(&(old.org_id, id));
+        }
+        index.insert((warehouse_org, id), ());
+    });
+    ARCHIVED_WAREHOUSES.with(|archived| {
+        let mut archived = archived.borrow_mut();
+        if let Some(old) = &old {
+            archived.remove(&(old.org_id, id));
+        }
+        if warehouse.archived_at.is_some() {
+            archived.insert((warehouse_org, id), ());
+        }
+    });
+    search::replace_warehouse(old.as_ref().map(|old| (old.org_id, name_key(&old.name))), new_key, id);
+    if old.is_none() {
+        totals::record_warehouse_added(warehouse_org);
+    }
+}
+
+pub(crate) fn remove_warehouse(id: WarehouseId) -> Option<Warehouse> {
+    let old = WAREHOUSE_STORAGE.with(|storage| storage.borrow_mut().remove(&id))?;
+    certified::record_change(RecordKey::Warehouse(id), Some(certified::leaf(&old)), None);
+    audit::record_warehouse(Some(&old), None);
+    let key = (old.org_id, name_key(&old.name));
+    WAREHOUSE_NAME_INDEX.with(|index| index.borrow_mut().remove(&(key.clone(), id)));
+    ORG_WAREHOUSES.with(|index| index.borrow_mut().remove(&(old.org_id, id)));
+    ARCHIVED_WAREHOUSES.with(|archived| archived.borrow_mut().remove(&(old.org_id, id)));
+    search::remove_warehouse(key, id);
+    totals::record_warehouse_removed(old.org_id);
+    Some(old)
+}
+
+// Inserts or replaces a stock item
+pub(crate) fn insert_item(item: StockItem) {
+    let id = item.item_id;
+    let new_key = (item.warehouse_id, name_key(&item.item_name));
+
+    let warehouse_id = item.warehouse_id;
+    let org_id = item.org_id;
+    let created_by = item.created_by;
+    let old = STOCK_STORAGE.with(|storage| storage.borrow_mut().insert(id, item.clone()));
+    totals::record_item(old.as_ref(), Some(&item));
+    certified::record_change(RecordKey::Item(id), old.as_ref().map(certified::leaf), Some(certified::leaf(&item)));
+    audit::record_item(old.as_ref(), Some(&item));
+    history::record(old.as_ref(), Some(&item));
+    pubsub::record_item(old.as_ref(), Some(&item));
+    ITEM_NAME_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(old) = &old {
+            index.remove(&((old.warehouse_id, name_key(&old.item_name)), id));
+        }
+        index.insert((new_key.clone(), id), ());
+    });
+    search::replace_item(
+        old.as_ref().map(|old| (old.org_id, name_key(&old.item_name))),
+        (org_id, new_key.1),
+        id,
+    );
+    WAREHOUSE_ITEMS.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(old) = &old {
+            index.remove(&(old.warehouse_id, id));
+        }
+        index.insert((warehouse_id, id), ());
+    });
+    ITEMS_BY_CREATOR.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(old) = &old {
+            index.remove(&(MemberKey(old.created_by), id));
+        }
+        index.insert((MemberKey(created_by), id), ());
+    });
+    ITEMS_BY_CATEGORY.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(category_id) = old.as_ref().and_then(|old| old.category_id) {
+            index.remove(&(category_id, id));
+        }
+        if let Some(category_id) = item.category_id {
+            index.insert((category_id, id), ());
+        }
+    });
+    ITEMS_BY_BIN.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(bin_id) = old.as_ref().and_then(|old| old.bin_id) {
+            index.remove(&(bin_id, id));
+        }
+        if let Some(bin_id) = item.bin_id {
+            index.insert((bin_id, id), ());
+        }
+    });
+    LOW_STOCK.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(old) = &old {
+            index.remove(&(old.warehouse_id, id));
+        }
+        if reorder::is_low(&item) {
+            index.insert((warehouse_id, id), ());
+        }
+    });
+    if let Some(old) = &old {
+        remove_codes(old);
+    }
+    insert_codes(&item);
+}
+
+pub(crate) fn remove_item(id: ItemId) -> Option<StockItem> {
+    let old = STOCK_STORAGE.with(|storage| storage.borrow_mut().remove(&id))?;
+    certified::record_change(RecordKey::Item(id), Some(certified::leaf(&old)), None);
+    audit::record_item(Some(&old), None);
+    history::record(Some(&old), None);
+    pubsub::record_item(Some(&old), None);
+    ITEM_NAME_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&((old.warehouse_id, name_key(&old.item_name)), id))
+    });
+    WAREHOUSE_ITEMS.with(|index| index.borrow_mut().remove(&(old.warehouse_id, id)));
+    ITEMS_BY_CREATOR.with(|index| index.borrow_mut().remove(&(MemberKey(old.created_by), id)));
+    if let Some(category_id) = old.category_id {
+        ITEMS_BY_CATEGORY.with(|index| index.borrow_mut().remove(&(category_id, id)));
+    }
+    if let Some(bin_id) = old.bin_id {
+        ITEMS_BY_BIN.with(|index| index.borrow_mut().remove(&(bin_id, id)));
+    }
+    LOW_STOCK.with(|index| index.borrow_mut().remove(&(old.warehouse_id, id)));
+    remove_codes(&old);
+    search::remove_item((old.org_id, name_key(&old.item_name)), id);
+    totals::record_item(Some(&old), None);
+    pricing::clear_item(id);
+    reservation::clear_item(id);
+    Some(old)
+}
+
+// Callers check the codes are free with codes::ensure_codes_free
+fn insert_codes(item: &StockItem) {
+    let key = |code: &String| (item.warehouse_id, code_key(code));
+    SKU_INDEX.with(|index| {
+        if let Some(sku) = &item.sku {
+            index.borrow_mut().insert(key(sku), item.item_id);
+        }
+    });
+    BARCODE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for barcode in &item.barcodes {
+            index.insert(key(barcode), item.item_id);
+        }
+    });
+}
+
+fn remove_codes(item: &StockItem) {
+    let key = |code: &String| (item.warehouse_id, code_key(code));
+    SKU_INDEX.with(|index| {
+        if let Some(sku) = &item.sku {
+            index.borrow_mut().remove(&key(sku));
+        }
+    });
+    BARCODE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for barcode in &item.barcodes {
+            index.remove(&key(barcode));
+        }
+    });
+}
+
+// Rebuilds both name indexes, for when name_key changes
+pub(crate) fn rebuild_name_indexes() {
+    WAREHOUSE_NAME_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<WarehouseNameKey> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+    ITEM_NAME_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<ItemNameKey> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+    ensure_indexes();
+}
+
+// Populates the secondary indexes from the primary maps when they are missing,
+// i.e. on the first upgrade after they were introduced
+pub(crate) fn ensure_indexes() {
+    let needs_rebuild = WAREHOUSE_NAME_INDEX.with(|index| index.borrow().is_empty())
+        && WAREHOUSE_STORAGE.with(|storage| !storage.borrow().is_empty());
+    if needs_rebuild {
+        WAREHOUSE_STORAGE.with(|storage| {
+            WAREHOUSE_NAME_INDEX.with(|index| {
+                let mut index = index.borrow_mut();
+                for (id, warehouse) in storage.borrow().iter() {
+                    index.insert(((warehouse.org_id, name_key(&warehouse.name)), id), ());
+                }
+            })
+        });
+    }
+
+    let needs_rebuild = ITEM_NAME_INDEX.with(|index| index.borrow().is_empty())
+        && STOCK_STORAGE.with(|storage| !storage.borrow().is_empty());
+    if needs_rebuild {
+        STOCK_STORAGE.with(|storage| {
+            ITEM_NAME_INDEX.with(|index| {
+                let mut index = index.borrow_mut();
+                for (id, item) in storage.borrow().iter() {
+                    index.insert(((item.warehouse_id, name_key(&item.item_name)), id), ());
+                }
+            })
+        });
+    }
+
+    let needs_rebuild = WAREHOUSE_ITEMS.with(|index| index.borrow().is_empty())
+        && STOCK_STORAGE.with(|storage| !storage.borrow().is_empty());
+    if needs_rebuild {
+        STOCK_STORAGE.with(|storage| {
+            WAREHOUSE_ITEMS.with(|index| {
+                let mut index = index.borrow_mut();
+                for (id, item) in storage.borrow().iter() {
+                    index.insert((item.warehouse_id, id), ());
+                }
+            })
+        });
+    }
+
+    let needs_rebuild = ORG_WAREHOUSES.with(|index| index.borrow().is_empty())
+        && WAREHOUSE_STORAGE.with(|storage| !storage.borrow().is_empty());
+    if needs_rebuild {
+        WAREHOUSE_STORAGE.with(|storage| {
+            ORG_WAREHOUSES.with(|index| {
+                let mut index = index.borrow_mut();
+                for (id, warehouse) in storage.borrow().iter() {
+                    index.insert((warehouse.org_id, id), ());
+                }
+            })
+        });
+    }
+
+    let needs_rebuild = ITEMS_BY_CREATOR.with(|index| index.borrow().is_empty())
+        && STOCK_STORAGE.with(|storage| !storage.borrow().is_empty());
+    if needs_rebuild {
+        STOCK_STORAGE.with(|storage| {
+            ITEMS_BY_CREATOR.with(|index| {
+                let mut index = index.borrow_mut();
+                for (id, item) in storage.borrow().iter() {
+                    index.insert((MemberKey(item.created_by), id), ());
+                }
+            })
+        });
+    }
+}
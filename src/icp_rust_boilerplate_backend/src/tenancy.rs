@@ -0,0 +1,102 @@
+// Every warehouse and stock item belongs to one organization. Callers only ever see
+// records of their own organization: lookups of another tenant's records behave exactly
+// like lookups of records that do not exist. The records created before tenancy live in
+// DEFAULT_ORG, which migration 4 gave a record and an Owner. Principals without a
+// membership are in NO_ORG, which holds no records and may make no updates but the ones
+// that join an organization; controllers without one act in DEFAULT_ORG.
+use crate::organization::{self, Role};
+use crate::{idempotency, Error, MutationOptions, _ensure_admin, MEMBERSHIPS, MEMBER_ROLES};
+use candid::Principal;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+pub(crate) const DEFAULT_ORG: u64 = 0;
+pub(crate) const NO_ORG: u64 = u64::MAX; // Never the id of an organization record
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct MemberKey(pub(crate) Principal);
+
+impl Default for MemberKey {
+    fn default() -> Self {
+        MemberKey(Principal::anonymous())
+    }
+}
+
+impl Storable for MemberKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        MemberKey(Principal::from_slice(&bytes))
+    }
+}
+
+impl BoundedStorable for MemberKey {
+    const MAX_SIZE: u32 = 29; // Longest principal
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_my_organization() -> Option<u64> {
+    Some(caller_org()).filter(|org_id| *org_id != NO_ORG)
+}
+
+// Moves a principal into an organization as a Member, or out of every organization with None.
+// For controllers; organizations manage their own members in organization.rs.
+#[ic_cdk::update]
+fn assign_organization(
+    principal: Principal,
+    org_id: Option<u64>,
+    options: Option<MutationOptions>,
+) -> Result<(), Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("assign_organization", options, || {
+        _ensure_admin()?;
+        let membership = match org_id {
+            Some(org_id) => {
+                if organization::organization(org_id).is_none() {
+                    return Err(Error::not_found("organization", org_id));
+                }
+                // A member that is moved within its own organization keeps its role
+                let role = organization::role_of(org_id, principal).unwrap_or(Role::Member);
+                Some((org_id, role))
+            }
+            None => None,
+        };
+        set_membership(principal, membership);
+        Ok(())
+    })
+}
+
+pub(crate) fn caller_org() -> u64 {
+    let caller = crate::env::caller();
+    match org_of(caller) {
+        NO_ORG if crate::env::is_controller(&caller) => DEFAULT_ORG,
+        org_id => org_id,
+    }
+}
+
+pub(crate) fn org_of(principal: Principal) -> u64 {
+    MEMBERSHIPS
+        .with(|members| members.borrow().get(&MemberKey(principal)))
+        .unwrap_or(NO_ORG)
+}
+
+// The only writer of MEMBERSHIPS and MEMBER_ROLES, which keeps them in step
+pub(crate) fn set_membership(principal: Principal, membership: Option<(u64, Role)>) {
+    let key = MemberKey(principal);
+    let previous = MEMBERSHIPS.with(|members| match membership {
+        Some((org_id, _)) => members.borrow_mut().insert(key.clone(), org_id),
+        None => members.borrow_mut().remove(&key),
+    });
+    MEMBER_ROLES.with(|roles| {
+        let mut roles = roles.borrow_mut();
+        if let Some(previous) = previous {
+            roles.remove(&(previous, key.clone()));
+        }
+        if let Some((org_id, role)) = membership {
+            roles.insert((org_id, key), role);
+        }
+    });
+}
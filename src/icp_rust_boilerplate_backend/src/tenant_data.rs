@@ -0,0 +1,155 @@
+// Export and removal of everything one organization owns, for customers leaving the
+// platform. Both work in chunks: the export is paged with a cursor, and deletion is
+// repeated until it reports `complete`, at which point the organization's totals are
+// zero and its record, members, invitations, quotas, feature settings, categories, unit
+// conversions, webhooks, reservations, purchase orders, sales orders, deleted items,
+// event subscriptions and scheduled reports are gone.
+// Stored idempotency responses are not searched; they expire with the retention window.
+use crate::ids::{ItemId, WarehouseId};
+use crate::organization::{self, Member, Organization, Role};
+use crate::settings::page_size;
+use crate::tenancy::DEFAULT_ORG;
+use crate::totals::org_totals;
+use crate::{alerts, category, counting, feature, idempotency, purchasing, pubsub, purge, quota, reports, reservation, sales, store, trash, units, Error, MutationOptions, StockItem, Warehouse, WAREHOUSE_STORAGE};
+
+const DELETE_BATCH: usize = 10; // Warehouses deleted per call; their stock is purged by the timer
+
+// Where the next export page starts
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ExportCursor {
+    warehouse_id: WarehouseId,
+    warehouse_sent: bool,       // The warehouse record was on an earlier page
+    after_item_id: Option<ItemId>, // Last item of the warehouse on an earlier page
+}
+
+// Warehouses in id order, each followed by its items in id order. The organization and
+// its members are only on the first page.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct TenantExportPage {
+    organization: Option<Organization>,
+    members: Vec<Member>,
+    warehouses: Vec<Warehouse>,
+    items: Vec<StockItem>,
+    next: Option<ExportCursor>, // None on the last page
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct TenantDeletion {
+    org_id: u64,
+    warehouses_deleted: u64, // By this call
+    warehouses_remaining: u64,
+    items_remaining: u64, // Including stock of deleted warehouses that is still being purged
+    complete: bool,
+}
+
+// `limit` counts warehouses and items together
+#[ic_cdk::query]
+fn export_tenant_data(org_id: u64, cursor: Option<ExportCursor>, limit: Option<u64>) -> Result<TenantExportPage, Error> {
+    let limit = page_size(limit)?;
+    let organization = tenant(org_id)?;
+
+    let mut page = TenantExportPage::default();
+    if cursor.is_none() {
+        page.organization = Some(organization);
+        page.members = organization::members(org_id);
+    }
+
+    let start = cursor.as_ref().map_or(WarehouseId(0), |cursor| cursor.warehouse_id);
+    let mut remaining = limit;
+    for warehouse_id in store::warehouse_ids_in_org(org_id).into_iter().filter(|id| *id >= start) {
+        let resumed = cursor.as_ref().filter(|cursor| cursor.warehouse_id == warehouse_id);
+        let mut after_item_id = resumed.and_then(|cursor| cursor.after_item_id);
+
+        if !resumed.is_some_and(|cursor| cursor.warehouse_sent) {
+            if remaining == 0 {
+                page.next = Some(ExportCursor {
+                    warehouse_id,
+                    warehouse_sent: false,
+                    after_item_id: None,
+                });
+                break;
+            }
+            if let Some(warehouse) = WAREHOUSE_STORAGE.with(|storage| storage.borrow().get(&warehouse_id)) {
+                page.warehouses.push(warehouse);
+            }
+            remaining -= 1;
+        }
+
+        // One extra item tells whether the warehouse continues on the next page
+        let mut items = store::items_in_warehouse(warehouse_id, after_item_id, remaining + 1);
+        let more = items.len() > remaining;
+        items.truncate(remaining);
+        remaining -= items.len();
+        if let Some(last) = items.last() {
+            after_item_id = Some(last.item_id);
+        }
+        page.items.extend(items);
+        if more {
+            page.next = Some(ExportCursor {
+                warehouse_id,
+                warehouse_sent: true,
+                after_item_id,
+            });
+            break;
+        }
+    }
+
+    Ok(page)
+}
+
+// Call repeatedly until `complete`; each call deletes up to DELETE_BATCH warehouses
+#[ic_cdk::update]
+fn delete_tenant_data(org_id: u64, options: Option<MutationOptions>) -> Result<TenantDeletion, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("delete_tenant_data", options, || _delete_tenant_data(org_id))
+}
+
+fn _delete_tenant_data(org_id: u64) -> Result<TenantDeletion, Error> {
+    tenant(org_id)?;
+
+    let batch: Vec<WarehouseId> = store::warehouse_ids_in_org(org_id).into_iter().take(DELETE_BATCH).collect();
+    for warehouse_id in &batch {
+        counting::release_warehouse(*warehouse_id);
+        store::remove_warehouse(*warehouse_id);
+        purge::purge_warehouse(*warehouse_id);
+    }
+
+    let totals = org_totals(org_id);
+    let complete = totals.warehouse_count == 0 && totals.item_count == 0;
+    if complete {
+        feature::clear_org(org_id);
+        category::clear_org(org_id);
+        units::clear_org(org_id);
+        alerts::clear_org(org_id);
+        reservation::clear_org(org_id);
+        purchasing::clear_org(org_id);
+        sales::clear_org(org_id);
+        trash::clear_org(org_id);
+        pubsub::clear_org(org_id);
+        reports::clear_org(org_id);
+        quota::clear_org(org_id);
+        organization::remove_organization(org_id);
+    }
+
+    Ok(TenantDeletion {
+        org_id,
+        warehouses_deleted: batch.len() as u64,
+        warehouses_remaining: totals.warehouse_count,
+        items_remaining: totals.item_count,
+        complete,
+    })
+}
+
+// Only organizations with a record can be exported or deleted, by their Owners or by controllers
+fn tenant(org_id: u64) -> Result<Organization, Error> {
+    if org_id == DEFAULT_ORG {
+        return Err(Error::invalid_input(
+            "org_id",
+            "NOT_SUPPORTED",
+            "The default organization cannot be exported or deleted".to_string(),
+        ));
+    }
+    // Checked first, so callers cannot probe which organizations exist
+    organization::ensure_role_or_controller(org_id, Role::Owner)?;
+    organization::organization(org_id).ok_or_else(|| Error::not_found("organization", org_id))
+}
@@ -0,0 +1,225 @@
+// Counts and quantity sums kept up to date by store.rs on every write, so summary
+// queries never iterate the stock map. Totals exist for the whole canister, per
+// organization and per warehouse.
+use crate::ids::WarehouseId;
+use crate::tenancy::caller_org;
+use crate::{Error, StockItem, ORG_TOTALS, STOCK_STORAGE, TOTALS, WAREHOUSE_STORAGE, WAREHOUSE_TOTALS};
+use crate::_get_warehouse;
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) struct InventoryTotals {
+    pub(crate) warehouse_count: u64,
+    pub(crate) item_count: u64,
+    pub(crate) total_quantity: u128, // Sum over all items, which can exceed u64
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) struct WarehouseTotals {
+    pub(crate) item_count: u64,
+    total_quantity: u128,
+}
+
+impl Storable for InventoryTotals {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InventoryTotals {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for WarehouseTotals {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for WarehouseTotals {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Totals of the caller's organization
+#[ic_cdk::query]
+fn get_inventory_totals() -> InventoryTotals {
+    org_totals(caller_org())
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_warehouse_totals(warehouse_id: WarehouseId) -> Result<WarehouseTotals, Error> {
+    if _get_warehouse(&warehouse_id).is_none() {
+        return Err(Error::not_found("warehouse", warehouse_id));
+    }
+    Ok(warehouse_totals(warehouse_id))
+}
+
+pub(crate) fn totals() -> InventoryTotals {
+    TOTALS.with(|cell| cell.borrow().get().clone())
+}
+
+pub(crate) fn org_totals(org_id: u64) -> InventoryTotals {
+    ORG_TOTALS.with(|map| map.borrow().get(&org_id).unwrap_or_default())
+}
+
+pub(crate) fn warehouse_totals(warehouse_id: WarehouseId) -> WarehouseTotals {
+    WAREHOUSE_TOTALS.with(|map| map.borrow().get(&warehouse_id).unwrap_or_default())
+}
+
+// Called with the previous and the new version of an item; either may be absent
+pub(crate) fn record_item(old: Option<&StockItem>, new: Option<&StockItem>) {
+    if let Some(old) = old {
+        // Saturating, so drifted totals (see check_integrity) cannot trap a write
+        let quantity = old.quantity as u128;
+        update_totals(old.org_id, |t| {
+            t.item_count = t.item_count.saturating_sub(1);
+            t.total_quantity = t.total_quantity.saturating_sub(quantity);
+        });
+        update_warehouse(old.warehouse_id, |w| {
+            w.item_count = w.item_count.saturating_sub(1);
+            w.total_quantity = w.total_quantity.saturating_sub(quantity);
+        });
+    }
+    if let Some(new) = new {
+        let quantity = new.quantity as u128;
+        update_totals(new.org_id, |t| {
+            t.item_count += 1;
+            t.total_quantity += quantity;
+        });
+        update_warehouse(new.warehouse_id, |w| {
+            w.item_count += 1;
+            w.total_quantity += quantity;
+        });
+    }
+}
+
+pub(crate) fn record_warehouse_added(org_id: u64) {
+    update_totals(org_id, |t| t.warehouse_count += 1);
+}
+
+pub(crate) fn record_warehouse_removed(org_id: u64) {
+    update_totals(org_id, |t| t.warehouse_count = t.warehouse_count.saturating_sub(1));
+}
+
+// Recomputes everything from the primary maps
+pub(crate) fn rebuild() {
+    let (totals, per_org, per_warehouse) = compute();
+    set_totals(totals);
+    ORG_TOTALS.with(|map| {
+        let mut map = map.borrow_mut();
+        let stale: Vec<u64> = map.iter().map(|(id, _)| id).collect();
+        for id in stale {
+            map.remove(&id);
+        }
+        for (id, org) in per_org {
+            map.insert(id, org);
+        }
+    });
+    WAREHOUSE_TOTALS.with(|map| {
+        let mut map = map.borrow_mut();
+        let stale: Vec<WarehouseId> = map.iter().map(|(id, _)| id).collect();
+        for id in stale {
+            map.remove(&id);
+        }
+        for (id, warehouse) in per_warehouse {
+            map.insert(id, warehouse);
+        }
+    });
+}
+
+// Populates the totals on the first upgrade after they (or the per-org totals) were introduced
+pub(crate) fn ensure_totals() {
+    let missing = (totals() == InventoryTotals::default() || ORG_TOTALS.with(|map| map.borrow().is_empty()))
+        && WAREHOUSE_STORAGE.with(|storage| !storage.borrow().is_empty());
+    if missing {
+        rebuild();
+    }
+}
+
+// True when the cached values match a full recount
+pub(crate) fn verify() -> bool {
+    let (expected, per_org, per_warehouse) = compute();
+    let cached_orgs: BTreeMap<u64, InventoryTotals> = ORG_TOTALS.with(|map| map.borrow().iter().collect());
+    let cached_warehouses: BTreeMap<WarehouseId, WarehouseTotals> =
+        WAREHOUSE_TOTALS.with(|map| map.borrow().iter().collect());
+    totals() == expected && cached_orgs == per_org && cached_warehouses == per_warehouse
+}
+
+type Recount = (InventoryTotals, BTreeMap<u64, InventoryTotals>, BTreeMap<WarehouseId, WarehouseTotals>);
+
+fn compute() -> Recount {
+    let mut totals = InventoryTotals::default();
+    let mut per_org: BTreeMap<u64, InventoryTotals> = BTreeMap::new();
+    let mut per_warehouse: BTreeMap<WarehouseId, WarehouseTotals> = BTreeMap::new();
+
+    WAREHOUSE_STORAGE.with(|storage| {
+        for (_, warehouse) in storage.borrow().iter() {
+            totals.warehouse_count += 1;
+            per_org.entry(warehouse.org_id).or_default().warehouse_count += 1;
+        }
+    });
+
+    STOCK_STORAGE.with(|storage| {
+        for (_, item) in storage.borrow().iter() {
+            let quantity = item.quantity as u128;
+            totals.item_count += 1;
+            totals.total_quantity += quantity;
+            let org = per_org.entry(item.org_id).or_default();
+            org.item_count += 1;
+            org.total_quantity += quantity;
+            let warehouse = per_warehouse.entry(item.warehouse_id).or_default();
+            warehouse.item_count += 1;
+            warehouse.total_quantity += quantity;
+        }
+    });
+
+    (totals, per_org, per_warehouse)
+}
+
+// Applies the change to the canister-wide totals and to those of the organization
+fn update_totals(org_id: u64, change: impl Fn(&mut InventoryTotals)) {
+    let mut totals = totals();
+    change(&mut totals);
+    set_totals(totals);
+
+    ORG_TOTALS.with(|map| {
+        let mut map = map.borrow_mut();
+        let mut org = map.get(&org_id).unwrap_or_default();
+        change(&mut org);
+        if org == InventoryTotals::default() {
+            map.remove(&org_id);
+        } else {
+            map.insert(org_id, org);
+        }
+    });
+}
+
+fn update_warehouse(warehouse_id: WarehouseId, change: impl FnOnce(&mut WarehouseTotals)) {
+    WAREHOUSE_TOTALS.with(|map| {
+        let mut map = map.borrow_mut();
+        let mut warehouse = map.get(&warehouse_id).unwrap_or_default();
+        change(&mut warehouse);
+        if warehouse.item_count == 0 {
+            map.remove(&warehouse_id);
+        } else {
+            map.insert(warehouse_id, warehouse);
+        }
+    });
+}
+
+fn set_totals(totals: InventoryTotals) {
+    TOTALS.with(|cell| cell.borrow_mut().set(totals)).unwrap();
+}
@@ -0,0 +1,185 @@
+// Items that delete_item emptied are kept here for `trash_ttl_secs` (see settings.rs), so
+// a deletion made by mistake can be undone with restore_item. The item is kept as it was
+// before the deletion, quantity included. An hourly timer removes expired entries while
+// there are any; it pauses in maintenance mode, like the purge. Entries whose warehouse is
+// purged go with it, since there is nothing left to restore them into.
+use crate::ids::{ItemId, WarehouseId};
+use crate::tenancy::caller_org;
+use crate::{bins, category, codes, counting, idempotency, maintenance, quota, settings, staffing, store};
+use crate::{Error, MutationOptions, StockItem, TRASH, TRASH_EXPIRY, TRASH_TIMER};
+use crate::{_check_version, _ensure_not_archived, _ensure_room, _get_warehouse};
+use candid::{Decode, Encode, Principal};
+use crate::env::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::time::Duration;
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const PURGE_BATCH: usize = 500; // Entries removed per tick
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TrashedItem {
+    item: StockItem,
+    deleted_at: u64,
+    deleted_by: Principal,
+    expires_at: u64,
+}
+
+impl Storable for TrashedItem {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TrashedItem {
+    const MAX_SIZE: u32 = StockItem::MAX_SIZE + 128; // A StockItem and the deletion fields
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Deleted items of the caller's organization in item id order
+#[ic_cdk::query]
+fn list_deleted_items() -> Vec<TrashedItem> {
+    let org_id = caller_org();
+    TRASH.with(|trash| {
+        trash
+            .borrow()
+            .iter()
+            .map(|(_, trashed)| trashed)
+            .filter(|trashed| trashed.item.org_id == org_id)
+            .collect()
+    })
+}
+
+// Puts a deleted item back into its warehouse, under its old id. The name and codes must
+// still be free there; a category deleted in the meantime is dropped.
+#[ic_cdk::update]
+fn restore_item(item_id: ItemId, options: Option<MutationOptions>) -> Result<StockItem, Error> {
+    let expected_version = idempotency::expected_version(&options);
+    idempotency::run("restore_item", options, || _restore_item(item_id, expected_version))
+}
+
+fn _restore_item(item_id: ItemId, expected_version: Option<u64>) -> Result<StockItem, Error> {
+    let trashed = TRASH
+        .with(|trash| trash.borrow().get(&item_id))
+        .filter(|trashed| trashed.item.org_id == caller_org())
+        .ok_or_else(|| Error::not_found("deleted_item", item_id))?;
+    let mut item = trashed.item;
+    _check_version("item", item_id, item.version, expected_version)?;
+
+    let warehouse = _get_warehouse(&item.warehouse_id).ok_or_else(|| Error::not_found("warehouse", item.warehouse_id))?;
+    counting::ensure_unfrozen(warehouse.id)?;
+    _ensure_not_archived(&warehouse)?;
+    staffing::ensure_on_shift(warehouse.id, warehouse.org_id, staffing::Duty::Receiving)?;
+    if let Some(existing_id) = store::item_id_by_name(item.warehouse_id, &item.item_name) {
+        return Err(Error::conflict(
+            "item",
+            Some(existing_id.into()),
+            "DUPLICATE_ITEM_NAME",
+            format!(
+                "Item with id={} in warehouse_id={} already uses the name {:?}",
+                existing_id, item.warehouse_id, item.item_name
+            ),
+        ));
+    }
+    codes::ensure_codes_free(&item)?;
+    quota::ensure_item_quota(item.org_id)?;
+    _ensure_room(item.warehouse_id, 1)?;
+
+    item.category_id = item.category_id.filter(|id| category::category(*id).is_some());
+    item.bin_id = item.bin_id.filter(|id| bins::exists_in(*id, item.warehouse_id));
+    item.updated_at = Some(time());
+    item.updated_by = Some(crate::env::caller());
+    item.version += 1;
+
+    remove(item_id);
+    store::insert_item(item.clone());
+    Ok(item)
+}
+
+// Keeps `item` as it was before delete_item emptied it
+pub(crate) fn put(item: StockItem) {
+    let now = time();
+    let expires_at = now.saturating_add(settings::trash_ttl_ns());
+    let item_id = item.item_id;
+    remove(item_id);
+    TRASH.with(|trash| {
+        trash.borrow_mut().insert(
+            item_id,
+            TrashedItem {
+                item,
+                deleted_at: now,
+                deleted_by: crate::env::caller(),
+                expires_at,
+            },
+        )
+    });
+    TRASH_EXPIRY.with(|expiry| expiry.borrow_mut().insert((expires_at, item_id), ()));
+    resume();
+}
+
+pub(crate) fn clear_org(org_id: u64) {
+    clear(|item| item.org_id == org_id);
+}
+
+pub(crate) fn clear_warehouse(warehouse_id: WarehouseId) {
+    clear(|item| item.warehouse_id == warehouse_id);
+}
+
+// Timers do not survive upgrades; started again while the trash holds items
+pub(crate) fn resume() {
+    let idle = TRASH_TIMER.with(|timer| timer.borrow().is_none());
+    if idle && TRASH.with(|trash| !trash.borrow().is_empty()) {
+        let timer = ic_cdk_timers::set_timer_interval(PURGE_INTERVAL, purge_expired);
+        TRASH_TIMER.with(|current| *current.borrow_mut() = Some(timer));
+    }
+}
+
+fn purge_expired() {
+    if TRASH.with(|trash| trash.borrow().is_empty()) {
+        if let Some(timer) = TRASH_TIMER.with(|timer| timer.borrow_mut().take()) {
+            ic_cdk_timers::clear_timer(timer);
+        }
+        return;
+    }
+    if maintenance::is_enabled() {
+        return;
+    }
+
+    let now = time();
+    let expired: Vec<ItemId> = TRASH_EXPIRY.with(|expiry| {
+        expiry
+            .borrow()
+            .iter()
+            .take(PURGE_BATCH)
+            .take_while(|((expires_at, _), _)| *expires_at <= now)
+            .map(|((_, item_id), _)| item_id)
+            .collect()
+    });
+    for item_id in expired {
+        remove(item_id);
+    }
+}
+
+fn clear(matches: impl Fn(&StockItem) -> bool) {
+    let item_ids: Vec<ItemId> = TRASH.with(|trash| {
+        trash
+            .borrow()
+            .iter()
+            .filter(|(_, trashed)| matches(&trashed.item))
+            .map(|(item_id, _)| item_id)
+            .collect()
+    });
+    for item_id in item_ids {
+        remove(item_id);
+    }
+}
+
+fn remove(item_id: ItemId) {
+    if let Some(trashed) = TRASH.with(|trash| trash.borrow_mut().remove(&item_id)) {
+        TRASH_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&(trashed.expires_at, item_id)));
+    }
+}
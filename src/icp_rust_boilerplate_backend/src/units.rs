@@ -0,0 +1,201 @@
+// Units of measure. Every item stores its quantity in one unit, chosen when it is first
+// received. Stock received in another unit is converted first: a box of N holds N pieces,
+// and other pairs convert through the organization's conversion table, where each entry
+// says how many `to` one `from` is. A conversion only applies when the result is whole.
+use crate::organization::{self, Role};
+use crate::tenancy::caller_org;
+use crate::{idempotency, Error, MutationOptions, UNIT_CONVERSIONS};
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+pub(crate) const MAX_CONVERSIONS: usize = 32; // Per organization
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum Unit {
+    #[default]
+    Piece,
+    Kilogram,
+    Liter,
+    BoxOf(u64), // A box of this many pieces
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Conversion {
+    from: Unit,
+    to: Unit,
+    factor: u64, // One `from` is this many `to`
+}
+
+// The conversion table of one organization
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ConversionTable {
+    conversions: Vec<Conversion>,
+}
+
+impl Storable for ConversionTable {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ConversionTable {
+    const MAX_SIZE: u32 = 2048; // MAX_CONVERSIONS entries
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_unit_conversions() -> Vec<Conversion> {
+    table(caller_org()).conversions
+}
+
+#[ic_cdk::query]
+fn convert_quantity(quantity: u64, from: Unit, to: Unit) -> Result<u64, Error> {
+    convert(caller_org(), quantity, from, to)
+}
+
+// Adds the conversion, or replaces the one between the same two units
+#[ic_cdk::update]
+fn set_unit_conversion(conversion: Conversion, options: Option<MutationOptions>) -> Result<Vec<Conversion>, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("set_unit_conversion", options, || _set_unit_conversion(conversion))
+}
+
+fn _set_unit_conversion(conversion: Conversion) -> Result<Vec<Conversion>, Error> {
+    validate_conversion(&conversion)?;
+    let org_id = caller_org();
+    organization::ensure_role_or_controller(org_id, Role::Admin)?;
+
+    let mut table = table(org_id);
+    table.conversions.retain(|other| !other.joins(conversion.from, conversion.to));
+    if table.conversions.len() >= MAX_CONVERSIONS {
+        return Err(Error::capacity_exceeded(
+            "TOO_MANY_ENTRIES",
+            MAX_CONVERSIONS as u64,
+            table.conversions.len() as u64 + 1,
+            format!("An organization has at most {} unit conversions", MAX_CONVERSIONS),
+        ));
+    }
+    table.conversions.push(conversion);
+
+    UNIT_CONVERSIONS.with(|tables| tables.borrow_mut().insert(org_id, table.clone()));
+    Ok(table.conversions)
+}
+
+#[ic_cdk::update]
+fn remove_unit_conversion(from: Unit, to: Unit, options: Option<MutationOptions>) -> Result<Vec<Conversion>, Error> {
+    idempotency::reject_expected_version(&options)?;
+    idempotency::run("remove_unit_conversion", options, || _remove_unit_conversion(from, to))
+}
+
+fn _remove_unit_conversion(from: Unit, to: Unit) -> Result<Vec<Conversion>, Error> {
+    let org_id = caller_org();
+    organization::ensure_role_or_controller(org_id, Role::Admin)?;
+
+    let mut table = table(org_id);
+    let before = table.conversions.len();
+    table.conversions.retain(|other| !other.joins(from, to));
+    if table.conversions.len() == before {
+        return Err(no_conversion(from, to));
+    }
+
+    UNIT_CONVERSIONS.with(|tables| tables.borrow_mut().insert(org_id, table.clone()));
+    Ok(table.conversions)
+}
+
+// `quantity` in `from`, expressed in `to`
+pub(crate) fn convert(org_id: u64, quantity: u64, from: Unit, to: Unit) -> Result<u64, Error> {
+    let (numerator, denominator) = match (from, to) {
+        _ if from == to => (1, 1),
+        (Unit::BoxOf(n), Unit::Piece) => (n, 1),
+        (Unit::Piece, Unit::BoxOf(n)) => (1, n),
+        (Unit::BoxOf(n), Unit::BoxOf(m)) => (n, m),
+        _ => {
+            let table = table(org_id);
+            let direct = table.conversions.iter().find(|c| c.from == from && c.to == to);
+            let reverse = table.conversions.iter().find(|c| c.from == to && c.to == from);
+            match (direct, reverse) {
+                (Some(conversion), _) => (conversion.factor, 1),
+                (None, Some(conversion)) => (1, conversion.factor),
+                (None, None) => return Err(no_conversion(from, to)),
+            }
+        }
+    };
+
+    let scaled = quantity as u128 * numerator as u128;
+    if !scaled.is_multiple_of(denominator as u128) {
+        return Err(Error::invalid_input(
+            "quantity",
+            "NOT_WHOLE_UNITS",
+            format!("{} {:?} is not a whole number of {:?}", quantity, from, to),
+        ));
+    }
+    u64::try_from(scaled / denominator as u128).map_err(|_| {
+        Error::invalid_input(
+            "quantity",
+            "VALUE_OUT_OF_RANGE",
+            format!("{} {:?} in {:?} exceeds the largest quantity", quantity, from, to),
+        )
+    })
+}
+
+pub(crate) fn validate_unit(field: &str, unit: Unit) -> Result<(), Error> {
+    if unit == Unit::BoxOf(0) {
+        return Err(Error::invalid_input(
+            field,
+            "VALUE_NOT_POSITIVE",
+            format!("{} must be a box of at least one piece", field),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_conversion(conversion: &Conversion) -> Result<(), Error> {
+    validate_unit("from", conversion.from)?;
+    validate_unit("to", conversion.to)?;
+    if conversion.from == conversion.to {
+        return Err(Error::invalid_input(
+            "to",
+            "SAME_UNIT",
+            "A conversion must be between two different units".to_string(),
+        ));
+    }
+    if conversion.factor == 0 {
+        return Err(Error::invalid_input(
+            "factor",
+            "VALUE_NOT_POSITIVE",
+            "factor must be greater than zero".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Removes the conversion table of an organization
+pub(crate) fn clear_org(org_id: u64) {
+    UNIT_CONVERSIONS.with(|tables| tables.borrow_mut().remove(&org_id));
+}
+
+impl Conversion {
+    // Whether this entry converts between `a` and `b`, in either direction
+    fn joins(&self, a: Unit, b: Unit) -> bool {
+        (self.from == a && self.to == b) || (self.from == b && self.to == a)
+    }
+}
+
+fn table(org_id: u64) -> ConversionTable {
+    UNIT_CONVERSIONS
+        .with(|tables| tables.borrow().get(&org_id))
+        .unwrap_or_default()
+}
+
+fn no_conversion(from: Unit, to: Unit) -> Error {
+    Error::invalid_input(
+        "unit",
+        "NO_CONVERSION",
+        format!("No conversion between {:?} and {:?} is defined", from, to),
+    )
+}
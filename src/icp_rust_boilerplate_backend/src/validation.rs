@@ -0,0 +1,133 @@
+use crate::{settings, units, Error, StockItemPayload, UpdateItemPayload, UpdateWarehousePayload, WarehousePayload};
+use unicode_normalization::UnicodeNormalization;
+
+pub(crate) const MAX_NAME_LENGTH: usize = 100; // In characters; the setting can only lower it
+
+pub(crate) const MAX_ADDRESS_LENGTH: usize = 200; // In characters
+
+// Normalizes the name and checks it is non-empty, free of control characters and within
+// the max_name_length setting
+pub(crate) fn validate_name(field: &str, name: &str) -> Result<String, Error> {
+    validate_text(field, name, settings::max_name_length())
+}
+
+// A warehouse address, normalized like a name
+pub(crate) fn validate_address(field: &str, address: &str) -> Result<String, Error> {
+    validate_text(field, address, MAX_ADDRESS_LENGTH)
+}
+
+// Both or neither, in degrees: latitude within ±90, longitude within ±180
+pub(crate) fn validate_location(latitude: Option<f64>, longitude: Option<f64>) -> Result<(), Error> {
+    match (latitude, longitude) {
+        (Some(latitude), Some(longitude)) => {
+            validate_degrees("latitude", latitude, 90.0)?;
+            validate_degrees("longitude", longitude, 180.0)
+        }
+        (None, None) => Ok(()),
+        (Some(_), None) => Err(invalid("longitude", "MISSING_VALUE", "must be given together with latitude")),
+        (None, Some(_)) => Err(invalid("latitude", "MISSING_VALUE", "must be given together with longitude")),
+    }
+}
+
+fn validate_degrees(field: &str, degrees: f64, limit: f64) -> Result<(), Error> {
+    if !degrees.is_finite() || degrees.abs() > limit {
+        return Err(invalid(
+            field,
+            "VALUE_OUT_OF_RANGE",
+            &format!("must be between -{} and {} degrees, got {}", limit, limit, degrees),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_text(field: &str, text: &str, max_length: usize) -> Result<String, Error> {
+    // Checked before normalizing, which would turn a line break or tab into a space
+    if let Some(control) = text.chars().find(|c| c.is_control()) {
+        return Err(invalid(
+            field,
+            "CONTROL_CHARACTER",
+            &format!("must not contain control characters, found {:?}", control),
+        ));
+    }
+    let text = normalize_name(text);
+
+    if text.is_empty() {
+        return Err(invalid(field, "EMPTY_VALUE", "must not be empty"));
+    }
+
+    let length = text.chars().count();
+    if length > max_length {
+        return Err(invalid(
+            field,
+            "VALUE_TOO_LONG",
+            &format!("must be at most {} characters, got {}", max_length, length),
+        ));
+    }
+
+    Ok(text)
+}
+
+// NFC, trimmed, with every run of whitespace collapsed into a single space, so that
+// composed and decomposed spellings of the same name (e.g. "é" vs "e\u{301}") compare equal
+pub(crate) fn normalize_name(name: &str) -> String {
+    name.nfc()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub(crate) fn validate_quantity(field: &str, quantity: u64) -> Result<(), Error> {
+    if quantity == 0 {
+        return Err(invalid(field, "VALUE_NOT_POSITIVE", "must be greater than zero"));
+    }
+    Ok(())
+}
+
+// Returns the payload with its name normalized for storage
+pub(crate) fn validate_warehouse_payload(payload: WarehousePayload) -> Result<WarehousePayload, Error> {
+    validate_location(payload.latitude, payload.longitude)?;
+
+    Ok(WarehousePayload {
+        name: validate_name("name", &payload.name)?,
+        address: payload.address.map(|address| validate_address("address", &address)).transpose()?,
+        ..payload
+    })
+}
+
+pub(crate) fn validate_update_warehouse_payload(payload: UpdateWarehousePayload) -> Result<UpdateWarehousePayload, Error> {
+    validate_location(payload.latitude, payload.longitude)?;
+
+    Ok(UpdateWarehousePayload {
+        name: payload.name.map(|name| validate_name("name", &name)).transpose()?,
+        address: payload.address.map(|address| validate_address("address", &address)).transpose()?,
+        ..payload
+    })
+}
+
+pub(crate) fn validate_stock_item_payload(payload: StockItemPayload) -> Result<StockItemPayload, Error> {
+    validate_quantity("quantity", payload.quantity)?;
+    if let Some(unit) = payload.unit {
+        units::validate_unit("unit", unit)?;
+    }
+
+    Ok(StockItemPayload {
+        item_name: validate_name("item_name", &payload.item_name)?,
+        ..payload
+    })
+}
+
+pub(crate) fn validate_update_item_payload(payload: UpdateItemPayload) -> Result<UpdateItemPayload, Error> {
+    if let Some(quantity) = payload.quantity {
+        validate_quantity("quantity", quantity)?;
+    }
+
+    Ok(UpdateItemPayload {
+        item_name: payload.item_name.map(|name| validate_name("item_name", &name)).transpose()?,
+        ..payload
+    })
+}
+
+fn invalid(field: &str, code: &str, problem: &str) -> Error {
+    Error::invalid_input(field, code, format!("{} {}", field, problem))
+}